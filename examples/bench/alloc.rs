@@ -0,0 +1,140 @@
+//! Allocations made by the event loop
+//!
+//! Counts the heap allocations of every `run_once` iteration with a global
+//! allocator wrapper, once with the loop idle and once echoing messages
+//! for connected clients. An idle loop should not allocate at all: the
+//! `epoll_wait` events buffer and the read buffers are reused.
+//!
+//! Usage: cargo run --release --example bench_alloc -- [iterations] [clients]
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    env,
+    io::{ErrorKind, Read, Write},
+    net::TcpStream,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use epoll_worker::{
+    Bytes, ClientId, EpollServer, EventHandler, HandlerAction, ListenerId, ServerConfig,
+    ServerContext,
+};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+// SAFETY: defers to the system allocator, only counting calls
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+struct EchoHandler;
+
+impl EventHandler for EchoHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::Reply(data))
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+/// Allocations per iteration of `iterations` calls to `step`
+fn measure(iterations: u64, mut step: impl FnMut()) -> f64 {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..iterations {
+        step();
+    }
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64 / iterations as f64
+}
+
+fn main() -> epoll_worker::Result<()> {
+    let mut args = env::args().skip(1);
+    let iterations: u64 = args.next().and_then(|n| n.parse().ok()).unwrap_or(10_000);
+    let client_count: usize = args.next().and_then(|n| n.parse().ok()).unwrap_or(16);
+
+    let mut server = EpollServer::new_with_config(
+        "127.0.0.1:0",
+        EchoHandler,
+        ServerConfig::default().max_events(1024),
+    )?;
+    let addr = server.local_addr()?;
+
+    let idle = measure(iterations, || {
+        server.run_once(Some(0)).unwrap();
+    });
+    println!("idle loop:  {idle:.2} allocations per iteration");
+
+    let mut clients: Vec<TcpStream> = (0..client_count)
+        .map(|_| TcpStream::connect(addr).unwrap())
+        .collect();
+    while server.metrics().active < client_count as u64 {
+        server.run_once(Some(10))?;
+    }
+
+    for client in &clients {
+        client.set_nonblocking(true)?;
+    }
+    let payload = [b'x'; 64];
+    let mut reply = [0u8; 64];
+    let mut received = vec![0; client_count];
+    let echo = measure(iterations, || {
+        for client in &mut clients {
+            client.write_all(&payload).unwrap();
+        }
+        // Step the loop until every client got its reply back
+        received.fill(0);
+        while received.iter().any(|&len| len < payload.len()) {
+            server.run_once(Some(10)).unwrap();
+            for (client, len) in clients.iter_mut().zip(&mut received) {
+                match client.read(&mut reply[*len..]) {
+                    Ok(read) => *len += read,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => panic!("echo client failed: {e}"),
+                }
+            }
+        }
+    });
+    println!("echo loop:  {echo:.2} allocations per round of {client_count} messages");
+    Ok(())
+}
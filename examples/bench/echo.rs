@@ -0,0 +1,59 @@
+//! Echo server for benchmarking
+//!
+//! Sends back every byte it receives, with logging kept off the hot path.
+//!
+//! Usage: cargo run --release --example bench_echo -- [addr]
+//! Load with: cargo run --release --example bench_loadgen -- echo 127.0.0.1:9000
+
+use std::env;
+
+use epoll_worker::{
+    Bytes, ClientId, EpollServer, EventHandler, HandlerAction, ListenerId, ServerConfig,
+    ServerContext,
+};
+
+struct EchoHandler;
+
+impl EventHandler for EchoHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &std::net::TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::Reply(data))
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+fn main() -> epoll_worker::Result<()> {
+    env_logger::init();
+
+    let addr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:9000".to_string());
+    let config = ServerConfig::default().nodelay(true).wait_timeout(None);
+    let mut server = EpollServer::new_with_config(addr, EchoHandler, config)?;
+    server.run(None)
+}
@@ -0,0 +1,245 @@
+//! Broadcast fan-out across worker loops
+//!
+//! Connects `clients` loopback clients spread over `workers` worker loops,
+//! then broadcasts `rounds` messages to all of them twice:
+//!
+//! - per client: one `ServerHandle::send_to` per client, each a command of
+//!   its own with a copy of the payload and a wake-up of its loop
+//! - two-level: one `Broadcaster::broadcast`, a command per worker sharing
+//!   one payload, which each loop then queues to its own clients
+//!
+//! and reports, for each, how long queueing a message takes the sender
+//! and how long until every client has read it (medians over the rounds).
+//!
+//! Each client holds two fds: raise the limit first (`ulimit -n 250000`).
+//! The clients connect through one listener per 20000 of them so
+//! loopback does not run out of ephemeral ports.
+//!
+//! Usage: cargo run --release --example bench_fanout -- [clients] [workers] [rounds] [payload_size]
+
+use std::{
+    env,
+    io::{Read, Result},
+    net::{TcpListener, TcpStream},
+    process,
+    sync::{
+        Arc, Mutex,
+        mpsc::{Receiver, channel},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use epoll_worker::{
+    Broadcaster, Bytes, ClientId, EpollServer, EventHandler, HandlerAction, ListenerId,
+    ServerConfig, ServerContext, ServerHandle,
+};
+
+/// Clients connecting through each listener
+const CLIENTS_PER_LISTENER: usize = 20_000;
+
+/// Threads reading what the clients receive
+const READERS: usize = 8;
+
+/// Records the ids of its clients, for the per-client sends
+struct RecordingHandler {
+    clients: Arc<Mutex<Vec<ClientId>>>,
+}
+
+impl EventHandler for RecordingHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        self.clients.lock().unwrap().push(client_id);
+        Ok(())
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::None)
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+/// A running worker loop and the ids of its clients
+struct Worker {
+    handle: ServerHandle,
+    clients: Arc<Mutex<Vec<ClientId>>>,
+    thread: thread::JoinHandle<epoll_worker::Result<()>>,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let number = |index: usize, default: usize| {
+        args.get(index)
+            .and_then(|arg| arg.parse().ok())
+            .unwrap_or(default)
+    };
+    let clients = number(0, 100_000).max(1);
+    let workers = number(1, 4).max(1);
+    let rounds = number(2, 20).max(1);
+    let payload_size = number(3, 64).max(1);
+
+    if let Err(e) = run(clients, workers, rounds, payload_size) {
+        eprintln!("Benchmark failed: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(clients: usize, workers: usize, rounds: usize, payload_size: usize) -> Result<()> {
+    let mut broadcaster = Broadcaster::new();
+    let workers: Vec<Worker> = (0..workers)
+        .map(|_| {
+            let clients = Arc::new(Mutex::new(Vec::new()));
+            let handler = RecordingHandler {
+                clients: clients.clone(),
+            };
+            let mut server = EpollServer::new_worker(handler, ServerConfig::default())?;
+            let handle = server.handle();
+            broadcaster.add_worker(handle.clone());
+            Ok(Worker {
+                handle,
+                clients,
+                thread: thread::spawn(move || server.run(Some(100))),
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    println!(
+        "{clients} clients over {} workers, {payload_size} byte payload, {rounds} rounds",
+        workers.len()
+    );
+    let streams = connect(clients, &workers)?;
+    while broadcaster.connected_clients() < clients {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Clients dealt out in turn, so no reader is left without any: it
+    // would report every round at once
+    let mut chunks: Vec<Vec<TcpStream>> = (0..clients.min(READERS)).map(|_| Vec::new()).collect();
+    let readers = chunks.len();
+    for (index, stream) in streams.into_iter().enumerate() {
+        chunks[index % readers].push(stream);
+    }
+    let (done, finished) = channel();
+    let readers: Vec<_> = chunks
+        .into_iter()
+        .map(|mut chunk| {
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut received = vec![0u8; payload_size];
+                for _ in 0..rounds * 2 {
+                    for stream in &mut chunk {
+                        stream.read_exact(&mut received)?;
+                    }
+                    if done.send(()).is_err() {
+                        break;
+                    }
+                }
+                Ok::<_, std::io::Error>(())
+            })
+        })
+        .collect();
+    drop(done);
+
+    let payload = vec![b'x'; payload_size];
+    let per_client = measure("per client", rounds, &finished, readers.len(), || {
+        for worker in &workers {
+            for &client_id in worker.clients.lock().unwrap().iter() {
+                worker.handle.send_to(client_id, payload.clone())?;
+            }
+        }
+        Ok(())
+    })?;
+    let two_level = measure("two-level", rounds, &finished, readers.len(), || {
+        Ok(broadcaster.broadcast(payload.clone())?)
+    })?;
+    let speedup = |per_client: Duration, two_level: Duration| {
+        per_client.as_secs_f64() / two_level.as_secs_f64().max(f64::EPSILON)
+    };
+    println!(
+        "  speedup: {:.1}x queueing, {:.1}x delivery",
+        speedup(per_client.0, two_level.0),
+        speedup(per_client.1, two_level.1)
+    );
+
+    for reader in readers {
+        reader
+            .join()
+            .map_err(|_| std::io::Error::other("reader thread panicked"))??;
+    }
+    for worker in workers {
+        worker.handle.shutdown()?;
+        match worker.thread.join() {
+            Ok(result) => result?,
+            Err(_) => return Err(std::io::Error::other("worker thread panicked")),
+        }
+    }
+    Ok(())
+}
+
+/// Connect `count` clients, handing the accepted ends to the workers in
+/// turn, and return the client ends
+fn connect(count: usize, workers: &[Worker]) -> Result<Vec<TcpStream>> {
+    let listeners = (0..count.div_ceil(CLIENTS_PER_LISTENER))
+        .map(|_| TcpListener::bind("127.0.0.1:0"))
+        .collect::<Result<Vec<_>>>()?;
+    let mut streams = Vec::with_capacity(count);
+    for index in 0..count {
+        let listener = &listeners[index / CLIENTS_PER_LISTENER];
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (accepted, _) = listener.accept()?;
+        workers[index % workers.len()].handle.add_client(accepted)?;
+        streams.push(stream);
+    }
+    Ok(streams)
+}
+
+/// Broadcast `rounds` times with `send`, printing the median time to queue
+/// a message and the median time until each of the `readers` got it, and
+/// return both
+fn measure(
+    name: &str,
+    rounds: usize,
+    finished: &Receiver<()>,
+    readers: usize,
+    mut send: impl FnMut() -> Result<()>,
+) -> Result<(Duration, Duration)> {
+    let mut queued = Vec::with_capacity(rounds);
+    let mut delivered = Vec::with_capacity(rounds);
+    for _ in 0..rounds {
+        let start = Instant::now();
+        send()?;
+        queued.push(start.elapsed());
+        for _ in 0..readers {
+            finished
+                .recv()
+                .map_err(|_| std::io::Error::other("a reader stopped"))?;
+        }
+        delivered.push(start.elapsed());
+    }
+    queued.sort_unstable();
+    delivered.sort_unstable();
+    let (queued, delivered) = (queued[rounds / 2], delivered[rounds / 2]);
+    println!("  {name}: queued in {queued:?}, delivered in {delivered:?}");
+    Ok((queued, delivered))
+}
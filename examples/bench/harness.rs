@@ -0,0 +1,273 @@
+//! Self-contained reactor benchmark
+//!
+//! Starts an echo server on a loopback port in its own thread, then loads
+//! it from one thread per connection, in two phases of `seconds` each:
+//!
+//! - churn: every worker connects, echoes one byte and disconnects, over
+//!   and over, measuring connections per second
+//! - echo: every worker keeps one connection and one payload in flight,
+//!   measuring messages per second and round trip latency percentiles
+//!
+//! With a baseline file the results are compared to it, and the process
+//! exits with status 1 if any of them is more than 15% worse; without one
+//! (or if the file does not exist yet) the results are written to it.
+//!
+//! Usage: cargo run --release --example bench_harness -- [connections] [seconds] [payload_size] [baseline_file]
+
+use std::{
+    env, fs,
+    io::{ErrorKind, Read, Result, Write},
+    net::{SocketAddr, TcpStream},
+    process, thread,
+    time::{Duration, Instant},
+};
+
+use epoll_worker::{
+    Bytes, ClientId, EpollServer, EventHandler, HandlerAction, ListenerId, ServerConfig,
+    ServerContext,
+};
+
+/// Relative change in any result reported as a regression
+const TOLERANCE: f64 = 0.15;
+
+struct EchoHandler;
+
+impl EventHandler for EchoHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::Reply(data))
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+/// One measured value, with whether a larger one is better
+struct Sample {
+    name: &'static str,
+    value: f64,
+    higher_is_better: bool,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let number = |index: usize, default: usize| {
+        args.get(index)
+            .and_then(|arg| arg.parse().ok())
+            .unwrap_or(default)
+    };
+    let connections = number(0, 32).max(1);
+    let duration = Duration::from_secs(number(1, 5) as u64);
+    let payload_size = number(2, 64).max(1);
+    let baseline = args.get(3);
+
+    let results = match run(connections, duration, payload_size) {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("Benchmark failed: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let Some(path) = baseline else {
+        return;
+    };
+    match fs::read_to_string(path) {
+        Ok(saved) => {
+            if compare(&results, &saved) {
+                process::exit(1);
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let lines: String = results
+                .iter()
+                .map(|sample| format!("{}={}\n", sample.name, sample.value))
+                .collect();
+            if let Err(e) = fs::write(path, lines) {
+                eprintln!("Failed to write the baseline to {}: {}", path, e);
+                process::exit(1);
+            }
+            println!("baseline written to {path}");
+        }
+        Err(e) => {
+            eprintln!("Failed to read the baseline {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn run(connections: usize, duration: Duration, payload_size: usize) -> Result<Vec<Sample>> {
+    let config = ServerConfig::default().nodelay(true).max_events(1024);
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", EchoHandler, config)?;
+    let addr = server.local_addr()?;
+    let handle = server.handle();
+    let server_thread = thread::spawn(move || server.run(Some(100)));
+
+    println!("{connections} connections, {payload_size} byte payload, {duration:?} per phase");
+    let churned = run_workers(connections, duration, move |deadline| churn(addr, deadline));
+    let connects: u64 = churned.iter().sum();
+    let mut latencies: Vec<Duration> = run_workers(connections, duration, move |deadline| {
+        echo(addr, payload_size, deadline)
+    })
+    .into_iter()
+    .flatten()
+    .collect();
+    latencies.sort_unstable();
+
+    handle.shutdown()?;
+    match server_thread.join() {
+        Ok(result) => result?,
+        Err(_) => return Err(std::io::Error::other("server thread panicked")),
+    }
+
+    let seconds = duration.as_secs_f64().max(f64::EPSILON);
+    let percentile = |p: f64| {
+        let index = ((latencies.len() as f64 * p) as usize).min(latencies.len().saturating_sub(1));
+        latencies.get(index).copied().unwrap_or_default()
+    };
+    let results = vec![
+        Sample {
+            name: "connections_per_sec",
+            value: (connects as f64 / seconds).round(),
+            higher_is_better: true,
+        },
+        Sample {
+            name: "messages_per_sec",
+            value: (latencies.len() as f64 / seconds).round(),
+            higher_is_better: true,
+        },
+        Sample {
+            name: "p50_us",
+            value: percentile(0.50).as_micros() as f64,
+            higher_is_better: false,
+        },
+        Sample {
+            name: "p99_us",
+            value: percentile(0.99).as_micros() as f64,
+            higher_is_better: false,
+        },
+    ];
+    for sample in &results {
+        println!("  {}: {}", sample.name, sample.value);
+    }
+    Ok(results)
+}
+
+/// Run `work` on `count` threads until `duration` elapsed, collecting
+/// what each of them returned
+fn run_workers<T: Send + 'static>(
+    count: usize,
+    duration: Duration,
+    work: impl Fn(Instant) -> T + Send + Clone + 'static,
+) -> Vec<T> {
+    let deadline = Instant::now() + duration;
+    let workers: Vec<_> = (0..count)
+        .map(|_| {
+            let work = work.clone();
+            thread::spawn(move || work(deadline))
+        })
+        .collect();
+    workers
+        .into_iter()
+        .filter_map(|worker| worker.join().ok())
+        .collect()
+}
+
+/// Connect, echo a byte and disconnect until `deadline`, returning how
+/// many connections completed
+fn churn(addr: SocketAddr, deadline: Instant) -> u64 {
+    let mut completed = 0;
+    let mut byte = [0u8; 1];
+    while Instant::now() < deadline {
+        let echoed = TcpStream::connect(addr).and_then(|mut stream| {
+            stream.set_nodelay(true)?;
+            stream.write_all(b"x")?;
+            stream.read_exact(&mut byte)
+        });
+        if echoed.is_ok() {
+            completed += 1;
+        }
+    }
+    completed
+}
+
+/// Ping-pong a payload on one connection until `deadline`, returning the
+/// latency of every round trip
+fn echo(addr: SocketAddr, payload_size: usize, deadline: Instant) -> Vec<Duration> {
+    let payload = vec![b'x'; payload_size];
+    let mut response = vec![0u8; payload_size];
+    let mut latencies = Vec::new();
+    let Ok(mut stream) = TcpStream::connect(addr) else {
+        return latencies;
+    };
+    if stream.set_nodelay(true).is_err() {
+        return latencies;
+    }
+    while Instant::now() < deadline {
+        let started = Instant::now();
+        if stream.write_all(&payload).is_err() || stream.read_exact(&mut response).is_err() {
+            break;
+        }
+        latencies.push(started.elapsed());
+    }
+    latencies
+}
+
+/// Print how `results` compare to the `saved` baseline, returning `true`
+/// if any of them regressed past the tolerance
+fn compare(results: &[Sample], saved: &str) -> bool {
+    let mut regressed = false;
+    for sample in results {
+        let baseline = saved.lines().find_map(|line| {
+            let (name, value) = line.split_once('=')?;
+            (name == sample.name).then(|| value.parse::<f64>().ok())?
+        });
+        let Some(baseline) = baseline.filter(|baseline| *baseline > 0.0) else {
+            println!("  {}: no baseline", sample.name);
+            continue;
+        };
+        let change = (sample.value - baseline) / baseline;
+        let worse = match sample.higher_is_better {
+            true => -change,
+            false => change,
+        };
+        let verdict = if worse > TOLERANCE {
+            regressed = true;
+            "REGRESSION"
+        } else {
+            "ok"
+        };
+        println!(
+            "  {}: {} vs {} ({:+.1}%) {}",
+            sample.name,
+            sample.value,
+            baseline,
+            change * 100.0,
+            verdict
+        );
+    }
+    regressed
+}
@@ -0,0 +1,76 @@
+//! HTTP/1.1 keep-alive server for benchmarking
+//!
+//! Answers every request with a fixed plaintext body and keeps the
+//! connection open, pipelined requests get one response each in order.
+//! The response matches the usual "plaintext" benchmark so numbers can be
+//! put next to other servers answering the same bytes.
+//!
+//! Usage: cargo run --release --example bench_http -- [addr]
+//! Load with: wrk -t4 -c256 -d30s http://127.0.0.1:9001/
+
+use std::env;
+
+use epoll_worker::{
+    Bytes, ClientId, EpollServer, EventHandler, HandlerAction, ListenerId, ServerConfig,
+    ServerContext,
+};
+
+const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\n\
+Content-Type: text/plain\r\n\
+Content-Length: 13\r\n\
+Connection: keep-alive\r\n\
+\r\n\
+Hello, World!";
+
+const HEADER_END: &[u8] = b"\r\n\r\n";
+
+struct HttpHandler;
+
+impl EventHandler for HttpHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &std::net::TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        // Requests carry no body, so each header terminator is one request
+        let requests = data
+            .windows(HEADER_END.len())
+            .filter(|window| *window == HEADER_END)
+            .count();
+        Ok(HandlerAction::Reply(RESPONSE.repeat(requests).into()))
+    }
+
+    fn is_data_complete(&mut self, data: &[u8]) -> bool {
+        data.ends_with(HEADER_END)
+    }
+}
+
+fn main() -> epoll_worker::Result<()> {
+    env_logger::init();
+
+    let addr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:9001".to_string());
+    let config = ServerConfig::default().nodelay(true).wait_timeout(None);
+    let mut server = EpollServer::new_with_config(addr, HttpHandler, config)?;
+    server.run(None)
+}
@@ -0,0 +1,221 @@
+//! Load generator for the benchmark servers
+//!
+//! - `echo`: every connection sends a payload and waits for it to come
+//!   back before sending the next one, reporting round trips per second
+//!   and their latency percentiles
+//! - `pubsub`: every connection subscribes to one topic and a single
+//!   publisher publishes to it, each message after its own copy came back,
+//!   reporting messages published and delivered per second
+//!
+//! Usage: cargo run --release --example bench_loadgen -- <echo|pubsub> <addr> [connections] [seconds] [payload_size]
+
+use std::{
+    env,
+    io::{BufRead, BufReader, ErrorKind, Read, Result, Write},
+    net::TcpStream,
+    process,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const TOPIC: &str = "bench";
+
+struct Options {
+    addr: String,
+    connections: usize,
+    duration: Duration,
+    payload_size: usize,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (Some(mode), Some(addr)) = (args.first(), args.get(1)) else {
+        eprintln!(
+            "Usage: bench_loadgen <echo|pubsub> <addr> [connections] [seconds] [payload_size]"
+        );
+        process::exit(1);
+    };
+    let number = |index: usize, default: usize| {
+        args.get(index)
+            .and_then(|arg| arg.parse().ok())
+            .unwrap_or(default)
+    };
+    let options = Options {
+        addr: addr.clone(),
+        connections: number(2, 50).max(1),
+        duration: Duration::from_secs(number(3, 10) as u64),
+        payload_size: number(4, 64).max(1),
+    };
+
+    let result = match mode.as_str() {
+        "echo" => run_echo(&options),
+        "pubsub" => run_pubsub(&options),
+        _ => {
+            eprintln!("Unknown mode `{}`, expected echo or pubsub", mode);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Load generation failed: {}", e);
+        process::exit(1);
+    }
+}
+
+fn connect(addr: &str) -> Result<TcpStream> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// Send `payload` and wait for all of it to come back
+fn round_trip(stream: &mut TcpStream, payload: &[u8], response: &mut [u8]) -> Result<Duration> {
+    let started = Instant::now();
+    stream.write_all(payload)?;
+    stream.read_exact(response)?;
+    Ok(started.elapsed())
+}
+
+/// Ping-pong the payload on every connection until the time is up
+fn run_echo(options: &Options) -> Result<()> {
+    let deadline = Instant::now() + options.duration;
+    let errors = Arc::new(AtomicU64::new(0));
+
+    let workers: Vec<_> = (0..options.connections)
+        .map(|_| {
+            let addr = options.addr.clone();
+            let payload = vec![b'x'; options.payload_size];
+            let errors = errors.clone();
+            thread::spawn(move || {
+                let mut latencies = Vec::new();
+                let mut response = vec![0u8; payload.len()];
+                let mut stream = None;
+                while Instant::now() < deadline {
+                    let result = match &mut stream {
+                        Some(stream) => round_trip(stream, &payload, &mut response),
+                        None => connect(&addr).and_then(|connected| {
+                            round_trip(stream.insert(connected), &payload, &mut response)
+                        }),
+                    };
+                    match result {
+                        Ok(latency) => latencies.push(latency),
+                        Err(_) => {
+                            // Reconnect on the next round trip
+                            errors.fetch_add(1, Ordering::Relaxed);
+                            stream = None;
+                        }
+                    }
+                }
+                latencies
+            })
+        })
+        .collect();
+
+    let mut latencies: Vec<Duration> = workers
+        .into_iter()
+        .flat_map(|worker| worker.join().unwrap_or_default())
+        .collect();
+    latencies.sort_unstable();
+
+    let seconds = options.duration.as_secs_f64().max(f64::EPSILON);
+    let percentile = |p: f64| {
+        let index = ((latencies.len() as f64 * p) as usize).min(latencies.len().saturating_sub(1));
+        latencies.get(index).copied().unwrap_or_default()
+    };
+    println!(
+        "echo: {} connections, {} byte payload, {:?}",
+        options.connections, options.payload_size, options.duration
+    );
+    println!(
+        "  round trips: {} ({:.0}/s)",
+        latencies.len(),
+        latencies.len() as f64 / seconds
+    );
+    println!(
+        "  latency: p50 {:?}, p99 {:?}, max {:?}",
+        percentile(0.50),
+        percentile(0.99),
+        latencies.last().copied().unwrap_or_default()
+    );
+    println!("  errors: {}", errors.load(Ordering::Relaxed));
+    Ok(())
+}
+
+/// Fan messages out to every subscriber until the time is up
+fn run_pubsub(options: &Options) -> Result<()> {
+    let done = Arc::new(AtomicBool::new(false));
+    let delivered = Arc::new(AtomicU64::new(0));
+
+    let mut subscribers = Vec::with_capacity(options.connections);
+    for _ in 0..options.connections {
+        let mut stream = connect(&options.addr)?;
+        stream.write_all(format!("SUB {TOPIC}\n").as_bytes())?;
+        stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+
+        let done = done.clone();
+        let delivered = delivered.clone();
+        subscribers.push(thread::spawn(move || {
+            let mut reader = BufReader::new(stream);
+            let mut line = Vec::new();
+            while !done.load(Ordering::Relaxed) {
+                match reader.read_until(b'\n', &mut line) {
+                    Ok(0) => return,
+                    Ok(_) if line.ends_with(b"\n") => {
+                        delivered.fetch_add(1, Ordering::Relaxed);
+                        line.clear();
+                    }
+                    // Timed out mid-line, the rest follows on the next read
+                    Ok(_) => {}
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                    Err(_) => return,
+                }
+            }
+        }));
+    }
+
+    // The publisher waits for its own copy, so the server is never flooded
+    // with more than one message in flight
+    let mut publisher = connect(&options.addr)?;
+    publisher.write_all(format!("SUB {TOPIC}\n").as_bytes())?;
+    let mut reader = BufReader::new(publisher.try_clone()?);
+    // Let the subscriptions land before the clock starts
+    thread::sleep(Duration::from_millis(200));
+
+    let message = format!("PUB {TOPIC} {}\n", "x".repeat(options.payload_size));
+    let mut line = String::new();
+    let mut published = 0u64;
+    let started = Instant::now();
+    while started.elapsed() < options.duration {
+        publisher.write_all(message.as_bytes())?;
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        published += 1;
+    }
+    let elapsed = started.elapsed().as_secs_f64().max(f64::EPSILON);
+
+    done.store(true, Ordering::Relaxed);
+    for subscriber in subscribers {
+        let _ = subscriber.join();
+    }
+    let delivered = delivered.load(Ordering::Relaxed);
+    println!(
+        "pubsub: {} subscribers, {} byte payload, {:?}",
+        options.connections, options.payload_size, options.duration
+    );
+    println!(
+        "  published: {} ({:.0}/s)",
+        published,
+        published as f64 / elapsed
+    );
+    println!(
+        "  delivered: {} ({:.0}/s)",
+        delivered,
+        delivered as f64 / elapsed
+    );
+    Ok(())
+}
@@ -0,0 +1,91 @@
+//! Publish/subscribe server for benchmarking fan-out
+//!
+//! Line based protocol:
+//! - `SUB <topic>` subscribes the client to `topic`
+//! - `PUB <topic> <payload>` sends `<topic> <payload>` to every subscriber
+//!
+//! Usage: cargo run --release --example bench_pubsub -- [addr]
+//! Load with: cargo run --release --example bench_loadgen -- pubsub 127.0.0.1:9002
+
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+};
+
+use epoll_worker::{
+    Bytes, ClientId, EpollServer, EventHandler, HandlerAction, ListenerId, ServerConfig,
+    ServerContext, protocol::LineCodec,
+};
+
+#[derive(Default)]
+struct PubSubHandler {
+    subscribers: HashMap<String, HashSet<ClientId>>,
+}
+
+impl EventHandler for PubSubHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &std::net::TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        client_id: ClientId,
+    ) -> std::io::Result<()> {
+        for subscribers in self.subscribers.values_mut() {
+            subscribers.remove(&client_id);
+        }
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        let line = String::from_utf8_lossy(&data);
+        match line.split_once(' ') {
+            Some(("SUB", topic)) => {
+                self.subscribers
+                    .entry(topic.to_string())
+                    .or_default()
+                    .insert(client_id);
+            }
+            Some(("PUB", message)) => {
+                let topic = message.split(' ').next().unwrap_or_default();
+                if let Some(subscribers) = self.subscribers.get(topic) {
+                    for &subscriber in subscribers {
+                        ctx.send_to(subscriber, message.as_bytes().to_vec())?;
+                    }
+                }
+            }
+            _ => return Ok(HandlerAction::Reply(b"ERR unknown command".into())),
+        }
+        Ok(HandlerAction::None)
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+fn main() -> epoll_worker::Result<()> {
+    env_logger::init();
+
+    let addr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:9002".to_string());
+    let config = ServerConfig::default()
+        .codec(|| Box::new(LineCodec::new()))
+        .nodelay(true)
+        .wait_timeout(None);
+    let mut server = EpollServer::new_with_config(addr, PubSubHandler::default(), config)?;
+    server.run(None)
+}
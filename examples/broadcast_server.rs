@@ -3,8 +3,11 @@
 //! Usage: RUST_LOG=info cargo run --example broadcast_server
 //! Connect with: <telnet localhost 8080> or <client provided in example>
 
-use env_logger;
-use epoll_worker::{ClientId, EpollServer, EventHandler, HandlerAction};
+use epoll_worker::{
+    Bytes, ClientId, EpollServer, EventHandler, HandlerAction, ListenerId, ServerConfig,
+    ServerContext,
+    protocol::{CodecStack, LineCodec, TelnetCodec},
+};
 use log::info;
 
 struct BroadcastHandler;
@@ -12,7 +15,9 @@ struct BroadcastHandler;
 impl EventHandler for BroadcastHandler {
     fn on_connection(
         &mut self,
+        _ctx: &mut ServerContext,
         client_id: ClientId,
+        _listener_id: ListenerId,
         stream: &std::net::TcpStream,
     ) -> std::io::Result<()> {
         info!(
@@ -23,24 +28,40 @@ impl EventHandler for BroadcastHandler {
         Ok(())
     }
 
-    fn on_disconnect(&mut self, client_id: ClientId) -> std::io::Result<()> {
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        client_id: ClientId,
+    ) -> std::io::Result<()> {
         info!("Client {} disconnected", client_id);
         Ok(())
     }
 
-    fn on_message(&mut self, client_id: ClientId, data: &[u8]) -> std::io::Result<HandlerAction> {
-        let message = format!("[Client_{}] {}", client_id, String::from_utf8_lossy(data));
-        Ok(HandlerAction::Broadcast(message.into_bytes()))
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        let message = format!("[Client_{}] {}", client_id, String::from_utf8_lossy(&data));
+        Ok(HandlerAction::Broadcast(message.into()))
     }
     fn is_data_complete(&mut self, _data: &[u8]) -> bool {
         true
     }
 }
 
-fn main() -> std::io::Result<()> {
+fn main() -> epoll_worker::Result<()> {
     env_logger::init();
 
+    // Telnet clients negotiate options, strip them before splitting lines
+    let config = ServerConfig::default().codec(|| {
+        Box::new(CodecStack::new(vec![
+            Box::new(TelnetCodec::new()),
+            Box::new(LineCodec::new()),
+        ]))
+    });
     let handler = BroadcastHandler;
-    let mut server = EpollServer::new("127.0.0.1:8080", handler)?;
+    let mut server = EpollServer::new_with_config("127.0.0.1:8080", handler, config)?;
     server.run(None)
 }
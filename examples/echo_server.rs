@@ -2,7 +2,9 @@
 //!
 //! Usage: RUST_LOG=info cargo run --example echo_server
 
-use epoll_worker::{ClientId, EpollServer, EventHandler, HandlerAction};
+use epoll_worker::{
+    Bytes, ClientId, EpollServer, EventHandler, HandlerAction, ListenerId, ServerContext,
+};
 use log::info;
 
 struct EchoHandler;
@@ -10,7 +12,9 @@ struct EchoHandler;
 impl EventHandler for EchoHandler {
     fn on_connection(
         &mut self,
+        _ctx: &mut ServerContext,
         client_id: ClientId,
+        _listener_id: ListenerId,
         stream: &std::net::TcpStream,
     ) -> std::io::Result<()> {
         info!(
@@ -21,24 +25,29 @@ impl EventHandler for EchoHandler {
         Ok(())
     }
 
-    fn on_disconnect(&mut self, client_id: ClientId) -> std::io::Result<()> {
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        client_id: ClientId,
+    ) -> std::io::Result<()> {
         info!("Client {} disconnected", client_id);
         Ok(())
     }
 
     fn on_message(
         &mut self,
+        _ctx: &mut ServerContext,
         _client_id: ClientId,
-        data: &[u8],
+        data: Bytes,
     ) -> std::io::Result<epoll_worker::HandlerAction> {
-        Ok(HandlerAction::Reply(data.to_vec()))
+        Ok(HandlerAction::Reply(data))
     }
 
     fn is_data_complete(&mut self, _data: &[u8]) -> bool {
         true
     }
 }
-fn main() -> std::io::Result<()> {
+fn main() -> epoll_worker::Result<()> {
     env_logger::init();
 
     let handler = EchoHandler;
@@ -1,12 +1,18 @@
 //! Basic HTTP server serving simple responses
 //!
+//! Requests are framed by `HttpCodec`, which keeps connections alive and
+//! answers pipelined requests in order.
+//!
 //! Usage: RUST_LOG=info cargo run --example http_server
-//! Test with: curl http://localhost:8080
+//! Test with: curl http://localhost:8080 http://localhost:8080/missing
 
-use epoll_worker::{ClientId, EpollServer, EventHandler, HandlerAction};
-use log::{debug, info};
+use epoll_worker::{
+    Bytes, ClientId, EpollServer, EventHandler, HandlerAction, ListenerId, ServerConfig,
+    ServerContext,
+    protocol::{HttpCodec, HttpRequest},
+};
 
-const HTML_200: &'static str = r#"
+const HTML_200: &str = r#"
 <!DOCTYPE html>
 <html lang="en">
   <head>
@@ -20,7 +26,7 @@ const HTML_200: &'static str = r#"
 </html>
 "#;
 
-const HTML_404: &'static str = r#"
+const HTML_404: &str = r#"
 <!DOCTYPE html>
 <html lang="en">
   <head>
@@ -39,66 +45,61 @@ struct HttpHandler;
 impl EventHandler for HttpHandler {
     fn on_connection(
         &mut self,
-        client_id: ClientId,
-        stream: &std::net::TcpStream,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &std::net::TcpStream,
     ) -> std::io::Result<()> {
         Ok(())
     }
 
-    fn on_disconnect(&mut self, client_id: ClientId) -> std::io::Result<()> {
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
         Ok(())
     }
 
-    fn on_message(&mut self, _client_id: ClientId, data: &[u8]) -> std::io::Result<HandlerAction> {
-        let request = String::from_utf8_lossy(data);
-        let (status_line, contents) = match request.lines().next() {
-            Some(first_line) => {
-                if first_line.starts_with("GET / HTTP/1.1") {
-                    ("HTTP/1.1 200 OK", HTML_200)
-                } else if first_line.starts_with("GET ") && first_line.ends_with(" HTTP/1.1") {
-                    ("HTTP/1.1 404 NOT FOUND", HTML_404)
-                } else {
-                    ("HTTP/1.1 400 BAD REQUEST", HTML_404)
-                }
-            }
-            None => ("HTTP/1.1 400 BAD REQUEST", HTML_404),
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        let request = HttpRequest::parse(&data)?;
+        let (status_line, contents) = match (request.method, request.target) {
+            ("GET", "/") => ("HTTP/1.1 200 OK", HTML_200),
+            ("GET", _) => ("HTTP/1.1 404 NOT FOUND", HTML_404),
+            _ => ("HTTP/1.1 405 METHOD NOT ALLOWED", HTML_404),
         };
         let length = contents.len();
+        // The codec closes the connection after this response if the
+        // request asked for it
+        let connection = if request.keep_alive() {
+            "keep-alive"
+        } else {
+            "close"
+        };
 
-        let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
+        let response = format!(
+            "{status_line}\r\nContent-Length: {length}\r\nConnection: {connection}\r\n\r\n{contents}"
+        );
 
-        Ok(HandlerAction::Reply(response.as_bytes().to_vec()))
+        Ok(HandlerAction::Reply(response.into()))
     }
 
-    fn is_data_complete(&mut self, data: &[u8]) -> bool {
-        let data_str = String::from_utf8_lossy(data);
-        let mut lines = data_str.lines();
-        if let Some(line) = lines.next() {
-            if let Some(method) = line.split(" ").nth(0) {
-                match method {
-                    "GET" | "DELETE" => return true,
-                    _ => (),
-                }
-            }
-        }
-
-        if let Some(content_len) = lines.find(|l| l.to_lowercase().starts_with("content-length: "))
-        {
-            if let Some(len) = content_len.to_lowercase().strip_prefix("content-length: ") {
-                let is_valid = data.len()
-                    > len
-                        .parse::<usize>()
-                        .expect("content-length to be valid number");
-                return is_valid;
-            }
-        }
-        false
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        // Requests are framed by the codec
+        true
     }
 }
-fn main() -> std::io::Result<()> {
+
+fn main() -> epoll_worker::Result<()> {
     env_logger::init();
 
     let handler = HttpHandler;
-    let mut server = EpollServer::new("127.0.0.1:8080", handler)?;
+    let config = ServerConfig::default().codec(|| Box::new(HttpCodec::new()));
+    let mut server = EpollServer::new_with_config("127.0.0.1:8080", handler, config)?;
     server.run(None)
 }
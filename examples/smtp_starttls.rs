@@ -0,0 +1,102 @@
+//! Minimal SMTP greeter upgrading connections with STARTTLS
+//!
+//! Speaks just enough SMTP to greet clients, advertise STARTTLS and switch
+//! the connection to TLS in place when asked to.
+//!
+//! Usage: RUST_LOG=info cargo run --features tls --example smtp_starttls -- <cert.pem> <key.pem>
+//! Test with: openssl s_client -connect 127.0.0.1:2525 -starttls smtp
+
+use std::{collections::HashSet, env, process};
+
+use epoll_worker::{
+    Bytes, ClientId, EpollServer, EventHandler, HandlerAction, ListenerId, ServerConfig,
+    ServerContext,
+    protocol::{LineCodec, TlsConfig},
+};
+use log::{error, info};
+
+struct SmtpHandler {
+    tls: TlsConfig,
+    /// Clients that already switched to TLS
+    secured: HashSet<ClientId>,
+}
+
+impl EventHandler for SmtpHandler {
+    fn on_connection(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &std::net::TcpStream,
+    ) -> std::io::Result<()> {
+        info!("Client {} connected", client_id);
+        ctx.send_to(client_id, b"220 epoll-worker ESMTP ready".to_vec())?;
+        Ok(())
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        client_id: ClientId,
+    ) -> std::io::Result<()> {
+        info!("Client {} disconnected", client_id);
+        self.secured.remove(&client_id);
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        let line = String::from_utf8_lossy(&data);
+        let command = line
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_uppercase();
+        let encrypted = self.secured.contains(&client_id);
+
+        let reply: &[u8] = match command.as_str() {
+            "EHLO" if encrypted => b"250 epoll-worker greets you securely",
+            "EHLO" => b"250-epoll-worker greets you\r\n250 STARTTLS",
+            "HELO" => b"250 epoll-worker",
+            "STARTTLS" if !encrypted => {
+                // The go-ahead must leave in plaintext, so it is queued
+                // before the codec is switched
+                ctx.send_to(client_id, b"220 Ready to start TLS".to_vec())?;
+                let tls = Box::new(self.tls.codec()?);
+                ctx.switch_stack(client_id, vec![tls, Box::new(LineCodec::new())]);
+                self.secured.insert(client_id);
+                info!("Client {} upgraded to TLS", client_id);
+                return Ok(HandlerAction::None);
+            }
+            "NOOP" => b"250 OK",
+            "QUIT" => return Ok(HandlerAction::ReplyAndClose(b"221 Bye".into())),
+            _ => b"502 Command not implemented",
+        };
+        Ok(HandlerAction::Reply(reply.into()))
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+fn main() -> epoll_worker::Result<()> {
+    env_logger::init();
+
+    let (Some(cert), Some(key)) = (env::args().nth(1), env::args().nth(2)) else {
+        error!("Usage: smtp_starttls <cert.pem> <key.pem>");
+        process::exit(1);
+    };
+
+    let handler = SmtpHandler {
+        tls: TlsConfig::from_pem_files(cert, key)?,
+        secured: HashSet::new(),
+    };
+    let config = ServerConfig::default().codec(|| Box::new(LineCodec::new()));
+    let mut server = EpollServer::new_with_config("127.0.0.1:2525", handler, config)?;
+    server.run(None)
+}
@@ -0,0 +1,115 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Result},
+    net::TcpListener,
+};
+
+use crate::{
+    error::os_error,
+    ffi::{ECONNABORTED, EMFILE, ENFILE, ENOBUFS, ENOMEM, EPERM, EPROTO},
+    sockopt,
+};
+
+/// Why accepting a connection failed, see `EventHandler::on_accept_error`
+#[derive(Debug)]
+pub enum AcceptError {
+    /// The process (`EMFILE`) or the system (`ENFILE`) is out of file
+    /// descriptors
+    ///
+    /// Accepting is paused for `ServerConfig::accept_error_backoff`, or
+    /// until a client leaves. With `ServerConfig::reserve_accept_fd` the
+    /// waiting connections are accepted and closed first.
+    FdExhausted(io::Error),
+    /// The kernel is out of memory or socket buffers, accepting is paused
+    /// like for [`AcceptError::FdExhausted`]
+    OutOfMemory(io::Error),
+    /// The connection failed while waiting to be accepted, e.g. the peer
+    /// reset it; the next ones are accepted as usual
+    ConnectionAborted(io::Error),
+    /// Any other failure, accepting is paused like for
+    /// [`AcceptError::FdExhausted`]
+    Other(io::Error),
+}
+
+impl AcceptError {
+    /// The failure behind the error
+    pub fn io_error(&self) -> &io::Error {
+        match self {
+            AcceptError::FdExhausted(e)
+            | AcceptError::OutOfMemory(e)
+            | AcceptError::ConnectionAborted(e)
+            | AcceptError::Other(e) => e,
+        }
+    }
+
+    /// Returns `true` if the server stops accepting for a while, as the
+    /// next `accept` would likely fail the same way
+    pub fn pauses_accepts(&self) -> bool {
+        !matches!(self, AcceptError::ConnectionAborted(_))
+    }
+}
+
+impl From<io::Error> for AcceptError {
+    fn from(error: io::Error) -> Self {
+        match os_error(&error) {
+            Some(EMFILE | ENFILE) => AcceptError::FdExhausted(error),
+            Some(ENOMEM | ENOBUFS) => AcceptError::OutOfMemory(error),
+            Some(ECONNABORTED | EPROTO | EPERM) => AcceptError::ConnectionAborted(error),
+            _ => AcceptError::Other(error),
+        }
+    }
+}
+
+impl fmt::Display for AcceptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AcceptError::FdExhausted(e) => write!(f, "out of file descriptors: {e}"),
+            AcceptError::OutOfMemory(e) => write!(f, "out of kernel memory: {e}"),
+            AcceptError::ConnectionAborted(e) => write!(f, "connection aborted: {e}"),
+            AcceptError::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for AcceptError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.io_error())
+    }
+}
+
+/// A file descriptor kept open to be given up when the process runs out
+///
+/// Connections left in the backlog while the process is out of file
+/// descriptors wait there until it times out on the peer's side. Closing
+/// the reserved fd frees one, so each of them can be accepted and closed
+/// right away: the peer sees the connection closed instead of hanging.
+pub(crate) struct ReservedFd(Option<File>);
+
+impl ReservedFd {
+    pub fn open() -> Result<Self> {
+        Ok(ReservedFd(Some(File::open("/dev/null")?)))
+    }
+
+    /// Accept and close the connections waiting on `listener`, returning
+    /// how many were closed
+    ///
+    /// Stops at the first failure, the reserved fd is taken again before
+    /// returning if the process has one to spare by then.
+    pub fn shed(&mut self, listener: &TcpListener) -> u64 {
+        let mut shed = 0;
+        loop {
+            self.0 = None;
+            // Dropped right away, closing the connection
+            let accepted = sockopt::accept(listener).map(drop);
+            self.0 = File::open("/dev/null").ok();
+            match accepted {
+                Ok(()) => shed += 1,
+                Err(_) => return shed,
+            }
+            if self.0.is_none() {
+                return shed;
+            }
+        }
+    }
+}
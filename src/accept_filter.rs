@@ -0,0 +1,143 @@
+use std::{
+    fmt,
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+/// Decides which peers may connect
+///
+/// Called right after `accept`, before any client state is allocated or
+/// `on_connection` is called; refused connections are closed immediately.
+/// Install it with `EpollServer::set_accept_filter`.
+pub trait AcceptFilter {
+    /// Returns `true` if the peer at `peer_addr` may connect
+    fn allow(&mut self, peer_addr: SocketAddr) -> bool;
+}
+
+/// Block of IP addresses in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`
+///
+/// Parsing a bare address gives a block holding only that address.
+/// IPv4 blocks also match IPv4-mapped IPv6 peers (`::ffff:10.1.2.3`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Block of the addresses sharing the first `prefix_len` bits of `addr`
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("prefix length {prefix_len} is longer than {max_len}"),
+            ));
+        }
+
+        let network = match addr {
+            IpAddr::V4(v4) => Ipv4Addr::from(u32::from(v4) & v4_mask(prefix_len)).into(),
+            IpAddr::V6(v6) => Ipv6Addr::from(u128::from(v6) & v6_mask(prefix_len)).into(),
+        };
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+
+    /// Returns `true` if `ip` is in the block
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                u32::from(ip) & v4_mask(self.prefix_len) == u32::from(network)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                u128::from(ip) & v6_mask(self.prefix_len) == u128::from(network)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn v4_mask(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+}
+
+fn v6_mask(prefix_len: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0)
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid CIDR block `{s}`"));
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| invalid())?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Cidr::new(addr, prefix_len)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Only lets in peers from the listed blocks
+#[derive(Debug, Clone, Default)]
+pub struct AllowList {
+    networks: Vec<Cidr>,
+}
+
+impl AllowList {
+    pub fn new<I: IntoIterator<Item = Cidr>>(networks: I) -> Self {
+        AllowList {
+            networks: networks.into_iter().collect(),
+        }
+    }
+}
+
+impl AcceptFilter for AllowList {
+    fn allow(&mut self, peer_addr: SocketAddr) -> bool {
+        self.networks
+            .iter()
+            .any(|network| network.contains(peer_addr.ip()))
+    }
+}
+
+/// Turns away peers from the listed blocks
+#[derive(Debug, Clone, Default)]
+pub struct DenyList {
+    networks: Vec<Cidr>,
+}
+
+impl DenyList {
+    pub fn new<I: IntoIterator<Item = Cidr>>(networks: I) -> Self {
+        DenyList {
+            networks: networks.into_iter().collect(),
+        }
+    }
+}
+
+impl AcceptFilter for DenyList {
+    fn allow(&mut self, peer_addr: SocketAddr) -> bool {
+        !self
+            .networks
+            .iter()
+            .any(|network| network.contains(peer_addr.ip()))
+    }
+}
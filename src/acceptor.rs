@@ -0,0 +1,209 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    os::fd::AsRawFd,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+
+use crate::{
+    Event, EventFlags, PeerRole,
+    accept_error::AcceptError,
+    broadcaster::Broadcaster,
+    reactor::{PlatformReactor, Reactor},
+    server_handle::ServerHandle,
+    sockopt,
+};
+
+/// How long accepting stops after `accept` failed, e.g. out of file
+/// descriptors, as retrying right away would fail the same way
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How an `Acceptor` picks the worker of each new connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Distribution {
+    /// Each worker in turn
+    #[default]
+    RoundRobin,
+    /// The worker with the fewest clients, counting the connections
+    /// handed to it that it did not pick up yet
+    LeastLoaded,
+}
+
+/// Accepts connections on one thread and spreads them over worker loops
+///
+/// Each worker is an `EpollServer` created with `EpollServer::new_worker`
+/// and running on its own thread. Accepted sockets are queued to the
+/// worker through its `ServerHandle`, which wakes the worker's loop up
+/// through its eventfd, and the worker then serves the client like one it
+/// accepted itself. This scales over several cores from a single listener,
+/// without `SO_REUSEPORT`.
+///
+/// ```no_run
+/// # use epoll_worker::{Acceptor, EpollServer, EventHandler, ServerConfig};
+/// # fn serve<H: EventHandler + Send + 'static>(handlers: Vec<H>) -> epoll_worker::Result<()> {
+/// let mut acceptor = Acceptor::bind("0.0.0.0:8080")?;
+/// for handler in handlers {
+///     let mut worker = EpollServer::new_worker(handler, ServerConfig::default())?;
+///     acceptor.add_worker(worker.handle());
+///     std::thread::spawn(move || worker.run(None));
+/// }
+/// acceptor.run(None)
+/// # }
+/// ```
+pub struct Acceptor {
+    listener: TcpListener,
+    epoll: PlatformReactor,
+    workers: Vec<ServerHandle>,
+    distribution: Distribution,
+    /// Worker the next connection goes to in round-robin
+    next_worker: usize,
+    shutdown_signal: Arc<AtomicBool>,
+    /// Connections were left waiting after `accept` failed, accepting is
+    /// attempted again after the next wait even if no event comes
+    retry_accepts: bool,
+}
+
+impl Acceptor {
+    /// Listen on `addr`, workers are added with `Acceptor::add_worker`
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> crate::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        let epoll = PlatformReactor::new()?;
+        let flags = EventFlags::READ | EventFlags::EDGE;
+        epoll.add_interest(listener.as_raw_fd(), Event::new(flags, PeerRole::Server))?;
+
+        Ok(Acceptor {
+            listener,
+            epoll,
+            workers: Vec::new(),
+            distribution: Distribution::default(),
+            next_worker: 0,
+            shutdown_signal: Arc::new(AtomicBool::new(false)),
+            retry_accepts: false,
+        })
+    }
+
+    /// Hand connections to the loop behind `worker`
+    pub fn add_worker(&mut self, worker: ServerHandle) {
+        self.workers.push(worker);
+    }
+
+    /// `Broadcaster` reaching the clients of the workers added so far
+    pub fn broadcaster(&self) -> Broadcaster {
+        let mut broadcaster = Broadcaster::new();
+        for worker in &self.workers {
+            broadcaster.add_worker(worker.clone());
+        }
+        broadcaster
+    }
+
+    /// Configure how the worker of each connection is picked,
+    /// round-robin by default
+    pub fn set_distribution(&mut self, distribution: Distribution) {
+        self.distribution = distribution;
+    }
+
+    pub fn local_addr(&self) -> crate::Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Flag stopping `Acceptor::run` once set, the workers keep running
+    pub fn shutdown_signal(&self) -> Arc<AtomicBool> {
+        self.shutdown_signal.clone()
+    }
+
+    /// Accept connections until the shutdown signal is set
+    ///
+    /// Checks the signal at least every `timeout` milliseconds, every
+    /// second by default. Fails if there is no worker left to serve the
+    /// connections.
+    pub fn run(&mut self, timeout: Option<i32>) -> crate::Result<()> {
+        info!(
+            "Acceptor listening on {} for {} worker(s)",
+            self.local_addr()?,
+            self.workers.len()
+        );
+
+        let mut events = Vec::with_capacity(1);
+        while !self.shutdown_signal.load(Ordering::Relaxed) {
+            events.clear();
+            let mut timeout = Duration::from_millis(timeout.unwrap_or(1000).max(0) as u64);
+            if self.retry_accepts {
+                timeout = timeout.min(ACCEPT_ERROR_BACKOFF);
+            }
+            self.epoll.wait(&mut events, Some(timeout), None)?;
+            if !events.is_empty() || self.retry_accepts {
+                self.accept_pending()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Accept every waiting connection and hand each one to a worker
+    ///
+    /// Stops at an `accept` error that would likely repeat, leaving the
+    /// rest of the connections for after the next wait.
+    fn accept_pending(&mut self) -> Result<()> {
+        self.retry_accepts = false;
+        loop {
+            match sockopt::accept(&self.listener) {
+                Ok((stream, addr)) => {
+                    debug!("Accepted {}", addr);
+                    self.dispatch(stream)?;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => match AcceptError::from(e) {
+                    error if error.pauses_accepts() => {
+                        warn!("Error accepting new client, pausing: {}", error);
+                        self.retry_accepts = true;
+                        return Ok(());
+                    }
+                    error => debug!("Error accepting new client: {}", error),
+                },
+            }
+        }
+    }
+
+    /// Queue `stream` to a worker, dropping the workers that are gone
+    fn dispatch(&mut self, mut stream: TcpStream) -> Result<()> {
+        while !self.workers.is_empty() {
+            let index = self.pick_worker();
+            // The stream only comes back if the worker is gone
+            match self.workers[index].adopt(stream) {
+                Ok(()) => return Ok(()),
+                Err(returned) => {
+                    warn!("Worker {} is gone, removing it", index);
+                    self.workers.remove(index);
+                    stream = returned;
+                }
+            }
+        }
+        Err(Error::new(
+            ErrorKind::NotConnected,
+            "no worker left to serve connections",
+        ))
+    }
+
+    fn pick_worker(&mut self) -> usize {
+        match self.distribution {
+            Distribution::RoundRobin => {
+                let index = self.next_worker % self.workers.len();
+                self.next_worker = index + 1;
+                index
+            }
+            Distribution::LeastLoaded => self
+                .workers
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, worker)| worker.load())
+                .map_or(0, |(index, _)| index),
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! A middleware writing one line per handled message
+//!
+//! An entry is opened when a message comes in and written once the server
+//! acted on the handler's answer, with what was received and sent to the
+//! client meanwhile. For the request/response protocols, e.g. HTTP on top
+//! of a codec, that is one line per request.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Write as _},
+    io::{ErrorKind, Write},
+    net::SocketAddr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+
+use crate::{client_id::ClientId, middleware::Middleware};
+
+/// Default for [`AccessLog::max_buffered`]
+const MAX_BUFFERED: usize = 1 << 20;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Layout of the lines written by an [`AccessLog`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// After the Common Log Format: peer IP, client id in place of the
+    /// user, time, action in place of the request line, bytes in and out
+    ///
+    /// `127.0.0.1 - 1 [16/Oct/2026:09:30:00 +0000] "reply" 6 12`
+    #[default]
+    Common,
+    /// `Common` followed by the full peer address and the time taken in
+    /// microseconds
+    ///
+    /// `127.0.0.1 - 1 [16/Oct/2026:09:30:00 +0000] "reply" 6 12 "127.0.0.1:41234" 87`
+    Combined,
+    /// One JSON object per line, with the time in RFC 3339
+    ///
+    /// `{"time":"2026-10-16T09:30:00.123Z","client_id":1,"peer_addr":"127.0.0.1:41234","bytes_in":6,"bytes_out":12,"duration_us":87,"action":"reply"}`
+    Json,
+}
+
+/// Message being handled
+struct Entry {
+    time: SystemTime,
+    started: Instant,
+    bytes_in: usize,
+    bytes_out: usize,
+}
+
+/// [`Middleware`] logging every handled message of every client
+///
+/// Lines are buffered and written when `writer` takes them: a writer
+/// returning `WouldBlock`, e.g. a non-blocking pipe to a log shipper, never
+/// stalls the loop. What it does not take is kept for the next entry, up
+/// to [`AccessLog::max_buffered`] bytes, entries past that are dropped.
+///
+/// Best added first, so the sizes logged are those of the data the other
+/// middlewares have not rewritten, bytes out being counted before the
+/// codec frames them.
+pub struct AccessLog<W: Write> {
+    writer: W,
+    format: AccessLogFormat,
+    peers: HashMap<ClientId, SocketAddr>,
+    entries: HashMap<ClientId, Entry>,
+    buffer: Vec<u8>,
+    max_buffered: usize,
+    /// Entries dropped since the buffer was last emptied
+    dropped: u64,
+}
+
+impl<W: Write> AccessLog<W> {
+    pub fn new(writer: W, format: AccessLogFormat) -> Self {
+        AccessLog {
+            writer,
+            format,
+            peers: HashMap::new(),
+            entries: HashMap::new(),
+            buffer: Vec::new(),
+            max_buffered: MAX_BUFFERED,
+            dropped: 0,
+        }
+    }
+
+    /// Bytes kept while the writer does not take them, 1 MiB by default
+    pub fn max_buffered(mut self, bytes: usize) -> Self {
+        self.max_buffered = bytes;
+        self
+    }
+
+    fn record(&mut self, client_id: ClientId, entry: Entry, action: &str) {
+        let peer_addr = self.peers.get(&client_id).copied();
+        let mut line = String::new();
+        // Writing to a String does not fail
+        let _ = write_entry(&mut line, self.format, client_id, peer_addr, &entry, action);
+        if self.buffer.len() + line.len() > self.max_buffered {
+            self.dropped += 1;
+        } else {
+            self.buffer.extend_from_slice(line.as_bytes());
+        }
+        self.flush();
+    }
+
+    /// Write what the writer takes without blocking
+    fn flush(&mut self) {
+        let mut written = 0;
+        while written < self.buffer.len() {
+            match self.writer.write(&self.buffer[written..]) {
+                Ok(0) => break,
+                Ok(len) => written += len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Access log write failed: {}", e);
+                    break;
+                }
+            }
+        }
+        self.buffer.drain(..written);
+        if self.buffer.is_empty() && self.dropped > 0 {
+            warn!("Access log dropped {} entries", self.dropped);
+            self.dropped = 0;
+        }
+    }
+}
+
+impl<W: Write> Middleware for AccessLog<W> {
+    fn on_accept(&mut self, client_id: ClientId, peer_addr: SocketAddr) -> bool {
+        self.peers.insert(client_id, peer_addr);
+        true
+    }
+
+    fn on_inbound_data(
+        &mut self,
+        client_id: ClientId,
+        data: Vec<u8>,
+    ) -> std::io::Result<Option<Vec<u8>>> {
+        let entry = Entry {
+            time: SystemTime::now(),
+            started: Instant::now(),
+            bytes_in: data.len(),
+            bytes_out: 0,
+        };
+        self.entries.insert(client_id, entry);
+        Ok(Some(data))
+    }
+
+    fn on_outbound_data(&mut self, client_id: ClientId, data: Vec<u8>) -> Vec<u8> {
+        if let Some(entry) = self.entries.get_mut(&client_id) {
+            entry.bytes_out += data.len();
+        }
+        data
+    }
+
+    fn on_message_handled(&mut self, client_id: ClientId, outcome: &'static str) {
+        if let Some(entry) = self.entries.remove(&client_id) {
+            self.record(client_id, entry, outcome);
+        }
+    }
+
+    fn on_disconnect(&mut self, client_id: ClientId) {
+        self.peers.remove(&client_id);
+        self.entries.remove(&client_id);
+    }
+}
+
+impl<W: Write> Drop for AccessLog<W> {
+    fn drop(&mut self) {
+        self.flush();
+        let _ = self.writer.flush();
+    }
+}
+
+fn write_entry(
+    line: &mut String,
+    format: AccessLogFormat,
+    client_id: ClientId,
+    peer_addr: Option<SocketAddr>,
+    entry: &Entry,
+    action: &str,
+) -> fmt::Result {
+    let since_epoch = entry.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let duration_us = entry.started.elapsed().as_micros();
+    let (bytes_in, bytes_out) = (entry.bytes_in, entry.bytes_out);
+    match format {
+        AccessLogFormat::Common | AccessLogFormat::Combined => {
+            match peer_addr {
+                Some(addr) => write!(line, "{}", addr.ip())?,
+                None => line.push('-'),
+            }
+            let time = clf_time(since_epoch);
+            write!(
+                line,
+                " - {client_id} [{time}] \"{action}\" {bytes_in} {bytes_out}"
+            )?;
+            if format == AccessLogFormat::Combined {
+                match peer_addr {
+                    Some(addr) => write!(line, " \"{addr}\"")?,
+                    None => line.push_str(" \"-\""),
+                }
+                write!(line, " {duration_us}")?;
+            }
+        }
+        AccessLogFormat::Json => {
+            let time = rfc3339_time(since_epoch);
+            write!(line, "{{\"time\":\"{time}\",\"client_id\":{client_id},")?;
+            if let Some(addr) = peer_addr {
+                write!(line, "\"peer_addr\":\"{addr}\",")?;
+            }
+            write!(
+                line,
+                "\"bytes_in\":{bytes_in},\"bytes_out\":{bytes_out},\"duration_us\":{duration_us},\"action\":\"{action}\"}}"
+            )?;
+        }
+    }
+    line.push('\n');
+    Ok(())
+}
+
+/// `16/Oct/2026:09:30:00 +0000`
+fn clf_time(since_epoch: Duration) -> String {
+    let (year, month, day, secs) = civil_time(since_epoch);
+    format!(
+        "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+        MONTHS[month as usize - 1],
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// `2026-10-16T09:30:00.123Z`
+fn rfc3339_time(since_epoch: Duration) -> String {
+    let (year, month, day, secs) = civil_time(since_epoch);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// UTC year, month, day and seconds into the day of a time since the epoch
+fn civil_time(since_epoch: Duration) -> (u64, u64, u64, u64) {
+    let secs = since_epoch.as_secs();
+    // Days to a date after Howard Hinnant's `civil_from_days`, with years
+    // starting in March so leap days come last
+    let days = secs / 86_400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day, secs % 86_400)
+}
@@ -0,0 +1,266 @@
+//! Routing of connections to handlers by the protocol agreed on with ALPN
+
+use std::{
+    collections::HashMap,
+    io::Result,
+    mem::ManuallyDrop,
+    net::{SocketAddr, TcpStream},
+    os::fd::{FromRawFd, RawFd},
+};
+
+use crate::{
+    EventFlags,
+    accept_error::AcceptError,
+    bytes::Bytes,
+    client_id::ClientId,
+    context::{ListenerId, PRIMARY_LISTENER, ServerContext},
+    handler::{
+        ConsumeResult, ErrorDirective, EventHandler, HandlerAction, OverflowAction, ServerError,
+    },
+    protocol::PeerIdentity,
+    timer::TimerId,
+    watch::{FileEvent, WatchId},
+};
+
+/// Index of the fallback in `AlpnDispatcher::handlers`
+const FALLBACK: usize = 0;
+
+/// [`EventHandler`] serving each client with the handler of the protocol
+/// it agreed on with ALPN
+///
+/// One port can then speak e.g. HTTP/1.1, HTTP/2 and a raw protocol,
+/// offered with `TlsConfig::alpn_protocols(dispatcher.protocols())`.
+/// A client whose codec handshakes is handed to its handler once the
+/// handshake completes: `on_connection` is called then, right before
+/// `on_tls_handshake`. Clients without a handshake, and those that agreed
+/// on no routed protocol, go to the fallback.
+///
+/// Every handler sees the loop's own events (ticks, timers, custom, pipe
+/// and file events, shutdown) and should ignore those it did not ask for.
+/// Listener events and errors about no client go to the fallback, as does
+/// `is_data_complete`, which only matters for clients without a codec.
+pub struct AlpnDispatcher {
+    /// The fallback, then the routed handlers
+    handlers: Vec<Box<dyn EventHandler + Send>>,
+    /// Routed protocols in the order they were added, with their handler
+    protocols: Vec<(Vec<u8>, usize)>,
+    /// Handler serving each client, once it is known
+    clients: HashMap<ClientId, usize>,
+}
+
+impl AlpnDispatcher {
+    pub fn new(fallback: impl EventHandler + Send + 'static) -> Self {
+        AlpnDispatcher {
+            handlers: vec![Box::new(fallback)],
+            protocols: Vec::new(),
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Serve the clients that agreed on `protocol` with `handler`
+    pub fn route(
+        mut self,
+        protocol: impl Into<Vec<u8>>,
+        handler: impl EventHandler + Send + 'static,
+    ) -> Self {
+        self.handlers.push(Box::new(handler));
+        self.protocols
+            .push((protocol.into(), self.handlers.len() - 1));
+        self
+    }
+
+    /// Routed protocols, in the order they were added
+    pub fn protocols(&self) -> Vec<Vec<u8>> {
+        self.protocols
+            .iter()
+            .map(|(protocol, _)| protocol.clone())
+            .collect()
+    }
+
+    fn handler_for(&self, protocol: Option<&[u8]>) -> usize {
+        protocol
+            .and_then(|protocol| self.protocols.iter().find(|(routed, _)| routed == protocol))
+            .map_or(FALLBACK, |(_, index)| *index)
+    }
+
+    /// Handler of the client, `None` until its handshake completes
+    fn client_handler(&mut self, client_id: ClientId) -> Option<&mut Box<dyn EventHandler + Send>> {
+        let index = *self.clients.get(&client_id)?;
+        self.handlers.get_mut(index)
+    }
+
+    fn fallback(&mut self) -> &mut Box<dyn EventHandler + Send> {
+        &mut self.handlers[FALLBACK]
+    }
+}
+
+impl EventHandler for AlpnDispatcher {
+    fn on_connection(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        listener_id: ListenerId,
+        stream: &TcpStream,
+    ) -> Result<()> {
+        if ctx.client_handshaking(client_id) {
+            // Handed over in `on_tls_handshake`
+            return Ok(());
+        }
+        self.clients.insert(client_id, FALLBACK);
+        self.fallback()
+            .on_connection(ctx, client_id, listener_id, stream)
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        data: Bytes,
+    ) -> Result<HandlerAction> {
+        match self.client_handler(client_id) {
+            Some(handler) => handler.on_message(ctx, client_id, data),
+            None => Ok(HandlerAction::None),
+        }
+    }
+
+    fn on_disconnect(&mut self, ctx: &mut ServerContext, client_id: ClientId) -> Result<()> {
+        let result = match self.client_handler(client_id) {
+            Some(handler) => handler.on_disconnect(ctx, client_id),
+            None => Ok(()),
+        };
+        self.clients.remove(&client_id);
+        result
+    }
+
+    fn is_data_complete(&mut self, data: &[u8]) -> bool {
+        self.fallback().is_data_complete(data)
+    }
+
+    fn on_data_chunk(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        data: &[u8],
+    ) -> Result<ConsumeResult> {
+        match self.client_handler(client_id) {
+            Some(handler) => handler.on_data_chunk(ctx, client_id, data),
+            None => self.fallback().on_data_chunk(ctx, client_id, data),
+        }
+    }
+
+    fn on_buffer_overflow(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+    ) -> OverflowAction {
+        match self.client_handler(client_id) {
+            Some(handler) => handler.on_buffer_overflow(ctx, client_id),
+            None => OverflowAction::Disconnect,
+        }
+    }
+
+    fn on_connection_rejected(
+        &mut self,
+        ctx: &mut ServerContext,
+        addr: SocketAddr,
+        stream: &TcpStream,
+    ) {
+        self.fallback().on_connection_rejected(ctx, addr, stream)
+    }
+
+    fn on_accept_error(&mut self, ctx: &mut ServerContext, error: &AcceptError) {
+        self.fallback().on_accept_error(ctx, error)
+    }
+
+    fn on_error(
+        &mut self,
+        ctx: &mut ServerContext,
+        error: &ServerError,
+        client_id: Option<ClientId>,
+    ) -> ErrorDirective {
+        match client_id.and_then(|id| self.client_handler(id)) {
+            Some(handler) => handler.on_error(ctx, error, client_id),
+            None => self.fallback().on_error(ctx, error, client_id),
+        }
+    }
+
+    fn on_timer(&mut self, ctx: &mut ServerContext, timer_id: TimerId) {
+        for handler in &mut self.handlers {
+            handler.on_timer(ctx, timer_id);
+        }
+    }
+
+    fn on_tick(&mut self, ctx: &mut ServerContext) {
+        for handler in &mut self.handlers {
+            handler.on_tick(ctx);
+        }
+    }
+
+    fn on_write_complete(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        bytes_flushed: u64,
+    ) {
+        if let Some(handler) = self.client_handler(client_id) {
+            handler.on_write_complete(ctx, client_id, bytes_flushed);
+        }
+    }
+
+    fn on_custom_event(&mut self, ctx: &mut ServerContext, token: u64, flags: EventFlags) {
+        for handler in &mut self.handlers {
+            handler.on_custom_event(ctx, token, flags);
+        }
+    }
+
+    fn on_fd_readable(&mut self, ctx: &mut ServerContext, token: u64, fd: RawFd) {
+        for handler in &mut self.handlers {
+            handler.on_fd_readable(ctx, token, fd);
+        }
+    }
+
+    fn on_file_event(&mut self, ctx: &mut ServerContext, watch_id: WatchId, event: FileEvent) {
+        for handler in &mut self.handlers {
+            handler.on_file_event(ctx, watch_id, event.clone());
+        }
+    }
+
+    fn on_shutdown(&mut self, ctx: &mut ServerContext) {
+        for handler in &mut self.handlers {
+            handler.on_shutdown(ctx);
+        }
+    }
+
+    fn on_rate_limited(&mut self, ctx: &mut ServerContext, client_id: ClientId) {
+        if let Some(handler) = self.client_handler(client_id) {
+            handler.on_rate_limited(ctx, client_id);
+        }
+    }
+
+    fn on_tls_handshake(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        peer_identity: &PeerIdentity,
+    ) -> Result<()> {
+        let index = match self.clients.get(&client_id) {
+            Some(index) => *index,
+            None => {
+                let index = self.handler_for(peer_identity.alpn_protocol.as_deref());
+                let Some(fd) = ctx.client_fd(client_id) else {
+                    return Ok(());
+                };
+                // SAFETY: the fd is owned by the client's state, which
+                // outlives this call because disconnects are only processed
+                // after the handler returns. `ManuallyDrop` makes sure the
+                // borrowed fd is not closed here.
+                let stream = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+                self.clients.insert(client_id, index);
+                let listener_id = ctx.listener_id(client_id).unwrap_or(PRIMARY_LISTENER);
+                self.handlers[index].on_connection(ctx, client_id, listener_id, &stream)?;
+                index
+            }
+        };
+        self.handlers[index].on_tls_handshake(ctx, client_id, peer_identity)
+    }
+}
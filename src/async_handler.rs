@@ -0,0 +1,452 @@
+//! Handlers written with async/await, behind the `async` feature
+//!
+//! [`async_handler`] turns an [`AsyncHandler`], whose `on_message`
+//! returns a future, into an `EventHandler`. The future is polled right
+//! away, and its `HandlerAction` applied like a synchronous handler's if
+//! it completes at once. If it does not, it is kept as a task of the
+//! client and polled again from the event loop whenever it is woken, its
+//! action applied once it completes. No runtime is involved:
+//!
+//! * wakers push the task on a ready list and write an eventfd, so a task
+//!   can be woken from any thread;
+//! * the futures of [`AsyncIo`] wait for an fd to become readable or
+//!   writable, with their own epoll instance.
+//!
+//! Both the eventfd and the fds awaited are watched by that epoll
+//! instance, which the adapter registers in the server's with
+//! `ServerContext::register_fd` under [`ASYNC_TOKEN`] (or the token given
+//! to [`AsyncAdapter::with_token`]).
+//!
+//! ```no_run
+//! # use epoll_worker::{async_handler::{AsyncHandler, AsyncIo, TaskFuture}, *};
+//! # use std::io::Write;
+//! struct Forward;
+//!
+//! impl AsyncHandler for Forward {
+//!     fn on_message(
+//!         &mut self,
+//!         _ctx: &mut ServerContext,
+//!         io: &AsyncIo,
+//!         _client_id: ClientId,
+//!         data: Bytes,
+//!     ) -> TaskFuture {
+//!         let io = io.clone();
+//!         Box::pin(async move {
+//!             let mut upstream = io.connect("127.0.0.1:9000".parse().unwrap()).await?;
+//!             upstream.write_all(&data)?;
+//!             Ok(HandlerAction::Reply("forwarded".into()))
+//!         })
+//!     }
+//! }
+//!
+//! # fn main() -> epoll_worker::Result<()> {
+//! let handler = epoll_worker::async_handler::async_handler(Forward)?;
+//! EpollServer::new("0.0.0.0:8080", handler)?.run(None)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    future::Future,
+    io::{ErrorKind, Result},
+    net::{SocketAddr, TcpStream},
+    os::fd::{AsRawFd, RawFd},
+    pin::Pin,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Wake},
+    time::Duration,
+};
+
+use log::error;
+
+use crate::{
+    Event, EventFlags, MAX_CUSTOM_TOKEN, PeerRole,
+    bytes::Bytes,
+    client_id::ClientId,
+    context::{ListenerId, ServerContext},
+    epoll::Epoll,
+    handler::{EventHandler, HandlerAction},
+    reactor::Reactor,
+    server_handle::Waker,
+    sockopt,
+};
+
+/// Token the adapter registers its epoll instance with by default
+pub const ASYNC_TOKEN: u64 = MAX_CUSTOM_TOKEN;
+
+/// Future returned by [`AsyncHandler::on_message`]
+///
+/// It runs on the loop's thread and need not be `Send`. It cannot borrow
+/// the `ServerContext`: what it sends is the action it completes with.
+pub type TaskFuture = Pin<Box<dyn Future<Output = Result<HandlerAction>>>>;
+
+/// `EventHandler` whose messages are handled by futures
+pub trait AsyncHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called with each complete message, the returned future may await
+    /// the I/O of `io`
+    ///
+    /// An error it completes with disconnects the client, once it is
+    /// pending the server's `ErrorPolicy` no longer applies.
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        io: &AsyncIo,
+        client_id: ClientId,
+        data: Bytes,
+    ) -> TaskFuture;
+
+    /// Called when the client is gone, after its pending tasks were
+    /// dropped
+    fn on_disconnect(&mut self, _ctx: &mut ServerContext, _client_id: ClientId) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether `data` holds a complete message, every read is one by
+    /// default
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+/// Wrap `handler` into an `EventHandler`
+pub fn async_handler<H: AsyncHandler>(handler: H) -> crate::Result<AsyncAdapter<H>> {
+    AsyncAdapter::new(handler)
+}
+
+/// Ids of tasks woken since the loop last polled them
+type ReadyList = Arc<Mutex<Vec<u64>>>;
+
+/// Waker of one task
+struct TaskWaker {
+    task_id: u64,
+    ready: ReadyList,
+    eventfd: Arc<Waker>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.ready.lock().unwrap().push(self.task_id);
+        if let Err(e) = self.eventfd.wake() {
+            error!("Failed to wake task {} up: {}", self.task_id, e);
+        }
+    }
+}
+
+/// State shared by the adapter and the futures of [`AsyncIo`]
+struct IoState {
+    poller: Epoll,
+    eventfd: Arc<Waker>,
+    /// Wakers of the futures waiting for an fd, by fd
+    waiting: RefCell<HashMap<RawFd, std::task::Waker>>,
+    /// fds added to the poller so far, re-armed rather than added again
+    registered: RefCell<HashSet<RawFd>>,
+}
+
+/// I/O the futures of an [`AsyncHandler`] can await
+///
+/// Cheap to clone into a future. Only one future may wait for a given fd
+/// at a time.
+#[derive(Clone)]
+pub struct AsyncIo {
+    state: Rc<IoState>,
+}
+
+impl AsyncIo {
+    fn new() -> Result<Self> {
+        let poller = Epoll::new()?;
+        let eventfd = Arc::new(Waker::new()?);
+        poller.add_interest(
+            eventfd.as_raw_fd(),
+            Event::new(EventFlags::READ, PeerRole::Waker),
+        )?;
+        Ok(AsyncIo {
+            state: Rc::new(IoState {
+                poller,
+                eventfd,
+                waiting: RefCell::default(),
+                registered: RefCell::default(),
+            }),
+        })
+    }
+
+    /// Wait until `fd` can be read from without blocking
+    ///
+    /// `fd` must be non-blocking, and stay open while awaited.
+    pub fn readable(&self, fd: RawFd) -> Readiness {
+        Readiness::new(self.clone(), fd, EventFlags::READ)
+    }
+
+    /// Wait until `fd` can be written to without blocking
+    pub fn writable(&self, fd: RawFd) -> Readiness {
+        Readiness::new(self.clone(), fd, EventFlags::WRITE)
+    }
+
+    /// Connect to `addr` without blocking the loop
+    ///
+    /// The stream is non-blocking: read and write it until `WouldBlock`,
+    /// then await [`AsyncIo::readable`] or [`AsyncIo::writable`].
+    pub async fn connect(&self, addr: SocketAddr) -> Result<TcpStream> {
+        let (stream, connected) = sockopt::connect(addr)?;
+        if !connected {
+            self.writable(stream.as_raw_fd()).await?;
+            if let Some(e) = stream.take_error()? {
+                return Err(e);
+            }
+        }
+        Ok(stream)
+    }
+
+    /// Arm a one-shot notification of `interest` on `fd`
+    fn arm(&self, fd: RawFd, interest: EventFlags) -> Result<()> {
+        let event = || Event::new(interest | EventFlags::ONESHOT, PeerRole::Custom(fd as u64));
+        let mut registered = self.state.registered.borrow_mut();
+        if registered.contains(&fd) {
+            match self.state.poller.modify_interest(fd, event()) {
+                // The fd was closed and its number reused since
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                result => return result,
+            }
+        } else {
+            registered.insert(fd);
+        }
+        self.state.poller.add_interest(fd, event())
+    }
+
+    /// Wake the futures whose fd became ready, and reset the eventfd
+    fn dispatch(&self) -> Result<()> {
+        let mut events = Vec::with_capacity(64);
+        loop {
+            self.state
+                .poller
+                .wait(&mut events, Some(Duration::ZERO), None)?;
+            if events.is_empty() {
+                break;
+            }
+            for event in events.drain(..) {
+                match event.role() {
+                    PeerRole::Waker => self.state.eventfd.reset()?,
+                    PeerRole::Custom(fd) => {
+                        let waker = self.state.waiting.borrow_mut().remove(&(fd as RawFd));
+                        if let Some(waker) = waker {
+                            waker.wake();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Future of [`AsyncIo::readable`] and [`AsyncIo::writable`]
+pub struct Readiness {
+    io: AsyncIo,
+    fd: RawFd,
+    interest: EventFlags,
+    armed: bool,
+}
+
+impl Readiness {
+    fn new(io: AsyncIo, fd: RawFd, interest: EventFlags) -> Self {
+        Readiness {
+            io,
+            fd,
+            interest,
+            armed: false,
+        }
+    }
+}
+
+impl Future for Readiness {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut waiting = self.io.state.waiting.borrow_mut();
+        if self.armed && !waiting.contains_key(&self.fd) {
+            // Removed by `dispatch` once ready
+            return Poll::Ready(Ok(()));
+        }
+        waiting.insert(self.fd, cx.waker().clone());
+        drop(waiting);
+        if !self.armed {
+            if let Err(e) = self.io.arm(self.fd, self.interest) {
+                self.io.state.waiting.borrow_mut().remove(&self.fd);
+                return Poll::Ready(Err(e));
+            }
+            self.armed = true;
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Readiness {
+    fn drop(&mut self) {
+        if self.armed {
+            self.io.state.waiting.borrow_mut().remove(&self.fd);
+        }
+    }
+}
+
+/// Pending future of a client
+struct Task {
+    client_id: ClientId,
+    future: TaskFuture,
+}
+
+/// `EventHandler` running an [`AsyncHandler`], see the module
+/// documentation
+pub struct AsyncAdapter<H> {
+    handler: H,
+    io: AsyncIo,
+    ready: ReadyList,
+    tasks: HashMap<u64, Task>,
+    next_task_id: u64,
+    token: u64,
+    registered: bool,
+}
+
+impl<H: AsyncHandler> AsyncAdapter<H> {
+    pub fn new(handler: H) -> crate::Result<Self> {
+        Ok(AsyncAdapter {
+            handler,
+            io: AsyncIo::new()?,
+            ready: ReadyList::default(),
+            tasks: HashMap::new(),
+            next_task_id: 0,
+            token: ASYNC_TOKEN,
+            registered: false,
+        })
+    }
+
+    /// Register the adapter's epoll instance under `token` instead of
+    /// [`ASYNC_TOKEN`], to keep that token for other fds
+    pub fn with_token(mut self, token: u64) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Number of futures pending
+    pub fn pending_tasks(&self) -> usize {
+        self.tasks.len()
+    }
+
+    fn register(&mut self, ctx: &mut ServerContext) -> Result<()> {
+        if !self.registered {
+            let fd = self.io.state.poller.as_raw_fd();
+            ctx.register_fd(fd, EventFlags::READ, self.token)?;
+            self.registered = true;
+        }
+        Ok(())
+    }
+
+    /// Poll `future` once, returns its output if it completed
+    fn poll_task(&self, task_id: u64, future: &mut TaskFuture) -> Poll<Result<HandlerAction>> {
+        let waker = std::task::Waker::from(Arc::new(TaskWaker {
+            task_id,
+            ready: self.ready.clone(),
+            eventfd: self.io.state.eventfd.clone(),
+        }));
+        future.as_mut().poll(&mut Context::from_waker(&waker))
+    }
+
+    /// Poll the woken tasks until none is left
+    fn run_ready_tasks(&mut self, ctx: &mut ServerContext) {
+        loop {
+            let ready = std::mem::take(&mut *self.ready.lock().unwrap());
+            if ready.is_empty() {
+                return;
+            }
+            for task_id in ready {
+                // Gone if it completed or its client left in the meantime
+                let Some(mut task) = self.tasks.remove(&task_id) else {
+                    continue;
+                };
+                match self.poll_task(task_id, &mut task.future) {
+                    Poll::Pending => {
+                        self.tasks.insert(task_id, task);
+                    }
+                    Poll::Ready(Ok(action)) => {
+                        if let Err(e) = ctx.handle_action(task.client_id, action) {
+                            error!("Failed to apply the action of task {}: {}", task_id, e);
+                        }
+                    }
+                    Poll::Ready(Err(e)) => {
+                        error!("Task of client {} failed: {}", task.client_id, e);
+                        ctx.disconnect(task.client_id);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<H: AsyncHandler> EventHandler for AsyncAdapter<H> {
+    fn on_connection(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        listener_id: ListenerId,
+        stream: &TcpStream,
+    ) -> Result<()> {
+        self.register(ctx)?;
+        self.handler
+            .on_connection(ctx, client_id, listener_id, stream)
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        data: Bytes,
+    ) -> Result<HandlerAction> {
+        self.register(ctx)?;
+        let mut future = self.handler.on_message(ctx, &self.io, client_id, data);
+        let task_id = self.next_task_id;
+        self.next_task_id += 1;
+        match self.poll_task(task_id, &mut future) {
+            Poll::Ready(result) => result,
+            Poll::Pending => {
+                self.tasks.insert(task_id, Task { client_id, future });
+                Ok(HandlerAction::None)
+            }
+        }
+    }
+
+    fn on_disconnect(&mut self, ctx: &mut ServerContext, client_id: ClientId) -> Result<()> {
+        self.tasks.retain(|_, task| task.client_id != client_id);
+        self.handler.on_disconnect(ctx, client_id)
+    }
+
+    fn is_data_complete(&mut self, data: &[u8]) -> bool {
+        self.handler.is_data_complete(data)
+    }
+
+    fn on_custom_event(&mut self, ctx: &mut ServerContext, token: u64, _flags: EventFlags) {
+        if token != self.token {
+            return;
+        }
+        match self.io.dispatch() {
+            Ok(()) => self.run_ready_tasks(ctx),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => error!("Failed to poll the awaited fds: {}", e),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    net::SocketAddr,
+    sync::mpsc::{Sender, SyncSender},
+};
+
+use crate::{client_id::ClientId, context::ListenerId};
+
+/// Why a client was disconnected, see [`AuditEvent::Disconnected`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DisconnectReason {
+    /// The peer closed the connection, or its codec received a close frame
+    PeerClosed,
+    /// The handler or a middleware asked for it, through the context, a
+    /// `HandlerAction`, `ConsumeResult::Close` or `ErrorDirective`
+    Requested,
+    /// `ServerConfig::idle_timeout` or `ServerConfig::message_timeout`
+    /// expired
+    Timeout,
+    /// The client went over a size or rate limit of the `ServerConfig`
+    LimitExceeded,
+    /// Reading, writing, decoding or handling its data failed
+    Error,
+    /// The server was dropped with the client still connected
+    ServerShutdown,
+}
+
+/// Lifecycle event of a connection, for audit logs
+///
+/// Events are delivered to the [`AuditSink`] installed with
+/// `EpollServer::set_audit_sink` as they happen, from the loop's thread.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AuditEvent {
+    /// A connection was admitted, right before `on_connection`
+    Accepted {
+        client_id: ClientId,
+        peer_addr: SocketAddr,
+        listener_id: ListenerId,
+    },
+    /// The client's codec completed its TLS handshake
+    TlsHandshakeDone {
+        client_id: ClientId,
+        peer_addr: SocketAddr,
+    },
+    /// The client left, reported after `on_disconnect`
+    Disconnected {
+        client_id: ClientId,
+        peer_addr: SocketAddr,
+        reason: DisconnectReason,
+    },
+    /// A message from the client went over its rate limit, or, with
+    /// `client_id` unset, a connection from `peer_addr` went over
+    /// `ServerConfig::ip_accept_rate` and was closed
+    RateLimited {
+        client_id: Option<ClientId>,
+        peer_addr: SocketAddr,
+    },
+    /// More than `ServerConfig::max_pending_writes` bytes were queued for
+    /// the client, which is disconnected
+    BackpressureHit {
+        client_id: ClientId,
+        peer_addr: SocketAddr,
+        pending: usize,
+    },
+}
+
+/// Receiver of the [`AuditEvent`]s of the server
+///
+/// Closures `FnMut(&AuditEvent)` are sinks, and so are channel senders:
+/// a `Sender` never blocks, a `SyncSender` drops the events its full
+/// channel has no room for rather than stalling the loop.
+pub trait AuditSink {
+    fn on_audit_event(&mut self, event: &AuditEvent);
+}
+
+impl<F: FnMut(&AuditEvent)> AuditSink for F {
+    fn on_audit_event(&mut self, event: &AuditEvent) {
+        self(event)
+    }
+}
+
+impl AuditSink for Sender<AuditEvent> {
+    fn on_audit_event(&mut self, event: &AuditEvent) {
+        // Nothing left to deliver to once the receiver is gone
+        let _ = self.send(event.clone());
+    }
+}
+
+impl AuditSink for SyncSender<AuditEvent> {
+    fn on_audit_event(&mut self, event: &AuditEvent) {
+        let _ = self.try_send(event.clone());
+    }
+}
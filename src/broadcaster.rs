@@ -0,0 +1,61 @@
+use std::time::Instant;
+
+use crate::{bytes::Bytes, error::Error, server_handle::ServerHandle};
+
+/// Broadcasts to the clients of several worker loops
+///
+/// A broadcast is one command per worker, all of them carrying the same
+/// reference counted payload, and each loop then queues that payload to
+/// its own clients. Reaching 100k clients over 4 workers takes 4 queued
+/// commands and wake-ups instead of 100k, and one copy of the data.
+///
+/// ```no_run
+/// # use epoll_worker::{Acceptor, Broadcaster};
+/// # fn publish(acceptor: &Acceptor) -> epoll_worker::Result<()> {
+/// let broadcaster: Broadcaster = acceptor.broadcaster();
+/// broadcaster.broadcast(b"tick\n".to_vec())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct Broadcaster {
+    workers: Vec<ServerHandle>,
+}
+
+impl Broadcaster {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Include the clients of the loop behind `worker`
+    pub fn add_worker(&mut self, worker: ServerHandle) {
+        self.workers.push(worker);
+    }
+
+    /// Queue data to be written to every client of every worker
+    ///
+    /// Only clients connected when this is called receive the data, the
+    /// same instant applying to all workers. Fails with
+    /// `Error::ShutdownInProgress` if a worker is gone, after queueing the
+    /// data to the others.
+    pub fn broadcast(&self, data: impl Into<Bytes>) -> crate::Result<()> {
+        let data = data.into();
+        let emitted_at = Instant::now();
+        let mut result = Ok(());
+        for worker in &self.workers {
+            match worker.broadcast_emitted_at(data.clone(), emitted_at) {
+                Ok(()) => {}
+                Err(Error::ShutdownInProgress) => result = Err(Error::ShutdownInProgress),
+                Err(e) => return Err(e),
+            }
+        }
+        result
+    }
+
+    /// Number of clients the workers had at the end of their last tick
+    pub fn connected_clients(&self) -> usize {
+        self.workers
+            .iter()
+            .map(ServerHandle::connected_clients)
+            .sum()
+    }
+}
@@ -0,0 +1,42 @@
+/// Pool of fixed-size buffers reused by the event loop
+///
+/// Reading from a socket needs scratch space before the bytes are copied
+/// into the client's read buffer. Checking buffers out of the pool and
+/// returning them afterwards avoids an allocation per read event.
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    slab_size: usize,
+    max_buffers: usize,
+    free: Vec<Vec<u8>>,
+}
+
+impl BufferPool {
+    /// Create a pool of `max_buffers` buffers of `slab_size` bytes each
+    ///
+    /// All buffers are allocated upfront.
+    pub fn new(slab_size: usize, max_buffers: usize) -> Self {
+        let free = (0..max_buffers).map(|_| vec![0u8; slab_size]).collect();
+        BufferPool {
+            slab_size,
+            max_buffers,
+            free,
+        }
+    }
+
+    /// Take a buffer out of the pool
+    ///
+    /// Allocates a new one if the pool is exhausted.
+    pub fn checkout(&mut self) -> Vec<u8> {
+        self.free.pop().unwrap_or_else(|| vec![0u8; self.slab_size])
+    }
+
+    /// Return a buffer to the pool
+    ///
+    /// The buffer is dropped if the pool is already full or if it is
+    /// not one of ours (wrong size).
+    pub fn checkin(&mut self, buffer: Vec<u8>) {
+        if self.free.len() < self.max_buffers && buffer.len() == self.slab_size {
+            self.free.push(buffer);
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! Fault injection into the client I/O, with the `chaos` feature
+//!
+//! A server configured with `ServerConfig::chaos` makes the socket reads
+//! and writes of its clients misbehave the way a loaded network does:
+//! short reads and writes, spurious `WouldBlock`s, slow flushes, lost
+//! data and connections reset under the handler's feet. It is meant for
+//! tests of applications built on `EventHandler`, never for production.
+//! Without the feature the types here are empty and the hooks do nothing,
+//! the client I/O calls them regardless.
+
+use std::io::Result;
+#[cfg(feature = "chaos")]
+use std::{
+    io::{Error, ErrorKind},
+    thread,
+    time::Duration,
+};
+
+use crate::{client_id::ClientId, config::ServerConfig};
+
+/// Seed of the faults unless `ChaosConfig::seed` sets another
+#[cfg(feature = "chaos")]
+const DEFAULT_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Faults injected into the client I/O, see `ServerConfig::chaos`
+///
+/// Every rate is the probability, between `0.0` and `1.0`, that a single
+/// read or write call fails that way. Faults are drawn from a generator
+/// seeded with [`ChaosConfig::seed`] and the client id, so a test
+/// replays the same faults for the same clients and traffic.
+///
+/// Reads and the writes of queued buffers are affected, files and
+/// `DataSource` streams are written untouched.
+#[cfg(feature = "chaos")]
+#[derive(Debug, Clone)]
+pub struct ChaosConfig {
+    write_drop_rate: f64,
+    would_block_rate: f64,
+    disconnect_rate: f64,
+    max_read: Option<usize>,
+    max_write: Option<usize>,
+    flush_delay: Option<Duration>,
+    seed: u64,
+}
+
+#[cfg(feature = "chaos")]
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        ChaosConfig {
+            write_drop_rate: 0.0,
+            would_block_rate: 0.0,
+            disconnect_rate: 0.0,
+            max_read: None,
+            max_write: None,
+            flush_delay: None,
+            seed: DEFAULT_SEED,
+        }
+    }
+}
+
+#[cfg(feature = "chaos")]
+impl ChaosConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Share of writes reported as sent without reaching the socket, the
+    /// peer never receives their data
+    pub fn write_drop_rate(mut self, rate: f64) -> Self {
+        self.write_drop_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Share of reads and writes failing with `WouldBlock` before the
+    /// socket is touched
+    ///
+    /// The client is re-armed so the loop hears from it again, even with
+    /// edge-triggered notifications.
+    pub fn would_block_rate(mut self, rate: f64) -> Self {
+        self.would_block_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Share of reads and writes failing with `ConnectionReset`, which
+    /// goes through `EventHandler::on_error` like a real reset
+    pub fn disconnect_rate(mut self, rate: f64) -> Self {
+        self.disconnect_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Read at most `bytes` per call, so messages arrive in pieces
+    pub fn max_read(mut self, bytes: usize) -> Self {
+        self.max_read = Some(bytes.max(1));
+        self
+    }
+
+    /// Write at most `bytes` per call, so replies leave in pieces
+    pub fn max_write(mut self, bytes: usize) -> Self {
+        self.max_write = Some(bytes.max(1));
+        self
+    }
+
+    /// Block the loop for `delay` before every flush of a client's queue,
+    /// as a slow `send` would
+    pub fn flush_delay(mut self, delay: Duration) -> Self {
+        self.flush_delay = Some(delay);
+        self
+    }
+
+    /// Seed of the fault generator
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+/// What the next write of a client does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WriteChaos {
+    /// Write up to this many bytes
+    Write(usize),
+    /// Pretend everything was written
+    #[cfg_attr(not(feature = "chaos"), allow(dead_code))]
+    Drop,
+}
+
+/// Faults of one client
+#[derive(Debug, Default)]
+pub(crate) struct Chaos {
+    #[cfg(feature = "chaos")]
+    injector: Option<Injector>,
+}
+
+#[cfg(feature = "chaos")]
+#[derive(Debug)]
+struct Injector {
+    config: ChaosConfig,
+    /// xorshift64* state, never zero
+    state: u64,
+    /// A `WouldBlock` was injected while the socket may still be ready
+    rearm: bool,
+}
+
+#[cfg(feature = "chaos")]
+impl Injector {
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Draw whether a fault of probability `rate` happens
+    fn roll(&mut self, rate: f64) -> bool {
+        // The top 53 bits make a uniform f64 in [0, 1)
+        let draw = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+        rate > 0.0 && draw < rate
+    }
+
+    /// The fault failing the next call, if any
+    fn fault(&mut self) -> Result<()> {
+        if self.roll(self.config.disconnect_rate) {
+            return Err(Error::new(
+                ErrorKind::ConnectionReset,
+                "Connection reset by ChaosConfig",
+            ));
+        }
+        if self.roll(self.config.would_block_rate) {
+            self.rearm = true;
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        Ok(())
+    }
+}
+
+impl Chaos {
+    /// Faults of the client `client_id` under `config`
+    #[allow(unused_variables)]
+    pub fn new(config: &ServerConfig, client_id: ClientId) -> Self {
+        Chaos {
+            #[cfg(feature = "chaos")]
+            injector: config.chaos_config().map(|config| {
+                // splitmix64 of the seed and the id, so clients get
+                // unrelated sequences
+                let mut state = config
+                    .seed
+                    .wrapping_add(client_id.get().wrapping_mul(DEFAULT_SEED));
+                state = (state ^ (state >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                state = (state ^ (state >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                Injector {
+                    config: config.clone(),
+                    state: (state ^ (state >> 31)).max(1),
+                    rearm: false,
+                }
+            }),
+        }
+    }
+
+    /// How many of `len` bytes the next read may take, or the error it
+    /// fails with
+    pub fn read_len(&mut self, len: usize) -> Result<usize> {
+        #[cfg(feature = "chaos")]
+        if let Some(injector) = &mut self.injector {
+            injector.fault()?;
+            return Ok(injector.config.max_read.map_or(len, |max| len.min(max)));
+        }
+        Ok(len)
+    }
+
+    /// What the next write of `len` bytes does, or the error it fails with
+    pub fn write_len(&mut self, len: usize) -> Result<WriteChaos> {
+        #[cfg(feature = "chaos")]
+        if let Some(injector) = &mut self.injector {
+            injector.fault()?;
+            if injector.roll(injector.config.write_drop_rate) {
+                return Ok(WriteChaos::Drop);
+            }
+            let len = injector.config.max_write.map_or(len, |max| len.min(max));
+            return Ok(WriteChaos::Write(len));
+        }
+        Ok(WriteChaos::Write(len))
+    }
+
+    /// Sleep for the configured flush delay
+    pub fn delay_flush(&self) {
+        #[cfg(feature = "chaos")]
+        if let Some(delay) = self.injector.as_ref().and_then(|i| i.config.flush_delay) {
+            thread::sleep(delay);
+        }
+    }
+
+    /// Whether an injected `WouldBlock` left the client to re-arm
+    pub fn rearm_pending(&self) -> bool {
+        #[cfg(feature = "chaos")]
+        if let Some(injector) = &self.injector {
+            return injector.rearm;
+        }
+        false
+    }
+
+    /// Clear the re-arm request, returning whether there was one
+    pub fn take_rearm(&mut self) -> bool {
+        #[cfg(feature = "chaos")]
+        if let Some(injector) = &mut self.injector {
+            return std::mem::take(&mut injector.rearm);
+        }
+        false
+    }
+}
@@ -0,0 +1,36 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+};
+
+/// Values the handler attached to a client, at most one per type
+#[derive(Default)]
+pub(crate) struct ClientData {
+    values: HashMap<TypeId, Box<dyn Any + Send>>,
+}
+
+impl ClientData {
+    /// Store `value`, returning the previous value of the same type
+    pub fn insert<T: Any + Send>(&mut self, value: T) -> Option<T> {
+        self.values
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    pub fn get<T: Any + Send>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    pub fn get_mut<T: Any + Send>(&mut self) -> Option<&mut T> {
+        self.values.get_mut(&TypeId::of::<T>())?.downcast_mut()
+    }
+
+    pub fn remove<T: Any + Send>(&mut self) -> Option<T> {
+        self.values
+            .remove(&TypeId::of::<T>())?
+            .downcast()
+            .ok()
+            .map(|value| *value)
+    }
+}
@@ -0,0 +1,64 @@
+use std::{fmt, net::SocketAddr};
+
+/// Identifies a connected client
+///
+/// Every `EventHandler` callback, `HandlerAction` and `ServerHandle`
+/// command names clients by their id. Application ids convert from and to
+/// `u64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ClientId(u64);
+
+impl ClientId {
+    pub const fn new(id: u64) -> Self {
+        ClientId(id)
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for ClientId {
+    fn from(id: u64) -> Self {
+        ClientId(id)
+    }
+}
+
+impl From<ClientId> for u64 {
+    fn from(id: ClientId) -> Self {
+        id.0
+    }
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Largest id a [`ClientIdAllocator`] may hand out
+///
+/// Ids double as epoll tokens, the bits above are used to tell clients
+/// apart from the server's other file descriptors.
+pub const MAX_CLIENT_ID: ClientId = ClientId((1 << 61) - 1);
+
+/// Issues the ids of newly accepted clients
+///
+/// By default ids are issued by the server's client storage: the first
+/// client gets id 1, and an id is not handed out again before its storage
+/// slot was reused by hundreds of millions of clients. An
+/// allocator lets the application use its own identities instead, e.g.
+/// ids issued by an auth service or a stable mapping per peer, so handlers
+/// can pass them to `send_to` and friends directly. Install it with
+/// `EpollServer::set_id_allocator`.
+///
+/// Ids must be unique among connected clients, non-zero and at most
+/// [`MAX_CLIENT_ID`]. A connection that gets an invalid id is closed right
+/// away.
+pub trait ClientIdAllocator {
+    /// Id for the client just accepted from `peer_addr`
+    fn allocate(&mut self, peer_addr: SocketAddr) -> ClientId;
+
+    /// Called after `on_disconnect`, the id may be handed out again
+    fn release(&mut self, _client_id: ClientId) {}
+}
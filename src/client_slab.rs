@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use crate::{
+    client_id::{ClientId, MAX_CLIENT_ID},
+    client_state::ClientState,
+};
+
+/// Bits of a slab issued id holding the slot number plus one
+const SLOT_BITS: u32 = 32;
+
+/// Largest generation a slab issued id holds below `MAX_CLIENT_ID`
+const MAX_GENERATION: u32 = (MAX_CLIENT_ID.get() >> SLOT_BITS) as u32;
+
+#[derive(Default)]
+struct Slot {
+    /// Bumped every time the slot is vacated
+    generation: u32,
+    client: Option<(ClientId, ClientState)>,
+}
+
+/// The connected clients, stored in a vector of slots
+///
+/// Ids issued by the slab with [`ClientSlab::vacant_id`] are made of the
+/// slot number plus one and the slot's generation, so looking a client up
+/// is indexing the vector. A vacated slot is reused by the next client
+/// under its next generation: the old id no longer matches, and commands
+/// queued for a client that left never reach the next one. Ids from a
+/// `ClientIdAllocator` are mapped to their slot on the side.
+#[derive(Default)]
+pub(crate) struct ClientSlab {
+    slots: Vec<Slot>,
+    /// Vacated slots, the last one is reused first
+    free: Vec<usize>,
+    /// Slots of the clients whose ids the slab did not issue
+    foreign: HashMap<ClientId, usize>,
+    len: usize,
+}
+
+impl ClientSlab {
+    /// The id [`ClientSlab::insert`] stores the next client under without
+    /// mapping it on the side
+    ///
+    /// Never one of a connected client, nor one a client had since the
+    /// slot was last reused `MAX_GENERATION` times.
+    pub fn vacant_id(&self) -> ClientId {
+        let index = self.next_slot();
+        let generation = self.slots.get(index).map_or(0, |slot| slot.generation);
+        ClientId::new(((generation as u64) << SLOT_BITS) | (index as u64 + 1))
+    }
+
+    /// Store `client` under `id`, which must not be connected already
+    pub fn insert(&mut self, id: ClientId, client: ClientState) {
+        debug_assert!(!self.contains_key(&id), "client {id} is already stored");
+        let issued = id == self.vacant_id();
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot::default());
+                self.slots.len() - 1
+            }
+        };
+        if !issued {
+            self.foreign.insert(id, index);
+        }
+        self.slots[index].client = Some((id, client));
+        self.len += 1;
+    }
+
+    pub fn remove(&mut self, id: &ClientId) -> Option<ClientState> {
+        let index = self.slot_of(*id)?;
+        let slot = &mut self.slots[index];
+        let (_, client) = slot.client.take()?;
+        slot.generation = match slot.generation {
+            MAX_GENERATION => 0,
+            generation => generation + 1,
+        };
+        self.foreign.remove(id);
+        self.free.push(index);
+        self.len -= 1;
+        Some(client)
+    }
+
+    pub fn get(&self, id: &ClientId) -> Option<&ClientState> {
+        let index = self.slot_of(*id)?;
+        self.slots[index].client.as_ref().map(|(_, client)| client)
+    }
+
+    pub fn get_mut(&mut self, id: &ClientId) -> Option<&mut ClientState> {
+        let index = self.slot_of(*id)?;
+        self.slots[index].client.as_mut().map(|(_, client)| client)
+    }
+
+    pub fn contains_key(&self, id: &ClientId) -> bool {
+        self.slot_of(*id).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The connected clients, in slot order
+    pub fn iter(&self) -> impl Iterator<Item = (&ClientId, &ClientState)> {
+        self.slots
+            .iter()
+            .filter_map(|slot| slot.client.as_ref().map(|(id, client)| (id, client)))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &ClientId> {
+        self.iter().map(|(id, _)| id)
+    }
+
+    fn next_slot(&self) -> usize {
+        self.free.last().copied().unwrap_or(self.slots.len())
+    }
+
+    /// Slot of the connected client `id`
+    fn slot_of(&self, id: ClientId) -> Option<usize> {
+        let index = match self.foreign.get(&id) {
+            Some(&index) => index,
+            None => (id.get() & ((1 << SLOT_BITS) - 1)).checked_sub(1)? as usize,
+        };
+        let (stored, _) = self.slots.get(index)?.client.as_ref()?;
+        (*stored == id).then_some(index)
+    }
+}
@@ -1,96 +1,824 @@
 use std::{
     collections::VecDeque,
-    io::{ErrorKind, Result, Write},
+    fs::File,
+    io::{Error, ErrorKind, Result},
     net::{Shutdown, SocketAddr, TcpStream},
     os::fd::{AsRawFd, RawFd},
+    ptr,
+    time::Instant,
 };
 
-#[derive(Debug)]
+use crate::{
+    EventFlags,
+    audit::DisconnectReason,
+    bytes::Bytes,
+    chaos::{Chaos, WriteChaos},
+    client_data::ClientData,
+    context::{ListenerId, PRIMARY_LISTENER},
+    ep_syscall,
+    ffi::{IoVec, MSG_DONTWAIT, MSG_NOSIGNAL, MsgHdr},
+    handler::DataSource,
+    protocol::{Codec, Frame, PeerIdentity, ProxyHeader, ReadBuf, Transport, decode_proxy_header},
+    rate_limit::RateLimiter,
+    sockopt,
+    telemetry::MessageTrace,
+    trace::ConnectionSpan,
+};
+
+/// Flags of every `send` and `recv` of a client socket, so neither blocks
+/// nor raises `SIGPIPE` whatever the socket and signal settings
+const SOCKET_FLAGS: i32 = MSG_DONTWAIT | MSG_NOSIGNAL;
+
+/// Most buffers gathered into one `sendmsg` call, well below `IOV_MAX`
+const MAX_IOVECS: usize = 64;
+
+/// How a read from a client socket ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReadOutcome {
+    /// Bytes were read, more may be waiting
+    Data(usize),
+    /// The client closed its end, after the bytes already read
+    Eof,
+    /// Nothing is left to read for now
+    WouldBlock,
+}
+
+/// Bytes pulled from a `DataSource` at a time
+const STREAM_CHUNK_LEN: usize = 64 * 1024;
+
+/// Write path counters of a single client
+///
+/// Frequent short writes and `WouldBlock`s mean the peer is not reading
+/// fast enough, the connection is limited by the receiver and not by us.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WriteStats {
+    /// Number of `write` calls that accepted only part of the buffer
+    pub partial_writes: u64,
+    /// Number of times the socket buffer was full (`WouldBlock`)
+    pub would_blocks: u64,
+}
+
+/// Lane of the write queue a buffer is queued in
+///
+/// High priority buffers are written before every normal one still
+/// queued, in the order they were queued, so control frames such as a
+/// WebSocket pong are not stuck behind a large payload. Buffers are never
+/// split: one already being written, or a file or stream already started,
+/// is finished first.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    #[default]
+    Normal,
+    High,
+}
+
+/// What happens to the connection once the write queue drains
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum AfterDrain {
+    /// Keep it open
+    #[default]
+    Nothing,
+    /// Shut the write side down, reading carries on until the peer's EOF
+    ShutdownWrite,
+    /// Disconnect the client
+    Close,
+}
+
+/// Data waiting to be written to the client
+enum QueuedWrite {
+    /// In-memory buffer, possibly queued to other clients as well
+    Bytes(Bytes),
+    /// In-memory buffer queued with `Priority::High`, ahead of the others
+    Urgent(Bytes),
+    /// Small messages copied one after the other, see
+    /// `ServerConfig::coalesce_writes`
+    Coalesced(Vec<u8>),
+    /// Region of a file, sent with `sendfile` without copying it through
+    /// userspace
+    File {
+        file: File,
+        offset: i64,
+        remaining: usize,
+        /// Part of the file was sent
+        started: bool,
+    },
+    /// Response pulled from its source one chunk at a time
+    Stream {
+        source: Box<dyn DataSource + Send>,
+        /// Last chunk pulled, `written` bytes of it are out
+        chunk: Vec<u8>,
+        written: usize,
+    },
+}
+
+impl QueuedWrite {
+    /// Contents of an in-memory buffer, `None` for a file or a stream
+    fn bytes(&self) -> Option<&[u8]> {
+        match self {
+            QueuedWrite::Bytes(buffer) | QueuedWrite::Urgent(buffer) => Some(buffer),
+            QueuedWrite::Coalesced(buffer) => Some(buffer),
+            QueuedWrite::File { .. } | QueuedWrite::Stream { .. } => None,
+        }
+    }
+}
+
 pub(crate) struct ClientState {
     stream: TcpStream,
-    read_buffer: Vec<u8>,
-    write_queue: VecDeque<Vec<u8>>,
-    write_buffer: Option<Vec<u8>>,
+    peer_addr: SocketAddr,
+    listener_id: ListenerId,
+    /// When the client was accepted
+    connected_at: Instant,
+    read_buffer: ReadBuf,
+    write_queue: VecDeque<QueuedWrite>,
+    /// Bytes of the front buffer already written
     write_offset: usize,
-    current_interests: u32,
+    /// Bytes of the queued buffers not written yet, queued files excluded
+    buffered_bytes: usize,
+    /// Size of the buffers small messages are copied into, if they are
+    coalesce_limit: Option<usize>,
+    current_interests: EventFlags,
+    reads_paused: bool,
+    after_drain: AfterDrain,
+    /// `TCP_CORK` set by the handler, kept until it uncorks
+    corked: bool,
+    /// `TCP_CORK` set for a multi-part response, cleared once it is written
+    auto_corked: bool,
+    write_stats: WriteStats,
+    codec: Option<Box<dyn Codec + Send>>,
+    /// Bytes ever queued and written, to tell when a traced response is out
+    queued_bytes: u64,
+    written_bytes: u64,
+    /// `written_bytes` when the queue last drained
+    drained_at: u64,
+    /// Traces waiting for `written_bytes` to reach their end offset, with
+    /// the time their response was queued
+    pending_traces: VecDeque<(u64, Instant, MessageTrace)>,
+    completed_traces: Vec<MessageTrace>,
+    data: ClientData,
+    rate_limiter: Option<RateLimiter>,
+    /// When data was last read from the client
+    last_read_at: Instant,
+    /// Since when the read buffer holds an incomplete message
+    partial_since: Option<Instant>,
+    /// A PROXY header must be decoded before any other data
+    awaiting_proxy_header: bool,
+    proxy_header: Option<ProxyHeader>,
+    span: ConnectionSpan,
+    /// The codec was negotiating the connection when last checked
+    handshaking: bool,
+    /// Why the client is being disconnected, the first reason noted wins
+    disconnect_reason: Option<DisconnectReason>,
+    chaos: Chaos,
 }
 
 impl ClientState {
-    pub fn new(stream: TcpStream) -> Self {
+    pub fn new(
+        stream: TcpStream,
+        peer_addr: SocketAddr,
+        read_capacity: usize,
+        write_queue_capacity: usize,
+    ) -> Self {
+        let now = Instant::now();
         ClientState {
             stream,
-            read_buffer: Vec::with_capacity(16384),
-            write_queue: VecDeque::with_capacity(16),
-            write_buffer: None,
+            peer_addr,
+            listener_id: PRIMARY_LISTENER,
+            connected_at: now,
+            read_buffer: ReadBuf::with_capacity(read_capacity),
+            write_queue: VecDeque::with_capacity(write_queue_capacity),
             write_offset: 0,
-            current_interests: 0,
+            buffered_bytes: 0,
+            coalesce_limit: None,
+            current_interests: EventFlags::empty(),
+            reads_paused: false,
+            after_drain: AfterDrain::default(),
+            corked: false,
+            auto_corked: false,
+            write_stats: WriteStats::default(),
+            codec: None,
+            queued_bytes: 0,
+            written_bytes: 0,
+            drained_at: 0,
+            pending_traces: VecDeque::new(),
+            completed_traces: Vec::new(),
+            data: ClientData::default(),
+            rate_limiter: None,
+            last_read_at: now,
+            partial_since: None,
+            awaiting_proxy_header: false,
+            proxy_header: None,
+            span: ConnectionSpan::default(),
+            handshaking: false,
+            disconnect_reason: None,
+            chaos: Chaos::default(),
+        }
+    }
+
+    pub fn queue_write(&mut self, data: Bytes) {
+        self.queue_write_with_priority(data, Priority::Normal);
+    }
+
+    /// Queue `data` in the lane of `priority`
+    pub fn queue_write_with_priority(&mut self, data: Bytes, priority: Priority) {
+        self.queued_bytes += data.len() as u64;
+        self.buffered_bytes += data.len();
+        match priority {
+            Priority::Normal => match self.coalesce_limit {
+                Some(limit) if data.len() < limit => self.coalesce(&data, limit),
+                _ => self.write_queue.push_back(QueuedWrite::Bytes(data)),
+            },
+            Priority::High => {
+                // After the entry being written and the urgent ones
+                // queued before
+                let start = usize::from(self.front_started());
+                let position = self
+                    .write_queue
+                    .iter()
+                    .skip(start)
+                    .position(|queued| !matches!(queued, QueuedWrite::Urgent(_)))
+                    .map_or(self.write_queue.len(), |index| start + index);
+                self.write_queue.insert(position, QueuedWrite::Urgent(data));
+            }
+        }
+    }
+
+    /// Copy `data` at the end of the last coalesced buffer, or of a new
+    /// one if it would outgrow `limit`
+    fn coalesce(&mut self, data: &[u8], limit: usize) {
+        if let Some(QueuedWrite::Coalesced(buffer)) = self.write_queue.back_mut()
+            && buffer.len() + data.len() <= limit
+        {
+            buffer.extend_from_slice(data);
+            return;
+        }
+        let mut buffer = Vec::with_capacity(limit);
+        buffer.extend_from_slice(data);
+        self.write_queue.push_back(QueuedWrite::Coalesced(buffer));
+    }
+
+    /// Whether part of the front entry was written, it is then finished
+    /// before anything queued with priority
+    fn front_started(&self) -> bool {
+        match self.write_queue.front() {
+            Some(QueuedWrite::Bytes(_) | QueuedWrite::Urgent(_) | QueuedWrite::Coalesced(_)) => {
+                self.write_offset > 0
+            }
+            Some(QueuedWrite::File { started, .. }) => *started,
+            Some(QueuedWrite::Stream { chunk, .. }) => !chunk.is_empty(),
+            None => false,
+        }
+    }
+
+    /// Queue `len` bytes of `file` starting at `offset`
+    ///
+    /// The file is sent as is, after everything queued before it.
+    pub fn queue_file(&mut self, file: File, offset: u64, len: usize) -> Result<()> {
+        let offset = i64::try_from(offset)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "file offset too large"))?;
+        if len > 0 {
+            self.queued_bytes += len as u64;
+            self.write_queue.push_back(QueuedWrite::File {
+                file,
+                offset,
+                remaining: len,
+                started: false,
+            });
+        }
+        Ok(())
+    }
+
+    /// Queue a response pulled from `source` as the socket drains
+    ///
+    /// Like a file, it is sent as is after everything queued before it.
+    pub fn queue_stream(&mut self, source: Box<dyn DataSource + Send>) {
+        self.write_queue.push_back(QueuedWrite::Stream {
+            source,
+            chunk: Vec::new(),
+            written: 0,
+        });
+    }
+
+    /// Queue an application message, framed by the codec if there is one
+    pub fn queue_message(&mut self, data: Bytes) {
+        self.queue_message_with_priority(data, Priority::Normal);
+    }
+
+    /// Queue an application message in the lane of `priority`
+    pub fn queue_message_with_priority(&mut self, data: Bytes, priority: Priority) {
+        let data = match &mut self.codec {
+            Some(codec) => codec.encode(&data).into(),
+            None => data,
+        };
+        self.queue_write_with_priority(data, priority);
+    }
+
+    pub fn set_codec(&mut self, codec: Option<Box<dyn Codec + Send>>) {
+        self.handshaking = codec.as_ref().is_some_and(|codec| codec.is_handshaking());
+        self.codec = codec;
+    }
+
+    /// Whether the codec was negotiating the connection when last checked
+    pub fn handshaking(&self) -> bool {
+        self.handshaking
+    }
+
+    /// Returns `true` the first time the codec is found done with the
+    /// handshake it started with
+    pub fn finished_handshake(&mut self) -> bool {
+        if !self.handshaking || self.codec.as_ref().is_some_and(|c| c.is_handshaking()) {
+            return false;
+        }
+        self.handshaking = false;
+        true
+    }
+
+    /// Identity the peer proved to the codec, see `Codec::peer_identity`
+    pub fn peer_identity(&self) -> Option<PeerIdentity> {
+        self.codec.as_ref()?.peer_identity()
+    }
+
+    /// Whether the codec expects no more messages, see
+    /// `Codec::wants_close`
+    pub fn codec_wants_close(&self) -> bool {
+        self.codec.as_ref().is_some_and(|codec| codec.wants_close())
+    }
+
+    pub fn has_codec(&self) -> bool {
+        self.codec.is_some()
+    }
+
+    pub fn transport(&self) -> Transport {
+        match &self.codec {
+            Some(codec) => codec.transport(),
+            None => Transport::Raw,
         }
     }
 
-    pub fn queue_write(&mut self, data: Vec<u8>) {
-        self.write_queue.push_back(data);
+    /// Decode the next frame from the read buffer and drop its bytes
+    pub fn decode_frame(&mut self) -> Result<Option<Frame>> {
+        let Some(codec) = &mut self.codec else {
+            return Ok(None);
+        };
+        codec.decode_buf(&mut self.read_buffer)
     }
 
     pub fn has_pending_writes(&self) -> bool {
-        !self.write_queue.is_empty() || self.write_buffer.is_some()
+        !self.write_queue.is_empty()
     }
 
+    /// Bytes of queued messages the client has not been sent yet
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    /// Write queued data until the queue is empty or the socket is full
+    ///
+    /// Consecutive queued buffers are gathered into a single `sendmsg` call,
+    /// so many small messages cost one syscall instead of one each. Queued
+    /// files are sent with `sendfile`, resuming where the last call stopped.
     pub fn flush_writes(&mut self) -> Result<bool> {
+        self.chaos.delay_flush();
         loop {
-            if self.write_buffer.is_none() {
-                if let Some(next_buffer) = self.write_queue.pop_front() {
-                    self.write_buffer = Some(next_buffer);
-                    self.write_offset = 0;
-                } else {
-                    self.stream.shutdown(Shutdown::Both)?;
+            let result = match self.write_queue.front() {
+                None => {
+                    self.release_auto_cork()?;
                     return Ok(true);
                 }
+                Some(
+                    QueuedWrite::Bytes(_) | QueuedWrite::Urgent(_) | QueuedWrite::Coalesced(_),
+                ) => self.write_buffers(),
+                Some(QueuedWrite::File { .. }) => self.write_file(),
+                Some(QueuedWrite::Stream { .. }) => self.write_stream(),
+            };
+
+            match result {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    // CAnnot write more now
+                    self.write_stats.would_blocks += 1;
+                    return Ok(false);
+                }
+                Err(e) => return Err(e),
             }
+        }
+    }
+
+    /// Set or clear `TCP_CORK` on behalf of the handler
+    pub fn set_corked(&mut self, corked: bool) -> Result<()> {
+        if corked != (self.corked || self.auto_corked) {
+            sockopt::set_cork(self.stream.as_raw_fd(), corked)?;
+        }
+        self.corked = corked;
+        self.auto_corked = false;
+        Ok(())
+    }
+
+    /// Whether the handler corked the socket and has not uncorked it
+    pub fn is_corked(&self) -> bool {
+        self.corked
+    }
 
-            if let Some(ref buffer) = self.write_buffer {
-                match self.stream.write(&buffer[self.write_offset..]) {
-                    Ok(0) => {
-                        // Cannot Write, Connection closed
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::BrokenPipe,
-                            "Connection closed",
-                        ));
-                    }
-                    Ok(bytes_written) => {
-                        self.write_offset += bytes_written;
-
-                        if self.write_offset >= buffer.len() {
-                            self.write_buffer = None;
-                            self.write_offset = 0;
-                        }
-                    }
-                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
-                        // CAnnot write more now
-                        return Ok(false);
-                    }
-                    Err(e) => return Err(e),
+    /// Cork the socket until the queue is written, so the parts of a
+    /// response leave in full segments
+    pub fn auto_cork(&mut self) -> Result<()> {
+        if !self.corked && !self.auto_corked {
+            sockopt::set_cork(self.stream.as_raw_fd(), true)?;
+            self.auto_corked = true;
+        }
+        Ok(())
+    }
+
+    fn release_auto_cork(&mut self) -> Result<()> {
+        if self.auto_corked {
+            sockopt::set_cork(self.stream.as_raw_fd(), false)?;
+            self.auto_corked = false;
+        }
+        Ok(())
+    }
+
+    /// Write the buffers at the front of the queue with one `sendmsg`
+    fn write_buffers(&mut self) -> Result<()> {
+        let mut iovecs = Vec::with_capacity(MAX_IOVECS.min(self.write_queue.len()));
+        for (index, queued) in self.write_queue.iter().take(MAX_IOVECS).enumerate() {
+            let Some(buffer) = queued.bytes() else {
+                break;
+            };
+            let offset = if index == 0 { self.write_offset } else { 0 };
+            iovecs.push(IoVec::from(&buffer[offset..]));
+        }
+        let mut total_len: usize = iovecs.iter().map(|iovec| iovec.len).sum();
+        match self.chaos.write_len(total_len)? {
+            WriteChaos::Drop => {
+                self.advance_writes(total_len);
+                return Ok(());
+            }
+            WriteChaos::Write(len) if len < total_len => {
+                let mut left = len;
+                iovecs.retain_mut(|iovec| {
+                    iovec.len = iovec.len.min(left);
+                    left -= iovec.len;
+                    iovec.len > 0
+                });
+                total_len = len;
+            }
+            WriteChaos::Write(_) => {}
+        }
+
+        let message = MsgHdr {
+            name: ptr::null_mut(),
+            namelen: 0,
+            iov: iovecs.as_mut_ptr(),
+            iovlen: iovecs.len(),
+            control: ptr::null_mut(),
+            controllen: 0,
+            flags: 0,
+        };
+        let fd = self.stream.as_raw_fd();
+        match ep_syscall!(sendmsg(fd, &raw const message, SOCKET_FLAGS))? {
+            // Cannot Write, Connection closed
+            0 if total_len > 0 => Err(Error::new(ErrorKind::BrokenPipe, "Connection closed")),
+            bytes_written => {
+                let bytes_written = bytes_written as usize;
+                if bytes_written < total_len {
+                    self.write_stats.partial_writes += 1;
                 }
+                self.advance_writes(bytes_written);
+                Ok(())
+            }
+        }
+    }
+
+    /// Send the file at the front of the queue with `sendfile`
+    fn write_file(&mut self) -> Result<()> {
+        let fd = self.stream.as_raw_fd();
+        let Some(QueuedWrite::File {
+            file,
+            offset,
+            remaining,
+            started,
+        }) = self.write_queue.front_mut()
+        else {
+            return Ok(());
+        };
+
+        // The kernel advances `offset` by the bytes sent
+        let sent = ep_syscall!(sendfile(fd, file.as_raw_fd(), offset, *remaining))? as usize;
+        if sent == 0 {
+            // The file ended before `len` bytes, it was truncated meanwhile
+            self.write_queue.pop_front();
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "File shorter than the requested length",
+            ));
+        }
+
+        *remaining -= sent;
+        *started = true;
+        if *remaining > 0 {
+            self.write_stats.partial_writes += 1;
+        } else {
+            self.write_queue.pop_front();
+        }
+        self.record_written(sent);
+        Ok(())
+    }
+
+    /// Write the current chunk of the stream at the front of the queue,
+    /// pulling the next one once it is out
+    fn write_stream(&mut self) -> Result<()> {
+        let fd = self.stream.as_raw_fd();
+        let Some(QueuedWrite::Stream {
+            source,
+            chunk,
+            written,
+        }) = self.write_queue.front_mut()
+        else {
+            return Ok(());
+        };
+
+        if *written == chunk.len() {
+            chunk.resize(STREAM_CHUNK_LEN, 0);
+            let pulled = source.pull(chunk)?.min(STREAM_CHUNK_LEN);
+            chunk.truncate(pulled);
+            *written = 0;
+            if pulled == 0 {
+                self.write_queue.pop_front();
+                return Ok(());
             }
+            // Counted as it is pulled, the length is not known upfront
+            self.queued_bytes += pulled as u64;
         }
+
+        let unsent = &chunk[*written..];
+        let sent = match ep_syscall!(send(fd, unsent.as_ptr(), unsent.len(), SOCKET_FLAGS))? {
+            // Cannot Write, Connection closed
+            0 => return Err(Error::new(ErrorKind::BrokenPipe, "Connection closed")),
+            sent => sent as usize,
+        };
+        *written += sent;
+        if *written < chunk.len() {
+            self.write_stats.partial_writes += 1;
+        }
+        self.record_written(sent);
+        Ok(())
+    }
+
+    /// Drop `written` bytes from the front of the pending buffers
+    fn advance_writes(&mut self, mut written: usize) {
+        self.record_written(written);
+        self.buffered_bytes -= written;
+        while let Some(buffer) = self.write_queue.front().and_then(QueuedWrite::bytes) {
+            let remaining = buffer.len() - self.write_offset;
+            if written < remaining {
+                self.write_offset += written;
+                return;
+            }
+
+            written -= remaining;
+            self.write_queue.pop_front();
+            self.write_offset = 0;
+        }
+    }
+
+    /// Trace the response to a message, which the handler just queued
+    pub fn track_trace(&mut self, mut trace: MessageTrace) {
+        if self.queued_bytes == self.written_bytes {
+            // Nothing to wait for
+            trace.queue_time = None;
+            trace.flushed_at = None;
+            self.completed_traces.push(trace);
+        } else {
+            self.pending_traces
+                .push_back((self.queued_bytes, Instant::now(), trace));
+        }
+    }
+
+    /// Bytes ever handed to the kernel
+    pub fn written_bytes(&self) -> u64 {
+        self.written_bytes
     }
 
-    pub fn current_interests(&self) -> u32 {
+    /// Bytes written since the queue last drained, which it just did
+    pub fn take_drained_bytes(&mut self) -> u64 {
+        let drained = self.written_bytes - self.drained_at;
+        self.drained_at = self.written_bytes;
+        drained
+    }
+
+    pub fn take_completed_traces(&mut self) -> Vec<MessageTrace> {
+        std::mem::take(&mut self.completed_traces)
+    }
+
+    /// Count `written` bytes and complete the traces they finished
+    fn record_written(&mut self, written: usize) {
+        self.written_bytes += written as u64;
+        if self.pending_traces.is_empty() {
+            return;
+        }
+
+        let now = Instant::now();
+        while let Some((end, _, _)) = self.pending_traces.front()
+            && *end <= self.written_bytes
+        {
+            let Some((_, queued_at, mut trace)) = self.pending_traces.pop_front() else {
+                break;
+            };
+            trace.queue_time = Some(now - queued_at);
+            trace.flushed_at = Some(now);
+            self.completed_traces.push(trace);
+        }
+    }
+
+    pub fn current_interests(&self) -> EventFlags {
         self.current_interests
     }
 
-    pub fn set_current_interests(&mut self, interests: u32) {
+    pub fn set_current_interests(&mut self, interests: EventFlags) {
         self.current_interests = interests;
     }
 
-    pub fn stream_mut(&mut self) -> &mut TcpStream {
-        &mut self.stream
+    pub fn reads_paused(&self) -> bool {
+        self.reads_paused
+    }
+
+    pub fn set_reads_paused(&mut self, paused: bool) {
+        self.reads_paused = paused;
+    }
+
+    pub fn after_drain(&self) -> AfterDrain {
+        self.after_drain
+    }
+
+    pub fn set_after_drain(&mut self, after_drain: AfterDrain) {
+        self.after_drain = after_drain;
+    }
+
+    /// Send a FIN, the client can still send to us
+    pub fn shutdown_write(&mut self) -> Result<()> {
+        self.after_drain = AfterDrain::Nothing;
+        self.stream.shutdown(Shutdown::Write)
+    }
+
+    pub fn write_stats(&self) -> WriteStats {
+        self.write_stats
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Address of the client, the one behind the proxy if a PROXY header
+    /// named it
+    pub fn client_addr(&self) -> SocketAddr {
+        match self.proxy_header {
+            Some(header) => header.source,
+            None => self.peer_addr,
+        }
+    }
+
+    pub fn proxy_header(&self) -> Option<ProxyHeader> {
+        self.proxy_header
+    }
+
+    pub fn awaiting_proxy_header(&self) -> bool {
+        self.awaiting_proxy_header
+    }
+
+    pub fn set_coalesce_limit(&mut self, limit: Option<usize>) {
+        self.coalesce_limit = limit;
+    }
+
+    pub fn span(&self) -> &ConnectionSpan {
+        &self.span
+    }
+
+    pub fn set_span(&mut self, span: ConnectionSpan) {
+        self.span = span;
+    }
+
+    pub fn set_chaos(&mut self, chaos: Chaos) {
+        self.chaos = chaos;
+    }
+
+    pub fn chaos(&self) -> &Chaos {
+        &self.chaos
+    }
+
+    pub fn chaos_mut(&mut self) -> &mut Chaos {
+        &mut self.chaos
+    }
+
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.disconnect_reason
+    }
+
+    /// Record why the client is about to be disconnected, unless a reason
+    /// was recorded already
+    pub fn note_disconnect_reason(&mut self, reason: DisconnectReason) {
+        self.disconnect_reason.get_or_insert(reason);
+    }
+
+    pub fn listener_id(&self) -> ListenerId {
+        self.listener_id
+    }
+
+    pub fn set_listener_id(&mut self, listener_id: ListenerId) {
+        self.listener_id = listener_id;
+    }
+
+    pub fn set_awaiting_proxy_header(&mut self, awaiting: bool) {
+        self.awaiting_proxy_header = awaiting;
+    }
+
+    /// Strip the PROXY header from the read buffer once it is complete
+    ///
+    /// Returns `false` while the header is incomplete.
+    pub fn decode_proxy_header(&mut self) -> Result<bool> {
+        let Some((len, header)) = decode_proxy_header(self.read_buffer.make_contiguous())? else {
+            return Ok(false);
+        };
+        self.read_buffer.consume(len);
+        self.proxy_header = header;
+        self.awaiting_proxy_header = false;
+        Ok(true)
+    }
+
+    pub fn connected_at(&self) -> Instant {
+        self.connected_at
+    }
+
+    pub fn data(&self) -> &ClientData {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut ClientData {
+        &mut self.data
+    }
+
+    pub fn take_data(&mut self) -> ClientData {
+        std::mem::take(&mut self.data)
+    }
+
+    pub fn last_read_at(&self) -> Instant {
+        self.last_read_at
+    }
+
+    pub fn set_last_read_at(&mut self, at: Instant) {
+        self.last_read_at = at;
+    }
+
+    pub fn partial_since(&self) -> Option<Instant> {
+        self.partial_since
+    }
+
+    /// Note whether an incomplete message is left in the read buffer,
+    /// keeping the time it was first seen
+    pub fn track_partial_message(&mut self, now: Instant) {
+        if self.read_buffer.is_empty() {
+            self.partial_since = None;
+        } else {
+            self.partial_since.get_or_insert(now);
+        }
+    }
+
+    pub fn set_rate_limiter(&mut self, rate_limiter: Option<RateLimiter>) {
+        self.rate_limiter = rate_limiter;
+    }
+
+    pub fn rate_limiter_mut(&mut self) -> Option<&mut RateLimiter> {
+        self.rate_limiter.as_mut()
+    }
+
+    /// Read what the socket received into `buffer`, `0` at end of stream
+    pub fn recv(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let fd = self.stream.as_raw_fd();
+        let len = self.chaos.read_len(buffer.len())?;
+        let received = ep_syscall!(recv(fd, buffer.as_mut_ptr(), len, SOCKET_FLAGS))?;
+        Ok(received as usize)
+    }
+
+    /// Receive up to `max_len` bytes straight into the read buffer
+    pub fn recv_into_buffer(&mut self, max_len: usize) -> Result<ReadOutcome> {
+        let fd = self.stream.as_raw_fd();
+        let max_len = match self.chaos.read_len(max_len) {
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(ReadOutcome::WouldBlock),
+            Err(e) => return Err(e),
+        };
+        match self.read_buffer.recv_from(fd, max_len, SOCKET_FLAGS) {
+            Ok(0) => Ok(ReadOutcome::Eof),
+            Ok(read) => Ok(ReadOutcome::Data(read)),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(ReadOutcome::WouldBlock),
+            Err(e) => Err(e),
+        }
     }
 
-    pub fn read_buf_mut(&mut self) -> &mut Vec<u8> {
+    pub fn read_buf_mut(&mut self) -> &mut ReadBuf {
         &mut self.read_buffer
     }
 
-    pub fn read_buf(&self) -> &[u8] {
+    pub fn read_buf(&self) -> &ReadBuf {
         &self.read_buffer
     }
 
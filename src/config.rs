@@ -0,0 +1,692 @@
+use std::{net::SocketAddr, time::Duration};
+
+#[cfg(feature = "chaos")]
+use crate::chaos::ChaosConfig;
+use crate::{
+    EventFlags, ep_syscall,
+    ffi::{RLIMIT_NOFILE, RLimit},
+    handler::ErrorPolicy,
+    protocol::Codec,
+    rate_limit::{RateLimit, RateLimitAction},
+    reactor::Backend,
+    signal::SignalMask,
+    sockopt::{ListenOptions, TcpKeepalive},
+};
+
+/// Creates the codec of every newly accepted client
+pub type CodecFactory = fn() -> Box<dyn Codec + Send>;
+
+/// How epoll reports readiness of the listener and the clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TriggerMode {
+    /// Notify once per readiness change (`EPOLLET`), the server always
+    /// drains sockets until `WouldBlock`
+    #[default]
+    EdgeTriggered,
+    /// Notify on every wait while the socket is ready
+    LevelTriggered,
+    /// Notify once and disable the socket (`EPOLLONESHOT`), the server
+    /// re-arms it after handling each event
+    OneShot,
+}
+
+impl TriggerMode {
+    /// Flags ORed into every interest mask
+    pub(crate) fn flags(self) -> EventFlags {
+        match self {
+            TriggerMode::EdgeTriggered => EventFlags::EDGE,
+            TriggerMode::LevelTriggered => EventFlags::empty(),
+            TriggerMode::OneShot => EventFlags::ONESHOT,
+        }
+    }
+}
+
+/// Tuning options for `EpollServer`
+///
+/// Created with `ServerConfig::default()` and adjusted through the
+/// chainable setters, then passed to `EpollServer::new_with_config`.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    max_connections: Option<usize>,
+    read_slab_size: usize,
+    read_slab_count: usize,
+    codec: Option<CodecFactory>,
+    trace_sampling: u32,
+    handler_error_policy: ErrorPolicy,
+    trigger_mode: TriggerMode,
+    backend: Backend,
+    max_events: usize,
+    max_accepts_per_wakeup: Option<usize>,
+    transient_wait_error: Option<fn(&std::io::Error) -> bool>,
+    exclusive_accept: bool,
+    accept_error_backoff: Duration,
+    reserve_accept_fd: bool,
+    read_buffer_capacity: usize,
+    write_queue_capacity: usize,
+    coalesce_limit: Option<usize>,
+    wait_timeout: Option<Duration>,
+    wait_signal_mask: Option<SignalMask>,
+    nodelay: bool,
+    keepalive: Option<TcpKeepalive>,
+    listen_options: ListenOptions,
+    systemd_notify: bool,
+    tick_interval: Option<Duration>,
+    metrics_interval: Option<Duration>,
+    metrics_addr: Option<SocketAddr>,
+    client_rate_limit: Option<RateLimit>,
+    ip_rate_limit: Option<RateLimit>,
+    rate_limit_action: RateLimitAction,
+    max_message_size: Option<usize>,
+    max_read_buffer: Option<usize>,
+    message_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    ip_accept_rate: Option<u32>,
+    max_pending_writes: Option<usize>,
+    proxy_protocol: bool,
+    streaming_reads: bool,
+    #[cfg(feature = "chaos")]
+    chaos: Option<ChaosConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_connections: None,
+            read_slab_size: 4096,
+            read_slab_count: 4,
+            codec: None,
+            trace_sampling: 0,
+            handler_error_policy: ErrorPolicy::Disconnect,
+            trigger_mode: TriggerMode::EdgeTriggered,
+            backend: Backend::Epoll,
+            max_events: 2048,
+            max_accepts_per_wakeup: None,
+            transient_wait_error: None,
+            exclusive_accept: false,
+            accept_error_backoff: Duration::from_millis(100),
+            reserve_accept_fd: false,
+            read_buffer_capacity: 16384,
+            write_queue_capacity: 16,
+            coalesce_limit: None,
+            wait_timeout: Some(Duration::from_millis(1000)),
+            wait_signal_mask: None,
+            nodelay: false,
+            keepalive: None,
+            listen_options: ListenOptions::default(),
+            systemd_notify: false,
+            tick_interval: None,
+            metrics_interval: None,
+            metrics_addr: None,
+            client_rate_limit: None,
+            ip_rate_limit: None,
+            rate_limit_action: RateLimitAction::Drop,
+            max_message_size: None,
+            max_read_buffer: None,
+            message_timeout: None,
+            idle_timeout: None,
+            ip_accept_rate: None,
+            max_pending_writes: None,
+            proxy_protocol: false,
+            streaming_reads: false,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+        }
+    }
+}
+
+/// File descriptors kept aside for the listener, epoll, timers and files
+/// when deriving the connection limit from `RLIMIT_NOFILE`
+const RESERVED_FDS: u64 = 64;
+
+impl ServerConfig {
+    /// Conservative limits for servers exposed to the internet
+    ///
+    /// Starts from the defaults and protects against the usual resource
+    /// exhaustion attacks:
+    /// - messages are limited to 64 KiB (`max_message_size`)
+    /// - a client's unprocessed data is capped at 1 MiB while reading
+    ///   (`max_read_buffer`)
+    /// - a started message must be complete within 10 seconds
+    ///   (`message_timeout`), so slow senders cannot hold connections
+    /// - clients silent for 5 minutes are dropped (`idle_timeout`)
+    /// - each IP may open 10 connections per second (`ip_accept_rate`)
+    /// - connections are capped below the open file limit
+    ///   (`max_connections`), leaving room for the server's own fds
+    /// - clients not reading their responses are dropped once 1 MiB is
+    ///   waiting for them (`max_pending_writes`)
+    ///
+    /// Every limit can still be adjusted with its setter.
+    pub fn hardened() -> Self {
+        let config = ServerConfig::default()
+            .max_message_size(64 * 1024)
+            .max_read_buffer(1024 * 1024)
+            .message_timeout(Some(Duration::from_secs(10)))
+            .idle_timeout(Some(Duration::from_secs(300)))
+            .ip_accept_rate(10)
+            .max_pending_writes(1024 * 1024);
+
+        match open_file_limit() {
+            Some(limit) => {
+                let connections = limit.saturating_sub(RESERVED_FDS).max(1);
+                config.max_connections(usize::try_from(connections).unwrap_or(usize::MAX))
+            }
+            None => config,
+        }
+    }
+
+    /// Limit the number of simultaneously connected clients
+    ///
+    /// Once the limit is reached the server stops accepting, new connections
+    /// wait in the kernel backlog until a client disconnects.
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Size and number of the pooled buffers used for socket reads
+    ///
+    /// Reads of up to `slab_size` bytes land straight in the client's read
+    /// buffer, which is grown first when less is free. Reads of clients
+    /// handled by `EventHandler::on_data_chunk` land in a pooled buffer of
+    /// `slab_size` bytes before being copied, `count` buffers are kept for
+    /// reuse.
+    pub fn read_buffer_pool(mut self, slab_size: usize, count: usize) -> Self {
+        self.read_slab_size = slab_size.max(1);
+        self.read_slab_count = count;
+        self
+    }
+
+    /// Frame every client's traffic with a codec
+    ///
+    /// Each accepted client gets its own codec from `factory`, the handler
+    /// then receives decoded messages and everything sent is encoded.
+    pub fn codec(mut self, factory: CodecFactory) -> Self {
+        self.codec = Some(factory);
+        self
+    }
+
+    /// Expect every connection to start with a PROXY protocol header
+    ///
+    /// Enable this behind a load balancer that sends one (HAProxy, AWS NLB
+    /// with proxy protocol, nginx `proxy_protocol on`): the header is
+    /// stripped before the codec or `on_message` see any data, and
+    /// `ServerContext::client_addr` reports the client behind the proxy
+    /// from then on. Connections without a valid header are closed, so
+    /// only enable it when every peer is a trusted proxy.
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Hand the data of clients without a codec to
+    /// `EventHandler::on_data_chunk` as it is read, instead of buffering it
+    /// until `is_data_complete`
+    pub fn streaming_reads(mut self, enabled: bool) -> Self {
+        self.streaming_reads = enabled;
+        self
+    }
+
+    /// Trace one message in every `one_in` for the installed `Telemetry`
+    ///
+    /// `0`, the default, disables tracing and `1` traces every message.
+    pub fn trace_sampling(mut self, one_in: u32) -> Self {
+        self.trace_sampling = one_in;
+        self
+    }
+
+    /// What to do when `on_message` fails, disconnecting by default
+    ///
+    /// Errors built from a `HandlerError` bring their own policy instead.
+    pub fn handler_error_policy(mut self, policy: ErrorPolicy) -> Self {
+        self.handler_error_policy = policy;
+        self
+    }
+
+    /// Readiness notification mode of the listener and the clients,
+    /// edge-triggered by default
+    pub fn trigger_mode(mut self, mode: TriggerMode) -> Self {
+        self.trigger_mode = mode;
+        self
+    }
+
+    /// Kernel interface driving the event loop, epoll by default
+    ///
+    /// `EpollServer::new_with_config` fails if the backend is not
+    /// available.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Most events fetched by a single `epoll_wait`
+    ///
+    /// The server allocates room for them once and reuses it on every
+    /// iteration of the loop.
+    pub fn max_events(mut self, max_events: usize) -> Self {
+        self.max_events = max_events.max(1);
+        self
+    }
+
+    /// Accept at most `max_accepts` connections each time the listener is
+    /// reported readable, the rest are accepted on the next iterations
+    ///
+    /// Without a limit the whole backlog is drained at once, which keeps
+    /// established clients waiting during connect floods.
+    pub fn max_accepts_per_wakeup(mut self, max_accepts: usize) -> Self {
+        self.max_accepts_per_wakeup = Some(max_accepts.max(1));
+        self
+    }
+
+    /// Register the listener with `EPOLLEXCLUSIVE`, disabled by default
+    ///
+    /// Meant for several loops sharing one listener, see
+    /// `EpollServer::from_listener_with_config`: a new connection then
+    /// wakes one of them instead of all. The flag does not combine with
+    /// `TriggerMode::OneShot`, the listener is registered without it.
+    pub fn exclusive_accept(mut self, exclusive: bool) -> Self {
+        self.exclusive_accept = exclusive;
+        self
+    }
+
+    /// How long accepting stops after the process ran out of file
+    /// descriptors or the kernel out of memory, 100ms by default
+    ///
+    /// The listener stays readable while connections wait, accepting
+    /// right away would only fail again. Accepting also resumes as soon
+    /// as a client leaves.
+    pub fn accept_error_backoff(mut self, backoff: Duration) -> Self {
+        self.accept_error_backoff = backoff;
+        self
+    }
+
+    /// Keep a file descriptor in reserve for when the process runs out,
+    /// disabled by default
+    ///
+    /// Out of file descriptors, the server then gives the reserved one up
+    /// to accept and close the waiting connections, so their peers see
+    /// the connection closed instead of waiting for a timeout.
+    pub fn reserve_accept_fd(mut self, reserve: bool) -> Self {
+        self.reserve_accept_fd = reserve;
+        self
+    }
+
+    /// Initial capacity of every client's read buffer, which accumulates
+    /// data until the handler considers it complete
+    pub fn read_buffer_capacity(mut self, capacity: usize) -> Self {
+        self.read_buffer_capacity = capacity;
+        self
+    }
+
+    /// Initial capacity of every client's queue of pending writes
+    pub fn write_queue_capacity(mut self, capacity: usize) -> Self {
+        self.write_queue_capacity = capacity;
+        self
+    }
+
+    /// Copy messages smaller than `max_bytes` queued one after the other
+    /// into shared buffers of up to `max_bytes`
+    ///
+    /// Thousands of tiny messages, as chat-style broadcasts queue, are
+    /// then written with a few buffers per `sendmsg` instead of one each,
+    /// at the cost of a copy. Larger messages are queued as they are.
+    /// Off by default.
+    pub fn coalesce_writes(mut self, max_bytes: usize) -> Self {
+        self.coalesce_limit = Some(max_bytes);
+        self
+    }
+
+    /// Longest time `epoll_wait` blocks when `EpollServer::run` is not
+    /// given a timeout, `None` blocks until an event arrives
+    ///
+    /// Defaults to one second. The loop wakes up earlier when one of its
+    /// deadlines (a client timeout, a metrics report) is due first. Waits
+    /// use `epoll_pwait2`, so timeouts below a millisecond are honoured on
+    /// Linux 5.11 and later.
+    pub fn wait_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.wait_timeout = timeout;
+        self
+    }
+
+    /// Signal mask of the loop's thread while it waits for events
+    ///
+    /// Signals blocked in the thread but not in `mask` can only interrupt
+    /// the wait, see `SignalMask`. The loop then goes on as after any
+    /// interrupted wait, with no events, checking the shutdown signal.
+    pub fn wait_signal_mask(mut self, mask: SignalMask) -> Self {
+        self.wait_signal_mask = Some(mask);
+        self
+    }
+
+    /// Decide which failures of `epoll_wait` the loop rides out
+    ///
+    /// A wait interrupted by a signal (`EINTR`) is always treated as a
+    /// wait without events. Any other error ends `run` unless
+    /// `is_transient` returns `true` for it, in which case it is logged
+    /// and the loop goes on, e.g. for `ENOMEM` under memory pressure.
+    pub fn transient_wait_errors(mut self, is_transient: fn(&std::io::Error) -> bool) -> Self {
+        self.transient_wait_error = Some(is_transient);
+        self
+    }
+
+    /// Set `TCP_NODELAY` on accepted clients, disabling Nagle's algorithm
+    ///
+    /// Can be changed per client with `ServerContext::set_nodelay`.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Enable TCP keepalive probing on accepted clients
+    ///
+    /// Can be changed per client with `ServerContext::set_keepalive`.
+    pub fn keepalive(mut self, keepalive: Option<TcpKeepalive>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Most connections waiting in the kernel to be accepted
+    ///
+    /// Defaults to 1024, the kernel caps it at `net.core.somaxconn`.
+    /// Applies to the listeners the server binds itself, not to one given
+    /// to `EpollServer::from_listener_with_config`.
+    pub fn listen_backlog(mut self, backlog: u32) -> Self {
+        self.listen_options.backlog = backlog;
+        self
+    }
+
+    /// Set `SO_REUSEADDR` on listeners, so a restarted server can bind
+    /// while connections of the previous one linger in `TIME_WAIT`
+    ///
+    /// Enabled by default, like `TcpListener::bind` does.
+    pub fn reuse_addr(mut self, reuse: bool) -> Self {
+        self.listen_options.reuse_addr = reuse;
+        self
+    }
+
+    /// Whether a listener bound to an IPv6 address also accepts IPv4
+    /// connections (`Some(false)`, dual-stack on `[::]`) or only IPv6 ones
+    /// (`Some(true)`, `IPV6_V6ONLY`)
+    ///
+    /// `None` keeps the system default, usually dual-stack.
+    pub fn ipv6_only(mut self, ipv6_only: Option<bool>) -> Self {
+        self.listen_options.ipv6_only = ipv6_only;
+        self
+    }
+
+    /// Tell systemd when the server is ready (`READY=1`, as `run` starts)
+    /// and when it stops (`STOPPING=1`), for services of `Type=notify`
+    ///
+    /// Disabled by default; without `NOTIFY_SOCKET` in the environment
+    /// there is nobody to notify and nothing is sent.
+    pub fn systemd_notify(mut self, notify: bool) -> Self {
+        self.systemd_notify = notify;
+        self
+    }
+
+    /// Call `EventHandler::on_tick` every `interval`
+    ///
+    /// Disabled by default. Ticks are driven by the wait timeout, so a
+    /// busy loop does not delay them and an idle one still wakes up.
+    pub fn tick_interval(mut self, interval: Option<Duration>) -> Self {
+        self.tick_interval = interval;
+        self
+    }
+
+    /// Report `Metrics` to the installed `Telemetry` every `interval`,
+    /// or log them at info level as one line if there is none
+    ///
+    /// Disabled by default, `EpollServer::metrics` is always available.
+    pub fn metrics_interval(mut self, interval: Option<Duration>) -> Self {
+        self.metrics_interval = interval;
+        self
+    }
+
+    /// Serve `/metrics` in Prometheus text format on `addr`
+    ///
+    /// The endpoint runs in the same event loop as the clients, port `0`
+    /// picks a free port, see `EpollServer::metrics_addr`.
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Limit the messages and bytes each client may send per second
+    pub fn client_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.client_rate_limit = Some(limit);
+        self
+    }
+
+    /// Limit the messages and bytes all clients from one IP address may
+    /// send per second, together
+    pub fn ip_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.ip_rate_limit = Some(limit);
+        self
+    }
+
+    /// What to do with clients over their rate limit, dropping their
+    /// messages by default
+    pub fn rate_limit_action(mut self, action: RateLimitAction) -> Self {
+        self.rate_limit_action = action;
+        self
+    }
+
+    /// Disconnect clients that buffer more than `max_size` bytes without
+    /// completing a message
+    pub fn max_message_size(mut self, max_size: usize) -> Self {
+        self.max_message_size = Some(max_size.max(1));
+        self
+    }
+
+    /// Stop reading from a client once `max_size` bytes it sent are
+    /// waiting to be processed, and call `EventHandler::on_buffer_overflow`
+    ///
+    /// Unlike `max_message_size`, which is checked once the handler had
+    /// its chance, this bounds the memory a client can take while its
+    /// socket is being drained.
+    pub fn max_read_buffer(mut self, max_size: usize) -> Self {
+        self.max_read_buffer = Some(max_size.max(1));
+        self
+    }
+
+    /// Disconnect clients that start a message and do not complete it
+    /// within `timeout`
+    ///
+    /// The loop wakes up when the first client is due, so a client is
+    /// dropped within about 10 ms of its deadline.
+    pub fn message_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.message_timeout = timeout;
+        self
+    }
+
+    /// Disconnect clients that send nothing for `timeout`
+    ///
+    /// Enforced on time like `message_timeout`.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Accept at most `per_sec` connections per second from one IP
+    /// address, further connections are closed right away
+    pub fn ip_accept_rate(mut self, per_sec: u32) -> Self {
+        self.ip_accept_rate = Some(per_sec.max(1));
+        self
+    }
+
+    /// Disconnect clients with more than `max_bytes` waiting to be written,
+    /// typically peers that stopped reading
+    pub fn max_pending_writes(mut self, max_bytes: usize) -> Self {
+        self.max_pending_writes = Some(max_bytes);
+        self
+    }
+
+    /// Inject the faults of `chaos` into every client's reads and writes,
+    /// to test the handler against a misbehaving network
+    #[cfg(feature = "chaos")]
+    pub fn chaos(mut self, chaos: ChaosConfig) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    #[cfg(feature = "chaos")]
+    pub(crate) fn chaos_config(&self) -> Option<&ChaosConfig> {
+        self.chaos.as_ref()
+    }
+
+    pub(crate) fn expects_proxy_header(&self) -> bool {
+        self.proxy_protocol
+    }
+
+    pub(crate) fn streams_reads(&self) -> bool {
+        self.streaming_reads
+    }
+
+    pub(crate) fn codec_factory(&self) -> Option<CodecFactory> {
+        self.codec
+    }
+
+    pub(crate) fn read_slab_size(&self) -> usize {
+        self.read_slab_size
+    }
+
+    pub(crate) fn read_slab_count(&self) -> usize {
+        self.read_slab_count
+    }
+
+    pub(crate) fn connection_limit(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    pub(crate) fn trace_sampling_rate(&self) -> u32 {
+        self.trace_sampling
+    }
+
+    pub(crate) fn error_policy(&self) -> ErrorPolicy {
+        self.handler_error_policy
+    }
+
+    pub(crate) fn trigger(&self) -> TriggerMode {
+        self.trigger_mode
+    }
+
+    pub(crate) fn poll_backend(&self) -> Backend {
+        self.backend
+    }
+
+    pub(crate) fn event_capacity(&self) -> usize {
+        self.max_events
+    }
+
+    pub(crate) fn accept_batch_limit(&self) -> Option<usize> {
+        self.max_accepts_per_wakeup
+    }
+
+    pub(crate) fn accepts_exclusively(&self) -> bool {
+        self.exclusive_accept
+    }
+
+    pub(crate) fn accept_backoff(&self) -> Duration {
+        self.accept_error_backoff
+    }
+
+    pub(crate) fn reserves_accept_fd(&self) -> bool {
+        self.reserve_accept_fd
+    }
+
+    pub(crate) fn client_read_capacity(&self) -> usize {
+        self.read_buffer_capacity
+    }
+
+    pub(crate) fn client_write_queue_capacity(&self) -> usize {
+        self.write_queue_capacity
+    }
+
+    pub(crate) fn write_coalesce_limit(&self) -> Option<usize> {
+        self.coalesce_limit
+    }
+
+    /// Timeout in milliseconds as `epoll_wait` takes it, `-1` blocks
+    pub(crate) fn wait_duration(&self) -> Option<Duration> {
+        self.wait_timeout
+    }
+
+    pub(crate) fn wait_sigmask(&self) -> Option<&SignalMask> {
+        self.wait_signal_mask.as_ref()
+    }
+
+    pub(crate) fn is_transient_wait_error(&self, error: &std::io::Error) -> bool {
+        self.transient_wait_error
+            .is_some_and(|is_transient| is_transient(error))
+    }
+
+    pub(crate) fn tcp_nodelay(&self) -> bool {
+        self.nodelay
+    }
+
+    pub(crate) fn tcp_keepalive(&self) -> Option<TcpKeepalive> {
+        self.keepalive
+    }
+
+    pub(crate) fn listen_options(&self) -> ListenOptions {
+        self.listen_options
+    }
+
+    pub(crate) fn notifies_systemd(&self) -> bool {
+        self.systemd_notify
+    }
+
+    pub(crate) fn tick_period(&self) -> Option<Duration> {
+        self.tick_interval
+    }
+
+    pub(crate) fn metrics_period(&self) -> Option<Duration> {
+        self.metrics_interval
+    }
+
+    pub(crate) fn metrics_endpoint_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    pub(crate) fn client_limit(&self) -> Option<RateLimit> {
+        self.client_rate_limit
+    }
+
+    pub(crate) fn ip_limit(&self) -> Option<RateLimit> {
+        self.ip_rate_limit
+    }
+
+    pub(crate) fn rate_action(&self) -> RateLimitAction {
+        self.rate_limit_action
+    }
+
+    pub(crate) fn message_size_limit(&self) -> Option<usize> {
+        self.max_message_size
+    }
+
+    pub(crate) fn read_buffer_limit(&self) -> Option<usize> {
+        self.max_read_buffer
+    }
+
+    pub(crate) fn partial_message_timeout(&self) -> Option<Duration> {
+        self.message_timeout
+    }
+
+    pub(crate) fn client_idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    pub(crate) fn accept_rate(&self) -> Option<u32> {
+        self.ip_accept_rate
+    }
+
+    pub(crate) fn pending_writes_limit(&self) -> Option<usize> {
+        self.max_pending_writes
+    }
+}
+
+/// Soft limit on open file descriptors, `None` if unknown or unlimited
+fn open_file_limit() -> Option<u64> {
+    let mut limit = RLimit::default();
+    ep_syscall!(getrlimit(RLIMIT_NOFILE, &raw mut limit)).ok()?;
+    (limit.cur != u64::MAX).then_some(limit.cur)
+}
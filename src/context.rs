@@ -0,0 +1,1309 @@
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    net::{SocketAddr, TcpListener},
+    os::fd::{AsRawFd, OwnedFd, RawFd},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+
+use crate::{
+    Event, EventFlags, MAX_CUSTOM_TOKEN, PeerRole,
+    audit::{AuditEvent, AuditSink, DisconnectReason},
+    bytes::Bytes,
+    client_data::ClientData,
+    client_id::ClientId,
+    client_slab::ClientSlab,
+    client_state::{AfterDrain, ClientState, Priority, WriteStats},
+    config::TriggerMode,
+    error::{Error, Result},
+    handler::{DataSource, HandlerAction},
+    middleware::MiddlewareChain,
+    protocol::{Codec, CodecStack, ProxyHeader, Transport},
+    reactor::Reactor,
+    rooms::Rooms,
+    server_reactor::ServerReactor,
+    sockopt::{self, ListenOptions, TcpKeepalive},
+    stats::AcceptStats,
+    timer::{Timer, TimerId},
+    watch::{FileEvent, WatchId, Watcher},
+};
+
+/// Identifies a listening socket, chosen when adding it with
+/// `EpollServer::add_listener`
+pub type ListenerId = u64;
+
+/// Id of the listener the server was created with
+pub const PRIMARY_LISTENER: ListenerId = 0;
+
+/// Server state shared with the handler
+///
+/// Every `EventHandler` callback receives a `&mut ServerContext`, so handlers
+/// can act on the server directly (send to other clients, broadcast,
+/// disconnect) instead of being limited to the returned `HandlerAction`.
+pub struct ServerContext {
+    listener: Option<TcpListener>,
+    listen_addr: SocketAddr,
+    /// How the listener is created again when rebinding
+    listen_options: ListenOptions,
+    /// Listeners added with `EpollServer::add_listener`, by id
+    extra_listeners: HashMap<ListenerId, TcpListener>,
+    epoll: ServerReactor,
+    clients: ClientSlab,
+    accepts_paused: bool,
+    at_capacity: bool,
+    /// Accepting stopped after `accept` failed, see `AcceptError`
+    accept_backoff: bool,
+    pending_disconnects: Vec<ClientId>,
+    accept_stats: AcceptStats,
+    timers: HashMap<TimerId, Timer>,
+    /// Timer ids by timerfd, the fd is what epoll reports
+    timer_ids: HashMap<RawFd, TimerId>,
+    /// Actions scheduled with [`ServerContext::defer`], by due time and
+    /// then in the order they were deferred
+    deferred: BTreeMap<(Instant, u64), (ClientId, HandlerAction)>,
+    deferred_count: u64,
+    /// Fires when the earliest deferred action is due
+    defer_timer: Option<Timer>,
+    /// Pipes registered with [`ServerContext::register_pipe`], by token
+    pipes: HashMap<u64, OwnedFd>,
+    /// Interest and token of the fds registered with
+    /// [`ServerContext::register_fd`], to re-arm them
+    custom_fds: HashMap<RawFd, (EventFlags, u64)>,
+    /// inotify instance, created by the first [`ServerContext::watch_path`]
+    watcher: Option<Watcher>,
+    /// Time the current tick started, see [`ServerContext::now`]
+    now: Instant,
+    trigger_mode: TriggerMode,
+    /// Listener is registered with `EPOLLEXCLUSIVE`
+    exclusive_accept: bool,
+    /// Clients whose epoll interests may be stale, applied once per tick
+    dirty_interests: HashSet<ClientId>,
+    /// Data of the client whose `on_disconnect` is running
+    departed_data: Option<(ClientId, ClientData)>,
+    /// Most bytes queued for a client before it is disconnected
+    max_pending_writes: Option<usize>,
+    middlewares: MiddlewareChain,
+    audit_sink: Option<Box<dyn AuditSink + Send>>,
+    rooms: Rooms,
+}
+
+impl ServerContext {
+    pub(crate) fn new(
+        listener: TcpListener,
+        epoll: ServerReactor,
+        trigger_mode: TriggerMode,
+        exclusive_accept: bool,
+    ) -> Result<Self> {
+        let mut context = Self::without_listener(listener.local_addr()?, epoll, trigger_mode);
+        context.exclusive_accept = exclusive_accept;
+        context.register_listener(listener)?;
+        Ok(context)
+    }
+
+    /// Context of a server that is handed its clients instead of
+    /// accepting them, `listen_addr` is only reported
+    pub(crate) fn without_listener(
+        listen_addr: SocketAddr,
+        epoll: ServerReactor,
+        trigger_mode: TriggerMode,
+    ) -> Self {
+        ServerContext {
+            listener: None,
+            listen_addr,
+            listen_options: ListenOptions::default(),
+            extra_listeners: HashMap::new(),
+            epoll,
+            clients: ClientSlab::default(),
+            accepts_paused: false,
+            at_capacity: false,
+            accept_backoff: false,
+            pending_disconnects: Vec::new(),
+            accept_stats: AcceptStats::default(),
+            timers: HashMap::new(),
+            timer_ids: HashMap::new(),
+            deferred: BTreeMap::new(),
+            deferred_count: 0,
+            defer_timer: None,
+            pipes: HashMap::new(),
+            custom_fds: HashMap::new(),
+            watcher: None,
+            now: Instant::now(),
+            trigger_mode,
+            exclusive_accept: false,
+            dirty_interests: HashSet::new(),
+            departed_data: None,
+            max_pending_writes: None,
+            middlewares: MiddlewareChain::default(),
+            audit_sink: None,
+            rooms: Rooms::default(),
+        }
+    }
+
+    /// Queue data to be written to the client
+    ///
+    /// The data goes through the middlewares, then is framed by the
+    /// client's codec if it has one. Without either the buffer is queued as
+    /// is, a `Bytes` sent to several clients is never copied. A client
+    /// with more than `ServerConfig::max_pending_writes` bytes waiting is
+    /// disconnected once the current callback returns.
+    ///
+    /// Returns `false` if there is no client with the given id.
+    pub fn send_to(&mut self, client_id: ClientId, data: impl Into<Bytes>) -> Result<bool> {
+        self.send_with_priority(client_id, data, Priority::Normal)
+    }
+
+    /// Queue data to be written to the client in the lane of `priority`,
+    /// see [`Priority`] and [`ServerContext::send_to`]
+    ///
+    /// Returns `false` if there is no client with the given id.
+    pub fn send_with_priority(
+        &mut self,
+        client_id: ClientId,
+        data: impl Into<Bytes>,
+        priority: Priority,
+    ) -> Result<bool> {
+        let mut data = data.into();
+        if !self.middlewares.is_empty() {
+            data = self.middlewares.outbound(client_id, data.to_vec()).into();
+        }
+        let buffered = match self.clients.get_mut(&client_id) {
+            Some(client) => {
+                client.queue_message_with_priority(data, priority);
+                client.buffered_bytes()
+            }
+            None => return Ok(false),
+        };
+        self.mark_interests_dirty(client_id);
+        self.enforce_pending_limit(client_id, buffered);
+        Ok(true)
+    }
+
+    /// Queue a response made of several parts, e.g. headers and a body
+    ///
+    /// Without a codec or middlewares the parts are queued as they are,
+    /// without being copied into one buffer, and the socket is corked until
+    /// they are written so they leave in full segments. Otherwise they are
+    /// joined and sent as one message, see [`ServerContext::send_to`].
+    ///
+    /// Returns `false` if there is no client with the given id.
+    pub fn send_parts<B: Into<Bytes>>(
+        &mut self,
+        client_id: ClientId,
+        parts: Vec<B>,
+    ) -> Result<bool> {
+        let parts: Vec<Bytes> = parts.into_iter().map(Into::into).collect();
+        if !self.middlewares.is_empty() {
+            return self.send_to(client_id, parts.concat());
+        }
+
+        let buffered = match self.clients.get_mut(&client_id) {
+            Some(client) if client.has_codec() => {
+                client.queue_message(parts.concat().into());
+                client.buffered_bytes()
+            }
+            Some(client) => {
+                if parts.len() > 1 {
+                    client.auto_cork()?;
+                }
+                for part in parts {
+                    client.queue_write(part);
+                }
+                client.buffered_bytes()
+            }
+            None => return Ok(false),
+        };
+        self.mark_interests_dirty(client_id);
+        self.enforce_pending_limit(client_id, buffered);
+        Ok(true)
+    }
+
+    /// Disconnect the client if more than `ServerConfig::max_pending_writes`
+    /// bytes are waiting for it
+    fn enforce_pending_limit(&mut self, client_id: ClientId, buffered: usize) {
+        if let Some(limit) = self.max_pending_writes
+            && buffered > limit
+        {
+            warn!(
+                "Client {} has {} bytes pending, over the limit of {}, disconnecting",
+                client_id, buffered, limit
+            );
+            if let Some(client) = self.clients.get(&client_id) {
+                let peer_addr = client.peer_addr();
+                self.audit(AuditEvent::BackpressureHit {
+                    client_id,
+                    peer_addr,
+                    pending: buffered,
+                });
+            }
+            self.disconnect_for(client_id, DisconnectReason::LimitExceeded);
+        }
+    }
+
+    /// Queue `len` bytes of `file`, starting at `offset`, to be written to
+    /// the client
+    ///
+    /// The file is streamed with `sendfile(2)`, without copying it through
+    /// userspace, and resumes on `EPOLLOUT` when the socket fills up. It is
+    /// sent as is, so clients with a codec are refused with
+    /// `Error::Unsupported`. The socket is corked until the file is
+    /// written, so headers queued before it share its first segment.
+    ///
+    /// Returns `false` if there is no client with the given id.
+    pub fn send_file(
+        &mut self,
+        client_id: ClientId,
+        file: File,
+        offset: u64,
+        len: usize,
+    ) -> Result<bool> {
+        match self.clients.get_mut(&client_id) {
+            Some(client) if client.has_codec() => {
+                return Err(Error::Unsupported("sendfile bypasses the client's codec"));
+            }
+            Some(client) => {
+                client.auto_cork()?;
+                client.queue_file(file, offset, len)?;
+            }
+            None => return Ok(false),
+        }
+        self.mark_interests_dirty(client_id);
+        Ok(true)
+    }
+
+    /// Queue a response pulled from `source` whenever the client's socket
+    /// can take more
+    ///
+    /// Only one chunk of the response is held in memory at a time, however
+    /// large it is. Like a file it is sent as is, so clients with a codec
+    /// are refused with `Error::Unsupported`.
+    ///
+    /// Returns `false` if there is no client with the given id.
+    pub fn send_stream(
+        &mut self,
+        client_id: ClientId,
+        source: impl DataSource + Send + 'static,
+    ) -> Result<bool> {
+        self.queue_stream(client_id, Box::new(source))
+    }
+
+    fn queue_stream(
+        &mut self,
+        client_id: ClientId,
+        source: Box<dyn DataSource + Send>,
+    ) -> Result<bool> {
+        match self.clients.get_mut(&client_id) {
+            Some(client) if client.has_codec() => {
+                return Err(Error::Unsupported(
+                    "streamed responses bypass the client's codec",
+                ));
+            }
+            Some(client) => client.queue_stream(source),
+            None => return Ok(false),
+        }
+        self.mark_interests_dirty(client_id);
+        Ok(true)
+    }
+
+    /// Queue data to be written to every connected client
+    ///
+    /// The recipients are the clients connected at the time of the call,
+    /// clients accepted later, even within the same tick, never receive it.
+    /// The recipients share one buffer, see [`ServerContext::send_to`].
+    pub fn broadcast(&mut self, data: impl Into<Bytes>) -> Result<()> {
+        let data = data.into();
+        for client_id in self.connected_clients() {
+            self.send_to(client_id, data.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Queue data to be written to every member of `room`
+    pub fn broadcast_room(&mut self, room: &str, data: impl Into<Bytes>) -> Result<()> {
+        let data = data.into();
+        for client_id in self.rooms.members(room) {
+            self.send_to(client_id, data.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Add the client to `room`, creating the room if needed
+    ///
+    /// Rooms group clients for [`ServerContext::broadcast_room`] and
+    /// `HandlerAction::BroadcastRoom`. A client can be in any number of
+    /// rooms, and leaves all of them when it disconnects, right after
+    /// `on_disconnect`.
+    ///
+    /// Returns `false` if there is no client with the given id or it
+    /// already is a member.
+    pub fn join(&mut self, client_id: ClientId, room: impl Into<String>) -> bool {
+        self.clients.contains_key(&client_id) && self.rooms.join(client_id, room.into())
+    }
+
+    /// Remove the client from `room`, returns `false` if it was not a member
+    pub fn leave(&mut self, client_id: ClientId, room: &str) -> bool {
+        self.rooms.leave(client_id, room)
+    }
+
+    /// Ids of the clients in `room`
+    pub fn room_members(&self, room: &str) -> Vec<ClientId> {
+        self.rooms.members(room)
+    }
+
+    /// Rooms the client is a member of
+    pub fn client_rooms(&self, client_id: ClientId) -> Vec<String> {
+        self.rooms.rooms_of(client_id)
+    }
+
+    pub(crate) fn leave_all_rooms(&mut self, client_id: ClientId) {
+        self.rooms.leave_all(client_id);
+    }
+
+    /// Broadcast on behalf of a sender outside the loop, to the clients
+    /// that were already connected at `emitted_at`
+    pub(crate) fn broadcast_emitted_at(&mut self, data: Bytes, emitted_at: Instant) -> Result<()> {
+        let recipients: Vec<ClientId> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.connected_at() <= emitted_at)
+            .map(|(&client_id, _)| client_id)
+            .collect();
+        for client_id in recipients {
+            self.send_to(client_id, data.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Disconnect the client
+    ///
+    /// The client is removed once the current callback returns, and
+    /// `on_disconnect` is invoked for it. Queued writes are discarded.
+    ///
+    /// Returns `false` if there is no client with the given id.
+    pub fn disconnect(&mut self, client_id: ClientId) -> bool {
+        self.disconnect_for(client_id, DisconnectReason::Requested)
+    }
+
+    /// Disconnect the client like [`ServerContext::disconnect`], reporting
+    /// `reason` in its `AuditEvent::Disconnected` unless it already has one
+    pub(crate) fn disconnect_for(&mut self, client_id: ClientId, reason: DisconnectReason) -> bool {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return false;
+        };
+        client.note_disconnect_reason(reason);
+        if !self.pending_disconnects.contains(&client_id) {
+            self.pending_disconnects.push(client_id);
+        }
+        true
+    }
+
+    /// Whether the client is to be disconnected at the end of the event
+    pub(crate) fn is_disconnecting(&self, client_id: ClientId) -> bool {
+        self.pending_disconnects.contains(&client_id)
+    }
+
+    /// Disconnect the client once everything queued for it is written
+    ///
+    /// Unlike [`ServerContext::disconnect`], a last reply queued before
+    /// this is delivered in full, however many `EPOLLOUT`s it takes.
+    /// Nothing more is read from the client meanwhile, and a client with
+    /// nothing queued is disconnected once the current callback returns.
+    ///
+    /// Returns `false` if there is no client with the given id.
+    pub fn close_after_write(&mut self, client_id: ClientId) -> bool {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return false;
+        };
+        if !client.has_pending_writes() {
+            return self.disconnect(client_id);
+        }
+        client.set_after_drain(AfterDrain::Close);
+        self.mark_interests_dirty(client_id);
+        true
+    }
+
+    /// Shut the write side of the connection down once everything queued
+    /// for the client is written
+    ///
+    /// The client gets an EOF but can keep sending, its messages are still
+    /// delivered until it closes its side, for protocols relying on TCP
+    /// half-close. Nothing can be sent to the client afterwards, writes
+    /// queued later fail and disconnect it.
+    ///
+    /// Returns `false` if there is no client with the given id.
+    pub fn shutdown_write(&mut self, client_id: ClientId) -> Result<bool> {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return Ok(false);
+        };
+        if client.has_pending_writes() {
+            client.set_after_drain(AfterDrain::ShutdownWrite);
+        } else {
+            client.shutdown_write()?;
+        }
+        Ok(true)
+    }
+
+    /// Ids of all currently connected clients
+    pub fn connected_clients(&self) -> Vec<ClientId> {
+        self.clients.keys().copied().collect()
+    }
+
+    /// Address of the client
+    ///
+    /// With `ServerConfig::proxy_protocol` this is the client behind the
+    /// proxy once its PROXY header arrived, which is before the first
+    /// `on_message`. Until then, and for headers without addresses, it is
+    /// the peer address of the socket.
+    pub fn client_addr(&self, client_id: ClientId) -> Option<SocketAddr> {
+        self.clients
+            .get(&client_id)
+            .map(|client| client.client_addr())
+    }
+
+    /// Listener the client connected through, `PRIMARY_LISTENER` for the
+    /// one the server was created with (and for clients handed over by an
+    /// `Acceptor`)
+    pub fn listener_id(&self, client_id: ClientId) -> Option<ListenerId> {
+        self.clients
+            .get(&client_id)
+            .map(|client| client.listener_id())
+    }
+
+    /// File descriptor of the client's socket
+    ///
+    /// Client ids are not fds, this is for the rare handler that needs the
+    /// socket itself, e.g. to set an option the server does not expose.
+    /// The fd belongs to the server and must not be closed.
+    pub fn client_fd(&self, client_id: ClientId) -> Option<RawFd> {
+        self.clients.get(&client_id).map(ClientState::as_raw_fd)
+    }
+
+    /// Whether the client's codec has a handshake to complete before
+    /// `EventHandler::on_tls_handshake` is called
+    pub(crate) fn client_handshaking(&self, client_id: ClientId) -> bool {
+        self.clients
+            .get(&client_id)
+            .is_some_and(ClientState::handshaking)
+    }
+
+    /// PROXY header the client's connection started with, if
+    /// `ServerConfig::proxy_protocol` is enabled and it carried addresses
+    pub fn client_proxy_header(&self, client_id: ClientId) -> Option<ProxyHeader> {
+        self.clients
+            .get(&client_id)
+            .and_then(|client| client.proxy_header())
+    }
+
+    /// Transport the client speaks, decided by its codec
+    pub fn client_transport(&self, client_id: ClientId) -> Option<Transport> {
+        self.clients
+            .get(&client_id)
+            .map(|client| client.transport())
+    }
+
+    /// Attach a value to the client, such as its user name or session
+    ///
+    /// Each client holds at most one value per type, setting another
+    /// value of the same type replaces it. The data lives as long as the
+    /// client and can still be read from `on_disconnect`.
+    ///
+    /// Returns `false` if there is no client with the given id.
+    pub fn set_client_data<T: Any + Send>(&mut self, client_id: ClientId, value: T) -> bool {
+        match self.clients.get_mut(&client_id) {
+            Some(client) => {
+                client.data_mut().insert(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Value of type `T` attached to the client
+    pub fn get_client_data<T: Any + Send>(&self, client_id: ClientId) -> Option<&T> {
+        match self.clients.get(&client_id) {
+            Some(client) => client.data().get(),
+            None => match &self.departed_data {
+                Some((id, data)) if *id == client_id => data.get(),
+                _ => None,
+            },
+        }
+    }
+
+    /// Mutable access to the value of type `T` attached to the client
+    pub fn get_client_data_mut<T: Any + Send>(&mut self, client_id: ClientId) -> Option<&mut T> {
+        match self.clients.get_mut(&client_id) {
+            Some(client) => client.data_mut().get_mut(),
+            None => match &mut self.departed_data {
+                Some((id, data)) if *id == client_id => data.get_mut(),
+                _ => None,
+            },
+        }
+    }
+
+    /// Detach the value of type `T` from the client and return it
+    pub fn remove_client_data<T: Any + Send>(&mut self, client_id: ClientId) -> Option<T> {
+        match self.clients.get_mut(&client_id) {
+            Some(client) => client.data_mut().remove(),
+            None => match &mut self.departed_data {
+                Some((id, data)) if *id == client_id => data.remove(),
+                _ => None,
+            },
+        }
+    }
+
+    /// Keep the data of a removed client readable during its `on_disconnect`
+    pub(crate) fn set_departed_data(&mut self, departed: Option<(ClientId, ClientData)>) {
+        self.departed_data = departed;
+    }
+
+    /// Switch the codec of an established connection
+    ///
+    /// Lets a connection upgrade protocols at a point chosen by the handler,
+    /// e.g. from HTTP to WebSocket, keeping its state and registration.
+    /// Messages queued before the switch keep their old framing while a
+    /// `HandlerAction` returned from the same callback is framed by the new
+    /// codec, so send the upgrade response with [`ServerContext::send_to`]
+    /// before switching. Bytes already received after the current frame are
+    /// decoded by the new codec.
+    ///
+    /// Returns `false` if there is no client with the given id.
+    pub fn switch_codec(&mut self, client_id: ClientId, codec: Box<dyn Codec + Send>) -> bool {
+        match self.clients.get_mut(&client_id) {
+            Some(client) => {
+                client.set_codec(Some(codec));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Switch to a stack of codecs, ordered from the wire up
+    ///
+    /// See [`ServerContext::switch_codec`] and [`CodecStack`].
+    pub fn switch_stack(
+        &mut self,
+        client_id: ClientId,
+        codecs: Vec<Box<dyn Codec + Send>>,
+    ) -> bool {
+        self.switch_codec(client_id, Box::new(CodecStack::new(codecs)))
+    }
+
+    /// Write path counters of the client
+    ///
+    /// Handlers can use them to spot receiver-limited connections and
+    /// slow down or shed them.
+    pub fn write_stats(&self, client_id: ClientId) -> Option<WriteStats> {
+        self.clients
+            .get(&client_id)
+            .map(|client| client.write_stats())
+    }
+
+    /// Time the current tick of the event loop started
+    ///
+    /// The clock is read once after every `epoll_wait` and shared by all
+    /// callbacks of that tick, so handlers checking many timeouts don't
+    /// each pay for `Instant::now`. It lags behind the real time by however
+    /// long the tick has been running, which is negligible unless a
+    /// callback blocks.
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Deadline `duration` from the start of the current tick
+    pub fn deadline_in(&self, duration: Duration) -> Instant {
+        self.now + duration
+    }
+
+    /// Returns `true` if `deadline` was reached by the start of the tick
+    pub fn is_expired(&self, deadline: Instant) -> bool {
+        deadline <= self.now
+    }
+
+    /// Time left until `deadline`, zero if it has passed
+    pub fn time_until(&self, deadline: Instant) -> Duration {
+        deadline.saturating_duration_since(self.now)
+    }
+
+    /// Start a new tick, see [`ServerContext::now`]
+    pub(crate) fn refresh_now(&mut self) {
+        self.now = Instant::now();
+    }
+
+    /// Call `EventHandler::on_timer` with `timer_id` once `delay` elapsed
+    ///
+    /// Setting a timer id that is already pending re-arms it.
+    pub fn set_timer(&mut self, delay: Duration, timer_id: TimerId) -> Result<()> {
+        self.arm_timer(timer_id, delay, None)
+    }
+
+    /// Call `EventHandler::on_timer` with `timer_id` every `interval`,
+    /// until the timer is cancelled
+    pub fn set_interval(&mut self, interval: Duration, timer_id: TimerId) -> Result<()> {
+        self.arm_timer(timer_id, interval, Some(interval))
+    }
+
+    /// Cancel a pending timer
+    ///
+    /// Returns `false` if there is no timer with the given id.
+    pub fn cancel_timer(&mut self, timer_id: TimerId) -> bool {
+        match self.timers.remove(&timer_id) {
+            // Closing the timerfd removes it from epoll as well
+            Some(timer) => {
+                self.timer_ids.remove(&timer.as_raw_fd());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn arm_timer(
+        &mut self,
+        timer_id: TimerId,
+        delay: Duration,
+        interval: Option<Duration>,
+    ) -> Result<()> {
+        if let Some(timer) = self.timers.get_mut(&timer_id) {
+            return Ok(timer.arm(delay, interval)?);
+        }
+
+        let mut timer = Timer::new()?;
+        timer.arm(delay, interval)?;
+        let fd = timer.as_raw_fd();
+        let flags = EventFlags::READ | EventFlags::EDGE;
+        self.epoll
+            .add_interest(fd, Event::new(flags, PeerRole::Timer(fd as u64)))?;
+
+        self.timer_ids.insert(fd, timer_id);
+        self.timers.insert(timer_id, timer);
+        Ok(())
+    }
+
+    /// Handle `action` for `client_id` once `delay` elapsed, e.g. a
+    /// delayed kick (`HandlerAction::Disconnect`) or a scheduled
+    /// announcement (`HandlerAction::SendToAll`)
+    ///
+    /// The action is handled like one returned by `on_message` from the
+    /// client. If the client is gone by then, what would go to it is
+    /// dropped while a broadcast still reaches the others. Actions due at
+    /// the same time are handled in the order they were deferred.
+    pub fn defer(
+        &mut self,
+        client_id: ClientId,
+        delay: Duration,
+        action: HandlerAction,
+    ) -> Result<()> {
+        let due = self.now + delay;
+        let earliest = self.deferred.keys().next().map(|&(due, _)| due);
+        self.deferred
+            .insert((due, self.deferred_count), (client_id, action));
+        self.deferred_count += 1;
+        if earliest.is_none_or(|earliest| due < earliest) {
+            self.arm_defer_timer(due)?;
+        }
+        Ok(())
+    }
+
+    /// Whether `fd` is the timer of the deferred actions
+    pub(crate) fn is_defer_timer(&self, fd: RawFd) -> bool {
+        self.defer_timer
+            .as_ref()
+            .is_some_and(|timer| timer.as_raw_fd() == fd)
+    }
+
+    /// Handle the deferred actions that are due, and arm the timer for
+    /// the next one
+    pub(crate) fn run_deferred(&mut self) -> Result<()> {
+        if let Some(timer) = &mut self.defer_timer {
+            timer.take_expirations()?;
+        }
+        let now = Instant::now();
+        while let Some(entry) = self.deferred.first_entry() {
+            if entry.key().0 > now {
+                let due = entry.key().0;
+                return self.arm_defer_timer(due);
+            }
+            let (client_id, action) = entry.remove();
+            if let Err(e) = self.handle_action(client_id, action) {
+                warn!(
+                    "Failed to handle an action deferred for {}: {}",
+                    client_id, e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn arm_defer_timer(&mut self, due: Instant) -> Result<()> {
+        let timer = match &mut self.defer_timer {
+            Some(timer) => timer,
+            None => {
+                let timer = Timer::new()?;
+                let fd = timer.as_raw_fd();
+                let flags = EventFlags::READ | EventFlags::EDGE;
+                self.epoll
+                    .add_interest(fd, Event::new(flags, PeerRole::Timer(fd as u64)))?;
+                self.defer_timer.insert(timer)
+            }
+        };
+        Ok(timer.arm(due.saturating_duration_since(Instant::now()), None)?)
+    }
+
+    /// Watch `fd` with the server's epoll instance
+    ///
+    /// `EventHandler::on_custom_event` is called with `token` whenever
+    /// the fd is ready for `interest`. Add `EventFlags::EDGE` to be
+    /// notified once per change instead of until the fd is drained, or
+    /// `EventFlags::ONESHOT` to be notified once until
+    /// [`ServerContext::rearm_fd`]. The caller keeps owning the fd,
+    /// closing it also unregisters it. Tokens go up to `MAX_CUSTOM_TOKEN`.
+    ///
+    /// Fails with [`Error::AlreadyRegistered`] if the fd is registered
+    /// already.
+    pub fn register_fd(&mut self, fd: RawFd, interest: EventFlags, token: u64) -> Result<()> {
+        if token > MAX_CUSTOM_TOKEN {
+            return Err(Error::InvalidInput(format!(
+                "token {token} is over MAX_CUSTOM_TOKEN"
+            )));
+        }
+        // The kernel tells duplicates apart: an fd still in the map may
+        // have been closed and its number reused since
+        let event = Event::new(interest, PeerRole::Custom(token));
+        self.epoll.add_interest(fd, event)?;
+        self.custom_fds.insert(fd, (interest, token));
+        Ok(())
+    }
+
+    /// Enable a one-shot registration of [`ServerContext::register_fd`]
+    /// again, with the same interest and token
+    ///
+    /// Fails with [`Error::NotRegistered`] if the fd was not registered,
+    /// deregistered or closed since.
+    pub fn rearm_fd(&mut self, fd: RawFd) -> Result<()> {
+        let &(interest, token) = self.custom_fds.get(&fd).ok_or(Error::NotRegistered(fd))?;
+        let event = Event::new(interest, PeerRole::Custom(token));
+        let result = self.epoll.modify_interest(fd, event);
+        if result.is_err() {
+            self.custom_fds.remove(&fd);
+        }
+        Ok(result?)
+    }
+
+    /// Stop watching an fd of [`ServerContext::register_fd`], which is
+    /// left open
+    ///
+    /// Fails with [`Error::NotRegistered`] if the fd was not registered,
+    /// deregistered or closed since.
+    pub fn deregister_fd(&mut self, fd: RawFd) -> Result<()> {
+        self.custom_fds
+            .remove(&fd)
+            .ok_or(Error::NotRegistered(fd))?;
+        Ok(self.epoll.remove_interest(fd)?)
+    }
+
+    /// Watch the read end of a pipe, typically the stdout or stderr of a
+    /// child process, which the server then owns
+    ///
+    /// The pipe is made non-blocking and `EventHandler::on_fd_readable` is
+    /// called with `token` and the fd while it has data, so a handler can
+    /// stream the output of a subprocess to its clients without blocking
+    /// the loop. Once the writing end is closed (the child exited) the
+    /// handler is called a last time, a read then returns 0, and the
+    /// server closes the pipe. Tokens share the range of
+    /// [`ServerContext::register_fd`].
+    pub fn register_pipe(&mut self, pipe: impl Into<OwnedFd>, token: u64) -> Result<()> {
+        if token > MAX_CUSTOM_TOKEN {
+            return Err(Error::InvalidInput(format!(
+                "token {token} is over MAX_CUSTOM_TOKEN"
+            )));
+        }
+        if self.pipes.contains_key(&token) {
+            return Err(Error::InvalidInput(format!(
+                "a pipe is already registered with token {token}"
+            )));
+        }
+        let pipe = pipe.into();
+        sockopt::set_nonblocking(pipe.as_raw_fd())?;
+        let event = Event::new(EventFlags::READ, PeerRole::Custom(token));
+        self.epoll.add_interest(pipe.as_raw_fd(), event)?;
+        self.pipes.insert(token, pipe);
+        Ok(())
+    }
+
+    /// Stop watching the pipe registered with `token` and close it
+    ///
+    /// Returns `false` if there is no such pipe.
+    pub fn close_pipe(&mut self, token: u64) -> bool {
+        // Closing the pipe removes it from epoll as well
+        self.pipes.remove(&token).is_some()
+    }
+
+    /// Fd of the pipe registered with `token`
+    pub(crate) fn pipe_fd(&self, token: u64) -> Option<RawFd> {
+        self.pipes.get(&token).map(AsRawFd::as_raw_fd)
+    }
+
+    /// Watch the file or directory at `path` for changes
+    ///
+    /// `EventHandler::on_file_event` is called with the returned id for
+    /// each change, see the [`watch`](crate::watch) module.
+    pub fn watch_path(&mut self, path: impl AsRef<Path>) -> Result<WatchId> {
+        let watcher = match &mut self.watcher {
+            Some(watcher) => watcher,
+            None => {
+                let watcher = Watcher::new()?;
+                let event = Event::new(EventFlags::READ, PeerRole::Watcher);
+                self.epoll.add_interest(watcher.as_raw_fd(), event)?;
+                self.watcher.insert(watcher)
+            }
+        };
+        Ok(watcher.add(path.as_ref())?)
+    }
+
+    /// Stop watching, returns `false` if `watch_id` was not watched
+    pub fn unwatch_path(&mut self, watch_id: WatchId) -> Result<bool> {
+        match &mut self.watcher {
+            Some(watcher) => Ok(watcher.remove(watch_id)?),
+            None => Ok(false),
+        }
+    }
+
+    /// Changes of the watched files since the last call
+    pub(crate) fn take_file_events(&mut self) -> Result<Vec<(WatchId, FileEvent)>> {
+        match &mut self.watcher {
+            Some(watcher) => Ok(watcher.read_events()?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Consume the expiration of the timer behind `fd`
+    ///
+    /// Returns the id of the timer if it actually expired, one-shot timers
+    /// are removed at that point.
+    pub(crate) fn expire_timer(&mut self, fd: RawFd) -> Result<Option<TimerId>> {
+        let Some(&timer_id) = self.timer_ids.get(&fd) else {
+            return Ok(None);
+        };
+        let Some(timer) = self.timers.get_mut(&timer_id) else {
+            return Ok(None);
+        };
+
+        if !timer.take_expirations()? {
+            return Ok(None);
+        }
+        if !timer.is_repeating() {
+            self.cancel_timer(timer_id);
+        }
+        Ok(Some(timer_id))
+    }
+
+    /// Stop accepting new connections
+    ///
+    /// The listener stays registered with epoll but without read interest,
+    /// so pending connections wait in the kernel backlog until
+    /// [`ServerContext::resume_accepts`] is called.
+    pub fn pause_accepts(&mut self) -> Result<()> {
+        if !self.accepts_paused {
+            self.accepts_paused = true;
+            self.update_listener_interests()?;
+        }
+        Ok(())
+    }
+
+    /// Start accepting new connections again after [`ServerContext::pause_accepts`]
+    ///
+    /// Re-arming the listener makes epoll report connections that queued up
+    /// while accepts were paused.
+    pub fn resume_accepts(&mut self) -> Result<()> {
+        if self.accepts_paused {
+            self.accepts_paused = false;
+            self.update_listener_interests()?;
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if accepting new connections is paused
+    pub fn accepts_paused(&self) -> bool {
+        self.accepts_paused
+    }
+
+    /// Enable or disable `TCP_NODELAY` for the client
+    ///
+    /// With Nagle's algorithm disabled small writes are sent right away
+    /// instead of being coalesced, trading bandwidth for latency.
+    ///
+    /// Returns `false` if there is no client with the given id.
+    pub fn set_nodelay(&mut self, client_id: ClientId, nodelay: bool) -> Result<bool> {
+        let Some(client) = self.clients.get(&client_id) else {
+            return Ok(false);
+        };
+        sockopt::set_nodelay(client.as_raw_fd(), nodelay)?;
+        Ok(true)
+    }
+
+    /// Hold back partial segments of the client until [`uncork`] is called
+    ///
+    /// Sets `TCP_CORK`: whatever is written meanwhile is only sent in full
+    /// segments, so a response written in several parts does not go out
+    /// as several small packets. The kernel sends held back data after
+    /// 200ms anyway.
+    ///
+    /// Returns `false` if there is no client with the given id.
+    ///
+    /// [`uncork`]: ServerContext::uncork
+    pub fn cork(&mut self, client_id: ClientId) -> Result<bool> {
+        match self.clients.get_mut(&client_id) {
+            Some(client) => client.set_corked(true)?,
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Clear `TCP_CORK`, flushing what the kernel held back
+    ///
+    /// Data still queued goes out as soon as it is written.
+    ///
+    /// Returns `false` if there is no client with the given id.
+    pub fn uncork(&mut self, client_id: ClientId) -> Result<bool> {
+        match self.clients.get_mut(&client_id) {
+            Some(client) => client.set_corked(false)?,
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Whether the client was corked with [`cork`] and not uncorked since
+    ///
+    /// Sockets corked for the time `ReplyParts` or `SendFile` are written
+    /// are not reported. Returns `None` if there is no client with the
+    /// given id.
+    ///
+    /// [`cork`]: ServerContext::cork
+    pub fn is_corked(&self, client_id: ClientId) -> Option<bool> {
+        self.clients.get(&client_id).map(ClientState::is_corked)
+    }
+
+    /// Configure TCP keepalive probing for the client, `None` disables it
+    ///
+    /// Returns `false` if there is no client with the given id.
+    pub fn set_keepalive(
+        &mut self,
+        client_id: ClientId,
+        keepalive: Option<TcpKeepalive>,
+    ) -> Result<bool> {
+        let Some(client) = self.clients.get(&client_id) else {
+            return Ok(false);
+        };
+        sockopt::set_keepalive(client.as_raw_fd(), keepalive)?;
+        Ok(true)
+    }
+
+    /// Accept path counters of the listener
+    pub fn accept_stats(&self) -> AcceptStats {
+        self.accept_stats
+    }
+
+    /// Stop reading from the client
+    ///
+    /// Removes read interest for the client, incoming data stays in the
+    /// kernel buffer until [`ServerContext::resume_client`] is called.
+    /// Pending writes are still flushed.
+    ///
+    /// Returns `false` if there is no client with the given id.
+    pub fn pause_client(&mut self, client_id: ClientId) -> Result<bool> {
+        match self.clients.get_mut(&client_id) {
+            Some(client) => client.set_reads_paused(true),
+            None => return Ok(false),
+        }
+        self.mark_interests_dirty(client_id);
+        Ok(true)
+    }
+
+    /// Start reading from the client again after [`ServerContext::pause_client`]
+    ///
+    /// Returns `false` if there is no client with the given id.
+    pub fn resume_client(&mut self, client_id: ClientId) -> Result<bool> {
+        match self.clients.get_mut(&client_id) {
+            Some(client) => client.set_reads_paused(false),
+            None => return Ok(false),
+        }
+        self.mark_interests_dirty(client_id);
+        Ok(true)
+    }
+
+    pub(crate) fn handle_action(
+        &mut self,
+        originating_client_id: ClientId,
+        action: HandlerAction,
+    ) -> Result<()> {
+        match action {
+            HandlerAction::Reply(data) => {
+                self.send_to(originating_client_id, data)?;
+            }
+            HandlerAction::ReplyWithPriority(data, priority) => {
+                self.send_with_priority(originating_client_id, data, priority)?;
+            }
+            HandlerAction::Broadcast(data) => {
+                // Send to all clients except the sender
+                for client_id in self.connected_clients() {
+                    if client_id != originating_client_id {
+                        self.send_to(client_id, data.clone())?;
+                    }
+                }
+            }
+            HandlerAction::SendTo {
+                target_client_id,
+                data,
+            } => {
+                self.send_to(target_client_id, data)?;
+            }
+            HandlerAction::SendToAll(data) => {
+                // Send to all clients including sender
+                self.broadcast(data)?;
+            }
+            HandlerAction::BroadcastRoom { room, data } => {
+                for client_id in self.rooms.members(&room) {
+                    if client_id != originating_client_id {
+                        self.send_to(client_id, data.clone())?;
+                    }
+                }
+            }
+            HandlerAction::ReplyParts(parts) => {
+                self.send_parts(originating_client_id, parts)?;
+            }
+            HandlerAction::SendFile { file, offset, len } => {
+                self.send_file(originating_client_id, file, offset, len)?;
+            }
+            HandlerAction::ReplyAndClose(data) => {
+                self.send_to(originating_client_id, data)?;
+                self.close_after_write(originating_client_id);
+            }
+            HandlerAction::FinishWrite => {
+                self.shutdown_write(originating_client_id)?;
+            }
+            HandlerAction::Disconnect => {
+                self.disconnect(originating_client_id);
+            }
+            HandlerAction::ReplyStream(source) => {
+                self.queue_stream(originating_client_id, source)?;
+            }
+            HandlerAction::None => (),
+        }
+        Ok(())
+    }
+
+    /// Recompute the client's interests at the end of the current tick
+    ///
+    /// Handlers, flushes and commands may all change what a client waits
+    /// for within one tick, so the registration is updated once, from the
+    /// final state, instead of after every change.
+    pub(crate) fn mark_interests_dirty(&mut self, client_id: ClientId) {
+        self.dirty_interests.insert(client_id);
+    }
+
+    /// Bring the registration of every changed client up to date
+    pub(crate) fn apply_interest_updates(&mut self) -> Result<()> {
+        let dirty: Vec<ClientId> = self.dirty_interests.drain().collect();
+        for client_id in dirty {
+            self.update_client_interests(client_id)?;
+        }
+        Ok(self.epoll.submit_modifications()?)
+    }
+
+    fn update_client_interests(&mut self, client_id: ClientId) -> Result<()> {
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            let fd = client.as_raw_fd();
+
+            let mut new_interests = self.trigger_mode.flags();
+
+            if !client.reads_paused() && client.after_drain() != AfterDrain::Close {
+                new_interests |= EventFlags::READ;
+            }
+
+            if client.has_pending_writes() {
+                new_interests |= EventFlags::WRITE;
+            }
+
+            // One-shot registrations are disabled after every event, so
+            // they are re-armed even if the interests did not change, as
+            // are clients a `ChaosConfig` made give up on a ready socket
+            let rearm = client.chaos_mut().take_rearm();
+            if client.current_interests() != new_interests
+                || self.trigger_mode == TriggerMode::OneShot
+                || rearm
+            {
+                let epoll_event = Event::new(new_interests, PeerRole::Client(client_id));
+                self.epoll.queue_modify_interest(fd, epoll_event)?;
+                client.set_current_interests(new_interests);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn set_max_pending_writes(&mut self, limit: Option<usize>) {
+        self.max_pending_writes = limit;
+    }
+
+    pub(crate) fn middlewares_mut(&mut self) -> &mut MiddlewareChain {
+        &mut self.middlewares
+    }
+
+    pub(crate) fn set_audit_sink(&mut self, sink: Box<dyn AuditSink + Send>) {
+        self.audit_sink = Some(sink);
+    }
+
+    /// Hand `event` to the audit sink, if one is installed
+    pub(crate) fn audit(&mut self, event: AuditEvent) {
+        if let Some(sink) = &mut self.audit_sink {
+            sink.on_audit_event(&event);
+        }
+    }
+
+    pub(crate) fn take_pending_disconnects(&mut self) -> Vec<ClientId> {
+        std::mem::take(&mut self.pending_disconnects)
+    }
+
+    /// Registers the listener's file descriptor to epoll insterest list
+    /// where we get notification for read events in the configured trigger
+    /// mode, unless accepts are paused.
+    fn register_listener(&mut self, listener: TcpListener) -> Result<()> {
+        let epoll_event = Event::new(self.listener_interests(), PeerRole::Server);
+        self.epoll.add_interest(listener.as_raw_fd(), epoll_event)?;
+        self.listener = Some(listener);
+        Ok(())
+    }
+
+    /// Listener is only interested in reads while accepting is allowed
+    fn listener_interests(&self) -> EventFlags {
+        let flags = if self.exclusive_accept {
+            // The kernel refuses one-shot exclusive registrations
+            EventFlags::EXCLUSIVE | self.trigger_mode.flags().difference(EventFlags::ONESHOT)
+        } else {
+            self.trigger_mode.flags()
+        };
+        if self.accepts_paused || self.at_capacity || self.accept_backoff {
+            flags
+        } else {
+            EventFlags::READ | flags
+        }
+    }
+
+    pub(crate) fn trigger_mode(&self) -> TriggerMode {
+        self.trigger_mode
+    }
+
+    pub(crate) fn update_listener_interests(&mut self) -> Result<()> {
+        let listeners = self
+            .listener
+            .iter()
+            .map(|listener| (listener, PeerRole::Server))
+            .chain(
+                self.extra_listeners
+                    .iter()
+                    .map(|(&id, listener)| (listener, PeerRole::Listener(id))),
+            );
+        for (listener, role) in listeners {
+            let epoll_event = Event::new(self.listener_interests(), role);
+            if self.exclusive_accept {
+                self.epoll.reregister(listener.as_raw_fd(), epoll_event)?;
+            } else {
+                self.epoll
+                    .modify_interest(listener.as_raw_fd(), epoll_event)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Register another listening socket, accepting like the first one
+    pub(crate) fn add_listener(&mut self, listener: TcpListener, id: ListenerId) -> Result<()> {
+        if id == PRIMARY_LISTENER || id > MAX_CUSTOM_TOKEN {
+            return Err(Error::InvalidInput(format!(
+                "listener id {id} is 0 or over MAX_CUSTOM_TOKEN"
+            )));
+        }
+        if self.extra_listeners.contains_key(&id) {
+            return Err(Error::InvalidInput(format!("listener id {id} is taken")));
+        }
+        let epoll_event = Event::new(self.listener_interests(), PeerRole::Listener(id));
+        self.epoll.add_interest(listener.as_raw_fd(), epoll_event)?;
+        self.extra_listeners.insert(id, listener);
+        Ok(())
+    }
+
+    /// Close an added listener, closing the fd also removes it from epoll
+    pub(crate) fn close_extra_listener(&mut self, id: ListenerId) -> Option<TcpListener> {
+        self.extra_listeners.remove(&id)
+    }
+
+    /// The listener with `id`, the primary one included
+    pub(crate) fn listener_by_id(&self, id: ListenerId) -> Option<&TcpListener> {
+        match id {
+            PRIMARY_LISTENER => self.listener.as_ref(),
+            id => self.extra_listeners.get(&id),
+        }
+    }
+
+    /// Stop or resume accepting based on the connection limit
+    pub(crate) fn set_at_capacity(&mut self, at_capacity: bool) -> Result<()> {
+        if self.at_capacity != at_capacity {
+            self.at_capacity = at_capacity;
+            self.update_listener_interests()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn at_capacity(&self) -> bool {
+        self.at_capacity
+    }
+
+    /// Stop or resume accepting after `accept` failed
+    pub(crate) fn set_accept_backoff(&mut self, backoff: bool) -> Result<()> {
+        if self.accept_backoff != backoff {
+            self.accept_backoff = backoff;
+            self.update_listener_interests()?;
+        }
+        Ok(())
+    }
+
+    /// Close the listener, closing the fd also removes it from epoll
+    pub(crate) fn close_listener(&mut self) {
+        self.listener = None;
+    }
+
+    /// Bind a new listener on the address the server was listening on
+    pub(crate) fn rebind_listener(&mut self) -> Result<()> {
+        let listener = sockopt::bind_listener(self.listen_addr, self.listen_options)?;
+        self.register_listener(listener)
+    }
+
+    pub(crate) fn set_listen_options(&mut self, options: ListenOptions) {
+        self.listen_options = options;
+    }
+
+    pub(crate) fn listener(&self) -> Option<&TcpListener> {
+        self.listener.as_ref()
+    }
+
+    pub(crate) fn listen_addr(&self) -> SocketAddr {
+        self.listen_addr
+    }
+
+    pub(crate) fn epoll(&self) -> &ServerReactor {
+        &self.epoll
+    }
+
+    pub(crate) fn clients(&self) -> &ClientSlab {
+        &self.clients
+    }
+
+    pub(crate) fn accept_stats_mut(&mut self) -> &mut AcceptStats {
+        &mut self.accept_stats
+    }
+
+    pub(crate) fn clients_mut(&mut self) -> &mut ClientSlab {
+        &mut self.clients
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    io::{ErrorKind, Result},
+    net::{SocketAddr, UdpSocket},
+};
+
+use log::{debug, error};
+
+use crate::context::ServerContext;
+
+/// Largest payload a UDP datagram can carry
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+/// Handler for UDP sockets bound with `EpollServer::bind_udp`
+///
+/// UDP has no connections, so there is only one callback per datagram.
+/// Returning `Some((addr, data))` sends `data` back to `addr` from the
+/// same socket, usually the sender of the datagram.
+pub trait DatagramHandler {
+    fn on_datagram(
+        &mut self,
+        ctx: &mut ServerContext,
+        addr: SocketAddr,
+        data: &[u8],
+    ) -> Option<(SocketAddr, Vec<u8>)>;
+}
+
+/// UDP socket registered in the server's epoll instance
+pub(crate) struct DatagramSocket {
+    socket: UdpSocket,
+    handler: Box<dyn DatagramHandler + Send>,
+    buffer: Vec<u8>,
+}
+
+impl DatagramSocket {
+    pub fn new(socket: UdpSocket, handler: Box<dyn DatagramHandler + Send>) -> Self {
+        DatagramSocket {
+            socket,
+            handler,
+            buffer: vec![0u8; MAX_DATAGRAM_SIZE],
+        }
+    }
+
+    /// Receive datagrams until the kernel buffer is drained
+    ///
+    /// Replies are sent right away; if the socket buffer is full the reply
+    /// is dropped, like any other lost datagram.
+    pub fn handle_readable(&mut self, ctx: &mut ServerContext) -> Result<()> {
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut self.buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    debug!("Drained all pending datagrams");
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+
+            debug!("Received datagram of {} bytes from {}", len, addr);
+            let Some((target, reply)) = self.handler.on_datagram(ctx, addr, &self.buffer[..len])
+            else {
+                continue;
+            };
+
+            match self.socket.send_to(&reply, target) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    debug!("Socket buffer full, dropped datagram to {}", target);
+                }
+                Err(e) => error!("Failed to send datagram to {}: {}", target, e),
+            }
+        }
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+}
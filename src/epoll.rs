@@ -1,11 +1,28 @@
+use std::ops::{BitAnd, BitOr, BitOrAssign};
+#[cfg(target_os = "linux")]
 use std::{
+    cell::{Cell, RefCell},
     io::{Error, Result},
-    os::fd::RawFd,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    time::Duration,
 };
 
-use log::{debug, error};
+#[cfg(target_os = "linux")]
+use log::debug;
 
-use crate::ep_syscall;
+use crate::ClientId;
+#[cfg(target_os = "linux")]
+use crate::{
+    ep_syscall,
+    error::os_error,
+    ffi::{
+        EEXIST, ENOENT, ENOSYS, EPOLL_CLOEXEC, F_GETFD, KERNEL_SIGSET_SIZE, SYS_EPOLL_PWAIT2,
+        TimeSpec,
+    },
+    io_uring::Ring,
+    reactor::{Backend, Reactor},
+    signal::SignalMask,
+};
 
 /// Represents either server or client
 ///
@@ -14,14 +31,68 @@ use crate::ep_syscall;
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum PeerRole {
     Server,
-    Client(u64),
+    /// Listener added with `EpollServer::add_listener`, identified by its
+    /// `ListenerId`
+    Listener(u64),
+    Client(ClientId),
+    /// UDP socket, identified by its index in the server
+    Datagram(u64),
+    /// Handler timer, identified by its timerfd
+    Timer(u64),
+    /// eventfd used by `ServerHandle`s to wake up the loop
+    Waker,
+    /// Listener or scrape connection of the built-in metrics endpoint,
+    /// identified by its fd
+    Metrics(u64),
+    /// fd registered with `EpollServer::register_fd`, identified by the
+    /// token given to it
+    Custom(u64),
+    /// inotify instance behind `ServerContext::watch_path`
+    Watcher,
 }
 
+/// Marks the `data` of datagram sockets so they never collide with client ids
+const DATAGRAM_TAG: u64 = 1 << 63;
+
+/// Marks the `data` of timers, for the same reason
+const TIMER_TAG: u64 = 1 << 62;
+
+/// Marks the `data` of metrics endpoint sockets, both bits are set so it
+/// must be checked before the datagram and timer tags
+const METRICS_TAG: u64 = DATAGRAM_TAG | TIMER_TAG;
+
+/// `data` of the waker, above any client id (see `MAX_CLIENT_ID`)
+const WAKER_TOKEN: u64 = 1 << 61;
+
+/// Marks the `data` of custom fds, must be checked before the timer tag
+const CUSTOM_TAG: u64 = TIMER_TAG | WAKER_TOKEN;
+
+/// Marks the `data` of added listeners, must be checked before the
+/// datagram tag
+const LISTENER_TAG: u64 = DATAGRAM_TAG | WAKER_TOKEN;
+
+/// `data` of the inotify instance, every tag bit set
+const WATCHER_TOKEN: u64 = DATAGRAM_TAG | TIMER_TAG | WAKER_TOKEN;
+
+/// Largest token of a custom fd, the bits above carry the tags
+pub const MAX_CUSTOM_TOKEN: u64 = WAKER_TOKEN - 1;
+
 impl From<u64> for PeerRole {
     fn from(value: u64) -> Self {
         match value {
             0 => PeerRole::Server,
-            others => PeerRole::Client(others),
+            WAKER_TOKEN => PeerRole::Waker,
+            WATCHER_TOKEN => PeerRole::Watcher,
+            tagged if tagged & METRICS_TAG == METRICS_TAG => {
+                PeerRole::Metrics(tagged & !METRICS_TAG)
+            }
+            tagged if tagged & CUSTOM_TAG == CUSTOM_TAG => PeerRole::Custom(tagged & !CUSTOM_TAG),
+            tagged if tagged & LISTENER_TAG == LISTENER_TAG => {
+                PeerRole::Listener(tagged & !LISTENER_TAG)
+            }
+            tagged if tagged & DATAGRAM_TAG != 0 => PeerRole::Datagram(tagged & !DATAGRAM_TAG),
+            tagged if tagged & TIMER_TAG != 0 => PeerRole::Timer(tagged & !TIMER_TAG),
+            others => PeerRole::Client(others.into()),
         }
     }
 }
@@ -30,7 +101,14 @@ impl From<PeerRole> for u64 {
     fn from(value: PeerRole) -> Self {
         match value {
             PeerRole::Server => 0,
-            PeerRole::Client(id) => id,
+            PeerRole::Listener(id) => id | LISTENER_TAG,
+            PeerRole::Client(id) => id.into(),
+            PeerRole::Datagram(index) => index | DATAGRAM_TAG,
+            PeerRole::Timer(fd) => fd | TIMER_TAG,
+            PeerRole::Waker => WAKER_TOKEN,
+            PeerRole::Watcher => WATCHER_TOKEN,
+            PeerRole::Metrics(fd) => fd | METRICS_TAG,
+            PeerRole::Custom(token) => token | CUSTOM_TAG,
         }
     }
 }
@@ -42,6 +120,7 @@ impl From<PeerRole> for u64 {
 ///     ADD = EPOLL_CTL_ADD
 ///     DEL = EPOLL_CTL_DEL
 ///     MOD = EPOLL_CTL_MOD
+#[cfg(any(target_os = "linux", feature = "wepoll"))]
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub(crate) enum Operation {
     /// Add entry to the interest list of the epoll instance
@@ -53,6 +132,7 @@ pub(crate) enum Operation {
     Mod,
 }
 
+#[cfg(any(target_os = "linux", feature = "wepoll"))]
 impl From<Operation> for i32 {
     fn from(value: Operation) -> Self {
         match value {
@@ -63,38 +143,107 @@ impl From<Operation> for i32 {
     }
 }
 
-/// Avalilable event types and input for `Event`
+/// Event types and input flags of an `Event`
 ///
-/// These variants are used to bitmask the `event` filed in `Event`
-#[allow(dead_code)]
-#[repr(i32)]
-pub(crate) enum EventType {
+/// Readiness types are reported by the kernel, input flags only change how
+/// an interest behaves. Flags combine with `|`:
+///
+/// ```
+/// use epoll_worker::EventFlags;
+///
+/// let interests = EventFlags::READ | EventFlags::WRITE | EventFlags::EDGE;
+/// assert!(interests.contains(EventFlags::READ | EventFlags::EDGE));
+/// assert!(!interests.contains(EventFlags::ONESHOT));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct EventFlags(u32);
+
+impl EventFlags {
     /// Read operation
-    Epollin = 0x1,
-    /// Write operation
-    Epollout = 0x4,
-    /// Stream socket peer closed connection or shut down
-    Epollrdhup = 0x2000,
+    pub const READ: Self = EventFlags(0x1);
     /// Exceptional condition
-    Epollpri = 0x2,
+    pub const PRIORITY: Self = EventFlags(0x2);
+    /// Write operation
+    pub const WRITE: Self = EventFlags(0x4);
     /// Error condition
-    Epollerr = 0x8,
+    pub const ERROR: Self = EventFlags(0x8);
     /// Hang up happened on associated fd
-    Epollhup = 0x10,
-    /// Request edge-trigerred notification
-    Epollet = 1 << 31,
+    pub const HANGUP: Self = EventFlags(0x10);
+    /// Stream socket peer closed connection or shut down
+    pub const READ_HANGUP: Self = EventFlags(0x2000);
+    /// Wake only one of the epoll instances waiting on the same fd
+    pub const EXCLUSIVE: Self = EventFlags(1 << 28);
     /// Request one-shot notification
-    Epolloneshot = 1 << 30,
+    pub const ONESHOT: Self = EventFlags(1 << 30);
+    /// Request edge-trigerred notification
+    pub const EDGE: Self = EventFlags(1 << 31);
+
+    /// No flag set
+    pub const fn empty() -> Self {
+        EventFlags(0)
+    }
+
+    /// Flags of the raw `events` mask, unknown bits are kept
+    pub const fn from_bits(bits: u32) -> Self {
+        EventFlags(bits)
+    }
+
+    /// Raw `events` mask passed to the kernel
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether every flag of `other` is set
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether any flag of `other` is set
+    pub const fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Flags of `self` that are not in `other`
+    pub const fn difference(self, other: Self) -> Self {
+        EventFlags(self.0 & !other.0)
+    }
+}
+
+impl BitOr for EventFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        EventFlags(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for EventFlags {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
+
+impl BitAnd for EventFlags {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        EventFlags(self.0 & other.0)
+    }
 }
 
 /// Corresponds to Linux's `epoll_event`
 ///
-/// events - is the bit mask composed by ORing together zero or more event
+/// events - is the bit mask composed by ORing together zero or more
+/// `EventFlags`
 ///
 /// data means user data/identifier
 #[derive(Debug)]
 #[repr(C, packed(1))]
-pub(crate) struct Event {
+pub struct Event {
     /// bit mask composed by ORing together zero or more event types
     /// returned by `epoll_wait`, and input flags which affect its behaviour
     events: u32,
@@ -105,15 +254,15 @@ pub(crate) struct Event {
 
 #[allow(dead_code)]
 impl Event {
-    pub fn new(bitmask: u32, identifier: PeerRole) -> Self {
+    pub fn new(flags: EventFlags, identifier: PeerRole) -> Self {
         Event {
-            events: bitmask,
+            events: flags.bits(),
             data: identifier.into(),
         }
     }
 
-    pub fn event_type(&self) -> u32 {
-        self.events
+    pub fn flags(&self) -> EventFlags {
+        EventFlags::from_bits(self.events)
     }
 
     pub fn role(&self) -> PeerRole {
@@ -133,35 +282,70 @@ impl Event {
 /// adding interest to epoll instance,
 /// modifyinf interest to epoll instance,
 /// deleting insterest from epoll instance
-pub(crate) struct Epoll {
-    epfd: RawFd,
+#[cfg(target_os = "linux")]
+pub struct Epoll {
+    epfd: OwnedFd,
+    /// Submits the interest modifications in batches, with
+    /// `Backend::BatchedEpollCtl`
+    ring: Option<RefCell<Ring>>,
+    /// Set once the kernel reported it has no `epoll_pwait2`
+    pwait2_missing: Cell<bool>,
 }
 
-impl Epoll {
+/// An epoll fd is itself readable while it has ready events, so one
+/// instance can be watched by another
+#[cfg(target_os = "linux")]
+impl AsRawFd for Epoll {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epfd.as_raw_fd()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl AsFd for Epoll {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.epfd.as_fd()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Reactor for Epoll {
     /// Create new instance of epoll
-    pub fn new() -> Result<Self> {
-        let epfd = ep_syscall!(epoll_create1(0))?;
+    fn new() -> Result<Self> {
+        // Close-on-exec, so processes spawned by handlers do not inherit it
+        let epfd = ep_syscall!(epoll_create1(EPOLL_CLOEXEC))?;
+        // SAFETY: the fd was just created and nothing else owns it
+        let epfd = unsafe { OwnedFd::from_raw_fd(epfd) };
 
-        // Validate the file descriptor (F_GETFD)
-        if let Err(e) = ep_syscall!(fcntl(epfd, 1)) {
-            let _ = ep_syscall!(close(epfd));
-            return Err(e);
-        }
+        // Validate the file descriptor
+        ep_syscall!(fcntl(epfd.as_raw_fd(), F_GETFD))?;
 
-        Ok(Epoll { epfd })
+        Ok(Epoll {
+            epfd,
+            ring: None,
+            pwait2_missing: Cell::new(false),
+        })
+    }
+
+    fn with_backend(backend: Backend) -> Result<Self> {
+        let mut epoll = Self::new()?;
+        if backend == Backend::BatchedEpollCtl {
+            epoll.ring = Some(RefCell::new(Ring::new()?));
+        }
+        Ok(epoll)
     }
 
     /// Get events from ready list
-    pub fn wait(&self, events: &mut Vec<Event>, timeout: Option<i32>) -> Result<()> {
-        let max_events = events.capacity() as i32;
-        let timeout = timeout.unwrap_or(1000);
+    fn wait(
+        &self,
+        events: &mut Vec<Event>,
+        timeout: Option<Duration>,
+        sigmask: Option<&SignalMask>,
+    ) -> Result<()> {
+        self.submit_queued()?;
 
-        let res = ep_syscall!(epoll_wait(
-            self.epfd,
-            events.as_mut_ptr(),
-            max_events,
-            timeout
-        ))?;
+        let max_events = events.capacity() as i32;
+        let res = self.pwait(events.as_mut_ptr(), max_events, timeout, sigmask)?;
 
         // Kernel should always return the bounded number of events
         if res > max_events {
@@ -172,7 +356,7 @@ impl Epoll {
             events.set_len(res as usize);
         }
 
-        if timeout.is_negative() {
+        if res == 0 {
             debug!("Epoll polling timeout reached, retrying...");
         } else {
             debug!("Received {} events from epoll", res);
@@ -181,29 +365,72 @@ impl Epoll {
     }
 
     /// Add event to interest list
-    pub fn add_interest(&self, fd: RawFd, mut event: Event) -> Result<()> {
+    fn add_interest(&self, fd: RawFd, mut event: Event) -> Result<()> {
+        self.submit_queued()?;
         self.control_interest(Operation::Add, fd, Some(&mut event))
     }
 
     /// Modify event in interest list
-    pub fn modify_interest(&self, fd: RawFd, mut event: Event) -> Result<()> {
+    fn modify_interest(&self, fd: RawFd, mut event: Event) -> Result<()> {
+        self.submit_queued()?;
         self.control_interest(Operation::Mod, fd, Some(&mut event))
     }
 
+    /// With a ring the modification is only queued, it is submitted with
+    /// the others by `submit_modifications`. Everything else stays
+    /// immediate, additions and removals because the fd may be closed and
+    /// reused right after them.
+    fn queue_modify_interest(&self, fd: RawFd, mut event: Event) -> Result<()> {
+        match &self.ring {
+            Some(ring) => ring.borrow_mut().queue_epoll_ctl(
+                self.epfd.as_raw_fd(),
+                Operation::Mod,
+                fd,
+                Some(event),
+            ),
+            None => self.control_interest(Operation::Mod, fd, Some(&mut event)),
+        }
+    }
+
+    fn submit_modifications(&self) -> Result<()> {
+        self.submit_queued()
+    }
+
     /// Remove event from interest list
-    pub fn remove_interest(&self, fd: RawFd) -> Result<()> {
+    ///
+    /// The fd is left open, its owner closes it.
+    fn remove_interest(&self, fd: RawFd) -> Result<()> {
+        self.submit_queued()?;
+        self.control_interest(Operation::Del, fd, None)
+    }
+
+    /// Exclusive registrations cannot be modified, they are removed and
+    /// added again, leaving the fd open
+    fn reregister(&self, fd: RawFd, mut event: Event) -> Result<()> {
+        self.submit_queued()?;
         self.control_interest(Operation::Del, fd, None)?;
+        self.control_interest(Operation::Add, fd, Some(&mut event))
+    }
+}
 
-        if let Err(e) = ep_syscall!(close(fd)) {
-            error!("Failed to close epoll fd {}: {}", fd, e);
-        }
-        Ok(())
+/// Name the failure `e` of the `epoll_ctl` `op` on `fd` when it says the fd
+/// is or is not registered
+#[cfg(target_os = "linux")]
+pub(crate) fn ctl_error(op: Operation, fd: RawFd, e: Error) -> Error {
+    match (op, os_error(&e)) {
+        (Operation::Add, Some(EEXIST)) => crate::Error::AlreadyRegistered(fd).into(),
+        (Operation::Mod | Operation::Del, Some(ENOENT)) => crate::Error::NotRegistered(fd).into(),
+        _ => e,
     }
+}
 
+#[cfg(target_os = "linux")]
+impl Epoll {
     fn control_interest(&self, op: Operation, fd: RawFd, event: Option<&mut Event>) -> Result<()> {
         if fd < 0 {
             // EBADF = 9 (Bad file descriptor)
-            return Err(Error::from_raw_os_error(9));
+            let error = Error::from_raw_os_error(9);
+            return Err(crate::error::Error::syscall("epoll_ctl", error));
         }
 
         let event_ptr = match event {
@@ -211,20 +438,74 @@ impl Epoll {
             None => std::ptr::null_mut(),
         };
 
-        let _ = ep_syscall!(epoll_ctl(self.epfd, i32::from(op), fd, event_ptr))?;
-
-        Ok(())
+        ep_syscall!(epoll_ctl(
+            self.epfd.as_raw_fd(),
+            i32::from(op),
+            fd,
+            event_ptr
+        ))
+        .map(|_| ())
+        .map_err(|e| ctl_error(op, fd, e))
     }
 
-    pub fn fd(&self) -> RawFd {
-        self.epfd
+    /// Wait with `epoll_pwait2`, whose timeout has nanosecond precision
+    ///
+    /// Kernels before 5.11 lack it, they get `epoll_pwait` with the timeout
+    /// rounded up to the next millisecond.
+    fn pwait(
+        &self,
+        events: *mut Event,
+        max_events: i32,
+        timeout: Option<Duration>,
+        sigmask: Option<&SignalMask>,
+    ) -> Result<i32> {
+        let sigmask = sigmask.map_or(std::ptr::null(), SignalMask::as_ptr);
+
+        if !self.pwait2_missing.get() {
+            let timespec = timeout.map(TimeSpec::from);
+            let timespec_ptr = timespec
+                .as_ref()
+                .map_or(std::ptr::null(), |timespec| timespec as *const TimeSpec);
+            match ep_syscall!(syscall(
+                SYS_EPOLL_PWAIT2,
+                i64::from(self.epfd.as_raw_fd()),
+                events,
+                i64::from(max_events),
+                timespec_ptr,
+                sigmask,
+                KERNEL_SIGSET_SIZE
+            )) {
+                Err(e) if crate::error::os_error(&e) == Some(ENOSYS) => {
+                    debug!("epoll_pwait2 is not available, waiting with epoll_pwait");
+                    self.pwait2_missing.set(true);
+                }
+                result => return result.map(|res| res as i32),
+            }
+        }
+
+        let timeout_ms = match timeout {
+            Some(timeout) => timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        ep_syscall!(epoll_pwait(
+            self.epfd.as_raw_fd(),
+            events,
+            max_events,
+            timeout_ms,
+            sigmask
+        ))
     }
-}
 
-impl Drop for Epoll {
-    fn drop(&mut self) {
-        if let Err(e) = ep_syscall!(close(self.epfd)) {
-            error!("Failed to close epoll fd {}: {}", self.epfd, e);
+    /// Submit the modifications queued in the ring, so they apply before
+    /// what comes next on the same fds
+    fn submit_queued(&self) -> Result<()> {
+        match &self.ring {
+            Some(ring) => ring.borrow_mut().submit(),
+            None => Ok(()),
         }
     }
+
+    pub fn fd(&self) -> RawFd {
+        self.epfd.as_raw_fd()
+    }
 }
@@ -1,80 +1,520 @@
 use std::{
     collections::HashMap,
-    io::{ErrorKind, Read, Result},
-    net::{SocketAddr, TcpListener, ToSocketAddrs},
-    os::fd::{AsRawFd, RawFd},
+    io::{ErrorKind, Result},
+    mem::ManuallyDrop,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    ops::ControlFlow,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::Path,
     sync::{
         Arc,
         atomic::{AtomicBool, Ordering},
     },
+    time::{Duration, Instant},
 };
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
 use crate::{
-    Epoll, Event, EventType, PeerRole,
-    client_state::ClientState,
-    handler::{EventHandler, HandlerAction},
+    Event, EventFlags, PeerRole,
+    accept_error::{AcceptError, ReservedFd},
+    accept_filter::AcceptFilter,
+    audit::{AuditEvent, AuditSink, DisconnectReason},
+    buffer_pool::BufferPool,
+    bytes::Bytes,
+    chaos::Chaos,
+    client_id::{ClientId, ClientIdAllocator, MAX_CLIENT_ID},
+    client_state::{AfterDrain, ClientState, ReadOutcome},
+    config::{ServerConfig, TriggerMode},
+    context::{ListenerId, PRIMARY_LISTENER, ServerContext},
+    datagram::{DatagramHandler, DatagramSocket},
+    handler::{
+        ConsumeResult, ErrorDirective, ErrorPolicy, EventHandler, HandlerError, OverflowAction,
+        ServerError,
+    },
+    metrics::Metrics,
+    metrics_endpoint::MetricsEndpoint,
+    middleware::Middleware,
+    protocol::{Frame, ReadBuf},
+    rate_limit::{RateLimit, RateLimitAction, RateLimiter},
+    reactor::{MockPoller, Reactor},
+    server_handle::{Command, CommandQueue, ServerHandle},
+    server_reactor::ServerReactor,
+    sockopt,
+    stats::{self, AcceptStats},
+    systemd,
+    telemetry::{MessageTrace, Telemetry},
+    timeout_policy::TimeoutPolicy,
+    trace::{self, ConnectionSpan},
+    watch::WatchId,
 };
 
-/// Represents the client id
-pub type ClientId = u64;
+/// Longest time between two checks of the clients against the idle and
+/// message timeouts, which also drop the idle accept rate limiters
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shortest time between two such checks, so clients expiring one after
+/// the other are disconnected together rather than by one scan each
+const SWEEP_GRANULARITY: Duration = Duration::from_millis(10);
+
+/// How the server recovers when its listening socket fails
+///
+/// On an error condition on the listener (e.g. the interface went down)
+/// the listener is closed and rebinding to the same address is attempted
+/// up to `max_attempts` times, `retry_interval` apart. Established clients
+/// keep being served in the meantime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RebindPolicy {
+    pub max_attempts: u32,
+    pub retry_interval: Duration,
+}
+
+impl Default for RebindPolicy {
+    fn default() -> Self {
+        RebindPolicy {
+            max_attempts: 5,
+            retry_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Progress of an ongoing rebind sequence
+#[derive(Debug, Clone, Copy)]
+struct RebindState {
+    attempts: u32,
+    next_attempt: Instant,
+}
 
 /// Server instance that listens for request
-pub struct EpollServer<H> {
-    listener: TcpListener,
-    epoll: Epoll,
-    clients: HashMap<ClientId, ClientState>,
+pub struct EpollServer<H: EventHandler + 'static> {
+    context: ServerContext,
+    config: ServerConfig,
+    buffer_pool: BufferPool,
+    /// Filled by every `epoll_wait`, its capacity is
+    /// `ServerConfig::max_events` and it is never reallocated
+    events: Vec<Event>,
+    datagram_sockets: Vec<DatagramSocket>,
     shutdown_signal: Arc<AtomicBool>,
+    commands: CommandQueue,
+    goodbye_message: Option<Vec<u8>>,
+    rebind_policy: RebindPolicy,
+    rebind_state: Option<RebindState>,
+    telemetry: Option<Box<dyn Telemetry + Send>>,
+    id_allocator: Option<Box<dyn ClientIdAllocator + Send>>,
+    /// Ids of the connected clients by fd
+    client_ids: HashMap<RawFd, ClientId>,
+    accept_filter: Option<Box<dyn AcceptFilter + Send>>,
+    /// When accepting resumes after `accept` failed
+    accept_backoff: Option<Instant>,
+    /// Given up to shed connections when out of file descriptors, see
+    /// `ServerConfig::reserve_accept_fd`
+    reserved_fd: Option<ReservedFd>,
+    /// Messages seen, to pick the ones to trace
+    message_count: u64,
+    metrics: Metrics,
+    /// When `on_tick` is next due
+    next_tick: Option<Instant>,
+    /// When metrics are next due to the telemetry
+    next_metrics_report: Option<Instant>,
+    metrics_endpoint: Option<MetricsEndpoint>,
+    /// Rate limiters shared by the clients of each IP, with the number of
+    /// clients connected from it
+    ip_limiters: HashMap<IpAddr, (usize, RateLimiter)>,
+    /// Clients paused for exceeding their rate limit, with when to resume
+    /// and the message held back until then
+    rate_paused: HashMap<ClientId, (Instant, Bytes)>,
+    /// Connection rate limiters of the IPs that connected recently
+    accept_limiters: HashMap<IpAddr, RateLimiter>,
+    /// When clients are next checked against the timeouts
+    next_sweep: Option<Instant>,
+    timeout_policy: TimeoutPolicy,
+    /// When a drain requested through `ServerHandle::drain` gives up
+    /// waiting for the clients
+    drain_deadline: Option<Instant>,
     handler: H,
 }
 
-impl<H: EventHandler> EpollServer<H> {
+impl<H: EventHandler + 'static> EpollServer<H> {
     /// Create new Server instance
     ///
     /// Requires valid address and handler that will be called
-    pub fn new<A: ToSocketAddrs>(addr: A, handler: H) -> Result<Self> {
-        let listener = TcpListener::bind(addr)?;
+    pub fn new<A: ToSocketAddrs>(addr: A, handler: H) -> crate::Result<Self> {
+        Self::new_with_config(addr, handler, ServerConfig::default())
+    }
+
+    /// Create new Server instance tuned by `config`
+    pub fn new_with_config<A: ToSocketAddrs>(
+        addr: A,
+        handler: H,
+        config: ServerConfig,
+    ) -> crate::Result<Self> {
+        let listener = sockopt::bind_listener(addr, config.listen_options())?;
+        Self::from_listener_with_config(listener, handler, config)
+    }
+
+    /// Create a server accepting on an already bound `listener`, e.g. one
+    /// inherited from the process it replaces
+    pub fn from_listener(listener: TcpListener, handler: H) -> crate::Result<Self> {
+        Self::from_listener_with_config(listener, handler, ServerConfig::default())
+    }
+
+    /// Create a server accepting on the sockets systemd passed to the
+    /// process (socket activation, `LISTEN_FDS`)
+    ///
+    /// The first socket is the `PRIMARY_LISTENER`, the next ones are added
+    /// as by `add_listener` with ids 1, 2, ... in the order of the
+    /// `ListenStream=` lines of the socket unit. Fails with `NotFound` if
+    /// the process was not socket activated. Enable
+    /// `ServerConfig::systemd_notify` to report readiness as well.
+    pub fn from_systemd(handler: H, config: ServerConfig) -> crate::Result<Self> {
+        let mut listeners = systemd::listen_fds()?.into_iter();
+        let primary = listeners
+            .next()
+            .ok_or_else(|| std::io::Error::from(ErrorKind::NotFound))?;
+        let mut server = Self::from_listener_with_config(primary, handler, config)?;
+        for (listener, id) in listeners.zip(1..) {
+            server.context.add_listener(listener, id)?;
+        }
+        Ok(server)
+    }
+
+    /// Create a server accepting on an already bound `listener`
+    ///
+    /// Several loops can serve the same port with clones of one listener
+    /// (`TcpListener::try_clone`), each on its own thread. Enable
+    /// `ServerConfig::exclusive_accept` so a connection wakes one loop
+    /// instead of all of them.
+    pub fn from_listener_with_config(
+        listener: TcpListener,
+        handler: H,
+        config: ServerConfig,
+    ) -> crate::Result<Self> {
+        let epoll = ServerReactor::with_backend(config.poll_backend())?;
+        Self::from_listener_on(listener, handler, config, epoll)
+    }
+
+    /// Create a server whose loop only sees the readiness injected into
+    /// `poller`, for deterministic tests
+    ///
+    /// Connections to `listener` are accepted once the listener's fd,
+    /// `poller.source_of(PeerRole::Server)`, is injected as readable, and
+    /// clients are read from or written to when theirs are. Their sockets
+    /// are real, only waiting for them is replaced. See [`MockPoller`].
+    pub fn with_mock_poller(
+        listener: TcpListener,
+        handler: H,
+        config: ServerConfig,
+        poller: MockPoller,
+    ) -> crate::Result<Self> {
+        Self::from_listener_on(listener, handler, config, ServerReactor::Mock(poller))
+    }
+
+    fn from_listener_on(
+        listener: TcpListener,
+        handler: H,
+        config: ServerConfig,
+        epoll: ServerReactor,
+    ) -> crate::Result<Self> {
         if let Err(e) = listener.set_nonblocking(true) {
             error!("Failed to set listener to non blocking");
-            return Err(e);
+            return Err(e.into());
         }
+        // The listener may come from code that did not set it
+        sockopt::set_cloexec(listener.as_raw_fd())?;
 
-        let epoll = Epoll::new()?;
-
-        debug!("Epoll instance created with efd: `{}`", epoll.fd());
-        Ok(EpollServer {
+        let context = ServerContext::new(
             listener,
             epoll,
-            clients: HashMap::new(),
+            config.trigger(),
+            config.accepts_exclusively(),
+        )?;
+        Ok(Self::with_context(context, handler, config)?)
+    }
+
+    /// Create a server without a listener, serving the connections an
+    /// `Acceptor` hands to it through its `ServerHandle`
+    ///
+    /// `local_addr` of a worker reports the unspecified address.
+    pub fn new_worker(handler: H, config: ServerConfig) -> crate::Result<Self> {
+        let epoll = ServerReactor::with_backend(config.poll_backend())?;
+        let unbound = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        let context = ServerContext::without_listener(unbound, epoll, config.trigger());
+        Ok(Self::with_context(context, handler, config)?)
+    }
+
+    fn with_context(mut context: ServerContext, handler: H, config: ServerConfig) -> Result<Self> {
+        let epoll = context.epoll();
+
+        if let ServerReactor::Platform(epoll) = epoll {
+            debug!("Epoll instance created with efd: `{}`", epoll.fd());
+        }
+
+        let commands = CommandQueue::new()?;
+        let waker_fd = commands.waker().as_raw_fd();
+        let waker_flags = EventFlags::READ | EventFlags::EDGE;
+        epoll.add_interest(waker_fd, Event::new(waker_flags, PeerRole::Waker))?;
+
+        let metrics_endpoint = config
+            .metrics_endpoint_addr()
+            .map(|addr| MetricsEndpoint::bind(addr, epoll))
+            .transpose()?;
+        let next_metrics_report = config
+            .metrics_period()
+            .map(|period| Instant::now() + period);
+        let next_tick = config.tick_period().map(|period| Instant::now() + period);
+        let needs_sweep = config.partial_message_timeout().is_some()
+            || config.client_idle_timeout().is_some()
+            || config.accept_rate().is_some();
+        // The first sweep schedules the next one from the timeouts
+        let next_sweep = needs_sweep.then(Instant::now);
+
+        let reserved_fd = match config.reserves_accept_fd() {
+            true => Some(ReservedFd::open()?),
+            false => None,
+        };
+
+        context.set_max_pending_writes(config.pending_writes_limit());
+        context.set_listen_options(config.listen_options());
+        Ok(EpollServer {
+            context,
+            buffer_pool: BufferPool::new(config.read_slab_size(), config.read_slab_count()),
+            events: Vec::with_capacity(config.event_capacity()),
+            config,
+            datagram_sockets: Vec::new(),
             shutdown_signal: Arc::new(AtomicBool::new(false)),
+            commands,
+            goodbye_message: None,
+            rebind_policy: RebindPolicy::default(),
+            rebind_state: None,
+            telemetry: None,
+            id_allocator: None,
+            client_ids: HashMap::new(),
+            accept_filter: None,
+            accept_backoff: None,
+            reserved_fd,
+            message_count: 0,
+            metrics: Metrics::default(),
+            next_tick,
+            next_metrics_report,
+            metrics_endpoint,
+            ip_limiters: HashMap::new(),
+            rate_paused: HashMap::new(),
+            accept_limiters: HashMap::new(),
+            next_sweep,
+            timeout_policy: TimeoutPolicy::default(),
+            drain_deadline: None,
             handler,
         })
     }
 
+    /// Watch `fd` with the server's epoll instance, see
+    /// [`ServerContext::register_fd`]
+    pub fn register_fd(
+        &mut self,
+        fd: RawFd,
+        interest: EventFlags,
+        token: u64,
+    ) -> crate::Result<()> {
+        self.context.register_fd(fd, interest, token)
+    }
+
+    /// Enable a one-shot registration again, see
+    /// [`ServerContext::rearm_fd`]
+    pub fn rearm_fd(&mut self, fd: RawFd) -> crate::Result<()> {
+        self.context.rearm_fd(fd)
+    }
+
+    /// Stop watching an fd, see [`ServerContext::deregister_fd`]
+    pub fn deregister_fd(&mut self, fd: RawFd) -> crate::Result<()> {
+        self.context.deregister_fd(fd)
+    }
+
+    /// Watch the read end of a child process's pipe, see
+    /// [`ServerContext::register_pipe`]
+    pub fn register_pipe(&mut self, pipe: impl Into<OwnedFd>, token: u64) -> crate::Result<()> {
+        self.context.register_pipe(pipe, token)
+    }
+
+    /// Watch the file or directory at `path` for changes, see
+    /// [`ServerContext::watch_path`]
+    pub fn watch_path(&mut self, path: impl AsRef<Path>) -> crate::Result<WatchId> {
+        self.context.watch_path(path)
+    }
+
+    /// Id of the client connected through `fd`
+    pub fn client_id_by_fd(&self, fd: RawFd) -> Option<ClientId> {
+        self.client_ids.get(&fd).copied()
+    }
+
+    /// Listen on another address, e.g. an admin port next to the public one
+    ///
+    /// Connections accepted on it are served by the same loop and handler
+    /// as the others, `ServerContext::listener_id` tells the handler which
+    /// listener a client came through. `listener_id` is picked by the
+    /// caller, any id but `PRIMARY_LISTENER` up to `MAX_CUSTOM_TOKEN`.
+    /// Pausing accepts and the connection limit apply to every listener;
+    /// an added listener that fails is closed, not rebound.
+    /// Returns the local address the listener is bound to.
+    pub fn add_listener<A: ToSocketAddrs>(
+        &mut self,
+        addr: A,
+        listener_id: ListenerId,
+    ) -> crate::Result<SocketAddr> {
+        let listener = sockopt::bind_listener(addr, self.config.listen_options())?;
+        let local_addr = listener.local_addr()?;
+        self.context.add_listener(listener, listener_id)?;
+        info!("Listener {} listening on {}", listener_id, local_addr);
+        Ok(local_addr)
+    }
+
+    /// Bind a UDP socket served by the same event loop
+    ///
+    /// Datagrams received on the socket are passed to `handler`, which can
+    /// reply to them and use the context to reach TCP clients.
+    /// Returns the local address the socket is bound to.
+    pub fn bind_udp<A, D>(&mut self, addr: A, handler: D) -> crate::Result<SocketAddr>
+    where
+        A: ToSocketAddrs,
+        D: DatagramHandler + Send + 'static,
+    {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        let local_addr = socket.local_addr()?;
+
+        let index = self.datagram_sockets.len() as u64;
+        let flags = EventFlags::READ | EventFlags::EDGE;
+        let epoll_event = Event::new(flags, PeerRole::Datagram(index));
+        self.context
+            .epoll()
+            .add_interest(socket.as_raw_fd(), epoll_event)?;
+
+        self.datagram_sockets
+            .push(DatagramSocket::new(socket, Box::new(handler)));
+        debug!("UDP socket bound on {}", local_addr);
+        Ok(local_addr)
+    }
+
     /// Run the server instance
     ///
-    /// Registers the listener's file descriptor to epoll insterest list
-    /// where we get notification for read events in Edge-Triggered manner.
-    /// Continously look for the events, and timeout if provided otherwise
-    /// uses `1000` as the default timeout
-    pub fn run(&mut self, timeout: Option<i32>) -> Result<()> {
-        info!("Server listening on {}", self.local_addr()?,);
-        // let event_bitmask: i32 = EventType::Epollin as i32 | EventType::Epolloneshot as i32;
-        let event_bitmask: i32 = EventType::Epollin as i32 | EventType::Epollet as i32;
-        let epoll_event = Event::new(event_bitmask as u32, PeerRole::Server);
-        self.epoll.add_interest(self.as_raw_fd(), epoll_event)?;
-
-        let mut notified_events = Vec::with_capacity(2048);
+    /// Continously look for the events, waiting at most `timeout`
+    /// milliseconds (negative blocks) if provided, otherwise uses
+    /// `ServerConfig::wait_timeout`, one second by default
+    pub fn run(&mut self, timeout: Option<i32>) -> crate::Result<()> {
+        match self.context.listener() {
+            Some(_) => info!("Server listening on {}", self.local_addr()?),
+            None => info!("Worker loop started"),
+        }
+
+        let timeout = self.loop_timeout(timeout);
+        self.notify_systemd("READY=1");
         while !self.shutdown_signal.load(Ordering::Relaxed) {
-            notified_events.clear();
-            self.epoll.wait(&mut notified_events, timeout)?;
+            self.step(timeout)?;
+        }
+
+        self.notify_systemd("STOPPING=1");
+        self.finish();
+        Ok(())
+    }
+
+    /// Run a single iteration of the event loop
+    ///
+    /// Waits for events as `run` does, at most `timeout` milliseconds
+    /// (negative blocks, `Some(0)` only polls) or `ServerConfig::wait_timeout`,
+    /// dispatches them and runs the periodic work due, then returns the
+    /// number of events handled. This lets the loop be driven from an
+    /// application's own main loop, interleaved with other work.
+    ///
+    /// A loop stepped this way ends when `is_shutting_down` turns true, and
+    /// should then call `finish`.
+    pub fn run_once(&mut self, timeout: Option<i32>) -> crate::Result<usize> {
+        let timeout = self.loop_timeout(timeout);
+        Ok(self.step(timeout)?)
+    }
+
+    /// Whether a shutdown was requested, through a `ServerHandle` or the
+    /// shutdown signal
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown_signal.load(Ordering::Relaxed)
+    }
+
+    /// Call `EventHandler::on_shutdown`, with every client still connected
+    ///
+    /// `run` does this before returning; a loop stepped with `run_once`
+    /// calls it once it is done.
+    pub fn finish(&mut self) {
+        self.handler.on_shutdown(&mut self.context);
+    }
+
+    /// Report `state` to systemd if `ServerConfig::systemd_notify` is set
+    fn notify_systemd(&self, state: &str) {
+        if self.config.notifies_systemd()
+            && let Err(e) = systemd::notify(state)
+        {
+            warn!("Failed to notify systemd of {}: {}", state, e);
+        }
+    }
+
+    /// Wait timeout of the loop, from the `timeout` given to `run`
+    fn loop_timeout(&self, timeout: Option<i32>) -> Option<Duration> {
+        match timeout {
+            Some(millis) => u64::try_from(millis).ok().map(Duration::from_millis),
+            None => self.config.wait_duration(),
+        }
+    }
 
-            if !notified_events.is_empty() {
-                self.handle_events(&notified_events)?;
+    /// One iteration of the loop: wait, dispatch, periodic work
+    ///
+    /// Returns the number of events notified.
+    fn step(&mut self, timeout: Option<Duration>) -> Result<usize> {
+        // Moved out while the events are handled, which needs `self`, and
+        // put back whatever happens so its allocation is kept
+        let mut notified_events = std::mem::take(&mut self.events);
+        let result = self.step_with(&mut notified_events, timeout);
+        self.events = notified_events;
+        result
+    }
+
+    fn step_with(
+        &mut self,
+        notified_events: &mut Vec<Event>,
+        timeout: Option<Duration>,
+    ) -> Result<usize> {
+        let _iteration = trace::iteration(self.metrics.waits);
+        self.retry_rebind();
+        self.end_accept_backoff(false);
+        self.resume_rate_limited_clients()?;
+
+        // The one point per tick where client registrations change
+        self.context.apply_interest_updates()?;
+
+        notified_events.clear();
+        let wait_timeout = self.wait_timeout(timeout);
+        let sigmask = self.config.wait_sigmask();
+        match self
+            .context
+            .epoll()
+            .wait(notified_events, wait_timeout, sigmask)
+        {
+            // A signal, e.g. one let through by the wait mask, the loop
+            // goes on with no events so the shutdown signal is checked
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) if self.config.is_transient_wait_error(&e) => {
+                warn!("Waiting for events failed, retrying: {}", e);
             }
+            result => result?,
         }
-        Ok(())
+        self.context.refresh_now();
+        self.metrics.record_wait(notified_events.len());
+
+        if !notified_events.is_empty() {
+            self.handle_events(notified_events)?;
+        }
+        self.tick();
+        self.report_metrics();
+        self.sweep_clients()?;
+        self.check_drained();
+        self.commands.set_connected(self.context.clients().len());
+        Ok(notified_events.len())
     }
 
     /// Handle notified events from epoll
@@ -89,69 +529,123 @@ impl<H: EventHandler> EpollServer<H> {
     fn handle_events(&mut self, events: &[Event]) -> Result<()> {
         for event in events {
             match event.role() {
-                PeerRole::Server => loop {
-                    match self.accept_new_client() {
-                        Ok(()) => continue,
-                        Err(e) if e.kind() == ErrorKind::WouldBlock => {
-                            debug!("Drained all pending connections");
-                            break;
+                PeerRole::Server => {
+                    if event
+                        .flags()
+                        .intersects(EventFlags::ERROR | EventFlags::HANGUP)
+                    {
+                        self.handle_listener_error();
+                    } else {
+                        self.accept_pending_clients(PRIMARY_LISTENER);
+                        if self.context.trigger_mode() == TriggerMode::OneShot {
+                            self.context.update_listener_interests()?;
+                        }
+                    }
+                }
+                PeerRole::Listener(id) => {
+                    if event
+                        .flags()
+                        .intersects(EventFlags::ERROR | EventFlags::HANGUP)
+                    {
+                        self.handle_extra_listener_error(id);
+                    } else {
+                        self.accept_pending_clients(id);
+                        if self.context.trigger_mode() == TriggerMode::OneShot {
+                            self.context.update_listener_interests()?;
                         }
-                        Err(e) => {
-                            error!("Error accepting new client: {}", e);
+                    }
+                }
+                PeerRole::Datagram(index) => {
+                    if let Some(datagram_socket) = self.datagram_sockets.get_mut(index as usize)
+                        && let Err(e) = datagram_socket.handle_readable(&mut self.context)
+                    {
+                        error!(
+                            "Error receiving on UDP socket {:?}: {}",
+                            datagram_socket.socket().local_addr(),
+                            e
+                        );
+                    }
+                }
+                PeerRole::Waker => self.handle_commands()?,
+                PeerRole::Metrics(fd) => {
+                    let metrics = self.metrics();
+                    if let Some(endpoint) = &mut self.metrics_endpoint {
+                        endpoint.handle_event(fd as RawFd, self.context.epoll(), &metrics);
+                    }
+                }
+                PeerRole::Timer(fd) if self.context.is_defer_timer(fd as RawFd) => {
+                    if let Err(e) = self.context.run_deferred() {
+                        error!("Error handling deferred actions: {}", e);
+                        self.report_error(ServerError::Internal(e.into()), None);
+                    }
+                }
+                PeerRole::Timer(fd) => match self.context.expire_timer(fd as RawFd) {
+                    Ok(Some(timer_id)) => self.handler.on_timer(&mut self.context, timer_id),
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!("Error reading timer fd {}: {}", fd, e);
+                        self.report_error(ServerError::Internal(e.into()), None);
+                    }
+                },
+                PeerRole::Watcher => match self.context.take_file_events() {
+                    Ok(events) => {
+                        for (watch_id, file_event) in events {
+                            self.handler
+                                .on_file_event(&mut self.context, watch_id, file_event);
                         }
                     }
+                    Err(e) => {
+                        error!("Error reading file events: {}", e);
+                        self.report_error(ServerError::Internal(e.into()), None);
+                    }
+                },
+                PeerRole::Custom(token) => match self.context.pipe_fd(token) {
+                    Some(fd) => self.handle_pipe_event(token, fd, event.flags()),
+                    None => {
+                        self.handler
+                            .on_custom_event(&mut self.context, token, event.flags());
+                    }
                 },
                 PeerRole::Client(id) => {
-                    let event_type = event.event_type() as i32;
-                    let read_event = EventType::Epollin as i32;
-                    let write_event = EventType::Epollout as i32;
-                    if let Some(client) = self.clients.get_mut(&id) {
+                    let flags = event.flags();
+                    if let Some(client) = self.context.clients().get(&id) {
+                        let _span = client.span().enter();
                         let mut should_disconnect = false;
-                        let mut need_interest_update = false;
-
-                        if event_type & read_event == read_event {
-                            match Self::handle_read(client) {
-                                Ok(bytes_read) => match bytes_read {
-                                    0 => should_disconnect = true,
-                                    _ => {
-                                        if self.handler.is_data_complete(client.read_buf()) {
-                                            match self.handler.on_message(id, client.read_buf()) {
-                                                Ok(action) => {
-                                                    client.read_buf_mut().clear();
-                                                    self.handle_action(id, action)?;
-                                                }
-                                                Err(e) => {
-                                                    error!(
-                                                        "Handler `on_message` error for client {}: {}",
-                                                        id, e
-                                                    );
-                                                    should_disconnect = true;
-                                                }
-                                            }
-                                        }
-                                    }
-                                },
-                                Err(_) => should_disconnect = true,
-                            }
+                        // One-shot clients are disabled until re-armed
+                        let mut need_interest_update =
+                            self.context.trigger_mode() == TriggerMode::OneShot;
+
+                        if flags.contains(EventFlags::READ) {
+                            should_disconnect = self.handle_client_read(id)?;
                         }
 
-                        if event_type & write_event == write_event {
-                            if let Some(client) = self.clients.get_mut(&id) {
-                                match client.flush_writes() {
-                                    Ok(true) => {
-                                        // All data written, remove write interest
-                                        need_interest_update = true;
-                                    }
-                                    Ok(false) => {
-                                        // More data to write, keep write interest
-                                    }
-                                    Err(_) => should_disconnect = true,
+                        if flags.contains(EventFlags::WRITE) {
+                            match self.flush_client(id) {
+                                Ok(true) => {
+                                    // All data written, remove write interest
+                                    need_interest_update = true;
+                                }
+                                Ok(false) => {
+                                    // More data to write, keep write interest
+                                }
+                                Err(e) => {
+                                    debug!("Failed to write to client {}: {}", id, e);
+                                    should_disconnect |=
+                                        self.report_error(ServerError::ClientIo(e), Some(id));
                                 }
                             }
                         }
 
+                        // An injected `WouldBlock` left data or room in the
+                        // socket, that the next event must not wait for
+                        need_interest_update |= self
+                            .context
+                            .clients()
+                            .get(&id)
+                            .is_some_and(|client| client.chaos().rearm_pending());
+
                         if need_interest_update && !should_disconnect {
-                            self.update_client_interests(id)?;
+                            self.context.mark_interests_dirty(id);
                         }
 
                         if should_disconnect {
@@ -160,161 +654,1572 @@ impl<H: EventHandler> EpollServer<H> {
                     }
                 }
             }
+            self.process_pending_disconnects()?;
         }
         Ok(())
     }
 
-    fn handle_action(
-        &mut self,
-        originating_client_id: ClientId,
-        action: HandlerAction,
-    ) -> Result<()> {
-        match action {
-            HandlerAction::Reply(data) => {
-                if let Some(client) = self.clients.get_mut(&originating_client_id) {
-                    client.queue_write(data);
-                    self.update_client_interests(originating_client_id)?;
-                }
+    /// Write what the client has queued, telling the handler whenever the
+    /// queue drains
+    ///
+    /// Data queued from `on_write_complete` is written right away: the
+    /// socket is still writable, and an edge-triggered registration would
+    /// not report it again. A client closing after its writes is
+    /// disconnected instead, one half-closing has its write side shut
+    /// down. Returns `true` once the queue is empty.
+    fn flush_client(&mut self, id: ClientId) -> Result<bool> {
+        loop {
+            let Some(client) = self.context.clients_mut().get_mut(&id) else {
+                return Ok(true);
+            };
+            let written_before = client.written_bytes();
+            let flushed = client.flush_writes();
+            let written = client.written_bytes() - written_before;
+            self.metrics.bytes_written += written;
+            if written > 0 {
+                trace::written(written);
             }
-            HandlerAction::Broadcast(data) => {
-                // Send to all clients except the sender
-                let client_ids: Vec<u64> = self.clients.keys().copied().collect();
-                for client_id in client_ids {
-                    if client_id != originating_client_id {
-                        if let Some(client) = self.clients.get_mut(&client_id) {
-                            client.queue_write(data.clone());
-                            self.update_client_interests(client_id)?;
-                        }
+            let bytes_flushed = match flushed {
+                Ok(true) => match client.after_drain() {
+                    AfterDrain::Close => {
+                        self.report_traces(id);
+                        self.context.disconnect(id);
+                        return Ok(true);
+                    }
+                    AfterDrain::ShutdownWrite => {
+                        client.shutdown_write()?;
+                        client.take_drained_bytes()
                     }
+                    AfterDrain::Nothing => client.take_drained_bytes(),
+                },
+                other => {
+                    self.report_traces(id);
+                    return other;
                 }
+            };
+            self.report_traces(id);
+
+            self.handler
+                .on_write_complete(&mut self.context, id, bytes_flushed);
+            let refilled = self
+                .context
+                .clients()
+                .get(&id)
+                .is_some_and(|client| client.has_pending_writes());
+            if !refilled {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Let the handler decide what follows `error`, see
+    /// `EventHandler::on_error`
+    ///
+    /// Returns `true` if the client the error is about should be
+    /// disconnected
+    fn report_error(&mut self, error: ServerError, client_id: Option<ClientId>) -> bool {
+        match self.handler.on_error(&mut self.context, &error, client_id) {
+            ErrorDirective::Ignore => false,
+            ErrorDirective::Disconnect => match client_id {
+                Some(id) => self.disconnecting(id, DisconnectReason::Error),
+                None => false,
+            },
+            ErrorDirective::Stop => {
+                warn!("Stopping the server after an error: {}", error);
+                self.shutdown_signal.store(true, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Record why the client is about to be disconnected, returning `true`
+    /// for the caller to pass on
+    fn disconnecting(&mut self, id: ClientId, reason: DisconnectReason) -> bool {
+        if let Some(client) = self.context.clients_mut().get_mut(&id) {
+            client.note_disconnect_reason(reason);
+        }
+        true
+    }
+
+    /// Close the failed listener and start the rebind sequence
+    fn handle_listener_error(&mut self) {
+        let err = self
+            .context
+            .listener()
+            .and_then(|listener| listener.take_error().ok().flatten())
+            .unwrap_or_else(|| std::io::Error::other("error condition on listening socket"));
+        error!("Listener on {} failed: {}", self.context.listen_addr(), err);
+        self.report_error(ServerError::Listener(err), None);
+
+        self.context.close_listener();
+        self.rebind_state = Some(RebindState {
+            attempts: 0,
+            next_attempt: Instant::now(),
+        });
+    }
+
+    /// Close an added listener that failed, it is not rebound
+    fn handle_extra_listener_error(&mut self, id: ListenerId) {
+        let Some(listener) = self.context.close_extra_listener(id) else {
+            return;
+        };
+        let err = listener
+            .take_error()
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| std::io::Error::other("error condition on listening socket"));
+        error!(
+            "Listener {} on {:?} failed: {}",
+            id,
+            listener.local_addr(),
+            err
+        );
+        self.report_error(ServerError::Listener(err), None);
+    }
+
+    /// Attempt to rebind the listener if a rebind is due
+    fn retry_rebind(&mut self) {
+        let Some(state) = self.rebind_state else {
+            return;
+        };
+        if Instant::now() < state.next_attempt {
+            return;
+        }
+
+        let attempts = state.attempts + 1;
+        match self.context.rebind_listener() {
+            Ok(()) => {
+                info!(
+                    "Listener rebound on {} after {} attempt(s)",
+                    self.context.listen_addr(),
+                    attempts
+                );
+                self.rebind_state = None;
+            }
+            Err(e) if attempts >= self.rebind_policy.max_attempts => {
+                error!(
+                    "Giving up rebinding listener on {} after {} attempt(s): {}",
+                    self.context.listen_addr(),
+                    attempts,
+                    e
+                );
+                self.rebind_state = None;
+                self.report_error(ServerError::Rebind(e.into()), None);
+            }
+            Err(e) => {
+                debug!("Rebind attempt {} failed: {}", attempts, e);
+                self.rebind_state = Some(RebindState {
+                    attempts,
+                    next_attempt: Instant::now() + self.rebind_policy.retry_interval,
+                });
+            }
+        }
+    }
+
+    /// Wait no longer than the next rebind attempt, end of an accept
+    /// backoff, tick, metrics report,
+    /// timeout sweep, client resumption or drain deadline, if one is
+    /// pending
+    fn wait_timeout(&mut self, timeout: Option<Duration>) -> Option<Duration> {
+        let policy = &mut self.timeout_policy;
+        policy.clear();
+        policy.track_all(self.rebind_state.map(|state| state.next_attempt));
+        policy.track_all(self.accept_backoff);
+        policy.track_all(self.next_tick);
+        policy.track_all(self.next_metrics_report);
+        policy.track_all(self.next_sweep);
+        policy.track_all(self.rate_paused.values().map(|(resume_at, _)| *resume_at));
+        policy.track_all(self.drain_deadline);
+        policy.timeout(Instant::now(), timeout)
+    }
+
+    /// Stop accepting and wait for the clients to leave, until `deadline`
+    fn start_drain(&mut self, deadline: Instant) {
+        info!(
+            "Draining {} clients, for at most {:?}",
+            self.context.clients().len(),
+            deadline.saturating_duration_since(Instant::now())
+        );
+        if let Err(e) = self.context.pause_accepts() {
+            error!("Failed to stop accepting for the drain: {}", e);
+        }
+        self.drain_deadline = Some(deadline);
+    }
+
+    /// Stop the loop once a drain is over
+    fn check_drained(&mut self) {
+        let Some(deadline) = self.drain_deadline else {
+            return;
+        };
+        let remaining = self.context.clients().len();
+        if remaining == 0 {
+            info!("Drain complete, every client left");
+        } else if Instant::now() >= deadline {
+            warn!("Drain deadline reached with {} clients left", remaining);
+        } else {
+            return;
+        }
+        self.drain_deadline = None;
+        self.shutdown_signal.store(true, Ordering::Relaxed);
+    }
+
+    /// Call `on_tick` if a tick is due
+    fn tick(&mut self) {
+        let (Some(next_tick), Some(period)) = (self.next_tick, self.config.tick_period()) else {
+            return;
+        };
+        let now = Instant::now();
+        if now < next_tick {
+            return;
+        }
+
+        self.next_tick = Some(now + period);
+        self.handler.on_tick(&mut self.context);
+    }
+
+    /// Hand a metrics snapshot to the telemetry if a report is due
+    fn report_metrics(&mut self) {
+        let (Some(next_report), Some(period)) =
+            (self.next_metrics_report, self.config.metrics_period())
+        else {
+            return;
+        };
+        let now = Instant::now();
+        if now < next_report {
+            return;
+        }
+
+        self.next_metrics_report = Some(now + period);
+        let metrics = self.metrics();
+        match &mut self.telemetry {
+            Some(telemetry) => telemetry.on_metrics(&metrics),
+            None => info!("Server stats: {}", metrics),
+        }
+    }
+
+    /// Disconnect clients past the idle or message timeout, if a sweep
+    /// is due
+    ///
+    /// The next sweep is scheduled for when the first remaining client
+    /// would expire, so timeouts are enforced on time rather than up to a
+    /// sweep interval late.
+    fn sweep_clients(&mut self) -> Result<()> {
+        let Some(next_sweep) = self.next_sweep else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if now < next_sweep {
+            return Ok(());
+        }
+
+        let idle_timeout = self.config.client_idle_timeout();
+        let message_timeout = self.config.partial_message_timeout();
+        // Clients going idle or starting a message from now on expire a
+        // whole timeout later at the earliest
+        let mut deadlines = TimeoutPolicy::default();
+        deadlines.track(now + SWEEP_INTERVAL);
+        deadlines.track_all(idle_timeout.map(|timeout| now + timeout));
+        deadlines.track_all(message_timeout.map(|timeout| now + timeout));
+
+        let mut expired = Vec::new();
+        for (id, client) in self.context.clients().iter() {
+            if let Some(timeout) = idle_timeout {
+                let deadline = client.last_read_at() + timeout;
+                if now >= deadline {
+                    info!("Client {} idle for {:?}, disconnecting", id, timeout);
+                    expired.push(*id);
+                    continue;
+                }
+                deadlines.track(deadline);
+            }
+            if let Some(timeout) = message_timeout
+                && let Some(since) = client.partial_since()
+            {
+                let deadline = since + timeout;
+                if now >= deadline {
+                    info!(
+                        "Client {} did not complete its message within {:?}, disconnecting",
+                        id, timeout
+                    );
+                    expired.push(*id);
+                    continue;
+                }
+                deadlines.track(deadline);
+            }
+        }
+        for id in expired {
+            self.context.disconnect_for(id, DisconnectReason::Timeout);
+        }
+        self.next_sweep = deadlines
+            .earliest()
+            .map(|deadline| deadline.max(now + SWEEP_GRANULARITY));
+
+        self.accept_limiters
+            .retain(|_, limiter| !limiter.is_idle(now));
+        self.process_pending_disconnects()
+    }
+
+    /// Snapshot of the event loop counters
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            active: self.context.clients().len() as u64,
+            ..self.metrics
+        }
+    }
+
+    /// Address of the metrics endpoint, if `ServerConfig::metrics_addr`
+    /// enabled it
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_endpoint
+            .as_ref()
+            .and_then(|endpoint| endpoint.local_addr().ok())
+    }
+
+    /// Let `allocator` pick the ids of new clients instead of the server's
+    /// own ids
+    pub fn set_id_allocator<A: ClientIdAllocator + Send + 'static>(&mut self, allocator: A) {
+        self.id_allocator = Some(Box::new(allocator));
+    }
+
+    /// Append `middleware` to the chain run around the handler
+    ///
+    /// Inbound hooks run in the order the middlewares were added, outbound
+    /// data goes through them in reverse.
+    pub fn add_middleware<M: Middleware + Send + 'static>(&mut self, middleware: M) {
+        self.context.middlewares_mut().push(Box::new(middleware));
+    }
+
+    /// Only accept connections from peers `filter` allows
+    pub fn set_accept_filter<F: AcceptFilter + Send + 'static>(&mut self, filter: F) {
+        self.accept_filter = Some(Box::new(filter));
+    }
+
+    /// Install the receiver of the server's measurements
+    ///
+    /// Message traces also need `ServerConfig::trace_sampling`.
+    pub fn set_telemetry<T: Telemetry + Send + 'static>(&mut self, telemetry: T) {
+        self.telemetry = Some(Box::new(telemetry));
+    }
+
+    /// Install the receiver of the connections' lifecycle events
+    ///
+    /// A closure or a channel `Sender` will do, see [`AuditSink`].
+    pub fn set_audit_sink<S: AuditSink + Send + 'static>(&mut self, sink: S) {
+        self.context.set_audit_sink(Box::new(sink));
+    }
+
+    /// Swap the handler for `handler`, returning the previous one
+    ///
+    /// Connections, their state and timers are kept, the new handler is
+    /// called for everything from then on. From other threads while the
+    /// server runs, use `ServerHandle::replace_handler`; to swap handlers of
+    /// different types, create the server with a
+    /// `Box<dyn EventHandler + Send>`.
+    pub fn replace_handler(&mut self, handler: H) -> H {
+        std::mem::replace(&mut self.handler, handler)
+    }
+
+    /// Configure how the listener is rebound after it fails
+    pub fn set_rebind_policy(&mut self, policy: RebindPolicy) {
+        self.rebind_policy = policy;
+    }
+
+    /// Returns `true` if the server currently has a working listener
+    pub fn is_listening(&self) -> bool {
+        self.context.listener().is_some()
+    }
+
+    /// Read from the client and pass complete data to the handler
+    ///
+    /// Returns `true` if the client should be disconnected
+    fn handle_client_read(&mut self, id: ClientId) -> Result<bool> {
+        let now = self.context.now();
+        let Some(client) = self.context.clients_mut().get_mut(&id) else {
+            return Ok(false);
+        };
+        if self.config.streams_reads() && !client.has_codec() {
+            return self.handle_streaming_read(id, now);
+        }
+
+        let Some(outcome) = self.read_client(id, now) else {
+            return Ok(true);
+        };
+        let Some(client) = self.context.clients_mut().get_mut(&id) else {
+            return Ok(false);
+        };
+
+        // Whatever arrived before an EOF is handled before disconnecting
+        let should_disconnect = match Self::check_proxy_header(client, id) {
+            ControlFlow::Break(should_disconnect) => should_disconnect,
+            ControlFlow::Continue(()) => {
+                self.handle_buffered_messages(id)? || self.exceeds_message_limit(id, now)
+            }
+        };
+        if outcome == ReadOutcome::Eof {
+            return Ok(self.disconnecting(id, DisconnectReason::PeerClosed));
+        }
+        Ok(should_disconnect)
+    }
+
+    /// Drain the client's socket into its read buffer, stopping whenever
+    /// the buffer goes over `ServerConfig::max_read_buffer`
+    ///
+    /// Returns how the last read ended, or `None` if the client should be
+    /// disconnected right away
+    fn read_client(&mut self, id: ClientId, now: Instant) -> Option<ReadOutcome> {
+        let limit = self.config.read_buffer_limit();
+        loop {
+            let Some(client) = self.context.clients_mut().get_mut(&id) else {
+                return Some(ReadOutcome::WouldBlock);
+            };
+            let read_before = self.metrics.bytes_read;
+            let outcome = match Self::handle_read(
+                client,
+                self.config.read_slab_size(),
+                &mut self.metrics.bytes_read,
+                limit,
+            ) {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    debug!("Failed to read from client {}: {}", id, e);
+                    return match self.report_error(ServerError::ClientIo(e), Some(id)) {
+                        true => None,
+                        false => Some(ReadOutcome::WouldBlock),
+                    };
+                }
+            };
+            if self.metrics.bytes_read > read_before {
+                client.set_last_read_at(now);
+                trace::read(self.metrics.bytes_read - read_before);
+            }
+
+            if limit.is_none_or(|limit| client.read_buf().len() <= limit) {
+                return Some(outcome);
+            }
+            if self.handle_buffer_overflow(id) {
+                return None;
+            }
+            if !matches!(outcome, ReadOutcome::Data(_)) {
+                // Nothing is left to read after the discarded bytes
+                return Some(outcome);
+            }
+        }
+    }
+
+    /// Let the handler decide what happens to a client over
+    /// `ServerConfig::max_read_buffer`
+    ///
+    /// Returns `true` if the client should be disconnected
+    fn handle_buffer_overflow(&mut self, id: ClientId) -> bool {
+        if let Some(client) = self.context.clients().get(&id) {
+            warn!(
+                "Client {} has {} unprocessed bytes, over the read buffer limit",
+                id,
+                client.read_buf().len()
+            );
+        }
+
+        match self.handler.on_buffer_overflow(&mut self.context, id) {
+            OverflowAction::Disconnect => self.disconnecting(id, DisconnectReason::LimitExceeded),
+            OverflowAction::Discard => {
+                if let Some(client) = self.context.clients_mut().get_mut(&id) {
+                    client.read_buf_mut().clear();
+                }
+                false
+            }
+        }
+    }
+
+    /// Strip the PROXY header the client's data starts with, if expected
+    ///
+    /// Breaks with `true` if the client should be disconnected, or `false`
+    /// if there is nothing to process yet.
+    fn check_proxy_header(client: &mut ClientState, id: ClientId) -> ControlFlow<bool> {
+        if client.awaiting_proxy_header() {
+            match client.decode_proxy_header() {
+                Ok(true) => {}
+                Ok(false) => return ControlFlow::Break(false),
+                Err(e) => {
+                    warn!("Invalid PROXY header from client {}: {}", id, e);
+                    client.note_disconnect_reason(DisconnectReason::Error);
+                    return ControlFlow::Break(true);
+                }
+            }
+        }
+        if client.read_buf().is_empty() {
+            // Only the PROXY header arrived so far
+            return ControlFlow::Break(false);
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Read from a streamed client, handing every chunk to `on_data_chunk`
+    /// before reading the next one
+    ///
+    /// Returns `true` if the client should be disconnected
+    fn handle_streaming_read(&mut self, id: ClientId, now: Instant) -> Result<bool> {
+        let mut buffer = self.buffer_pool.checkout();
+        let result = self.stream_chunks(id, &mut buffer, now);
+        self.buffer_pool.checkin(buffer);
+        Ok(result? || self.exceeds_message_limit(id, now))
+    }
+
+    fn stream_chunks(&mut self, id: ClientId, buffer: &mut [u8], now: Instant) -> Result<bool> {
+        loop {
+            let Some(client) = self.context.clients_mut().get_mut(&id) else {
+                return Ok(false);
+            };
+            if client.reads_paused() {
+                // Reading resumes with the client
+                return Ok(false);
             }
-            HandlerAction::SendTo {
-                target_client_id,
-                data,
-            } => {
-                if let Some(client) = self.clients.get_mut(&(target_client_id as u64)) {
-                    client.queue_write(data);
-                    self.update_client_interests(target_client_id as u64)?;
+
+            match client.recv(buffer) {
+                Ok(0) => return Ok(self.disconnecting(id, DisconnectReason::PeerClosed)),
+                Ok(n) => {
+                    client.read_buf_mut().extend_from_slice(&buffer[..n]);
+                    client.set_last_read_at(now);
+                    self.metrics.bytes_read += n as u64;
                 }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return Ok(self.disconnecting(id, DisconnectReason::Error)),
+            }
+
+            if self.consume_chunks(id)? {
+                return Ok(true);
+            }
+
+            let Some(client) = self.context.clients().get(&id) else {
+                return Ok(false);
+            };
+            let limit = self.config.read_buffer_limit();
+            if limit.is_some_and(|limit| client.read_buf().len() > limit)
+                && self.handle_buffer_overflow(id)
+            {
+                return Ok(true);
             }
-            HandlerAction::SendToAll(data) => {
-                // Send to all clients including sender
-                let client_ids: Vec<u64> = self.clients.keys().copied().collect();
-                for client_id in client_ids {
-                    if let Some(client) = self.clients.get_mut(&client_id) {
-                        client.queue_write(data.clone());
-                        self.update_client_interests(client_id)?;
+        }
+    }
+
+    /// Pass the client's unprocessed data to `on_data_chunk` until it
+    /// stops consuming
+    ///
+    /// Returns `true` if the client should be disconnected
+    fn consume_chunks(&mut self, id: ClientId) -> Result<bool> {
+        loop {
+            let Some(client) = self.context.clients_mut().get_mut(&id) else {
+                return Ok(false);
+            };
+            if let ControlFlow::Break(should_disconnect) = Self::check_proxy_header(client, id) {
+                return Ok(should_disconnect);
+            }
+
+            // Take the buffer out so the handler can borrow the context
+            let mut data = std::mem::take(client.read_buf_mut());
+            let result = self
+                .handler
+                .on_data_chunk(&mut self.context, id, data.make_contiguous());
+            let consumed = match result {
+                Ok(ConsumeResult::Consumed(consumed)) => consumed.min(data.len()),
+                Ok(ConsumeResult::Close) => {
+                    return Ok(self.disconnecting(id, DisconnectReason::Requested));
+                }
+                Err(e) => {
+                    self.metrics.handler_errors += 1;
+                    error!("Handler `on_data_chunk` error for client {}: {}", id, e);
+                    let error = ServerError::Handler {
+                        callback: "on_data_chunk",
+                        error: e,
+                    };
+                    if self.report_error(error, Some(id)) {
+                        return Ok(true);
                     }
+                    // Nothing consumed, the data is offered again after the
+                    // next read
+                    0
                 }
+            };
+
+            data.consume(consumed);
+            let Some(client) = self.context.clients_mut().get_mut(&id) else {
+                return Ok(false);
+            };
+            *client.read_buf_mut() = data;
+            if consumed == 0 {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Hand what the client's read buffer holds to the handler, through
+    /// its codec if it has one
+    ///
+    /// Returns `true` if the client should be disconnected
+    fn handle_buffered_messages(&mut self, id: ClientId) -> Result<bool> {
+        let Some(client) = self.context.clients().get(&id) else {
+            return Ok(false);
+        };
+        if client.has_codec() {
+            self.handle_client_frames(id)
+        } else {
+            self.handle_raw_message(id)
+        }
+    }
+
+    /// Pass the read buffer to the handler once it holds a complete message
+    ///
+    /// Returns `true` if the client should be disconnected
+    fn handle_raw_message(&mut self, id: ClientId) -> Result<bool> {
+        // Whatever follows a held back message waits for it
+        if self.rate_paused.contains_key(&id) {
+            return Ok(false);
+        }
+        let Some(client) = self.context.clients_mut().get_mut(&id) else {
+            return Ok(false);
+        };
+
+        if !self
+            .handler
+            .is_data_complete(client.read_buf_mut().make_contiguous())
+        {
+            return Ok(false);
+        }
+
+        // The buffer is handed over as the message, its capacity is reused
+        // when the handler did not keep the message around
+        let data = Bytes::from(client.read_buf_mut().take_vec());
+        let should_disconnect = self.deliver_message(id, data.clone())?;
+
+        if let Some(mut data) = data.try_into_vec()
+            && let Some(client) = self.context.clients_mut().get_mut(&id)
+        {
+            data.clear();
+            *client.read_buf_mut() = ReadBuf::from(data);
+        }
+        Ok(should_disconnect)
+    }
+
+    /// Returns `true` if the incomplete message left in the client's read
+    /// buffer is over `ServerConfig::max_message_size`
+    fn exceeds_message_limit(&mut self, id: ClientId, now: Instant) -> bool {
+        let Some(client) = self.context.clients_mut().get_mut(&id) else {
+            return false;
+        };
+        client.track_partial_message(now);
+
+        match self.config.message_size_limit() {
+            Some(limit) if client.read_buf().len() > limit => {
+                warn!(
+                    "Client {} sent {} bytes without completing a message, over the limit of {}",
+                    id,
+                    client.read_buf().len(),
+                    limit
+                );
+                client.note_disconnect_reason(DisconnectReason::LimitExceeded);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Pass a complete message through the middlewares to the handler
+    ///
+    /// Returns `true` if the client should be disconnected
+    fn deliver_message(&mut self, id: ClientId, data: Bytes) -> Result<bool> {
+        if self.context.middlewares_mut().is_empty() {
+            return self.dispatch_message(id, data);
+        }
+
+        match self.context.middlewares_mut().inbound(id, data.to_vec()) {
+            Ok(Some(data)) => self.dispatch_message(id, data.into()),
+            Ok(None) => Ok(false),
+            Err(e) => {
+                warn!("Middleware refused data from client {}: {}", id, e);
+                Ok(self.disconnecting(id, DisconnectReason::Requested))
+            }
+        }
+    }
+
+    /// Pass a complete message to the handler and act on its answer
+    ///
+    /// Returns `true` if the client should be disconnected
+    fn dispatch_message(&mut self, id: ClientId, data: Bytes) -> Result<bool> {
+        let delay = self.rate_limit_delay(id, data.len());
+        if !delay.is_zero() {
+            return self.handle_rate_limited(id, delay, data);
+        }
+
+        let started = self.sample_message().then(Instant::now);
+        let result = self.handler.on_message(&mut self.context, id, data);
+        let handler_duration = started.map(|started| started.elapsed());
+
+        let (should_disconnect, outcome) = match result {
+            Ok(action) => {
+                trace::action(&action);
+                let name = action.name();
+                self.context.handle_action(id, action)?;
+                (false, name)
+            }
+            Err(e) => {
+                self.metrics.handler_errors += 1;
+                (self.handle_message_error(id, e)?, "error")
+            }
+        };
+        self.context.middlewares_mut().handled(id, outcome);
+
+        let received_at = self.context.now();
+        if let Some(handler_duration) = handler_duration
+            && let Some(client) = self.context.clients_mut().get_mut(&id)
+        {
+            client.track_trace(MessageTrace {
+                client_id: id,
+                received_at,
+                handler_duration,
+                queue_time: None,
+                flushed_at: None,
+            });
+            self.report_traces(id);
+        }
+        Ok(should_disconnect)
+    }
+
+    /// How long until the client's and its IP's rate limits admit a message
+    /// of `len` bytes, the message is counted if it is admitted right away
+    fn rate_limit_delay(&mut self, id: ClientId, len: usize) -> Duration {
+        let now = self.context.now();
+        let Some(client) = self.context.clients_mut().get_mut(&id) else {
+            return Duration::ZERO;
+        };
+        let ip = client.peer_addr().ip();
+        let mut limiters = [
+            client.rate_limiter_mut(),
+            self.ip_limiters.get_mut(&ip).map(|(_, limiter)| limiter),
+        ];
+
+        let delay = limiters
+            .iter_mut()
+            .flatten()
+            .map(|limiter| limiter.time_until(len, now))
+            .max()
+            .unwrap_or_default();
+        if delay.is_zero() {
+            for limiter in limiters.into_iter().flatten() {
+                limiter.consume(len);
+            }
+        }
+        delay
+    }
+
+    /// Apply the rate limit action to a client whose message `data` was
+    /// over its limits, `delay` is how long until it would be admitted
+    ///
+    /// Returns `true` if the client should be disconnected
+    fn handle_rate_limited(&mut self, id: ClientId, delay: Duration, data: Bytes) -> Result<bool> {
+        debug!("Client {} exceeded its rate limit", id);
+        if let Some(client) = self.context.clients().get(&id) {
+            let peer_addr = client.peer_addr();
+            self.context.audit(AuditEvent::RateLimited {
+                client_id: Some(id),
+                peer_addr,
+            });
+        }
+        self.handler.on_rate_limited(&mut self.context, id);
+
+        match self.config.rate_action() {
+            RateLimitAction::Drop => Ok(false),
+            RateLimitAction::PauseReads => {
+                if self.context.pause_client(id)? {
+                    let resume_at = self.context.now() + delay;
+                    self.rate_paused.insert(id, (resume_at, data));
+                }
+                Ok(false)
+            }
+            RateLimitAction::Disconnect => {
+                Ok(self.disconnecting(id, DisconnectReason::LimitExceeded))
+            }
+        }
+    }
+
+    /// Resume reading from rate limited clients whose pause is over,
+    /// delivering the message they were held back on and what their read
+    /// buffer gathered behind it
+    fn resume_rate_limited_clients(&mut self) -> Result<()> {
+        if self.rate_paused.is_empty() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let due: Vec<ClientId> = self
+            .rate_paused
+            .iter()
+            .filter(|(_, (resume_at, _))| *resume_at <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in due {
+            let Some((_, data)) = self.rate_paused.remove(&id) else {
+                continue;
+            };
+            self.context.resume_client(id)?;
+            // Held back again if a limit shared by the IP is still spent
+            let mut should_disconnect = self.dispatch_message(id, data)?;
+            let buffered = self
+                .context
+                .clients()
+                .get(&id)
+                .is_some_and(|client| !client.read_buf().is_empty());
+            if !should_disconnect && buffered {
+                should_disconnect = self.handle_buffered_messages(id)?;
+            }
+            if should_disconnect {
+                self.context.disconnect(id);
             }
-            HandlerAction::None => (),
         }
         Ok(())
     }
 
-    fn update_client_interests(&mut self, client_id: ClientId) -> Result<()> {
-        if let Some(client) = self.clients.get_mut(&client_id) {
-            let fd = client.as_raw_fd();
+    /// Apply the error policy to a failed `on_message`
+    ///
+    /// Returns `true` if the client should be disconnected
+    fn handle_message_error(&mut self, id: ClientId, error: std::io::Error) -> Result<bool> {
+        let (policy, reply) = match error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<HandlerError>())
+        {
+            Some(handler_error) => (handler_error.policy(), handler_error.reply()),
+            None => (self.config.error_policy(), error.to_string().into_bytes()),
+        };
+
+        match policy {
+            ErrorPolicy::Disconnect => {
+                error!("Handler `on_message` error for client {}: {}", id, error);
+                return Ok(self.disconnecting(id, DisconnectReason::Error));
+            }
+            ErrorPolicy::Reply => {
+                debug!("Replying to handler error for client {}: {}", id, error);
+                self.context.send_to(id, reply)?;
+            }
+            ErrorPolicy::Ignore => {
+                debug!("Ignoring handler error for client {}: {}", id, error);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns `true` if the next message should be traced
+    fn sample_message(&mut self) -> bool {
+        let one_in = self.config.trace_sampling_rate();
+        if self.telemetry.is_none() || one_in == 0 {
+            return false;
+        }
+        self.message_count += 1;
+        self.message_count.is_multiple_of(one_in as u64)
+    }
 
-            let mut new_interests = EventType::Epollin as i32 | EventType::Epollet as i32;
+    /// Hand the completed traces of the client to the telemetry
+    fn report_traces(&mut self, id: ClientId) {
+        let Some(telemetry) = &mut self.telemetry else {
+            return;
+        };
+        let Some(client) = self.context.clients_mut().get_mut(&id) else {
+            return;
+        };
+        for trace in client.take_completed_traces() {
+            telemetry.on_message_trace(&trace);
+        }
+    }
 
-            if client.has_pending_writes() {
-                new_interests |= EventType::Epollout as i32;
+    /// Decode frames from the client's read buffer and dispatch them
+    ///
+    /// Returns `true` if the client should be disconnected
+    fn handle_client_frames(&mut self, id: ClientId) -> Result<bool> {
+        loop {
+            // Frames after a held back message wait for it
+            if self.rate_paused.contains_key(&id) {
+                return Ok(false);
             }
+            let Some(client) = self.context.clients_mut().get_mut(&id) else {
+                return Ok(false);
+            };
+
+            let frame = match client.decode_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(false),
+                Err(e) => {
+                    error!("Failed to decode data from client {}: {}", id, e);
+                    return Ok(self.report_error(ServerError::Decode(e), Some(id)));
+                }
+            };
 
-            let new_interests = new_interests as u32;
-            if client.current_interests() != new_interests {
-                let epoll_event = Event::new(new_interests, PeerRole::Client(client_id));
-                self.epoll.modify_interest(fd, epoll_event)?;
-                client.set_current_interests(new_interests);
+            match frame {
+                Frame::Message(data) => {
+                    if self.deliver_message(id, data.into())? {
+                        return Ok(true);
+                    }
+                }
+                Frame::Control(data) => {
+                    client.queue_write(data.into());
+                    self.context.mark_interests_dirty(id);
+                }
+                Frame::Consumed => {}
+                Frame::Close => return Ok(self.disconnecting(id, DisconnectReason::PeerClosed)),
+            }
+            if self.check_handshake(id) {
+                return Ok(true);
             }
+            if self.check_codec_close(id) {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Close the client once its replies are written if its codec expects
+    /// no more messages
+    ///
+    /// Returns `true` if so, nothing more is to be decoded for it
+    fn check_codec_close(&mut self, id: ClientId) -> bool {
+        let wants_close = self
+            .context
+            .clients()
+            .get(&id)
+            .is_some_and(|client| client.codec_wants_close());
+        if wants_close {
+            self.context.close_after_write(id);
+        }
+        wants_close
+    }
+
+    /// Report the end of the client's handshake the first time its codec
+    /// is found done with it, and let the handler authorize the client
+    ///
+    /// Returns `true` if the client should be disconnected
+    fn check_handshake(&mut self, id: ClientId) -> bool {
+        let Some(client) = self.context.clients_mut().get_mut(&id) else {
+            return false;
+        };
+        if !client.finished_handshake() {
+            return false;
+        }
+        let peer_addr = client.peer_addr();
+        let peer_identity = client.peer_identity();
+        self.context.audit(AuditEvent::TlsHandshakeDone {
+            client_id: id,
+            peer_addr,
+        });
+
+        let Some(peer_identity) = peer_identity else {
+            return false;
+        };
+        if let Err(e) = self
+            .handler
+            .on_tls_handshake(&mut self.context, id, &peer_identity)
+        {
+            self.metrics.handler_errors += 1;
+            error!("Handler `on_tls_handshake` error for client {}: {}", id, e);
+            let error = ServerError::Handler {
+                callback: "on_tls_handshake",
+                error: e,
+            };
+            return self.report_error(error, Some(id));
+        }
+        // Nothing more is decoded for a client the handler turned away
+        self.context.is_disconnecting(id)
+    }
+
+    /// Stop accepting new connections
+    ///
+    /// See [`ServerContext::pause_accepts`]
+    pub fn pause_accepts(&mut self) -> crate::Result<()> {
+        self.context.pause_accepts()
+    }
+
+    /// Start accepting new connections again
+    ///
+    /// See [`ServerContext::resume_accepts`]
+    pub fn resume_accepts(&mut self) -> crate::Result<()> {
+        self.context.resume_accepts()
+    }
+
+    /// Returns `true` if accepting new connections is paused
+    pub fn accepts_paused(&self) -> bool {
+        self.context.accepts_paused()
+    }
+
+    /// See [`ServerContext::accept_stats`]
+    pub fn accept_stats(&self) -> AcceptStats {
+        self.context.accept_stats()
+    }
+
+    /// See [`ServerContext::connected_clients`]
+    pub fn connected_clients(&self) -> Vec<ClientId> {
+        self.context.connected_clients()
+    }
+
+    /// Stop reading from the client
+    ///
+    /// See [`ServerContext::pause_client`]
+    pub fn pause_client(&mut self, client_id: ClientId) -> crate::Result<bool> {
+        self.context.pause_client(client_id)
+    }
+
+    /// Start reading from the client again
+    ///
+    /// See [`ServerContext::resume_client`]
+    pub fn resume_client(&mut self, client_id: ClientId) -> crate::Result<bool> {
+        self.context.resume_client(client_id)
+    }
+
+    /// Run the commands queued through `ServerHandle`s
+    fn handle_commands(&mut self) -> Result<()> {
+        if let Err(e) = self.commands.waker().reset() {
+            error!("Failed to reset the waker: {}", e);
         }
 
+        while let Some(command) = self.commands.try_next() {
+            match command {
+                Command::SendTo(client_id, data) => {
+                    if !self.context.send_to(client_id, data)? {
+                        debug!("Dropped command for unknown client {}", client_id);
+                    }
+                }
+                Command::Broadcast(data, emitted_at) => {
+                    self.context.broadcast_emitted_at(data, emitted_at)?
+                }
+                Command::Adopt(socket) => {
+                    self.adopt_client(socket);
+                    self.commands.finish_adoption(self.context.clients().len());
+                }
+                Command::Drain(deadline) => self.start_drain(deadline),
+                Command::ReplaceHandler(handler) => match handler.downcast::<H>() {
+                    Ok(handler) => {
+                        self.replace_handler(*handler);
+                        info!("Handler replaced");
+                    }
+                    Err(_) => error!("Dropped a replacement handler not of the server's type"),
+                },
+            }
+        }
         Ok(())
     }
 
-    /// Accept tcp connection from clients
+    /// Accept the connections waiting in the backlog, up to
+    /// `ServerConfig::max_accepts_per_wakeup`, recording how many were
+    /// accepted in this wakeup
+    fn accept_pending_clients(&mut self, listener_id: ListenerId) {
+        let backlog = self
+            .context
+            .listener_by_id(listener_id)
+            .and_then(|listener| stats::listener_backlog(listener).ok());
+
+        let batch_limit = self.config.accept_batch_limit();
+        let mut accepted = 0;
+        let mut attempts = 0;
+        loop {
+            if batch_limit.is_some_and(|limit| attempts >= limit) {
+                self.requeue_accepts();
+                break;
+            }
+            attempts += 1;
+            match self.accept_new_client(listener_id) {
+                Ok(true) => accepted += 1,
+                Ok(false) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    debug!("Drained all pending connections");
+                    break;
+                }
+                Err(e) => {
+                    let error = AcceptError::from(e);
+                    self.context.accept_stats_mut().record_error();
+                    let pauses = error.pauses_accepts();
+                    if pauses {
+                        self.start_accept_backoff(listener_id, &error);
+                    } else {
+                        debug!("Error accepting new client: {}", error);
+                    }
+                    self.handler.on_accept_error(&mut self.context, &error);
+                    if pauses {
+                        break;
+                    }
+                }
+            }
+        }
+        self.context
+            .accept_stats_mut()
+            .record_wakeup(accepted, backlog);
+    }
+
+    /// Stop accepting for `ServerConfig::accept_error_backoff` after
+    /// `error`, shedding the waiting connections first if out of file
+    /// descriptors with one in reserve
+    fn start_accept_backoff(&mut self, listener_id: ListenerId, error: &AcceptError) {
+        if let AcceptError::FdExhausted(_) = error
+            && let Some(reserved_fd) = &mut self.reserved_fd
+            && let Some(listener) = self.context.listener_by_id(listener_id)
+        {
+            let shed = reserved_fd.shed(listener);
+            warn!(
+                "Out of file descriptors, closed {} waiting connection(s)",
+                shed
+            );
+            self.context.accept_stats_mut().record_shed(shed);
+        }
+
+        let backoff = self.config.accept_backoff();
+        warn!(
+            "Error accepting new client, pausing for {:?}: {}",
+            backoff, error
+        );
+        self.accept_backoff = Some(Instant::now() + backoff);
+        if let Err(e) = self.context.set_accept_backoff(true) {
+            error!("Failed to pause accepting: {}", e);
+        }
+    }
+
+    /// Accept again once the backoff is over, or right away if `early`,
+    /// e.g. as a client left and freed its file descriptor
+    fn end_accept_backoff(&mut self, early: bool) {
+        let Some(until) = self.accept_backoff else {
+            return;
+        };
+        if !early && Instant::now() < until {
+            return;
+        }
+        debug!("Resuming accepting after an accept error");
+        self.accept_backoff = None;
+        if let Err(e) = self.context.set_accept_backoff(false) {
+            error!("Failed to resume accepting: {}", e);
+        }
+    }
+
+    /// Have the listener reported again on the next wait, so connections
+    /// left in the backlog are accepted after the other ready clients
     ///
-    /// Add interest for read events to epoll interest list
-    /// Uses the fd as the id for client while storing in map
-    fn accept_new_client(&mut self) -> Result<()> {
-        let (socket, addr) = self.listener.accept()?;
+    /// Modifying the registration makes epoll check the listener's
+    /// readiness again, which also brings a new edge-triggered event.
+    fn requeue_accepts(&mut self) {
+        debug!("Accept batch limit reached, deferring the rest of the backlog");
+        self.context.accept_stats_mut().record_batch_limited();
+        if let Err(e) = self.context.update_listener_interests() {
+            error!("Failed to requeue the listener: {}", e);
+        }
+    }
 
-        socket.set_nonblocking(true)?;
+    /// Accept the next connection
+    ///
+    /// Returns `false` if the accept filter, the IP's accept rate or a
+    /// middleware refused the peer
+    fn accept_new_client(&mut self, listener_id: ListenerId) -> Result<bool> {
+        let Some(listener) = self.context.listener_by_id(listener_id) else {
+            return Err(ErrorKind::WouldBlock.into());
+        };
+        let (socket, addr) = sockopt::accept(listener)?;
+        self.admit_client(socket, addr, listener_id)
+    }
+
+    /// Let the handler read from a registered pipe, and close it once the
+    /// writer is gone and nothing is left to read
+    fn handle_pipe_event(&mut self, token: u64, fd: RawFd, flags: EventFlags) {
+        self.handler.on_fd_readable(&mut self.context, token, fd);
+        if !flags.contains(EventFlags::READ)
+            && flags.intersects(EventFlags::HANGUP | EventFlags::ERROR)
+        {
+            self.context.close_pipe(token);
+        }
+    }
+
+    /// Serve a connection an `Acceptor` or `ServerHandle::add_client` handed
+    /// over
+    fn adopt_client(&mut self, socket: TcpStream) {
+        let admitted = socket
+            .peer_addr()
+            .and_then(|addr| self.admit_client(socket, addr, PRIMARY_LISTENER));
+        match admitted {
+            Ok(_) => {}
+            // Rejected by the connection limit
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => error!("Error adopting client: {}", e),
+        }
+    }
+
+    /// Run the checks on a new connection and register it
+    ///
+    /// Returns `false` if the accept filter, the IP's accept rate or a
+    /// middleware refused the peer, or setting the connection up failed,
+    /// and a `WouldBlock` error if the server is full
+    fn admit_client(
+        &mut self,
+        socket: TcpStream,
+        addr: SocketAddr,
+        listener_id: ListenerId,
+    ) -> Result<bool> {
+        if let Some(filter) = &mut self.accept_filter
+            && !filter.allow(addr)
+        {
+            debug!("Accept filter refused {}, closing connection", addr);
+            self.context.accept_stats_mut().record_filtered();
+            return Ok(false);
+        }
+
+        if !self.admit_connection_rate(addr.ip()) {
+            debug!("{} connects too often, closing connection", addr);
+            self.context.accept_stats_mut().record_rate_limited();
+            self.context.audit(AuditEvent::RateLimited {
+                client_id: None,
+                peer_addr: addr,
+            });
+            return Ok(false);
+        }
+
+        if let Some(limit) = self.config.connection_limit()
+            && self.context.clients().len() >= limit
+        {
+            // Stop accepting, the remaining connections wait in the backlog
+            // until a client disconnects
+            debug!("Connection limit of {} reached, rejecting {}", limit, addr);
+            self.context.set_at_capacity(true)?;
+            self.context.accept_stats_mut().record_rejection();
+            self.handler
+                .on_connection_rejected(&mut self.context, addr, &socket);
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        // Failures from here on are the connection's own: it is closed and
+        // accepting goes on
         let socket_fd = socket.as_raw_fd();
-        // use the file descriptor as the id for the client
-        // this is safe because fd is unique and we remove client
-        // from clients immediately, if we ever received disconnection
-        let identifier = socket_fd as u64;
+        if let Err(e) = self.configure_socket(socket_fd) {
+            return Ok(self.setup_failed(addr, None, e));
+        }
+        let identifier = match self.allocate_client_id(addr) {
+            Ok(identifier) => identifier,
+            Err(e) => return Ok(self.setup_failed(addr, None, e)),
+        };
+        if !self.context.middlewares_mut().accept(identifier, addr) {
+            debug!("Middleware refused {}, closing connection", addr);
+            self.context.accept_stats_mut().record_filtered();
+            self.release_client_id(identifier);
+            return Ok(false);
+        }
 
-        if let Err(e) = self.handler.on_connection(identifier, &socket) {
+        let flags = EventFlags::READ | self.context.trigger_mode().flags();
+        let epoll_event = Event::new(flags, PeerRole::Client(identifier));
+        if let Err(e) = self.context.epoll().add_interest(socket_fd, epoll_event) {
+            return Ok(self.setup_failed(addr, Some(identifier), e));
+        }
+
+        // The client is stored before the handler sees it,
+        // so the handler can already queue writes to it
+        let mut new_client = ClientState::new(
+            socket,
+            addr,
+            self.config.client_read_capacity(),
+            self.config.client_write_queue_capacity(),
+        );
+        new_client.set_current_interests(flags);
+        new_client.set_listener_id(listener_id);
+        new_client.set_coalesce_limit(self.config.write_coalesce_limit());
+        new_client.set_span(ConnectionSpan::new(identifier, addr));
+        new_client.set_chaos(Chaos::new(&self.config, identifier));
+        let span = new_client.span().enter();
+        new_client.set_awaiting_proxy_header(self.config.expects_proxy_header());
+        new_client.set_codec(self.config.codec_factory().map(|factory| factory()));
+        let now = self.context.now();
+        new_client.set_rate_limiter(
+            self.config
+                .client_limit()
+                .map(|limit| RateLimiter::new(limit, now)),
+        );
+        if let Some(limit) = self.config.ip_limit() {
+            self.ip_limiters
+                .entry(addr.ip())
+                .or_insert_with(|| (0, RateLimiter::new(limit, now)))
+                .0 += 1;
+        }
+        self.context.clients_mut().insert(identifier, new_client);
+        self.client_ids.insert(socket_fd, identifier);
+        self.metrics.accepted += 1;
+        self.context.audit(AuditEvent::Accepted {
+            client_id: identifier,
+            peer_addr: addr,
+            listener_id,
+        });
+
+        // SAFETY: the fd is owned by the `ClientState` stored above, which
+        // outlives this call because disconnects requested by the handler
+        // are only processed after it returns. `ManuallyDrop` makes sure
+        // this borrowed view never closes the fd.
+        let stream = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(socket_fd) });
+        if let Err(e) =
+            self.handler
+                .on_connection(&mut self.context, identifier, listener_id, &stream)
+        {
+            self.metrics.handler_errors += 1;
             error!(
                 "Handler `on_connection` failed for client id({}) addr({}): {}",
                 identifier, addr, e
             );
+            let error = ServerError::Handler {
+                callback: "on_connection",
+                error: e,
+            };
+            if self.report_error(error, Some(identifier)) {
+                self.context.disconnect(identifier);
+            }
         }
+        drop(span);
+        Ok(true)
+    }
 
-        let bitmask: i32 = EventType::Epollin as i32 | EventType::Epollet as i32;
-        let epoll_event = Event::new(bitmask as u32, PeerRole::Client(identifier));
-        self.epoll.add_interest(socket_fd, epoll_event)?;
-
-        let new_client = ClientState::new(socket);
-        self.clients.insert(identifier, new_client);
+    /// Apply the configured socket options to a new connection
+    fn configure_socket(&self, socket_fd: RawFd) -> Result<()> {
+        // Non-blocking already, accepted with `accept4` here or in the
+        // `Acceptor`
+        if self.config.tcp_nodelay() {
+            sockopt::set_nodelay(socket_fd, true)?;
+        }
+        if let Some(keepalive) = self.config.tcp_keepalive() {
+            sockopt::set_keepalive(socket_fd, Some(keepalive))?;
+        }
         Ok(())
     }
 
+    /// Give up on a connection whose setup failed with `error`, releasing
+    /// its id if it got one
+    ///
+    /// Always `false`, the connection is closed when its stream is dropped.
+    fn setup_failed(
+        &mut self,
+        addr: SocketAddr,
+        identifier: Option<ClientId>,
+        error: std::io::Error,
+    ) -> bool {
+        warn!(
+            "Failed to set up connection from {}, closing it: {}",
+            addr, error
+        );
+        self.context.accept_stats_mut().record_setup_failure();
+        if let Some(identifier) = identifier {
+            self.release_client_id(identifier);
+        }
+        false
+    }
+
+    /// Hand `identifier` back to the `ClientIdAllocator`, if one is installed
+    fn release_client_id(&mut self, identifier: ClientId) {
+        if let Some(allocator) = &mut self.id_allocator {
+            allocator.release(identifier);
+        }
+    }
+
+    /// Take a connection from the IP's `ServerConfig::ip_accept_rate`
+    ///
+    /// Returns `false` if the IP is over its rate
+    fn admit_connection_rate(&mut self, ip: IpAddr) -> bool {
+        let Some(rate) = self.config.accept_rate() else {
+            return true;
+        };
+        let now = self.context.now();
+        let limiter = self
+            .accept_limiters
+            .entry(ip)
+            .or_insert_with(|| RateLimiter::new(RateLimit::default().messages_per_sec(rate), now));
+        if !limiter.time_until(0, now).is_zero() {
+            return false;
+        }
+        limiter.consume(0);
+        true
+    }
+
+    /// Pick the id of a new client, the next one in sequence unless an
+    /// allocator is installed
+    fn allocate_client_id(&mut self, addr: SocketAddr) -> Result<ClientId> {
+        let Some(allocator) = &mut self.id_allocator else {
+            // Unlike fds, ids are not reused right away, see `ClientSlab`
+            return Ok(self.context.clients().vacant_id());
+        };
+
+        let identifier = allocator.allocate(addr);
+        if identifier.get() == 0
+            || identifier > MAX_CLIENT_ID
+            || self.context.clients().contains_key(&identifier)
+        {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("allocator returned unusable client id {identifier} for {addr}"),
+            ));
+        }
+        Ok(identifier)
+    }
+
     /// Handles data reading from file TcpStream
     ///
-    /// Read until we exhaust the kernel buffer or we get all the bytes
-    fn handle_read(client_state: &mut ClientState) -> Result<usize> {
-        let mut buffer = vec![0u8; 4096];
+    /// Read until we exhaust the kernel buffer or reach the end of the
+    /// stream, straight into the client's read buffer `read_len` bytes at
+    /// most at a time, and `bytes_read` counts every byte read. Reading
+    /// stops early, with `ReadOutcome::Data`, once the read buffer holds
+    /// more than `limit` bytes.
+    fn handle_read(
+        client_state: &mut ClientState,
+        read_len: usize,
+        bytes_read: &mut u64,
+        limit: Option<usize>,
+    ) -> Result<ReadOutcome> {
         let mut total_read = 0;
         loop {
-            match client_state.stream_mut().read(&mut buffer) {
-                Ok(0) => {
-                    debug!("Client closed connection or no more data to read");
-                    return Ok(0);
-                }
-                Ok(n) => {
+            match client_state.recv_into_buffer(read_len) {
+                Ok(ReadOutcome::Data(n)) => {
                     debug!("Read {} bytes", n);
-                    client_state.read_buf_mut().extend_from_slice(&buffer[..n]);
                     total_read += n;
+                    *bytes_read += n as u64;
+                    if limit.is_some_and(|limit| client_state.read_buf().len() > limit) {
+                        return Ok(ReadOutcome::Data(total_read));
+                    }
                 }
-                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                Ok(ReadOutcome::Eof) => {
                     debug!(
-                        "Drained the kernel's buffer (total read: {} bytes)",
+                        "Client closed connection (total read: {} bytes)",
                         total_read
                     );
-                    break;
+                    return Ok(ReadOutcome::Eof);
                 }
-                Err(e) => {
-                    return Err(e);
+                Ok(ReadOutcome::WouldBlock) => {
+                    debug!(
+                        "Drained the kernel's buffer (total read: {} bytes)",
+                        total_read
+                    );
+                    return Ok(ReadOutcome::WouldBlock);
                 }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
             }
         }
-        Ok(total_read)
     }
 
     fn handle_disconnection(&mut self, id: ClientId) -> Result<()> {
-        if let Some(client_socket) = self.clients.remove(&id) {
+        if let Some(mut client_socket) = self.context.clients_mut().remove(&id) {
+            let _span = client_socket.span().enter();
             let fd = client_socket.as_raw_fd();
-            self.epoll.remove_interest(fd)?;
+            self.client_ids.remove(&fd);
+            // The socket itself is closed once `client_socket`, its only
+            // owner, is dropped at the end of this block
+            if let Err(e) = self.context.epoll().remove_interest(fd) {
+                error!("Failed to deregister client {}: {}", id, e);
+            }
+            self.metrics.disconnected += 1;
+            self.rate_paused.remove(&id);
+            self.release_ip_limiter(client_socket.peer_addr().ip());
+
+            if self.context.at_capacity() {
+                self.context.set_at_capacity(false)?;
+            }
+            self.end_accept_backoff(true);
 
-            self.handler.on_disconnect(id)?;
+            self.context.middlewares_mut().disconnect(id);
+            self.context
+                .set_departed_data(Some((id, client_socket.take_data())));
+            let result = self.handler.on_disconnect(&mut self.context, id);
+            self.context.set_departed_data(None);
+            self.context.leave_all_rooms(id);
+            self.context.audit(AuditEvent::Disconnected {
+                client_id: id,
+                peer_addr: client_socket.peer_addr(),
+                reason: client_socket
+                    .disconnect_reason()
+                    .unwrap_or(DisconnectReason::Error),
+            });
+            self.release_client_id(id);
+            if let Err(e) = result {
+                self.metrics.handler_errors += 1;
+                error!("Handler `on_disconnect` failed for client {}: {}", id, e);
+                let error = ServerError::Handler {
+                    callback: "on_disconnect",
+                    error: e,
+                };
+                // The client is gone, there is nothing left to disconnect
+                self.report_error(error, Some(id));
+            }
         }
 
         Ok(())
     }
 
+    /// Forget the client's IP limiter once no client uses it and it has
+    /// nothing left to enforce
+    fn release_ip_limiter(&mut self, ip: IpAddr) {
+        let Some((clients, _)) = self.ip_limiters.get_mut(&ip) else {
+            return;
+        };
+        *clients = clients.saturating_sub(1);
+
+        // Limiters of IPs without clients are kept while they still hold
+        // back a reconnecting client, checked whenever a client leaves
+        let now = self.context.now();
+        self.ip_limiters
+            .retain(|_, (clients, limiter)| *clients > 0 || !limiter.is_idle(now));
+    }
+
+    /// Disconnect clients the handler asked to drop through the context
+    fn process_pending_disconnects(&mut self) -> Result<()> {
+        loop {
+            let pending = self.context.take_pending_disconnects();
+            if pending.is_empty() {
+                return Ok(());
+            }
+            for id in pending {
+                self.handle_disconnection(id)?;
+            }
+        }
+    }
+
+    /// Message sent to every connected client when the server is dropped
+    ///
+    /// Delivery is best effort, whatever fits in the socket buffer without
+    /// blocking is sent before the connection is closed.
+    pub fn set_goodbye_message(&mut self, message: Option<Vec<u8>>) {
+        self.goodbye_message = message;
+    }
+
     pub fn shutdown_signal(&self) -> Arc<AtomicBool> {
         self.shutdown_signal.clone()
     }
 
-    pub fn local_addr(&self) -> Result<SocketAddr> {
-        self.listener.local_addr()
+    /// Handle for other threads to reach clients or stop the server
+    pub fn handle(&self) -> ServerHandle {
+        self.commands.handle(self.shutdown_signal.clone())
     }
 
-    fn as_raw_fd(&self) -> RawFd {
-        self.listener.as_raw_fd()
+    pub fn local_addr(&self) -> crate::Result<SocketAddr> {
+        Ok(self.context.listen_addr())
+    }
+}
+
+/// Disconnect every client when the server goes away
+///
+/// Embedding applications may drop the server (e.g. on config reload) while
+/// clients are still connected, so each client gets the optional goodbye
+/// message and its pending writes flushed as far as the socket takes them
+/// without blocking, then is disconnected as if it had left: deregistered,
+/// closed and reported to `on_disconnect`. The listener is closed
+/// afterwards.
+impl<H: EventHandler + 'static> Drop for EpollServer<H> {
+    fn drop(&mut self) {
+        let goodbye_message = self.goodbye_message.take().map(Bytes::from);
+        let ids: Vec<ClientId> = self.context.clients().keys().copied().collect();
+        for id in ids {
+            if let Some(client) = self.context.clients_mut().get_mut(&id) {
+                client.note_disconnect_reason(DisconnectReason::ServerShutdown);
+                if let Some(message) = &goodbye_message {
+                    client.queue_message(message.clone());
+                }
+                if client.has_pending_writes()
+                    && let Err(e) = client.flush_writes()
+                {
+                    debug!("Failed to flush client {} on server drop: {}", id, e);
+                }
+            }
+
+            if let Err(e) = self.handle_disconnection(id) {
+                debug!("Error disconnecting client {} on server drop: {}", id, e);
+            }
+        }
     }
 }
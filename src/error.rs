@@ -0,0 +1,144 @@
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+};
+
+use crate::{handler::HandlerError, reactor::RawSource};
+
+/// Result of the server's public API
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Failure of the server's public API, by category
+///
+/// The event loop and the `EventHandler`, `Codec` and `Middleware` traits
+/// work with `std::io::Error`. Both convert into each other, so `?` works
+/// either way, and an `Error` carried through an `io::Error` (as a
+/// [`HandlerError`] returned by a handler is) comes back out unchanged.
+///
+/// A client that is gone is not an error: methods taking a client id
+/// report it as `Ok(false)`, as the client may have left while the call
+/// was on its way.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The system call `op` failed with `errno`
+    Syscall { op: &'static str, errno: i32 },
+    /// Any other I/O failure, e.g. binding the listening address
+    Io(io::Error),
+    /// The handler failed with its own [`ErrorPolicy`](crate::ErrorPolicy)
+    Handler(HandlerError),
+    /// Data does not follow the protocol, e.g. a frame a codec rejected
+    Protocol(String),
+    /// The operation does not apply, e.g. `sendfile` to a client with a
+    /// codec
+    Unsupported(&'static str),
+    /// An argument is out of range
+    InvalidInput(String),
+    /// The server is stopping or gone and takes no more commands
+    ShutdownInProgress,
+    /// The fd is registered with the reactor already, register it again
+    /// only after removing it
+    AlreadyRegistered(RawSource),
+    /// The fd is not registered with the reactor, or was closed since
+    NotRegistered(RawSource),
+}
+
+impl Error {
+    /// The closest `io::ErrorKind`, for code written against `io::Error`
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Syscall { errno, .. } => io::Error::from_raw_os_error(*errno).kind(),
+            Error::Io(e) => e.kind(),
+            Error::Handler(_) => ErrorKind::Other,
+            Error::Protocol(_) => ErrorKind::InvalidData,
+            Error::Unsupported(_) => ErrorKind::Unsupported,
+            Error::InvalidInput(_) => ErrorKind::InvalidInput,
+            Error::ShutdownInProgress => ErrorKind::BrokenPipe,
+            Error::AlreadyRegistered(_) => ErrorKind::AlreadyExists,
+            Error::NotRegistered(_) => ErrorKind::NotFound,
+        }
+    }
+
+    /// OS error code of a failed system call
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            Error::Syscall { errno, .. } => Some(*errno),
+            Error::Io(e) => e.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    /// Failure of the system call `op`, from the `io::Error` it set
+    pub(crate) fn syscall(op: &'static str, error: io::Error) -> io::Error {
+        match error.raw_os_error() {
+            Some(errno) => Error::Syscall { op, errno }.into(),
+            None => error,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Syscall { op, errno } => {
+                write!(f, "{op} failed: {}", io::Error::from_raw_os_error(*errno))
+            }
+            Error::Io(e) => e.fmt(f),
+            Error::Handler(e) => write!(f, "handler error: {e}"),
+            Error::Protocol(message) => write!(f, "protocol error: {message}"),
+            Error::Unsupported(message) => f.write_str(message),
+            Error::InvalidInput(message) => f.write_str(message),
+            Error::ShutdownInProgress => f.write_str("the server is shutting down"),
+            Error::AlreadyRegistered(fd) => write!(f, "fd {fd} is already registered"),
+            Error::NotRegistered(fd) => write!(f, "fd {fd} is not registered"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Handler(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        match error.downcast::<Error>() {
+            Ok(error) => error,
+            Err(error) => match error.downcast::<HandlerError>() {
+                Ok(error) => Error::Handler(error),
+                Err(error) => Error::Io(error),
+            },
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Io(error) => error,
+            Error::Handler(error) => error.into(),
+            error => io::Error::new(error.kind(), error),
+        }
+    }
+}
+
+impl From<HandlerError> for Error {
+    fn from(error: HandlerError) -> Self {
+        Error::Handler(error)
+    }
+}
+
+/// OS error code of `error`, also when it carries a failed [`Error::Syscall`]
+pub(crate) fn os_error(error: &io::Error) -> Option<i32> {
+    error.raw_os_error().or_else(|| {
+        error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<Error>())
+            .and_then(Error::errno)
+    })
+}
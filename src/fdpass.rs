@@ -0,0 +1,142 @@
+//! Passing file descriptors between processes
+//!
+//! An fd sent over a Unix socket with `SCM_RIGHTS` arrives in the
+//! receiving process as a new fd for the same open file. This lets a
+//! multi-process server accept connections in one process and serve them
+//! in worker processes: the acceptor sends each accepted `TcpStream` with
+//! [`send_fd`], and a worker turns what [`recv_fd`] returns back into a
+//! `TcpStream` for its loop with `ServerHandle::add_client`.
+//!
+//! ```no_run
+//! # use std::{net::TcpStream, os::unix::net::UnixStream};
+//! # use epoll_worker::{fdpass, ServerHandle};
+//! # fn worker(channel: UnixStream, handle: ServerHandle) -> epoll_worker::Result<()> {
+//! loop {
+//!     let stream = TcpStream::from(fdpass::recv_fd(&channel)?);
+//!     handle.add_client(stream)?;
+//! }
+//! # }
+//! ```
+
+use std::{
+    ffi::c_void,
+    io::{Error, ErrorKind, Result},
+    mem,
+    os::{
+        fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    ptr,
+};
+
+use crate::{
+    ep_syscall_retry,
+    ffi::{
+        CmsgHdr, IoVec, MSG_CMSG_CLOEXEC, MSG_CTRUNC, MSG_NOSIGNAL, MsgHdr, SCM_RIGHTS, SOL_SOCKET,
+    },
+};
+
+/// Ancillary data carrying one fd, laid out as `CMSG_SPACE(sizeof(int))`
+#[repr(C)]
+#[derive(Default)]
+struct FdControl {
+    header: CmsgHdr,
+    fd: RawFd,
+    /// Pads the data to the alignment of the header
+    padding: [u8; mem::size_of::<usize>() - mem::size_of::<RawFd>()],
+}
+
+/// `CMSG_LEN(sizeof(int))`, the header and the fd without padding
+const FD_CONTROL_LEN: usize = mem::offset_of!(FdControl, fd) + mem::size_of::<RawFd>();
+
+/// Byte sent along with the fd, stream sockets transfer no ancillary data
+/// without at least one byte of data
+const MARKER: u8 = b'F';
+
+/// Send `fd` to the process at the other end of `socket`
+///
+/// The fd stays open in this process, drop it once sent to close the
+/// connection here while the receiver keeps it open.
+pub fn send_fd(socket: &UnixStream, fd: impl AsFd) -> Result<()> {
+    let mut control = FdControl {
+        header: CmsgHdr {
+            len: FD_CONTROL_LEN,
+            level: SOL_SOCKET,
+            ty: SCM_RIGHTS,
+        },
+        fd: fd.as_fd().as_raw_fd(),
+        ..FdControl::default()
+    };
+    let data = [MARKER];
+    let mut iov = IoVec::from(&data[..]);
+    let message = MsgHdr {
+        name: ptr::null_mut(),
+        namelen: 0,
+        iov: &raw mut iov,
+        iovlen: 1,
+        control: (&raw mut control).cast::<c_void>(),
+        controllen: mem::size_of::<FdControl>(),
+        flags: 0,
+    };
+    let sent = ep_syscall_retry!(sendmsg(
+        socket.as_raw_fd(),
+        &raw const message,
+        MSG_NOSIGNAL
+    ))?;
+    if sent == 0 {
+        return Err(ErrorKind::WriteZero.into());
+    }
+    Ok(())
+}
+
+/// Receive an fd sent with [`send_fd`] by the process at the other end
+/// of `socket`, blocking until one arrives if the socket is blocking
+///
+/// The fd is close-on-exec. Fails with `UnexpectedEof` once the sender
+/// closed its end.
+pub fn recv_fd(socket: &UnixStream) -> Result<OwnedFd> {
+    let mut control = FdControl::default();
+    let mut data = [0u8];
+    let mut iov = IoVec {
+        base: data.as_mut_ptr(),
+        len: data.len(),
+    };
+    let mut message = MsgHdr {
+        name: ptr::null_mut(),
+        namelen: 0,
+        iov: &raw mut iov,
+        iovlen: 1,
+        control: (&raw mut control).cast::<c_void>(),
+        controllen: mem::size_of::<FdControl>(),
+        flags: 0,
+    };
+    let received = ep_syscall_retry!(recvmsg(
+        socket.as_raw_fd(),
+        &raw mut message,
+        MSG_CMSG_CLOEXEC
+    ))?;
+    if received == 0 {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+
+    let has_fd = message.controllen >= FD_CONTROL_LEN
+        && control.header.level == SOL_SOCKET
+        && control.header.ty == SCM_RIGHTS;
+    if !has_fd {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "message carries no file descriptor",
+        ));
+    }
+    // SAFETY: the kernel installed the fd in this process for us, nothing
+    // else owns it
+    let fd = unsafe { OwnedFd::from_raw_fd(control.fd) };
+    if message.flags & MSG_CTRUNC != 0 {
+        // More fds were sent than fit, the kernel closed the others
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "message carried more than one file descriptor",
+        ));
+    }
+    Ok(fd)
+}
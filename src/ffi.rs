@@ -1,6 +1,478 @@
 //! Epoll foreign function
 
+use std::time::Duration;
+
 use crate::Event;
+
+/// Corresponds to Linux's `struct iovec`
+///
+/// Describes one buffer of a vectored I/O call
+#[repr(C)]
+pub(crate) struct IoVec {
+    /// Start of the buffer
+    pub base: *const u8,
+    /// Length of the buffer in bytes
+    pub len: usize,
+}
+
+impl From<&[u8]> for IoVec {
+    fn from(buffer: &[u8]) -> Self {
+        IoVec {
+            base: buffer.as_ptr(),
+            len: buffer.len(),
+        }
+    }
+}
+
+/// `IPPROTO_TCP` socket option level
+pub(crate) const IPPROTO_TCP: i32 = 6;
+
+/// `SOL_SOCKET` socket option level
+pub(crate) const SOL_SOCKET: i32 = 1;
+
+/// `SO_KEEPALIVE` socket option, enables keepalive probes
+pub(crate) const SO_KEEPALIVE: i32 = 9;
+
+/// `TCP_NODELAY` socket option, disables Nagle's algorithm
+pub(crate) const TCP_NODELAY: i32 = 1;
+
+/// `TCP_CORK` socket option, holds back partial frames until cleared
+pub(crate) const TCP_CORK: i32 = 3;
+
+/// `TCP_KEEPIDLE` socket option, idle seconds before the first probe
+pub(crate) const TCP_KEEPIDLE: i32 = 4;
+
+/// `TCP_KEEPINTVL` socket option, seconds between probes
+pub(crate) const TCP_KEEPINTVL: i32 = 5;
+
+/// `TCP_KEEPCNT` socket option, unanswered probes before dropping
+pub(crate) const TCP_KEEPCNT: i32 = 6;
+
+/// `TCP_INFO` socket option, fills a `struct tcp_info`
+pub(crate) const TCP_INFO: i32 = 11;
+
+/// Leading fields of Linux's `struct tcp_info`
+///
+/// The kernel copies at most the length we pass in, so the fields we
+/// never read can be left out.
+#[repr(C)]
+#[derive(Default)]
+pub(crate) struct TcpInfo {
+    pub state: u8,
+    pub ca_state: u8,
+    pub retransmits: u8,
+    pub probes: u8,
+    pub backoff: u8,
+    pub options: u8,
+    /// `snd_wscale` and `rcv_wscale` bitfields
+    pub wscale: u8,
+    /// `delivery_rate_app_limited` and `fastopen_client_fail` bitfields
+    pub flags: u8,
+    pub rto: u32,
+    pub ato: u32,
+    pub snd_mss: u32,
+    pub rcv_mss: u32,
+    /// Unacknowledged segments, or the accept queue length of a listener
+    pub unacked: u32,
+    /// SACKed segments, or the accept queue capacity of a listener
+    pub sacked: u32,
+}
+
+/// `EPOLL_CLOEXEC`, same value as `O_CLOEXEC`
+pub(crate) const EPOLL_CLOEXEC: i32 = 0o2000000;
+
+/// `fcntl` operation reading the file descriptor flags
+pub(crate) const F_GETFD: i32 = 1;
+
+/// `fcntl` operation setting the file descriptor flags
+pub(crate) const F_SETFD: i32 = 2;
+
+/// `FD_CLOEXEC` file descriptor flag, the fd is closed by `execve`
+pub(crate) const FD_CLOEXEC: i32 = 1;
+
+/// `fcntl` operation reading the file status flags
+pub(crate) const F_GETFL: i32 = 3;
+
+/// `fcntl` operation setting the file status flags
+pub(crate) const F_SETFL: i32 = 4;
+
+/// `O_NONBLOCK` file status flag, reads and writes fail with `EAGAIN`
+/// instead of blocking
+pub(crate) const O_NONBLOCK: i32 = 0o4000;
+
+/// `AF_INET` address family, IPv4
+pub(crate) const AF_INET: i32 = 2;
+
+/// `AF_INET6` address family, IPv6
+pub(crate) const AF_INET6: i32 = 10;
+
+/// `SOCK_STREAM` socket type, TCP for the internet families
+pub(crate) const SOCK_STREAM: i32 = 1;
+
+/// `SOCK_NONBLOCK`, same value as `O_NONBLOCK`
+pub(crate) const SOCK_NONBLOCK: i32 = 0o4000;
+
+/// `SOCK_CLOEXEC`, same value as `O_CLOEXEC`
+pub(crate) const SOCK_CLOEXEC: i32 = 0o2000000;
+
+/// `SO_REUSEADDR` socket option, allows binding while old connections
+/// to the port linger in `TIME_WAIT`
+pub(crate) const SO_REUSEADDR: i32 = 2;
+
+/// `SO_TYPE` socket option, the socket type, e.g. `SOCK_STREAM`
+pub(crate) const SO_TYPE: i32 = 3;
+
+/// `SO_ACCEPTCONN` socket option, whether the socket is listening
+pub(crate) const SO_ACCEPTCONN: i32 = 30;
+
+/// `SO_DOMAIN` socket option, the address family of the socket
+pub(crate) const SO_DOMAIN: i32 = 39;
+
+/// `IPPROTO_IPV6` socket option level
+pub(crate) const IPPROTO_IPV6: i32 = 41;
+
+/// `IPV6_V6ONLY` socket option, keeps an IPv6 socket from also accepting
+/// IPv4 connections
+pub(crate) const IPV6_V6ONLY: i32 = 26;
+
+/// Corresponds to Linux's `struct sockaddr_in`
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub(crate) struct SockAddrIn {
+    pub family: u16,
+    /// Port in network byte order
+    pub port: u16,
+    pub addr: [u8; 4],
+    pub zero: [u8; 8],
+}
+
+/// Corresponds to Linux's `struct sockaddr_in6`
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub(crate) struct SockAddrIn6 {
+    pub family: u16,
+    /// Port in network byte order
+    pub port: u16,
+    pub flowinfo: u32,
+    pub addr: [u8; 16],
+    pub scope_id: u32,
+}
+
+/// Corresponds to Linux's `struct sockaddr_storage`, large enough for the
+/// address of any family
+#[repr(C, align(8))]
+#[derive(Clone, Copy)]
+pub(crate) struct SockAddrStorage {
+    pub family: u16,
+    pub data: [u8; 126],
+}
+
+impl Default for SockAddrStorage {
+    fn default() -> Self {
+        SockAddrStorage {
+            family: 0,
+            data: [0; 126],
+        }
+    }
+}
+
+/// Corresponds to Linux's `struct msghdr`, the message of `sendmsg` and
+/// `recvmsg`
+#[repr(C)]
+pub(crate) struct MsgHdr {
+    /// Peer address, unused on connected sockets
+    pub name: *mut std::ffi::c_void,
+    pub namelen: u32,
+    pub iov: *mut IoVec,
+    pub iovlen: usize,
+    /// Ancillary data, a sequence of `CmsgHdr` each followed by its data
+    pub control: *mut std::ffi::c_void,
+    pub controllen: usize,
+    /// Set by `recvmsg`, e.g. `MSG_CTRUNC`
+    pub flags: i32,
+}
+
+/// Corresponds to Linux's `struct cmsghdr`, the header of one piece of
+/// ancillary data
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub(crate) struct CmsgHdr {
+    /// Length of the header and the data, without the trailing padding
+    pub len: usize,
+    pub level: i32,
+    pub ty: i32,
+}
+
+/// `SCM_RIGHTS` ancillary data type, carries file descriptors
+pub(crate) const SCM_RIGHTS: i32 = 1;
+
+/// `MSG_CTRUNC` message flag, ancillary data did not fit in the buffer
+pub(crate) const MSG_CTRUNC: i32 = 0x8;
+
+/// `MSG_CMSG_CLOEXEC` flag of `recvmsg`, received fds are close-on-exec
+pub(crate) const MSG_CMSG_CLOEXEC: i32 = 0x4000_0000;
+
+/// `MSG_NOSIGNAL` flag of `sendmsg`, a closed peer is reported as `EPIPE`
+/// instead of raising `SIGPIPE`
+pub(crate) const MSG_NOSIGNAL: i32 = 0x4000;
+
+/// `MSG_DONTWAIT` flag of `send` and `recv`, fails with `EAGAIN` instead of
+/// blocking whatever the mode of the socket
+pub(crate) const MSG_DONTWAIT: i32 = 0x40;
+
+/// `CLOCK_MONOTONIC`, unaffected by changes of the system time
+pub(crate) const CLOCK_MONOTONIC: i32 = 1;
+
+/// `TFD_NONBLOCK`, same value as `O_NONBLOCK`
+pub(crate) const TFD_NONBLOCK: i32 = 0o4000;
+
+/// `TFD_CLOEXEC`, same value as `O_CLOEXEC`
+pub(crate) const TFD_CLOEXEC: i32 = 0o2000000;
+
+/// `EFD_NONBLOCK`, same value as `O_NONBLOCK`
+pub(crate) const EFD_NONBLOCK: i32 = 0o4000;
+
+/// `EFD_CLOEXEC`, same value as `O_CLOEXEC`
+pub(crate) const EFD_CLOEXEC: i32 = 0o2000000;
+
+/// `IN_NONBLOCK`, same value as `O_NONBLOCK`
+pub(crate) const IN_NONBLOCK: i32 = 0o4000;
+
+/// `IN_CLOEXEC`, same value as `O_CLOEXEC`
+pub(crate) const IN_CLOEXEC: i32 = 0o2000000;
+
+/// inotify event, a file in a watched directory was moved out of it
+pub(crate) const IN_MOVED_FROM: u32 = 0x40;
+
+/// inotify event, a file was moved into a watched directory
+pub(crate) const IN_MOVED_TO: u32 = 0x80;
+
+/// inotify event, a file opened for writing was closed
+pub(crate) const IN_CLOSE_WRITE: u32 = 0x8;
+
+/// inotify event, a file was created in a watched directory
+pub(crate) const IN_CREATE: u32 = 0x100;
+
+/// inotify event, a file in a watched directory was deleted
+pub(crate) const IN_DELETE: u32 = 0x200;
+
+/// inotify event, the watched file or directory itself was deleted
+pub(crate) const IN_DELETE_SELF: u32 = 0x400;
+
+/// inotify event, the watched file or directory itself was moved
+pub(crate) const IN_MOVE_SELF: u32 = 0x800;
+
+/// inotify event, the event queue overflowed and events were lost
+pub(crate) const IN_Q_OVERFLOW: u32 = 0x4000;
+
+/// inotify event, the watch was removed, explicitly or because the file is
+/// gone
+pub(crate) const IN_IGNORED: u32 = 0x8000;
+
+/// Corresponds to Linux's `struct inotify_event`, followed by `len` bytes
+/// of nul padded file name
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub(crate) struct InotifyEvent {
+    /// Watch descriptor the event is for, `-1` for `IN_Q_OVERFLOW`
+    pub wd: i32,
+    /// `IN_*` bits of the event
+    pub mask: u32,
+    /// Ties the two halves of a rename together
+    pub cookie: u32,
+    /// Length of the name that follows, padding included
+    pub len: u32,
+}
+
+/// `RLIMIT_NOFILE` resource, the most file descriptors a process may open
+pub(crate) const RLIMIT_NOFILE: i32 = 7;
+
+/// Corresponds to Linux's `struct rlimit`
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub(crate) struct RLimit {
+    /// Soft limit, the one enforced
+    pub cur: u64,
+    /// Hard limit, the ceiling for the soft limit
+    pub max: u64,
+}
+
+/// Corresponds to Linux's `struct timespec`
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub(crate) struct TimeSpec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl From<Duration> for TimeSpec {
+    fn from(duration: Duration) -> Self {
+        TimeSpec {
+            tv_sec: duration.as_secs() as i64,
+            tv_nsec: duration.subsec_nanos() as i64,
+        }
+    }
+}
+
+/// `epoll_pwait2` system call number, shared by every architecture
+pub(crate) const SYS_EPOLL_PWAIT2: i64 = 441;
+
+/// Size of the kernel's `sigset_t`, one bit for each of the 64 signals
+pub(crate) const KERNEL_SIGSET_SIZE: i64 = 8;
+
+/// `ENOSYS`, the system call does not exist on this kernel
+pub(crate) const ENOSYS: i32 = 38;
+
+/// `EMFILE`, the process reached its limit of open file descriptors
+pub(crate) const EMFILE: i32 = 24;
+
+/// `ENFILE`, the system reached its limit of open files
+pub(crate) const ENFILE: i32 = 23;
+
+/// `ENOMEM`, the kernel is out of memory
+pub(crate) const ENOMEM: i32 = 12;
+
+/// `ENOBUFS`, the kernel is out of socket buffers
+pub(crate) const ENOBUFS: i32 = 105;
+
+/// `ECONNABORTED`, a connection was reset while waiting to be accepted
+pub(crate) const ECONNABORTED: i32 = 103;
+
+/// `EPROTO`, a protocol error on a connection waiting to be accepted
+pub(crate) const EPROTO: i32 = 71;
+
+/// `EPERM`, e.g. a firewall rule refused a connection being accepted
+pub(crate) const EPERM: i32 = 1;
+
+/// `EINPROGRESS`, a non-blocking connect is under way
+#[cfg(feature = "async")]
+pub(crate) const EINPROGRESS: i32 = 115;
+
+/// `ENOENT`, e.g. an fd not registered with the epoll instance
+pub(crate) const ENOENT: i32 = 2;
+
+/// `EEXIST`, e.g. an fd registered with the epoll instance already
+pub(crate) const EEXIST: i32 = 17;
+
+/// Corresponds to Linux's `struct itimerspec`
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub(crate) struct ITimerSpec {
+    /// Period of a repeating timer, zero for a one-shot timer
+    pub interval: TimeSpec,
+    /// Time until the first expiration, zero disarms the timer
+    pub value: TimeSpec,
+}
+
+/// `io_uring_setup` system call number, shared by every architecture
+pub(crate) const SYS_IO_URING_SETUP: i64 = 425;
+
+/// `io_uring_enter` system call number, shared by every architecture
+pub(crate) const SYS_IO_URING_ENTER: i64 = 426;
+
+/// `mmap` offsets of the io_uring submission ring, completion ring and
+/// submission queue entries
+pub(crate) const IORING_OFF_SQ_RING: i64 = 0;
+pub(crate) const IORING_OFF_CQ_RING: i64 = 0x800_0000;
+pub(crate) const IORING_OFF_SQES: i64 = 0x1000_0000;
+
+/// Both rings share one mapping, at `IORING_OFF_SQ_RING`
+pub(crate) const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+
+/// Keep submitting the entries after one fails, each gets its completion
+pub(crate) const IORING_SETUP_SUBMIT_ALL: u32 = 1 << 7;
+
+/// `io_uring_enter` flag, wait for `min_complete` completions
+pub(crate) const IORING_ENTER_GETEVENTS: u32 = 1;
+
+/// `epoll_ctl` run from the ring
+pub(crate) const IORING_OP_EPOLL_CTL: u8 = 29;
+
+/// `PROT_READ | PROT_WRITE`
+pub(crate) const PROT_READ_WRITE: i32 = 0x3;
+
+/// `MAP_SHARED | MAP_POPULATE`
+pub(crate) const MAP_SHARED_POPULATE: i32 = 0x8001;
+
+/// Corresponds to Linux's `struct io_sqring_offsets`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct SqRingOffsets {
+    pub head: u32,
+    pub tail: u32,
+    pub ring_mask: u32,
+    pub ring_entries: u32,
+    pub flags: u32,
+    pub dropped: u32,
+    pub array: u32,
+    pub resv1: u32,
+    pub user_addr: u64,
+}
+
+/// Corresponds to Linux's `struct io_cqring_offsets`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct CqRingOffsets {
+    pub head: u32,
+    pub tail: u32,
+    pub ring_mask: u32,
+    pub ring_entries: u32,
+    pub overflow: u32,
+    pub cqes: u32,
+    pub flags: u32,
+    pub resv1: u32,
+    pub user_addr: u64,
+}
+
+/// Corresponds to Linux's `struct io_uring_params`
+///
+/// Filled by `io_uring_setup` with the sizes and the offsets of the
+/// fields of both rings in their mappings
+#[repr(C)]
+#[derive(Debug, Default)]
+pub(crate) struct IoUringParams {
+    pub sq_entries: u32,
+    pub cq_entries: u32,
+    pub flags: u32,
+    pub sq_thread_cpu: u32,
+    pub sq_thread_idle: u32,
+    pub features: u32,
+    pub wq_fd: u32,
+    pub resv: [u32; 3],
+    pub sq_off: SqRingOffsets,
+    pub cq_off: CqRingOffsets,
+}
+
+/// Corresponds to Linux's `struct io_uring_sqe`, one submitted operation
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct IoUringSqe {
+    pub opcode: u8,
+    pub flags: u8,
+    pub ioprio: u16,
+    pub fd: i32,
+    pub off: u64,
+    pub addr: u64,
+    pub len: u32,
+    pub op_flags: u32,
+    pub user_data: u64,
+    pub buf_index: u16,
+    pub personality: u16,
+    pub splice_fd_in: i32,
+    pub addr3: u64,
+    pub pad: u64,
+}
+
+/// Corresponds to Linux's `struct io_uring_cqe`, one completed operation
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct IoUringCqe {
+    pub user_data: u64,
+    /// Result of the operation, `-errno` on failure
+    pub res: i32,
+    pub flags: u32,
+}
+
 unsafe extern "C" {
     /// Creates new epoll instance
     ///
@@ -14,16 +486,6 @@ unsafe extern "C" {
     /// and the error is set to `errno` which is basically the `last_os_error`
     pub(crate) fn epoll_create1(size: i32) -> i32;
 
-    /// Closes a file descriptor
-    ///
-    /// This is used to close the epoll instance when no longer needed.
-    /// OS frees the resources associated with the epoll instance that we created.
-    ///
-    /// # Returns
-    ///
-    /// `0` on success and `-1` on error
-    pub(crate) fn close(fd: i32) -> i32;
-
     /// Add, modify or remove entries in interest list of epoll instance
     ///
     /// # Arguments
@@ -34,7 +496,10 @@ unsafe extern "C" {
     /// * `event` -
     pub(crate) fn epoll_ctl(epfd: i32, op: i32, fd: i32, event: *mut Event) -> i32;
 
-    /// Wait for events on epoll instance
+    /// Wait for events on epoll instance, with `sigmask` as the signal
+    /// mask during the wait
+    ///
+    /// Only used before Linux 5.11, `epoll_pwait2` takes its place.
     ///
     /// # Arguments
     ///
@@ -42,16 +507,342 @@ unsafe extern "C" {
     /// * `events` - buffer to fill the returned events notification
     /// * `max_events` - number of max events to be filled, must be greater than zero
     /// * `timeouot` - number of milliseconds that `epoll_wait` will block
-    pub(crate) fn epoll_wait(epfd: i32, events: *mut Event, max_events: i32, timeout: i32) -> i32;
+    /// * `sigmask` - signals blocked during the wait, or null to keep the
+    ///   thread's mask
+    pub(crate) fn epoll_pwait(
+        epfd: i32,
+        events: *mut Event,
+        max_events: i32,
+        timeout: i32,
+        sigmask: *const u64,
+    ) -> i32;
 
     /// Performs operation on open file descriptor
     ///
     /// Operation is defined by `op` argument.
-    /// Operations can be different type, but we are
-    /// only interested in verifying if the file descriptor
-    /// is valid or not.
+    /// We use it on the file descriptor flags, to check that a file
+    /// descriptor is valid and to make it close-on-exec, and on the status
+    /// flags to make pipes non-blocking.
     ///
+    /// ```text
     ///     F_GETFD - returns the file descriptor flags
     ///               value of F_GETFD is 1
+    ///     F_SETFD - sets the flags to the third argument
+    ///               value of F_SETFD is 2
+    ///     F_GETFL - returns the file status flags
+    ///               value of F_GETFL is 3
+    ///     F_SETFL - sets the status flags to the third argument
+    ///               value of F_SETFL is 4
+    /// ```
     pub(crate) fn fcntl(fd: i32, op: i32, ...) -> i32;
+
+    /// Receives data from a connected socket
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - socket file descriptor
+    /// * `buf` - buffer to fill
+    /// * `len` - size of `buf` in bytes
+    /// * `flags` - e.g. `MSG_DONTWAIT`
+    ///
+    /// # Returns
+    ///
+    /// Number of bytes received, `0` at end of stream, or `-1` on error
+    pub(crate) fn recv(fd: i32, buf: *mut u8, len: usize, flags: i32) -> isize;
+
+    /// Sends data on a connected socket
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - socket file descriptor
+    /// * `buf` - data to send
+    /// * `len` - length of `buf` in bytes
+    /// * `flags` - e.g. `MSG_DONTWAIT | MSG_NOSIGNAL`
+    ///
+    /// # Returns
+    ///
+    /// Number of bytes sent, which may be less than `len`, or `-1` on error
+    pub(crate) fn send(fd: i32, buf: *const u8, len: usize, flags: i32) -> isize;
+
+    /// Creates a socket
+    ///
+    /// # Arguments
+    ///
+    /// * `domain` - address family, `AF_INET` or `AF_INET6`
+    /// * `ty` - socket type, optionally or'ed with `SOCK_NONBLOCK` and
+    ///   `SOCK_CLOEXEC`
+    /// * `protocol` - `0` for the family's default protocol
+    ///
+    /// # Returns
+    ///
+    /// The file descriptor of the socket or `-1` on error
+    pub(crate) fn socket(domain: i32, ty: i32, protocol: i32) -> i32;
+
+    /// Connects a socket to `addr`, a non-blocking socket fails with
+    /// `EINPROGRESS` and becomes writable once the connection is made
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - socket file descriptor
+    /// * `addr` - a `SockAddrIn` or `SockAddrIn6`
+    /// * `addrlen` - size of `addr`
+    ///
+    /// # Returns
+    ///
+    /// `0` on success and `-1` on error
+    #[cfg(feature = "async")]
+    pub(crate) fn connect(fd: i32, addr: *const std::ffi::c_void, addrlen: u32) -> i32;
+
+    /// Assigns a local address to a socket
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - socket file descriptor
+    /// * `addr` - a `SockAddrIn` or `SockAddrIn6`
+    /// * `addrlen` - size of `addr`
+    ///
+    /// # Returns
+    ///
+    /// `0` on success and `-1` on error
+    pub(crate) fn bind(fd: i32, addr: *const std::ffi::c_void, addrlen: u32) -> i32;
+
+    /// Marks a bound socket as accepting connections
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - socket file descriptor
+    /// * `backlog` - most connections waiting to be accepted, capped by
+    ///   `net.core.somaxconn`
+    ///
+    /// # Returns
+    ///
+    /// `0` on success and `-1` on error
+    pub(crate) fn listen(fd: i32, backlog: i32) -> i32;
+
+    /// Accepts a connection, creating its socket with `flags` set
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - listening socket file descriptor
+    /// * `addr` - receives the peer address, a `SockAddrStorage`
+    /// * `addrlen` - size of `addr`, updated to the size of the address
+    /// * `flags` - `SOCK_NONBLOCK` and/or `SOCK_CLOEXEC`
+    ///
+    /// # Returns
+    ///
+    /// The file descriptor of the connection or `-1` on error
+    pub(crate) fn accept4(
+        fd: i32,
+        addr: *mut std::ffi::c_void,
+        addrlen: *mut u32,
+        flags: i32,
+    ) -> i32;
+
+    /// Sends a message, with ancillary data, on a socket
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - socket file descriptor
+    /// * `msg` - buffers to send and ancillary data
+    /// * `flags` - e.g. `MSG_NOSIGNAL`
+    ///
+    /// # Returns
+    ///
+    /// Number of bytes sent or `-1` on error
+    pub(crate) fn sendmsg(fd: i32, msg: *const MsgHdr, flags: i32) -> isize;
+
+    /// Receives a message, with ancillary data, from a socket
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - socket file descriptor
+    /// * `msg` - buffers to fill and room for ancillary data, its `flags`
+    ///   and `controllen` are updated
+    /// * `flags` - e.g. `MSG_CMSG_CLOEXEC`
+    ///
+    /// # Returns
+    ///
+    /// Number of bytes received, `0` at end of stream, or `-1` on error
+    pub(crate) fn recvmsg(fd: i32, msg: *mut MsgHdr, flags: i32) -> isize;
+
+    /// Copies data between two file descriptors inside the kernel
+    ///
+    /// # Arguments
+    ///
+    /// * `out_fd` - descriptor to write to, a socket for us
+    /// * `in_fd` - descriptor to read from, must support `mmap`-like access (a regular file)
+    /// * `offset` - where to start reading `in_fd`, updated to the byte after the last one read
+    /// * `count` - number of bytes to copy
+    ///
+    /// # Returns
+    ///
+    /// Number of bytes written to `out_fd`, which may be less than `count`,
+    /// or `-1` on error
+    pub(crate) fn sendfile(out_fd: i32, in_fd: i32, offset: *mut i64, count: usize) -> isize;
+
+    /// Reads a socket option
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - socket file descriptor
+    /// * `level` - protocol level of the option, e.g. `IPPROTO_TCP`
+    /// * `optname` - option to read
+    /// * `optval` - buffer the value is written to
+    /// * `optlen` - size of `optval`, updated to the size of the value
+    ///
+    /// # Returns
+    ///
+    /// `0` on success and `-1` on error
+    pub(crate) fn getsockopt(
+        fd: i32,
+        level: i32,
+        optname: i32,
+        optval: *mut std::ffi::c_void,
+        optlen: *mut u32,
+    ) -> i32;
+
+    /// Creates a timer that delivers its expirations through a file descriptor
+    ///
+    /// # Arguments
+    ///
+    /// * `clockid` - clock the timer runs on, e.g. `CLOCK_MONOTONIC`
+    /// * `flags` - `TFD_NONBLOCK` and/or `TFD_CLOEXEC`
+    ///
+    /// # Returns
+    ///
+    /// The file descriptor of the timer or `-1` on error
+    pub(crate) fn timerfd_create(clockid: i32, flags: i32) -> i32;
+
+    /// Arms or disarms the timer
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - timer file descriptor
+    /// * `flags` - `0` for a timeout relative to now
+    /// * `new_value` - first expiration and period of the timer
+    /// * `old_value` - receives the previous setting, may be null
+    ///
+    /// # Returns
+    ///
+    /// `0` on success and `-1` on error
+    pub(crate) fn timerfd_settime(
+        fd: i32,
+        flags: i32,
+        new_value: *const ITimerSpec,
+        old_value: *mut ITimerSpec,
+    ) -> i32;
+
+    /// Creates a file descriptor holding a `u64` counter, used for wakeups
+    ///
+    /// Writing adds to the counter and makes the fd readable, reading
+    /// returns the counter and resets it to zero.
+    ///
+    /// # Arguments
+    ///
+    /// * `initval` - initial value of the counter
+    /// * `flags` - `EFD_NONBLOCK` and/or `EFD_CLOEXEC`
+    ///
+    /// # Returns
+    ///
+    /// The file descriptor or `-1` on error
+    pub(crate) fn eventfd(initval: u32, flags: i32) -> i32;
+
+    /// Creates an inotify instance, reporting filesystem events on its fd
+    ///
+    /// # Arguments
+    ///
+    /// * `flags` - `IN_NONBLOCK` and/or `IN_CLOEXEC`
+    ///
+    /// # Returns
+    ///
+    /// The file descriptor of the instance or `-1` on error
+    pub(crate) fn inotify_init1(flags: i32) -> i32;
+
+    /// Watches `pathname` for the events in `mask`, or replaces the mask
+    /// of the existing watch of the same file
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - inotify instance
+    /// * `pathname` - nul terminated path of the file or directory
+    /// * `mask` - `IN_*` events to report
+    ///
+    /// # Returns
+    ///
+    /// The watch descriptor or `-1` on error
+    pub(crate) fn inotify_add_watch(fd: i32, pathname: *const std::ffi::c_char, mask: u32) -> i32;
+
+    /// Removes a watch, an `IN_IGNORED` event is reported for it
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - inotify instance
+    /// * `wd` - watch descriptor returned by `inotify_add_watch`
+    ///
+    /// # Returns
+    ///
+    /// `0` on success and `-1` on error
+    pub(crate) fn inotify_rm_watch(fd: i32, wd: i32) -> i32;
+
+    /// Sets a socket option
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - socket file descriptor
+    /// * `level` - protocol level of the option, e.g. `SOL_SOCKET`
+    /// * `optname` - option to set
+    /// * `optval` - buffer holding the new value
+    /// * `optlen` - size of `optval`
+    ///
+    /// # Returns
+    ///
+    /// `0` on success and `-1` on error
+    pub(crate) fn setsockopt(
+        fd: i32,
+        level: i32,
+        optname: i32,
+        optval: *const std::ffi::c_void,
+        optlen: u32,
+    ) -> i32;
+
+    /// Gets the limits of a process resource
+    ///
+    /// # Arguments
+    ///
+    /// * `resource` - resource to query, e.g. `RLIMIT_NOFILE`
+    /// * `rlim` - receives the soft and hard limits
+    ///
+    /// # Returns
+    ///
+    /// `0` on success and `-1` on error
+    pub(crate) fn getrlimit(resource: i32, rlim: *mut RLimit) -> i32;
+
+    /// Invokes the system call `number`, used for the io_uring calls
+    /// libc has no wrapper for
+    ///
+    /// # Returns
+    ///
+    /// The result of the call, or `-1` on error
+    pub(crate) fn syscall(number: i64, ...) -> i64;
+
+    /// Maps `length` bytes of `fd` at `offset` into memory
+    ///
+    /// # Returns
+    ///
+    /// The start of the mapping, or `MAP_FAILED` (`-1`) on error
+    pub(crate) fn mmap(
+        addr: *mut std::ffi::c_void,
+        length: usize,
+        prot: i32,
+        flags: i32,
+        fd: i32,
+        offset: i64,
+    ) -> *mut std::ffi::c_void;
+
+    /// Removes a mapping created by `mmap`
+    ///
+    /// # Returns
+    ///
+    /// `0` on success and `-1` on error
+    pub(crate) fn munmap(addr: *mut std::ffi::c_void, length: usize) -> i32;
 }
@@ -1,21 +1,577 @@
-use std::{io::Result, net::TcpStream};
+use std::{
+    fmt,
+    fs::File,
+    io::{Error, ErrorKind, Result},
+    net::{SocketAddr, TcpStream},
+    os::fd::RawFd,
+};
 
-use crate::epoll_server::ClientId;
+use crate::{
+    EventFlags,
+    accept_error::AcceptError,
+    bytes::Bytes,
+    client_id::ClientId,
+    client_state::Priority,
+    context::{ListenerId, ServerContext},
+    protocol::PeerIdentity,
+    timer::TimerId,
+    watch::{FileEvent, WatchId},
+};
 
+/// What the server sends after `on_message`
+///
+/// Data is carried as [`Bytes`], build it with `.into()` from a `Vec<u8>`,
+/// a `String` or a literal, or pass on the message itself.
 pub enum HandlerAction {
-    Broadcast(Vec<u8>),
-    Reply(Vec<u8>),
+    /// Send to every other client connected at the time the action is
+    /// handled, see [`ServerContext::broadcast`]
+    Broadcast(Bytes),
+    Reply(Bytes),
+    /// Reply in the lane of the given priority, e.g. a pong ahead of a
+    /// large payload still queued, see [`ServerContext::send_with_priority`]
+    ReplyWithPriority(Bytes, Priority),
+    /// Reply with a response made of several parts, e.g. headers and a
+    /// body, see [`ServerContext::send_parts`]
+    ReplyParts(Vec<Bytes>),
     SendTo {
-        target_client_id: u32,
-        data: Vec<u8>,
+        target_client_id: ClientId,
+        data: Bytes,
     },
-    SendToAll(Vec<u8>),
+    SendToAll(Bytes),
+    /// Send to every other member of `room`, see
+    /// [`ServerContext::join`]
+    BroadcastRoom {
+        room: String,
+        data: Bytes,
+    },
+    /// Send `len` bytes of `file` from `offset` to the client with
+    /// `sendfile`, bypassing the codec. See [`ServerContext::send_file`]
+    SendFile {
+        file: File,
+        offset: u64,
+        len: usize,
+    },
+    /// Reply and disconnect the client once the reply is written, see
+    /// [`ServerContext::close_after_write`]
+    ReplyAndClose(Bytes),
+    /// Shut the write side of the connection down once what is queued is
+    /// written, see [`ServerContext::shutdown_write`]
+    FinishWrite,
+    /// Disconnect the client, dropping what is queued for it, see
+    /// [`ServerContext::disconnect`]
+    Disconnect,
+    /// Reply with what `source` produces, pulled as the socket drains. See
+    /// [`ServerContext::send_stream`]
+    ReplyStream(Box<dyn DataSource + Send>),
     None,
 }
 
+impl HandlerAction {
+    /// Short name of the action, e.g. `"reply"`, as found in access logs
+    /// and traces
+    pub fn name(&self) -> &'static str {
+        match self {
+            HandlerAction::Broadcast(_) => "broadcast",
+            HandlerAction::Reply(_) => "reply",
+            HandlerAction::ReplyWithPriority(..) => "reply_with_priority",
+            HandlerAction::ReplyParts(_) => "reply_parts",
+            HandlerAction::SendTo { .. } => "send_to",
+            HandlerAction::SendToAll(_) => "send_to_all",
+            HandlerAction::BroadcastRoom { .. } => "broadcast_room",
+            HandlerAction::SendFile { .. } => "send_file",
+            HandlerAction::ReplyAndClose(_) => "reply_and_close",
+            HandlerAction::FinishWrite => "finish_write",
+            HandlerAction::Disconnect => "disconnect",
+            HandlerAction::ReplyStream(_) => "reply_stream",
+            HandlerAction::None => "none",
+        }
+    }
+}
+
+/// Producer of a response too large to hold in memory at once
+///
+/// The server calls `pull` whenever the client's socket can take more and
+/// the previous chunk is written, so only one chunk of the response is
+/// buffered at a time. Closures `FnMut(&mut [u8]) -> Result<usize>` are
+/// sources, e.g. `move |buf| file.read(buf)`.
+pub trait DataSource {
+    /// Fill the start of `buf`, returning how many bytes were written to it
+    ///
+    /// `Ok(0)` ends the stream. The source must not block: data that is
+    /// not available yet is best sent from a later callback instead. An
+    /// error disconnects the client.
+    fn pull(&mut self, buf: &mut [u8]) -> Result<usize>;
+}
+
+impl<F: FnMut(&mut [u8]) -> Result<usize>> DataSource for F {
+    fn pull(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self(buf)
+    }
+}
+
+/// How much of the data passed to `EventHandler::on_data_chunk` the
+/// handler processed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsumeResult {
+    /// The first `n` bytes were processed and are dropped, the rest is
+    /// passed again with the next data. `Consumed(0)` waits for more data.
+    Consumed(usize),
+    /// Disconnect the client
+    Close,
+}
+
+/// What the server does with a client whose read buffer went over
+/// `ServerConfig::max_read_buffer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowAction {
+    /// Drop the client
+    #[default]
+    Disconnect,
+    /// Throw the buffered data away and keep reading, the handler is
+    /// responsible for finding the start of the next message
+    Discard,
+}
+
+/// What the server does when `on_message` returns an error
+///
+/// The server wide default is set with `ServerConfig::handler_error_policy`,
+/// a [`HandlerError`] overrides it for a single error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// Log the error and drop the client
+    #[default]
+    Disconnect,
+    /// Send the error message to the client and keep the connection
+    Reply,
+    /// Log the error and keep the connection
+    Ignore,
+}
+
+/// Error carrying its own [`ErrorPolicy`]
+///
+/// Return it from `on_message` through `std::io::Error`, it converts with
+/// `?` or `.into()`:
+///
+/// ```
+/// use epoll_worker::{ErrorPolicy, HandlerError};
+///
+/// fn parse(data: &[u8]) -> std::io::Result<u32> {
+///     let text = std::str::from_utf8(data)
+///         .map_err(|_| HandlerError::new(ErrorPolicy::Reply, "ERR not utf-8"))?;
+///     text.trim()
+///         .parse()
+///         .map_err(|_| HandlerError::new(ErrorPolicy::Ignore, "not a number").into())
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandlerError {
+    policy: ErrorPolicy,
+    message: String,
+    reply: Option<Vec<u8>>,
+}
+
+impl HandlerError {
+    pub fn new(policy: ErrorPolicy, message: impl Into<String>) -> Self {
+        HandlerError {
+            policy,
+            message: message.into(),
+            reply: None,
+        }
+    }
+
+    /// Send `reply` instead of the error message with `ErrorPolicy::Reply`
+    pub fn with_reply(mut self, reply: Vec<u8>) -> Self {
+        self.reply = Some(reply);
+        self
+    }
+
+    pub fn policy(&self) -> ErrorPolicy {
+        self.policy
+    }
+
+    /// Data sent to the client with `ErrorPolicy::Reply`
+    pub fn reply(&self) -> Vec<u8> {
+        match &self.reply {
+            Some(reply) => reply.clone(),
+            None => self.message.clone().into_bytes(),
+        }
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+impl From<HandlerError> for std::io::Error {
+    fn from(error: HandlerError) -> Self {
+        std::io::Error::other(error)
+    }
+}
+
+/// An error the event loop ran into, reported to `EventHandler::on_error`
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ServerError {
+    /// A listening socket failed and was closed, the primary listener is
+    /// then rebound following the `RebindPolicy`
+    Listener(Error),
+    /// Rebinding the primary listener failed `RebindPolicy::max_attempts`
+    /// times, the server no longer accepts
+    Rebind(Error),
+    /// Reading from or writing to a client failed
+    ClientIo(Error),
+    /// The client's codec rejected its data
+    Decode(Error),
+    /// A handler callback other than `on_message`, whose errors follow
+    /// the [`ErrorPolicy`], failed
+    Handler {
+        callback: &'static str,
+        error: Error,
+    },
+    /// A timer, the file watcher or the deferred actions failed
+    Internal(Error),
+}
+
+impl ServerError {
+    /// The failure behind the error
+    pub fn io_error(&self) -> &Error {
+        match self {
+            ServerError::Listener(error)
+            | ServerError::Rebind(error)
+            | ServerError::ClientIo(error)
+            | ServerError::Decode(error)
+            | ServerError::Handler { error, .. }
+            | ServerError::Internal(error) => error,
+        }
+    }
+
+    /// What the server does unless the handler decides otherwise: a client
+    /// whose connection or data failed is disconnected, other errors are
+    /// only logged
+    ///
+    /// A failed `on_connection` keeps its client, the client of a failed
+    /// `on_disconnect` is gone already.
+    pub fn default_directive(&self) -> ErrorDirective {
+        match self {
+            ServerError::ClientIo(_) | ServerError::Decode(_) => ErrorDirective::Disconnect,
+            ServerError::Handler { callback, .. }
+                if !matches!(*callback, "on_connection" | "on_disconnect") =>
+            {
+                ErrorDirective::Disconnect
+            }
+            _ => ErrorDirective::Ignore,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Listener(e) => write!(f, "listener failed: {e}"),
+            ServerError::Rebind(e) => write!(f, "rebinding the listener failed: {e}"),
+            ServerError::ClientIo(e) => write!(f, "client I/O failed: {e}"),
+            ServerError::Decode(e) => write!(f, "decoding client data failed: {e}"),
+            ServerError::Handler { callback, error } => write!(f, "`{callback}` failed: {error}"),
+            ServerError::Internal(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.io_error())
+    }
+}
+
+/// What the server does after an error, returned by `EventHandler::on_error`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorDirective {
+    /// Keep going, the client if any stays connected
+    Ignore,
+    /// Drop the client the error is about, the same as `Ignore` for
+    /// errors about no client
+    Disconnect,
+    /// Stop the server, `run` returns after the current iteration
+    Stop,
+}
+
 pub trait EventHandler {
-    fn on_connection(&mut self, client_id: ClientId, stream: &TcpStream) -> Result<()>;
-    fn on_message(&mut self, client_id: ClientId, data: &[u8]) -> Result<HandlerAction>;
-    fn on_disconnect(&mut self, client_id: ClientId) -> Result<()>;
+    /// Called for each new client, with the listener it connected through:
+    /// `PRIMARY_LISTENER`, or the id given to `EpollServer::add_listener`
+    fn on_connection(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        listener_id: ListenerId,
+        stream: &TcpStream,
+    ) -> Result<()>;
+    /// Called with each complete message
+    ///
+    /// `data` is the client's read buffer handed over as is, returning it
+    /// (or a slice of it) in the action sends it without a copy.
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        data: Bytes,
+    ) -> Result<HandlerAction>;
+    fn on_disconnect(&mut self, ctx: &mut ServerContext, client_id: ClientId) -> Result<()>;
     fn is_data_complete(&mut self, data: &[u8]) -> bool;
+
+    /// Called with the client's unprocessed data after every read when
+    /// `ServerConfig::streaming_reads` is enabled, instead of
+    /// `is_data_complete` and `on_message`
+    ///
+    /// `data` holds what the handler did not consume so far followed by
+    /// the bytes just read, so a large upload can be processed piece by
+    /// piece with only the unconsumed tail kept in memory. The handler is
+    /// called again as long as it consumes bytes and some are left.
+    /// Codecs, middlewares and rate limits work on whole messages and do
+    /// not apply to streamed clients. An error disconnects the client.
+    fn on_data_chunk(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: &[u8],
+    ) -> Result<ConsumeResult> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "streaming reads need `EventHandler::on_data_chunk`",
+        ))
+    }
+
+    /// Called when a client buffered more than `ServerConfig::max_read_buffer`
+    /// bytes that were not processed yet
+    ///
+    /// Disconnects the client by default.
+    fn on_buffer_overflow(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> OverflowAction {
+        OverflowAction::Disconnect
+    }
+
+    /// Called when a connection is turned away because the server is full
+    ///
+    /// The stream is closed right after this returns, the handler may write
+    /// a short best-effort notice to it.
+    fn on_connection_rejected(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _addr: SocketAddr,
+        _stream: &TcpStream,
+    ) {
+    }
+
+    /// Called when accepting a connection failed
+    ///
+    /// Unless the error only concerns the connection being accepted, see
+    /// [`AcceptError::pauses_accepts`], the server stops accepting for
+    /// `ServerConfig::accept_error_backoff` before this is called.
+    fn on_accept_error(&mut self, _ctx: &mut ServerContext, _error: &AcceptError) {}
+
+    /// Called on errors inside the loop, with the client they are about
+    ///
+    /// The error is logged already. The returned directive decides what
+    /// follows, [`ServerError::default_directive`] by default, so
+    /// handlers can e.g. count errors against a budget, raise alerts or
+    /// stop the server.
+    fn on_error(
+        &mut self,
+        _ctx: &mut ServerContext,
+        error: &ServerError,
+        _client_id: Option<ClientId>,
+    ) -> ErrorDirective {
+        error.default_directive()
+    }
+
+    /// Called when a timer set with [`ServerContext::set_timer`] or
+    /// [`ServerContext::set_interval`] fires
+    fn on_timer(&mut self, _ctx: &mut ServerContext, _timer_id: TimerId) {}
+
+    /// Called every `ServerConfig::tick_interval`, for periodic work such
+    /// as heartbeats, cleanup or stats
+    fn on_tick(&mut self, _ctx: &mut ServerContext) {}
+
+    /// Called when the client's write queue drained, `bytes_flushed` were
+    /// written since it last did
+    ///
+    /// Lets a handler streaming a large response queue the next chunk only
+    /// once the previous ones are out, rather than queueing it all upfront.
+    fn on_write_complete(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _bytes_flushed: u64,
+    ) {
+    }
+
+    /// Called when an fd registered with [`ServerContext::register_fd`]
+    /// is ready, `flags` are the events the kernel reported
+    fn on_custom_event(&mut self, _ctx: &mut ServerContext, _token: u64, _flags: EventFlags) {}
+
+    /// Called when the pipe registered with [`ServerContext::register_pipe`]
+    /// has data to read from `fd`, or its writing end was closed
+    ///
+    /// The pipe is level-triggered: read until `WouldBlock` or the handler
+    /// is called again right away. A read returning 0 means the writer is
+    /// gone, the server then closes the pipe after its last call.
+    fn on_fd_readable(&mut self, _ctx: &mut ServerContext, _token: u64, _fd: RawFd) {}
+
+    /// Called when a path watched with [`ServerContext::watch_path`]
+    /// changed
+    fn on_file_event(&mut self, _ctx: &mut ServerContext, _watch_id: WatchId, _event: FileEvent) {}
+
+    /// Called when `EpollServer::run` stops after a shutdown request, while
+    /// every client is still connected
+    ///
+    /// This is the place to persist sessions (see `SessionStore` with the
+    /// `sessions` feature) or to queue a last message, which is flushed on
+    /// a best effort basis when the server is dropped.
+    fn on_shutdown(&mut self, _ctx: &mut ServerContext) {}
+
+    /// Called when a message from the client exceeded
+    /// `ServerConfig::client_rate_limit` or `ServerConfig::ip_rate_limit`
+    ///
+    /// The configured `RateLimitAction` is applied after this returns, it
+    /// decides whether the message is dropped or delivered later.
+    fn on_rate_limited(&mut self, _ctx: &mut ServerContext, _client_id: ClientId) {}
+
+    /// Called once the client's codec completed a TLS handshake, before any
+    /// of its application data is handed to the handler
+    ///
+    /// `peer_identity` carries the certificate chain the client presented,
+    /// verified with `TlsConfig::from_pem_files_with_client_auth`, and the
+    /// host name it asked for. Returning an error, or disconnecting the
+    /// client, turns it away unheard, see `ServerError::Handler`.
+    fn on_tls_handshake(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _peer_identity: &PeerIdentity,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Boxed handlers are handlers too, so a server can be an
+/// `EpollServer<Box<dyn EventHandler + Send>>` whose behaviour is picked at
+/// runtime and swapped with `replace_handler`
+impl<T: EventHandler + ?Sized> EventHandler for Box<T> {
+    fn on_connection(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        listener_id: ListenerId,
+        stream: &TcpStream,
+    ) -> Result<()> {
+        (**self).on_connection(ctx, client_id, listener_id, stream)
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        data: Bytes,
+    ) -> Result<HandlerAction> {
+        (**self).on_message(ctx, client_id, data)
+    }
+
+    fn on_disconnect(&mut self, ctx: &mut ServerContext, client_id: ClientId) -> Result<()> {
+        (**self).on_disconnect(ctx, client_id)
+    }
+
+    fn is_data_complete(&mut self, data: &[u8]) -> bool {
+        (**self).is_data_complete(data)
+    }
+
+    fn on_data_chunk(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        data: &[u8],
+    ) -> Result<ConsumeResult> {
+        (**self).on_data_chunk(ctx, client_id, data)
+    }
+
+    fn on_buffer_overflow(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+    ) -> OverflowAction {
+        (**self).on_buffer_overflow(ctx, client_id)
+    }
+
+    fn on_connection_rejected(
+        &mut self,
+        ctx: &mut ServerContext,
+        addr: SocketAddr,
+        stream: &TcpStream,
+    ) {
+        (**self).on_connection_rejected(ctx, addr, stream)
+    }
+
+    fn on_accept_error(&mut self, ctx: &mut ServerContext, error: &AcceptError) {
+        (**self).on_accept_error(ctx, error)
+    }
+
+    fn on_error(
+        &mut self,
+        ctx: &mut ServerContext,
+        error: &ServerError,
+        client_id: Option<ClientId>,
+    ) -> ErrorDirective {
+        (**self).on_error(ctx, error, client_id)
+    }
+
+    fn on_timer(&mut self, ctx: &mut ServerContext, timer_id: TimerId) {
+        (**self).on_timer(ctx, timer_id)
+    }
+
+    fn on_tick(&mut self, ctx: &mut ServerContext) {
+        (**self).on_tick(ctx)
+    }
+
+    fn on_write_complete(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        bytes_flushed: u64,
+    ) {
+        (**self).on_write_complete(ctx, client_id, bytes_flushed)
+    }
+
+    fn on_custom_event(&mut self, ctx: &mut ServerContext, token: u64, flags: EventFlags) {
+        (**self).on_custom_event(ctx, token, flags)
+    }
+
+    fn on_fd_readable(&mut self, ctx: &mut ServerContext, token: u64, fd: RawFd) {
+        (**self).on_fd_readable(ctx, token, fd)
+    }
+
+    fn on_file_event(&mut self, ctx: &mut ServerContext, watch_id: WatchId, event: FileEvent) {
+        (**self).on_file_event(ctx, watch_id, event)
+    }
+
+    fn on_shutdown(&mut self, ctx: &mut ServerContext) {
+        (**self).on_shutdown(ctx)
+    }
+
+    fn on_rate_limited(&mut self, ctx: &mut ServerContext, client_id: ClientId) {
+        (**self).on_rate_limited(ctx, client_id)
+    }
+
+    fn on_tls_handshake(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        peer_identity: &PeerIdentity,
+    ) -> Result<()> {
+        (**self).on_tls_handshake(ctx, client_id, peer_identity)
+    }
 }
@@ -0,0 +1,292 @@
+use std::{
+    ffi::c_void,
+    io::{Error, ErrorKind, Result},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use log::{debug, error};
+
+use crate::{
+    Event, ep_syscall,
+    epoll::{Operation, ctl_error},
+    ffi::{
+        IORING_ENTER_GETEVENTS, IORING_FEAT_SINGLE_MMAP, IORING_OFF_CQ_RING, IORING_OFF_SQ_RING,
+        IORING_OFF_SQES, IORING_OP_EPOLL_CTL, IORING_SETUP_SUBMIT_ALL, IoUringCqe, IoUringParams,
+        IoUringSqe, MAP_SHARED_POPULATE, PROT_READ_WRITE, SYS_IO_URING_ENTER, SYS_IO_URING_SETUP,
+    },
+};
+
+/// Submission queue size, a full queue is submitted right away
+const RING_ENTRIES: u32 = 256;
+
+/// One `mmap`ed region of the ring
+struct Mapping {
+    ptr: NonNull<c_void>,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize, offset: i64) -> Result<Self> {
+        // SAFETY: a fresh shared mapping of the ring fd, checked below
+        let ptr = unsafe {
+            crate::ffi::mmap(
+                ptr::null_mut(),
+                len,
+                PROT_READ_WRITE,
+                MAP_SHARED_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr as isize == -1 {
+            return Err(Error::last_os_error());
+        }
+        let ptr = NonNull::new(ptr).ok_or_else(Error::last_os_error)?;
+        Ok(Mapping { ptr, len })
+    }
+
+    /// Pointer to the `T` at `offset` bytes into the mapping
+    fn at<T>(&self, offset: u32) -> *mut T {
+        // SAFETY: the kernel reported `offset` as inside the mapping
+        unsafe { self.ptr.as_ptr().byte_add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is not used after this
+        if unsafe { crate::ffi::munmap(self.ptr.as_ptr(), self.len) } < 0 {
+            error!("Failed to unmap io_uring ring: {}", Error::last_os_error());
+        }
+    }
+}
+
+/// io_uring instance submitting `epoll_ctl` operations in batches
+///
+/// Interest changes are queued as `IORING_OP_EPOLL_CTL` entries and
+/// submitted together by `Ring::submit`, one `io_uring_enter` for what
+/// would otherwise be one `epoll_ctl` each. The kernel copies the
+/// `epoll_event` while submitting, so queued events only have to live
+/// until then. `submit` waits for their completions and reports the
+/// first failure, like the `epoll_ctl` calls would have.
+pub(crate) struct Ring {
+    fd: OwnedFd,
+    /// Both rings when the kernel maps them together
+    rings: Mapping,
+    /// Completion ring, when mapped apart from the submission ring
+    cq_ring: Option<Mapping>,
+    sqes: Mapping,
+    params: IoUringParams,
+    /// Events read by the queued entries, kept until they are submitted
+    events: Vec<Event>,
+    /// Operation and fd of each queued entry, indexed by its `user_data`
+    operations: Vec<(Operation, RawFd)>,
+    /// Entries queued since the last submission
+    queued: u32,
+}
+
+// SAFETY: the mappings belong to this ring only and are never shared, so
+// the ring can move to another thread with the server owning it
+unsafe impl Send for Ring {}
+
+impl Ring {
+    pub fn new() -> Result<Self> {
+        let mut params = IoUringParams {
+            flags: IORING_SETUP_SUBMIT_ALL,
+            ..IoUringParams::default()
+        };
+        // The kernel always creates ring fds close-on-exec
+        let fd = ep_syscall!(syscall(
+            SYS_IO_URING_SETUP,
+            i64::from(RING_ENTRIES),
+            &raw mut params
+        ))? as RawFd;
+        // SAFETY: the fd was just created and nothing else owns it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len = params.cq_off.cqes as usize
+            + params.cq_entries as usize * std::mem::size_of::<IoUringCqe>();
+        let single_mmap = params.features & IORING_FEAT_SINGLE_MMAP != 0;
+
+        // On failure the fd and the mappings made so far are dropped, and
+        // with them closed and unmapped
+        let rings_len = if single_mmap {
+            sq_len.max(cq_len)
+        } else {
+            sq_len
+        };
+        let rings = Mapping::new(fd.as_raw_fd(), rings_len, IORING_OFF_SQ_RING)?;
+        let cq_ring = match single_mmap {
+            true => None,
+            false => Some(Mapping::new(fd.as_raw_fd(), cq_len, IORING_OFF_CQ_RING)?),
+        };
+        let sqes_len = params.sq_entries as usize * std::mem::size_of::<IoUringSqe>();
+        let sqes = Mapping::new(fd.as_raw_fd(), sqes_len, IORING_OFF_SQES)?;
+
+        debug!("io_uring instance created with fd: `{}`", fd.as_raw_fd());
+        Ok(Ring {
+            fd,
+            rings,
+            cq_ring,
+            sqes,
+            events: Vec::with_capacity(params.sq_entries as usize),
+            operations: Vec::with_capacity(params.sq_entries as usize),
+            params,
+            queued: 0,
+        })
+    }
+
+    /// Queue an `epoll_ctl(epfd, op, fd, event)`
+    pub fn queue_epoll_ctl(
+        &mut self,
+        epfd: RawFd,
+        op: Operation,
+        fd: RawFd,
+        event: Option<Event>,
+    ) -> Result<()> {
+        // Entries still in flight point into `events`, which must not
+        // grow past its capacity and move
+        if self.operations.len() == self.params.sq_entries as usize {
+            self.submit()?;
+        }
+
+        let addr = match event {
+            Some(event) => {
+                self.events.push(event);
+                // The vector never grows past its capacity, so the
+                // pointers of the queued entries stay valid
+                self.events
+                    .last()
+                    .map_or(0, |event| event as *const Event as u64)
+            }
+            None => 0,
+        };
+        let user_data = self.operations.len() as u64;
+        self.operations.push((op, fd));
+        let sqe = IoUringSqe {
+            opcode: IORING_OP_EPOLL_CTL,
+            fd: epfd,
+            off: fd as u64,
+            addr,
+            len: i32::from(op) as u32,
+            user_data,
+            ..IoUringSqe::default()
+        };
+
+        let sq = &self.params.sq_off;
+        // SAFETY: the offsets come from the kernel, and the loop is the
+        // only producer of the submission queue
+        unsafe {
+            let tail = &*self.rings.at::<AtomicU32>(sq.tail);
+            let mask = *self.rings.at::<u32>(sq.ring_mask);
+            let index = tail.load(Ordering::Relaxed).wrapping_add(self.queued) & mask;
+            self.sqes.at::<IoUringSqe>(0).add(index as usize).write(sqe);
+            self.rings
+                .at::<u32>(sq.array)
+                .add(index as usize)
+                .write(index);
+        }
+        self.queued += 1;
+        Ok(())
+    }
+
+    /// Submit every queued entry with a single `io_uring_enter`, failing
+    /// with the error of the first entry that failed
+    ///
+    /// If the submission itself fails, the entries it did not take stay
+    /// queued with their events, to be submitted by the next call.
+    pub fn submit(&mut self) -> Result<()> {
+        if self.queued == 0 {
+            return Ok(());
+        }
+
+        // SAFETY: see `queue_epoll_ctl`, the release store publishes the
+        // entries written before it
+        let tail = unsafe { &*self.rings.at::<AtomicU32>(self.params.sq_off.tail) };
+        let first = tail.load(Ordering::Relaxed);
+        tail.store(first.wrapping_add(self.queued), Ordering::Release);
+
+        let mut submitted = 0;
+        while submitted < self.queued {
+            let remaining = self.queued - submitted;
+            match ep_syscall!(syscall(
+                SYS_IO_URING_ENTER,
+                i64::from(self.fd.as_raw_fd()),
+                i64::from(remaining),
+                i64::from(remaining),
+                i64::from(IORING_ENTER_GETEVENTS),
+                ptr::null::<c_void>(),
+                0i64
+            )) {
+                Ok(count) => submitted += count as u32,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                // An `io_uring_enter` failing after submitting entries
+                // returns their number instead, so none of the remaining
+                // ones was consumed: they are taken back, to be published
+                // again by the next call
+                Err(e) => {
+                    tail.store(first.wrapping_add(submitted), Ordering::Release);
+                    self.retire(submitted);
+                    return Err(e);
+                }
+            }
+        }
+        debug!("Submitted {} queued epoll_ctl", submitted);
+        self.retire(submitted);
+        self.reap_completions()
+    }
+
+    /// Forget the first `count` queued entries, now in the kernel's hands
+    fn retire(&mut self, count: u32) {
+        if count == self.queued {
+            self.queued = 0;
+            self.events.clear();
+        } else {
+            // Later entries point into `events`, which must not move: it is
+            // only cleared once they are all submitted
+            self.queued -= count;
+        }
+    }
+
+    /// Collect the completions of the submitted entries, failing with the
+    /// first error among them
+    fn reap_completions(&mut self) -> Result<()> {
+        let cq = self.params.cq_off;
+        let cq_ring = self.cq_ring.as_ref().unwrap_or(&self.rings);
+        let mut failure = None;
+        // SAFETY: the offsets come from the kernel, and the loop is the
+        // only consumer of the completion queue
+        unsafe {
+            let head = &*cq_ring.at::<AtomicU32>(cq.head);
+            let tail = (*cq_ring.at::<AtomicU32>(cq.tail)).load(Ordering::Acquire);
+            let mask = *cq_ring.at::<u32>(cq.ring_mask);
+            let cqes = cq_ring.at::<IoUringCqe>(cq.cqes);
+
+            let mut next = head.load(Ordering::Relaxed);
+            while next != tail {
+                let cqe = cqes.add((next & mask) as usize).read();
+                next = next.wrapping_add(1);
+                if cqe.res >= 0 {
+                    continue;
+                }
+                let Some(&(op, fd)) = self.operations.get(cqe.user_data as usize) else {
+                    continue;
+                };
+                let e = crate::Error::syscall("epoll_ctl", Error::from_raw_os_error(-cqe.res));
+                let e = ctl_error(op, fd, e);
+                match failure {
+                    None => failure = Some(e),
+                    Some(_) => error!("epoll_ctl on fd {} failed: {}", fd, e),
+                }
+            }
+            head.store(next, Ordering::Release);
+        }
+        if self.queued == 0 {
+            self.operations.clear();
+        }
+        failure.map_or(Ok(()), Err)
+    }
+}
@@ -1,28 +1,128 @@
+mod client_id;
 mod epoll;
-mod ffi;
+pub mod reactor;
+mod signal;
+#[cfg(all(windows, feature = "wepoll"))]
+mod wepoll;
+
+#[cfg(any(target_os = "linux", feature = "wepoll"))]
 pub(crate) use epoll::*;
 
-mod epoll_server;
-mod handler;
+pub use client_id::{ClientId, ClientIdAllocator, MAX_CLIENT_ID};
+pub use epoll::EventFlags;
+pub use reactor::Backend;
+pub use signal::SignalMask;
+
+/// Compiles `items` on Linux only
+///
+/// The server relies on Linux only system calls (timerfd, eventfd,
+/// inotify, `sendfile`...). On other platforms the crate is the readiness
+/// API of the `reactor` module, with `Wepoll` on Windows.
+macro_rules! linux_only {
+    ($($item:item)*) => {
+        $(
+            #[cfg(target_os = "linux")]
+            $item
+        )*
+    };
+}
 
-mod client_state;
+linux_only! {
+    mod ffi;
+    mod epoll_server;
+    pub mod fdpass;
+    mod handler;
+    mod io_uring;
+    pub mod protocol;
+    pub mod watch;
 
-pub use epoll_server::{ClientId, EpollServer};
-pub use handler::{EventHandler, HandlerAction};
+    mod accept_error;
+    mod accept_filter;
+    mod acceptor;
+    mod access_log;
+    mod alpn;
+    #[cfg(feature = "async")]
+    pub mod async_handler;
+    mod audit;
+    mod broadcaster;
+    mod buffer_pool;
+    mod bytes;
+    mod chaos;
+    mod client_data;
+    mod client_slab;
+    mod client_state;
+    mod config;
+    mod context;
+    mod datagram;
+    mod error;
+    mod metrics;
+    mod metrics_endpoint;
+    mod middleware;
+    mod mock_poller;
+    mod rate_limit;
+    mod rooms;
+    mod server_handle;
+    mod server_reactor;
+    #[cfg(feature = "sessions")]
+    mod session;
+    mod sockopt;
+    mod stats;
+    mod systemd;
+    mod telemetry;
+    mod timeout_policy;
+    mod timer;
+    mod trace;
+
+    pub use accept_error::AcceptError;
+    pub use accept_filter::{AcceptFilter, AllowList, Cidr, DenyList};
+    pub use acceptor::{Acceptor, Distribution};
+    pub use access_log::{AccessLog, AccessLogFormat};
+    pub use alpn::AlpnDispatcher;
+    pub use audit::{AuditEvent, AuditSink, DisconnectReason};
+    pub use broadcaster::Broadcaster;
+    pub use bytes::Bytes;
+    #[cfg(feature = "chaos")]
+    pub use chaos::ChaosConfig;
+    pub use client_state::{Priority, WriteStats};
+    pub use config::{CodecFactory, ServerConfig, TriggerMode};
+    pub use context::{ListenerId, PRIMARY_LISTENER, ServerContext};
+    pub use datagram::DatagramHandler;
+    pub use epoll_server::{EpollServer, RebindPolicy};
+    pub use error::{Error, Result};
+    pub use handler::{
+        ConsumeResult, DataSource, ErrorDirective, ErrorPolicy, EventHandler, HandlerAction,
+        HandlerError, OverflowAction, ServerError,
+    };
+    pub use metrics::Metrics;
+    pub use middleware::Middleware;
+    pub use rate_limit::{RateLimit, RateLimitAction};
+    pub use server_handle::ServerHandle;
+    #[cfg(feature = "sessions")]
+    pub use session::SessionStore;
+    pub use sockopt::TcpKeepalive;
+    pub use stats::AcceptStats;
+    pub use telemetry::{MessageTrace, Telemetry};
+    pub use timer::TimerId;
+}
 
 /// This is a helper macro to do syscall
 ///
 /// Basically we want to call function with zero, one or more arguments
 /// So we have the below format to match
 ///
+/// ```text
 ///     epoll_create1(1) or epoll_ctl(1,1,1, &raw mut Event)
+/// ```
 ///
 /// we do exactly that in the macro that is
 ///
+/// ```text
 ///     identifier bracket_open zero_or_more_expression bracker_close
+/// ```
 ///
 /// Note: In a function call trailing comman in arguments is ignored
 /// if atleast one argument is present by Rust
+#[cfg(target_os = "linux")]
 macro_rules! ep_syscall {
     ($epoll_fn:ident ( $($arg:expr),* )) => {{
 
@@ -34,11 +134,39 @@ macro_rules! ep_syscall {
         let result = unsafe { $crate::ffi::$epoll_fn($($arg,)*) };
 
         if result < 0 {
-            Err(std::io::Error::last_os_error())
+            let error = std::io::Error::last_os_error();
+            match error.kind() {
+                // Control flow rather than failures, kept allocation free
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted => Err(error),
+                _ => Err($crate::error::Error::syscall(stringify!($epoll_fn), error)),
+            }
         } else {
             Ok(result)
         }
     }};
 }
 
+#[cfg(target_os = "linux")]
 pub(crate) use ep_syscall;
+
+/// Same as `ep_syscall!`, calling again as long as the call fails with
+/// `EINTR`
+///
+/// Meant for calls that may block or are not otherwise retried by their
+/// caller, like `accept4` or `recvmsg` on a blocking socket. Never for
+/// `close`, which must not be called twice, nor for `epoll_wait`, whose
+/// interruption lets the loop check the shutdown signal.
+#[cfg(target_os = "linux")]
+macro_rules! ep_syscall_retry {
+    ($epoll_fn:ident ( $($arg:expr),* )) => {{
+        loop {
+            match $crate::ep_syscall!($epoll_fn($($arg),*)) {
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                result => break result,
+            }
+        }
+    }};
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) use ep_syscall_retry;
@@ -0,0 +1,135 @@
+use std::fmt::{self, Write};
+
+/// Counters of the event loop, since the server was created
+///
+/// Taken as a snapshot with `EpollServer::metrics`, or reported
+/// periodically when `ServerConfig::metrics_interval` is set. Displayed
+/// as a single line of `name=value` pairs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Metrics {
+    /// Connections accepted and handed to the handler
+    pub accepted: u64,
+    /// Clients connected at the time of the snapshot
+    pub active: u64,
+    /// Clients disconnected, by either side
+    pub disconnected: u64,
+    /// Bytes read from client sockets
+    pub bytes_read: u64,
+    /// Bytes handed to the kernel for client sockets
+    pub bytes_written: u64,
+    /// Calls to `epoll_wait`, including the ones that timed out
+    pub waits: u64,
+    /// Events returned by all `epoll_wait` calls
+    pub events: u64,
+    /// Most events returned by a single `epoll_wait`
+    pub max_events_per_wait: u64,
+    /// Errors returned by `on_connection` and `on_message`
+    pub handler_errors: u64,
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "active={} accepted={} disconnected={} bytes_read={} bytes_written={} \
+             waits={} events_per_wait={:.2} max_events_per_wait={} handler_errors={}",
+            self.active,
+            self.accepted,
+            self.disconnected,
+            self.bytes_read,
+            self.bytes_written,
+            self.waits,
+            self.events_per_wait(),
+            self.max_events_per_wait,
+            self.handler_errors
+        )
+    }
+}
+
+impl Metrics {
+    /// Mean number of events returned per `epoll_wait`
+    pub fn events_per_wait(&self) -> f64 {
+        if self.waits == 0 {
+            return 0.0;
+        }
+        self.events as f64 / self.waits as f64
+    }
+
+    /// Render the counters in the Prometheus text exposition format
+    ///
+    /// This is what the endpoint enabled by `ServerConfig::metrics_addr`
+    /// serves, it can also be used to export the metrics elsewhere.
+    pub fn prometheus_text(&self) -> String {
+        let samples: [(&str, &str, &str, u64); 9] = [
+            (
+                "connections_accepted_total",
+                "counter",
+                "Connections accepted",
+                self.accepted,
+            ),
+            (
+                "connections_active",
+                "gauge",
+                "Clients currently connected",
+                self.active,
+            ),
+            (
+                "connections_disconnected_total",
+                "counter",
+                "Clients disconnected",
+                self.disconnected,
+            ),
+            (
+                "read_bytes_total",
+                "counter",
+                "Bytes read from clients",
+                self.bytes_read,
+            ),
+            (
+                "written_bytes_total",
+                "counter",
+                "Bytes written to clients",
+                self.bytes_written,
+            ),
+            (
+                "epoll_waits_total",
+                "counter",
+                "Calls to epoll_wait",
+                self.waits,
+            ),
+            (
+                "epoll_events_total",
+                "counter",
+                "Events returned by epoll_wait",
+                self.events,
+            ),
+            (
+                "epoll_max_events_per_wait",
+                "gauge",
+                "Most events returned by a single epoll_wait",
+                self.max_events_per_wait,
+            ),
+            (
+                "handler_errors_total",
+                "counter",
+                "Errors returned by the handler",
+                self.handler_errors,
+            ),
+        ];
+
+        let mut text = String::new();
+        for (name, kind, help, value) in samples {
+            // Writing to a String cannot fail
+            let _ = writeln!(text, "# HELP epoll_worker_{name} {help}");
+            let _ = writeln!(text, "# TYPE epoll_worker_{name} {kind}");
+            let _ = writeln!(text, "epoll_worker_{name} {value}");
+        }
+        text
+    }
+
+    pub(crate) fn record_wait(&mut self, events: usize) {
+        self.waits += 1;
+        self.events += events as u64;
+        self.max_events_per_wait = self.max_events_per_wait.max(events as u64);
+    }
+}
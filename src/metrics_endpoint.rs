@@ -0,0 +1,149 @@
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Result, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::fd::{AsRawFd, RawFd},
+};
+
+use log::{debug, error};
+
+use crate::{Event, EventFlags, PeerRole, metrics::Metrics, reactor::Reactor, sockopt};
+
+/// Largest scrape request we are willing to buffer
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// Scrapes served at the same time, further connections are closed
+const MAX_SCRAPES: usize = 64;
+
+/// Built-in HTTP listener serving `/metrics` in Prometheus text format
+///
+/// Runs in the server's epoll loop next to the clients. Every scrape is
+/// one request on its own connection: once the request is read the
+/// response is written without blocking and the connection is closed. The
+/// response is small enough for a fresh socket buffer, if it does not fit
+/// the scrape simply fails.
+pub(crate) struct MetricsEndpoint {
+    listener: TcpListener,
+    /// Connections whose request is not complete yet, by fd
+    scrapes: HashMap<RawFd, (TcpStream, Vec<u8>)>,
+}
+
+impl MetricsEndpoint {
+    pub fn bind(addr: SocketAddr, epoll: &impl Reactor) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        let fd = listener.as_raw_fd();
+        let flags = EventFlags::READ | EventFlags::EDGE;
+        epoll.add_interest(fd, Event::new(flags, PeerRole::Metrics(fd as u64)))?;
+        debug!("Metrics endpoint listening on {}", listener.local_addr()?);
+
+        Ok(MetricsEndpoint {
+            listener,
+            scrapes: HashMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Handle readiness of the listener or of a scrape connection
+    pub fn handle_event(&mut self, fd: RawFd, epoll: &impl Reactor, metrics: &Metrics) {
+        if fd == self.listener.as_raw_fd() {
+            self.accept_scrapes(epoll);
+        } else {
+            self.read_scrape(fd, metrics);
+        }
+    }
+
+    fn accept_scrapes(&mut self, epoll: &impl Reactor) {
+        loop {
+            let stream = match sockopt::accept(&self.listener) {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("Error accepting metrics scrape: {}", e);
+                    return;
+                }
+            };
+            if self.scrapes.len() >= MAX_SCRAPES {
+                debug!("Too many metrics scrapes in progress, closing connection");
+                continue;
+            }
+
+            let fd = stream.as_raw_fd();
+            let flags = EventFlags::READ | EventFlags::EDGE;
+            match epoll.add_interest(fd, Event::new(flags, PeerRole::Metrics(fd as u64))) {
+                Ok(()) => {
+                    self.scrapes.insert(fd, (stream, Vec::new()));
+                }
+                Err(e) => error!("Failed to register metrics scrape: {}", e),
+            }
+        }
+    }
+
+    /// Read the scrape's request and answer it once complete
+    ///
+    /// Dropping the stream closes the fd, which also removes it from epoll.
+    fn read_scrape(&mut self, fd: RawFd, metrics: &Metrics) {
+        let Some((stream, request)) = self.scrapes.get_mut(&fd) else {
+            return;
+        };
+
+        let mut buffer = [0u8; 1024];
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => request.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    debug!("Metrics scrape failed: {}", e);
+                    self.scrapes.remove(&fd);
+                    return;
+                }
+            }
+        }
+
+        if !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            if request.len() > MAX_REQUEST_LEN {
+                self.scrapes.remove(&fd);
+            }
+            return;
+        }
+
+        let Some((mut stream, request)) = self.scrapes.remove(&fd) else {
+            return;
+        };
+        let response = response_to(&request, metrics);
+        if let Err(e) = stream.write_all(&response) {
+            debug!("Failed to send metrics: {}", e);
+        }
+    }
+}
+
+fn response_to(request: &[u8], metrics: &Metrics) -> Vec<u8> {
+    let request_line = request.split(|&byte| byte == b'\r').next().unwrap_or(&[]);
+    let mut parts = request_line.split(|&byte| byte == b' ');
+    let (method, path) = (parts.next(), parts.next());
+
+    let (status, content_type, body) = match (method, path) {
+        (Some(b"GET"), Some(b"/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            metrics.prometheus_text(),
+        ),
+        (Some(b"GET"), Some(_)) => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method Not Allowed\n".to_string(),
+        ),
+    };
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .into_bytes()
+}
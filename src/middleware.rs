@@ -0,0 +1,100 @@
+use std::{io::Result, net::SocketAddr};
+
+use crate::client_id::ClientId;
+
+/// Cross-cutting layer run by the server around the `EventHandler`
+///
+/// Middlewares are installed with `EpollServer::add_middleware` and form an
+/// ordered chain: inbound hooks run from the first middleware to the last
+/// before the handler sees anything, outbound data goes through the chain
+/// in reverse, so the first middleware is the one closest to the wire.
+/// Logging, compression, metrics or authentication can then be stacked
+/// without touching the handler.
+///
+/// Every hook has a pass-through default.
+pub trait Middleware {
+    /// Called for a new client before `on_connection`
+    ///
+    /// Returning `false` closes the connection, the handler and the
+    /// middlewares after this one never see it.
+    fn on_accept(&mut self, _client_id: ClientId, _peer_addr: SocketAddr) -> bool {
+        true
+    }
+
+    /// Called with each complete message before `on_message`
+    ///
+    /// Returns the data to pass on, or `None` to drop the message.
+    /// An error disconnects the client.
+    fn on_inbound_data(&mut self, _client_id: ClientId, data: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(Some(data))
+    }
+
+    /// Called with each message sent to the client, before its codec
+    /// frames it
+    fn on_outbound_data(&mut self, _client_id: ClientId, data: Vec<u8>) -> Vec<u8> {
+        data
+    }
+
+    /// Called once the server acted on the handler's answer to a message,
+    /// with the name of the action (see `HandlerAction::name`) or `"error"`
+    /// if `on_message` failed
+    ///
+    /// Data sent meanwhile went through `on_outbound_data` already.
+    fn on_message_handled(&mut self, _client_id: ClientId, _outcome: &'static str) {}
+
+    /// Called when a client leaves, before `on_disconnect`
+    fn on_disconnect(&mut self, _client_id: ClientId) {}
+}
+
+/// Middlewares of the server, in the order they were added
+#[derive(Default)]
+pub(crate) struct MiddlewareChain {
+    middlewares: Vec<Box<dyn Middleware + Send>>,
+}
+
+impl MiddlewareChain {
+    pub fn push(&mut self, middleware: Box<dyn Middleware + Send>) {
+        self.middlewares.push(middleware);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.middlewares.is_empty()
+    }
+
+    pub fn accept(&mut self, client_id: ClientId, peer_addr: SocketAddr) -> bool {
+        self.middlewares
+            .iter_mut()
+            .all(|middleware| middleware.on_accept(client_id, peer_addr))
+    }
+
+    pub fn inbound(&mut self, client_id: ClientId, mut data: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        for middleware in &mut self.middlewares {
+            match middleware.on_inbound_data(client_id, data)? {
+                Some(passed) => data = passed,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(data))
+    }
+
+    pub fn outbound(&mut self, client_id: ClientId, data: Vec<u8>) -> Vec<u8> {
+        self.middlewares
+            .iter_mut()
+            .rev()
+            .fold(data, |data, middleware| {
+                middleware.on_outbound_data(client_id, data)
+            })
+    }
+
+    pub fn handled(&mut self, client_id: ClientId, outcome: &'static str) {
+        for middleware in &mut self.middlewares {
+            middleware.on_message_handled(client_id, outcome);
+        }
+    }
+
+    pub fn disconnect(&mut self, client_id: ClientId) {
+        for middleware in &mut self.middlewares {
+            middleware.on_disconnect(client_id);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Result,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use crate::{
+    epoll::{Event, EventFlags, PeerRole},
+    reactor::{RawSource, Reactor},
+    signal::SignalMask,
+};
+
+/// Flags reported whatever the interests, as epoll does
+const ALWAYS_REPORTED: EventFlags =
+    EventFlags::from_bits(EventFlags::ERROR.bits() | EventFlags::HANGUP.bits());
+
+#[derive(Default)]
+struct MockState {
+    /// Registered sources, with their interests and the role they were
+    /// registered under
+    registrations: HashMap<RawSource, (EventFlags, PeerRole)>,
+    /// Injected readiness, reported by the next waits
+    ready: VecDeque<(RawSource, EventFlags)>,
+    waits: u64,
+}
+
+/// [`Reactor`] reporting only the readiness test code injects
+///
+/// A server created with `EpollServer::with_mock_poller` registers its
+/// listener, clients, timers and waker with the poller as usual, but
+/// `wait` never blocks nor asks the OS: it returns the events queued with
+/// [`MockPoller::inject`], so every step of the loop is decided by the
+/// test, without sleeping for sockets to become ready. The poller is a
+/// handle, clones share the same state.
+///
+/// Injected flags are filtered by the source's interests when it is
+/// waited for, like epoll would: a paused client gets no `READ`, errors
+/// and hangups always pass. One-shot registrations are disabled once
+/// reported. Sources need not be real fds, e.g. `EpollServer::register_fd`
+/// accepts any number to inject custom events for.
+#[derive(Clone, Default)]
+pub struct MockPoller {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockPoller {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report `flags` for `source` from the next wait
+    ///
+    /// Fails with `Error::NotRegistered` if `source` is not registered.
+    pub fn inject(&self, source: RawSource, flags: EventFlags) -> crate::Result<()> {
+        let mut state = self.state();
+        if !state.registrations.contains_key(&source) {
+            return Err(crate::Error::NotRegistered(source));
+        }
+        state.ready.push_back((source, flags));
+        Ok(())
+    }
+
+    /// Source registered under `role`, e.g. `PeerRole::Client(id)`
+    pub fn source_of(&self, role: PeerRole) -> Option<RawSource> {
+        self.state()
+            .registrations
+            .iter()
+            .find(|(_, (_, registered))| *registered == role)
+            .map(|(source, _)| *source)
+    }
+
+    /// Interests `source` is registered with, `None` if it is not
+    pub fn interests(&self, source: RawSource) -> Option<EventFlags> {
+        self.state()
+            .registrations
+            .get(&source)
+            .map(|(flags, _)| *flags)
+    }
+
+    /// Every registered source, with its interests and role
+    pub fn registrations(&self) -> Vec<(RawSource, EventFlags, PeerRole)> {
+        self.state()
+            .registrations
+            .iter()
+            .map(|(source, (flags, role))| (*source, *flags, *role))
+            .collect()
+    }
+
+    /// How many times `wait` was called
+    pub fn waits(&self) -> u64 {
+        self.state().waits
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        // The state is consistent after every operation, even one that
+        // panicked in another thread
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Reactor for MockPoller {
+    fn new() -> Result<Self> {
+        Ok(MockPoller::default())
+    }
+
+    fn add_interest(&self, source: RawSource, event: Event) -> Result<()> {
+        let mut state = self.state();
+        if state.registrations.contains_key(&source) {
+            return Err(crate::Error::AlreadyRegistered(source).into());
+        }
+        state
+            .registrations
+            .insert(source, (event.flags(), event.role()));
+        Ok(())
+    }
+
+    fn modify_interest(&self, source: RawSource, event: Event) -> Result<()> {
+        match self.state().registrations.get_mut(&source) {
+            Some(registration) => {
+                *registration = (event.flags(), event.role());
+                Ok(())
+            }
+            None => Err(crate::Error::NotRegistered(source).into()),
+        }
+    }
+
+    fn remove_interest(&self, source: RawSource) -> Result<()> {
+        let mut state = self.state();
+        if state.registrations.remove(&source).is_none() {
+            return Err(crate::Error::NotRegistered(source).into());
+        }
+        state.ready.retain(|(ready, _)| *ready != source);
+        Ok(())
+    }
+
+    fn wait(
+        &self,
+        events: &mut Vec<Event>,
+        _timeout: Option<Duration>,
+        _sigmask: Option<&SignalMask>,
+    ) -> Result<()> {
+        let mut state = self.state();
+        state.waits += 1;
+        while events.len() < events.capacity() {
+            let Some((source, flags)) = state.ready.pop_front() else {
+                break;
+            };
+            let Some((interests, role)) = state.registrations.get_mut(&source) else {
+                continue;
+            };
+            let reported = flags & (*interests | ALWAYS_REPORTED);
+            if reported.is_empty() {
+                continue;
+            }
+            if interests.contains(EventFlags::ONESHOT) {
+                *interests = EventFlags::empty();
+            }
+            events.push(Event::new(reported, *role));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use std::io::Result;
+
+use super::ReadBuf;
+
+/// Transport a client speaks, as reported by its codec
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// No codec, the handler sees raw bytes
+    Raw,
+    /// Frames prefixed with their length as a big-endian `u32`
+    LengthPrefixed,
+    /// WebSocket (RFC 6455)
+    WebSocket,
+    /// Lines terminated by `\r\n`
+    Line,
+    /// TLS without further framing
+    Tls,
+    /// Telnet, with its commands stripped
+    Telnet,
+    /// HTTP/1.x requests
+    Http,
+    /// Codec is still waiting for enough bytes to tell
+    Unknown,
+}
+
+/// Who is at the other end of a secured connection, see
+/// `EventHandler::on_tls_handshake`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PeerIdentity {
+    /// DER encoded certificate chain the client presented and the server
+    /// verified, its own certificate first, empty if it sent none
+    pub certificates: Vec<Vec<u8>>,
+    /// Host name the client asked for with SNI
+    pub server_name: Option<String>,
+    /// Application protocol agreed on with ALPN, e.g. `h2` or `http/1.1`
+    pub alpn_protocol: Option<Vec<u8>>,
+}
+
+impl PeerIdentity {
+    pub fn new(certificates: Vec<Vec<u8>>, server_name: Option<String>) -> Self {
+        PeerIdentity {
+            certificates,
+            server_name,
+            alpn_protocol: None,
+        }
+    }
+
+    pub fn with_alpn_protocol(mut self, protocol: Option<Vec<u8>>) -> Self {
+        self.alpn_protocol = protocol;
+        self
+    }
+}
+
+/// Result of decoding bytes received from a client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// Application message passed to `EventHandler::on_message`
+    Message(Vec<u8>),
+    /// Protocol level bytes written back to the client as-is
+    /// (handshake responses, pongs)
+    Control(Vec<u8>),
+    /// Bytes were consumed without producing anything to act on,
+    /// e.g. a pong or the first fragment of a message
+    Consumed,
+    /// Client asked to close the connection
+    Close,
+}
+
+/// Framing layer between the socket and the handler
+///
+/// The server keeps one codec per client. Received bytes are decoded
+/// frame by frame, and every message sent to the client through
+/// `ServerContext::send_to` or a `HandlerAction` is encoded first.
+pub trait Codec {
+    /// Decode one frame from the start of `buf`
+    ///
+    /// Returns the number of bytes consumed together with the frame,
+    /// or `None` if `buf` does not hold a complete frame yet.
+    /// An error disconnects the client.
+    fn decode(&mut self, buf: &[u8]) -> Result<Option<(usize, Frame)>>;
+
+    /// Decode one frame from the front of the client's read buffer,
+    /// consuming its bytes
+    ///
+    /// The default makes the buffered bytes contiguous, a copy only when
+    /// they wrap around the end of the buffer, and calls [`Codec::decode`].
+    /// Codecs able to decode from the two slices of [`ReadBuf::as_slices`]
+    /// can override it to avoid the copy.
+    fn decode_buf(&mut self, buf: &mut ReadBuf) -> Result<Option<Frame>> {
+        match self.decode(buf.make_contiguous())? {
+            Some((consumed, frame)) => {
+                buf.consume(consumed);
+                Ok(Some(frame))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Frame a message to be sent to the client
+    fn encode(&mut self, data: &[u8]) -> Vec<u8>;
+
+    /// Transport this codec speaks
+    fn transport(&self) -> Transport;
+
+    /// Returns `true` while the codec negotiates the connection before
+    /// carrying messages, e.g. during a TLS handshake
+    ///
+    /// The server reports `AuditEvent::TlsHandshakeDone` once a codec that
+    /// started out handshaking no longer is.
+    fn is_handshaking(&self) -> bool {
+        false
+    }
+
+    /// Identity the peer proved during the handshake, for codecs securing
+    /// the connection, once `is_handshaking` is over
+    fn peer_identity(&self) -> Option<PeerIdentity> {
+        None
+    }
+
+    /// Returns `true` once the codec expects no more messages, e.g. after
+    /// an HTTP request asking to close the connection
+    ///
+    /// The server then stops decoding and disconnects the client once
+    /// what is queued for it, the replies to the last messages included,
+    /// is written, see `ServerContext::close_after_write`.
+    fn wants_close(&self) -> bool {
+        false
+    }
+}
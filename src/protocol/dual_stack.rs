@@ -0,0 +1,74 @@
+use std::io::Result;
+
+use super::{Codec, Frame, LengthPrefixedCodec, PeerIdentity, Transport, WebSocketCodec};
+
+/// Serves raw TCP (length-prefixed) and WebSocket clients on one port
+///
+/// The transport is detected from the first bytes a client sends: an HTTP
+/// `GET` starts a WebSocket handshake, anything else is read as
+/// length-prefixed frames. The handler only ever sees unframed messages,
+/// and can ask `ServerContext::client_transport` which one a client uses.
+///
+/// Messages sent before the client sent anything are framed as
+/// length-prefixed, since WebSocket clients cannot receive data before
+/// their handshake anyway.
+#[derive(Default)]
+pub struct DualStackCodec {
+    detected: Option<Box<dyn Codec + Send>>,
+}
+
+impl DualStackCodec {
+    pub fn new() -> Self {
+        DualStackCodec::default()
+    }
+}
+
+impl Codec for DualStackCodec {
+    fn decode(&mut self, buf: &[u8]) -> Result<Option<(usize, Frame)>> {
+        if self.detected.is_none() {
+            let Some(prefix) = buf.first_chunk::<4>() else {
+                return Ok(None);
+            };
+            self.detected = Some(if prefix == b"GET " {
+                Box::new(WebSocketCodec::new())
+            } else {
+                Box::new(LengthPrefixedCodec::default())
+            });
+        }
+
+        match &mut self.detected {
+            Some(codec) => codec.decode(buf),
+            None => Ok(None),
+        }
+    }
+
+    fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        match &mut self.detected {
+            Some(codec) => codec.encode(data),
+            None => LengthPrefixedCodec::default().encode(data),
+        }
+    }
+
+    fn transport(&self) -> Transport {
+        match &self.detected {
+            Some(codec) => codec.transport(),
+            None => Transport::Unknown,
+        }
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.detected
+            .as_ref()
+            .is_some_and(|codec| codec.is_handshaking())
+    }
+
+    fn peer_identity(&self) -> Option<PeerIdentity> {
+        self.detected.as_ref()?.peer_identity()
+    }
+
+    fn wants_close(&self) -> bool {
+        self.detected
+            .as_ref()
+            .is_some_and(|codec| codec.wants_close())
+    }
+}
@@ -0,0 +1,275 @@
+use std::io::Result;
+
+use super::{Codec, Frame, Transport};
+
+const HEADER_END: &[u8] = b"\r\n\r\n";
+
+/// Default for the request head, request line and headers, see
+/// [`HttpCodec::with_limits`]
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+/// Default for the request body
+const MAX_BODY_LEN: usize = 1 << 20;
+
+/// Status the codec answers a request it cannot take with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rejection {
+    status: u16,
+    reason: &'static str,
+}
+
+const BAD_REQUEST: Rejection = Rejection {
+    status: 400,
+    reason: "Bad Request",
+};
+const PAYLOAD_TOO_LARGE: Rejection = Rejection {
+    status: 413,
+    reason: "Payload Too Large",
+};
+const HEADERS_TOO_LARGE: Rejection = Rejection {
+    status: 431,
+    reason: "Request Header Fields Too Large",
+};
+const NOT_IMPLEMENTED: Rejection = Rejection {
+    status: 501,
+    reason: "Not Implemented",
+};
+const VERSION_NOT_SUPPORTED: Rejection = Rejection {
+    status: 505,
+    reason: "HTTP Version Not Supported",
+};
+
+/// HTTP/1.x request framing, with keep-alive and pipelining
+///
+/// Each message is one whole request, head and body, to be read with
+/// [`HttpRequest::parse`]. Requests pipelined in one read are decoded one
+/// after the other, each handed to the handler once the previous one was
+/// handled, so responses go out in order. Outgoing messages are sent as
+/// they are, the handler writes whole responses.
+///
+/// Connections are kept alive unless a request says otherwise: after a
+/// request with `Connection: close`, or an HTTP/1.0 one without
+/// `Connection: keep-alive`, nothing more is decoded and the client is
+/// disconnected once the response queued for it is written. Requests the
+/// codec cannot take (malformed, over the limits or with a chunked body)
+/// are answered with an error status by the codec itself, and the
+/// connection closed the same way.
+#[derive(Debug, Clone)]
+pub struct HttpCodec {
+    max_head_len: usize,
+    max_body_len: usize,
+    /// No request is decoded anymore
+    closing: bool,
+}
+
+impl HttpCodec {
+    pub fn new() -> Self {
+        HttpCodec::with_limits(MAX_HEAD_LEN, MAX_BODY_LEN)
+    }
+
+    /// Requests with a head longer than `max_head_len` or a body longer
+    /// than `max_body_len` are rejected
+    pub fn with_limits(max_head_len: usize, max_body_len: usize) -> Self {
+        HttpCodec {
+            max_head_len,
+            max_body_len,
+            closing: false,
+        }
+    }
+
+    /// Answer with `rejection` and close, discarding the `buffered` bytes
+    fn reject(&mut self, rejection: Rejection, buffered: usize) -> Option<(usize, Frame)> {
+        self.closing = true;
+        let response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            rejection.status, rejection.reason
+        );
+        Some((buffered, Frame::Control(response.into_bytes())))
+    }
+}
+
+impl Default for HttpCodec {
+    fn default() -> Self {
+        HttpCodec::new()
+    }
+}
+
+impl Codec for HttpCodec {
+    fn decode(&mut self, buf: &[u8]) -> Result<Option<(usize, Frame)>> {
+        if self.closing {
+            return Ok(None);
+        }
+        let Some(head_len) = head_len(buf) else {
+            if buf.len() > self.max_head_len {
+                return Ok(self.reject(HEADERS_TOO_LARGE, buf.len()));
+            }
+            return Ok(None);
+        };
+        if head_len > self.max_head_len {
+            return Ok(self.reject(HEADERS_TOO_LARGE, buf.len()));
+        }
+
+        let request = match HttpRequest::parse_head(&buf[..head_len]) {
+            Ok(request) => request,
+            Err(rejection) => return Ok(self.reject(rejection, buf.len())),
+        };
+        let body_len = match request.body_len() {
+            Ok(len) if len > self.max_body_len => {
+                return Ok(self.reject(PAYLOAD_TOO_LARGE, buf.len()));
+            }
+            Ok(len) => len,
+            Err(rejection) => return Ok(self.reject(rejection, buf.len())),
+        };
+        let request_len = head_len + body_len;
+        if buf.len() < request_len {
+            return Ok(None);
+        }
+
+        self.closing = !request.keep_alive();
+        Ok(Some((
+            request_len,
+            Frame::Message(buf[..request_len].to_vec()),
+        )))
+    }
+
+    fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        data.to_vec()
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::Http
+    }
+
+    fn wants_close(&self) -> bool {
+        self.closing
+    }
+}
+
+/// Request decoded by an [`HttpCodec`], borrowing from its message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest<'a> {
+    pub method: &'a str,
+    pub target: &'a str,
+    /// Minor version, `1` for HTTP/1.1
+    pub version: u8,
+    /// Headers in the order they were received, values trimmed
+    pub headers: Vec<(&'a str, &'a str)>,
+    pub body: &'a [u8],
+}
+
+impl<'a> HttpRequest<'a> {
+    /// Parse a whole request, as delivered by [`HttpCodec`]
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        let invalid = |rejection: Rejection| crate::Error::Protocol(rejection.reason.into());
+        let head_len = head_len(data).ok_or_else(|| invalid(BAD_REQUEST))?;
+        let mut request = HttpRequest::parse_head(&data[..head_len]).map_err(invalid)?;
+        let body_len = request.body_len().map_err(invalid)?;
+        request.body = &data[head_len..data.len().min(head_len + body_len)];
+        Ok(request)
+    }
+
+    /// Value of the first header named `name`, whatever its case
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+
+    /// Whether the client wants the connection kept open after the
+    /// response, the default from HTTP/1.1 on
+    pub fn keep_alive(&self) -> bool {
+        let mut keep_alive = self.version >= 1;
+        for (_, value) in self
+            .headers
+            .iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case("connection"))
+        {
+            for option in value.split(',').map(str::trim) {
+                if option.eq_ignore_ascii_case("close") {
+                    return false;
+                }
+                if option.eq_ignore_ascii_case("keep-alive") {
+                    keep_alive = true;
+                }
+            }
+        }
+        keep_alive
+    }
+
+    /// Parse the request line and headers ending with the blank line,
+    /// leaving the body empty
+    fn parse_head(head: &'a [u8]) -> std::result::Result<Self, Rejection> {
+        let head =
+            std::str::from_utf8(&head[..head.len() - HEADER_END.len()]).map_err(|_| BAD_REQUEST)?;
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().unwrap_or_default();
+        let mut parts = request_line.split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(BAD_REQUEST);
+        };
+        if method.is_empty() || !method.bytes().all(|byte| byte.is_ascii_graphic()) {
+            return Err(BAD_REQUEST);
+        }
+        if target.is_empty() {
+            return Err(BAD_REQUEST);
+        }
+        let version = match version {
+            "HTTP/1.1" => 1,
+            "HTTP/1.0" => 0,
+            other if other.starts_with("HTTP/") => return Err(VERSION_NOT_SUPPORTED),
+            _ => return Err(BAD_REQUEST),
+        };
+
+        let mut headers = Vec::new();
+        for line in lines {
+            // Obsolete line folding is rejected, as RFC 9112 allows
+            let (name, value) = line.split_once(':').ok_or(BAD_REQUEST)?;
+            if name.is_empty() || !name.bytes().all(|byte| byte.is_ascii_graphic()) {
+                return Err(BAD_REQUEST);
+            }
+            headers.push((name, value.trim()));
+        }
+
+        Ok(HttpRequest {
+            method,
+            target,
+            version,
+            headers,
+            body: &[],
+        })
+    }
+
+    /// Length of the body announced by the headers
+    fn body_len(&self) -> std::result::Result<usize, Rejection> {
+        if self.header("transfer-encoding").is_some() {
+            return Err(NOT_IMPLEMENTED);
+        }
+        let mut body_len = None;
+        for (_, value) in self
+            .headers
+            .iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case("content-length"))
+        {
+            if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+                return Err(BAD_REQUEST);
+            }
+            let len = value.parse::<usize>().map_err(|_| PAYLOAD_TOO_LARGE)?;
+            // Differing lengths are how requests get smuggled
+            if body_len.is_some_and(|body_len| body_len != len) {
+                return Err(BAD_REQUEST);
+            }
+            body_len = Some(len);
+        }
+        Ok(body_len.unwrap_or(0))
+    }
+}
+
+/// Length of the request head at the start of `buf`, blank line included
+fn head_len(buf: &[u8]) -> Option<usize> {
+    buf.windows(HEADER_END.len())
+        .position(|window| window == HEADER_END)
+        .map(|position| position + HEADER_END.len())
+}
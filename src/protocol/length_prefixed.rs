@@ -0,0 +1,58 @@
+use std::io::Result;
+
+use super::{Codec, Frame, Transport};
+
+/// Size of the length header
+const HEADER_LEN: usize = 4;
+
+/// Frames prefixed with their length as a big-endian `u32`
+#[derive(Debug, Clone)]
+pub struct LengthPrefixedCodec {
+    max_frame_len: usize,
+}
+
+impl LengthPrefixedCodec {
+    /// Frames longer than `max_frame_len` are rejected
+    pub fn new(max_frame_len: usize) -> Self {
+        LengthPrefixedCodec { max_frame_len }
+    }
+}
+
+impl Default for LengthPrefixedCodec {
+    fn default() -> Self {
+        LengthPrefixedCodec::new(16 * 1024 * 1024)
+    }
+}
+
+impl Codec for LengthPrefixedCodec {
+    fn decode(&mut self, buf: &[u8]) -> Result<Option<(usize, Frame)>> {
+        let Some(header) = buf.first_chunk::<HEADER_LEN>() else {
+            return Ok(None);
+        };
+
+        let len = u32::from_be_bytes(*header) as usize;
+        if len > self.max_frame_len {
+            let message = format!("frame of {} bytes exceeds limit", len);
+            return Err(crate::Error::Protocol(message).into());
+        }
+
+        let frame_len = HEADER_LEN + len;
+        if buf.len() < frame_len {
+            return Ok(None);
+        }
+
+        let payload = buf[HEADER_LEN..frame_len].to_vec();
+        Ok(Some((frame_len, Frame::Message(payload))))
+    }
+
+    fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + data.len());
+        frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::LengthPrefixed
+    }
+}
@@ -0,0 +1,55 @@
+use std::io::Result;
+
+use super::{Codec, Frame, Transport};
+
+/// Line based framing as used by SMTP, IMAP, Redis inline commands...
+///
+/// Each message is one line without its `\r\n` (or bare `\n`)
+/// terminator, outgoing messages get `\r\n` appended.
+#[derive(Debug, Clone)]
+pub struct LineCodec {
+    max_line_len: usize,
+}
+
+impl LineCodec {
+    pub fn new() -> Self {
+        LineCodec::with_max_line_len(64 * 1024)
+    }
+
+    /// Lines longer than `max_line_len` are rejected
+    pub fn with_max_line_len(max_line_len: usize) -> Self {
+        LineCodec { max_line_len }
+    }
+}
+
+impl Default for LineCodec {
+    fn default() -> Self {
+        LineCodec::new()
+    }
+}
+
+impl Codec for LineCodec {
+    fn decode(&mut self, buf: &[u8]) -> Result<Option<(usize, Frame)>> {
+        let Some(newline) = buf.iter().position(|&byte| byte == b'\n') else {
+            if buf.len() > self.max_line_len {
+                return Err(crate::Error::Protocol("line exceeds limit".into()).into());
+            }
+            return Ok(None);
+        };
+
+        let line = &buf[..newline];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        Ok(Some((newline + 1, Frame::Message(line.to_vec()))))
+    }
+
+    fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut line = Vec::with_capacity(data.len() + 2);
+        line.extend_from_slice(data);
+        line.extend_from_slice(b"\r\n");
+        line
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::Line
+    }
+}
@@ -0,0 +1,31 @@
+//! Wire protocols spoken with clients
+//!
+//! A [`Codec`] turns the raw bytes read from a client into messages for
+//! the handler and frames outgoing messages. Without a codec the handler
+//! sees the raw bytes, as decided by `EventHandler::is_data_complete`.
+
+mod codec;
+mod dual_stack;
+mod http;
+mod length_prefixed;
+mod line;
+mod proxy_protocol;
+mod read_buf;
+mod stack;
+mod telnet;
+#[cfg(feature = "tls")]
+mod tls;
+mod websocket;
+
+pub use codec::{Codec, Frame, PeerIdentity, Transport};
+pub use dual_stack::DualStackCodec;
+pub use http::{HttpCodec, HttpRequest};
+pub use length_prefixed::LengthPrefixedCodec;
+pub use line::LineCodec;
+pub use proxy_protocol::{ProxyHeader, decode_proxy_header};
+pub use read_buf::ReadBuf;
+pub use stack::CodecStack;
+pub use telnet::{TelnetCodec, TelnetOptions};
+#[cfg(feature = "tls")]
+pub use tls::{ClientAuth, TlsCodec, TlsConfig};
+pub use websocket::WebSocketCodec;
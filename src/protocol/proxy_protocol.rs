@@ -0,0 +1,155 @@
+use std::{
+    io::{Error, Result},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str,
+};
+
+/// Signature starting every version 2 header
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Prefix of every version 1 header
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest version 1 header, `\r\n` included
+const V1_MAX_LEN: usize = 107;
+
+/// Addresses of a connection relayed by a proxy
+///
+/// Sent by the proxy (HAProxy, AWS NLB, nginx...) at the start of the
+/// connection, before any byte of the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// Address of the client that connected to the proxy
+    pub source: SocketAddr,
+    /// Address the client connected to on the proxy
+    pub destination: SocketAddr,
+}
+
+/// Decode a PROXY protocol header, version 1 or 2, from the start of `buf`
+///
+/// Returns the number of bytes of the header together with the addresses
+/// it carries, or `None` if `buf` does not hold the whole header yet.
+/// Headers without addresses (`UNKNOWN` in version 1, `LOCAL` or a non IP
+/// family in version 2) give `Some((len, None))`. Data that is not a
+/// PROXY header is an `Error::Protocol`.
+///
+/// See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>
+pub fn decode_proxy_header(buf: &[u8]) -> Result<Option<(usize, Option<ProxyHeader>)>> {
+    if buf.starts_with(V1_PREFIX) {
+        return decode_v1(buf);
+    }
+    if buf.starts_with(V2_SIGNATURE) {
+        return decode_v2(buf);
+    }
+
+    // Not enough bytes yet to tell, as long as they could start a header
+    let could_be = |prefix: &[u8]| prefix.starts_with(&buf[..buf.len().min(prefix.len())]);
+    if could_be(V1_PREFIX) || could_be(V2_SIGNATURE) {
+        return Ok(None);
+    }
+    Err(invalid("connection does not start with a PROXY header"))
+}
+
+/// `PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\n`
+fn decode_v1(buf: &[u8]) -> Result<Option<(usize, Option<ProxyHeader>)>> {
+    let window = &buf[..buf.len().min(V1_MAX_LEN)];
+    let Some(end) = window.windows(2).position(|pair| pair == b"\r\n") else {
+        if buf.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY header too long"));
+        }
+        return Ok(None);
+    };
+
+    let line = str::from_utf8(&buf[V1_PREFIX.len()..end])
+        .map_err(|_| invalid("PROXY header is not ASCII"))?;
+    let mut fields = line.split(' ');
+    let header = match fields.next() {
+        Some("UNKNOWN") => None,
+        Some(family @ ("TCP4" | "TCP6")) => {
+            let fields: Vec<&str> = fields.collect();
+            let [source, destination, source_port, destination_port] = fields[..] else {
+                return Err(invalid("PROXY header has the wrong number of fields"));
+            };
+            let source = parse_v1_addr(source, source_port)?;
+            let destination = parse_v1_addr(destination, destination_port)?;
+            if source.is_ipv4() != (family == "TCP4") || destination.is_ipv4() != source.is_ipv4() {
+                return Err(invalid("PROXY header addresses do not match the family"));
+            }
+            Some(ProxyHeader {
+                source,
+                destination,
+            })
+        }
+        _ => return Err(invalid("unknown PROXY header protocol")),
+    };
+    Ok(Some((end + 2, header)))
+}
+
+fn parse_v1_addr(ip: &str, port: &str) -> Result<SocketAddr> {
+    let ip: IpAddr = ip.parse().map_err(|_| invalid("invalid PROXY address"))?;
+    let port: u16 = port.parse().map_err(|_| invalid("invalid PROXY port"))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Binary header: signature, version and command, family, length, addresses
+fn decode_v2(buf: &[u8]) -> Result<Option<(usize, Option<ProxyHeader>)>> {
+    let Some(fixed) = buf.first_chunk::<16>() else {
+        return Ok(None);
+    };
+    let version_command = fixed[12];
+    let family = fixed[13];
+    let len = u16::from_be_bytes([fixed[14], fixed[15]]) as usize;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    let Some(addresses) = buf.get(16..16 + len) else {
+        return Ok(None);
+    };
+
+    let header = match (version_command & 0x0f, family) {
+        // LOCAL, the proxy's own connection (e.g. a health check)
+        (0, _) => None,
+        // PROXY over TCP or UDP on IPv4
+        (1, 0x11 | 0x12) => {
+            let Some(addresses) = addresses.first_chunk::<12>() else {
+                return Err(invalid("PROXY header too short for IPv4 addresses"));
+            };
+            let ip = |at: usize| {
+                IpAddr::from(Ipv4Addr::new(
+                    addresses[at],
+                    addresses[at + 1],
+                    addresses[at + 2],
+                    addresses[at + 3],
+                ))
+            };
+            let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+            Some(ProxyHeader {
+                source: SocketAddr::new(ip(0), port(8)),
+                destination: SocketAddr::new(ip(4), port(10)),
+            })
+        }
+        // PROXY over TCP or UDP on IPv6
+        (1, 0x21 | 0x22) => {
+            let Some(addresses) = addresses.first_chunk::<36>() else {
+                return Err(invalid("PROXY header too short for IPv6 addresses"));
+            };
+            let ip = |at: usize| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&addresses[at..at + 16]);
+                IpAddr::from(Ipv6Addr::from(octets))
+            };
+            let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+            Some(ProxyHeader {
+                source: SocketAddr::new(ip(0), port(32)),
+                destination: SocketAddr::new(ip(16), port(34)),
+            })
+        }
+        // Unix sockets and unspecified families carry no IP address
+        (1, _) => None,
+        _ => return Err(invalid("unknown PROXY header command")),
+    };
+    Ok(Some((16 + len, header)))
+}
+
+fn invalid(message: &str) -> Error {
+    crate::Error::Protocol(message.to_string()).into()
+}
@@ -0,0 +1,177 @@
+use std::{io::Result, os::fd::RawFd, ptr};
+
+use crate::{
+    ep_syscall,
+    ffi::{IoVec, MsgHdr},
+};
+
+/// Bytes received from a client and not processed yet
+///
+/// A ring buffer: consuming bytes moves the start of the data forward
+/// without copying the rest, and reads land in the free space after the
+/// data, wrapping around to the space consumed bytes left at the start.
+/// The data is then split in the two slices of [`ReadBuf::as_slices`]
+/// until [`ReadBuf::make_contiguous`] moves it back in one piece. The
+/// buffer only grows when it is full.
+#[derive(Debug, Default)]
+pub struct ReadBuf {
+    /// Initialized in full, its length is the capacity of the ring
+    storage: Vec<u8>,
+    /// Index of the first byte of data
+    head: usize,
+    len: usize,
+}
+
+impl ReadBuf {
+    /// Create an empty buffer holding `capacity` bytes before it grows
+    pub fn with_capacity(capacity: usize) -> Self {
+        ReadBuf {
+            storage: vec![0; capacity],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Number of bytes of data
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes the buffer holds before it grows
+    pub fn capacity(&self) -> usize {
+        self.storage.len()
+    }
+
+    /// The data in order, the second slice is empty unless the data wraps
+    /// around the end of the buffer
+    pub fn as_slices(&self) -> (&[u8], &[u8]) {
+        let end = self.head + self.len;
+        if end <= self.storage.len() {
+            (&self.storage[self.head..end], &[])
+        } else {
+            let wrapped = end - self.storage.len();
+            (&self.storage[self.head..], &self.storage[..wrapped])
+        }
+    }
+
+    /// The data as one slice, moved in place first if it wraps around
+    pub fn make_contiguous(&mut self) -> &[u8] {
+        if self.head + self.len > self.storage.len() {
+            self.storage.rotate_left(self.head);
+            self.head = 0;
+        }
+        &self.storage[self.head..self.head + self.len]
+    }
+
+    /// Drop `count` bytes from the front of the data
+    ///
+    /// # Panics
+    ///
+    /// If `count` is more than the length of the data.
+    pub fn consume(&mut self, count: usize) {
+        assert!(count <= self.len, "consumed more than the buffered data");
+        self.len -= count;
+        self.head = match self.len {
+            // Start over at the beginning, reads then wrap less often
+            0 => 0,
+            _ => (self.head + count) % self.storage.len(),
+        };
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Append `data`, growing the buffer if it does not fit
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.reserve(data.len());
+        let (first, second) = self.spare_mut();
+        let split = data.len().min(first.len());
+        first[..split].copy_from_slice(&data[..split]);
+        second[..data.len() - split].copy_from_slice(&data[split..]);
+        self.len += data.len();
+    }
+
+    /// Make room for at least `additional` more bytes, at least doubling
+    /// the capacity when it grows
+    pub fn reserve(&mut self, additional: usize) {
+        if self.storage.len() - self.len >= additional {
+            return;
+        }
+        // The free space after the data is extended, data wrapping around
+        // would no longer be in order
+        self.make_contiguous();
+        let capacity = (self.len + additional).max(self.storage.len() * 2);
+        self.storage.resize(capacity, 0);
+    }
+
+    /// Receive up to `max_len` bytes from the socket `fd` into the free
+    /// space with one scatter read, the `readv` of sockets, growing the
+    /// buffer first if less is free
+    ///
+    /// Returns the number of bytes received, `0` at end of stream.
+    pub(crate) fn recv_from(&mut self, fd: RawFd, max_len: usize, flags: i32) -> Result<usize> {
+        self.reserve(max_len);
+        let (first, second) = self.spare_mut();
+        let first_len = first.len().min(max_len);
+        let second_len = second.len().min(max_len - first_len);
+        let iovlen = if second_len == 0 { 1 } else { 2 };
+        let mut iovecs = [(first, first_len), (second, second_len)].map(|(spare, len)| IoVec {
+            base: spare.as_mut_ptr(),
+            len,
+        });
+        let mut message = MsgHdr {
+            name: ptr::null_mut(),
+            namelen: 0,
+            iov: iovecs.as_mut_ptr(),
+            iovlen,
+            control: ptr::null_mut(),
+            controllen: 0,
+            flags: 0,
+        };
+        let received = ep_syscall!(recvmsg(fd, &raw mut message, flags))? as usize;
+        self.len += received;
+        Ok(received)
+    }
+
+    /// Take the data out as a vector, leaving the buffer empty and without
+    /// capacity
+    pub(crate) fn take_vec(&mut self) -> Vec<u8> {
+        self.make_contiguous();
+        let mut data = std::mem::take(&mut self.storage);
+        data.truncate(self.head + self.len);
+        data.drain(..self.head);
+        self.clear();
+        data
+    }
+
+    /// The free space in order, after the data then before it
+    fn spare_mut(&mut self) -> (&mut [u8], &mut [u8]) {
+        let end = self.head + self.len;
+        if end < self.storage.len() {
+            let (before, after) = self.storage.split_at_mut(end);
+            (after, &mut before[..self.head])
+        } else {
+            let start = end - self.storage.len();
+            (&mut self.storage[start..self.head], &mut [])
+        }
+    }
+}
+
+impl From<Vec<u8>> for ReadBuf {
+    /// Make a buffer of `data`, whose spare capacity is kept for reads
+    fn from(mut data: Vec<u8>) -> Self {
+        let len = data.len();
+        data.resize(data.capacity(), 0);
+        ReadBuf {
+            storage: data,
+            head: 0,
+            len,
+        }
+    }
+}
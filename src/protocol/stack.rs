@@ -0,0 +1,110 @@
+use std::io::Result;
+
+use super::{Codec, Frame, PeerIdentity, Transport};
+
+/// Codec layer and the bytes the layer below decoded for it
+struct Layer {
+    codec: Box<dyn Codec + Send>,
+    pending: Vec<u8>,
+}
+
+/// Codecs stacked on top of each other
+///
+/// The first codec is closest to the wire, e.g. an encryption layer, and
+/// each message it decodes is fed to the next one, e.g. the application
+/// framing. Outgoing messages are encoded in the opposite order, and
+/// control frames of an upper layer are encoded by the layers below it.
+pub struct CodecStack {
+    layers: Vec<Layer>,
+}
+
+impl CodecStack {
+    /// `codecs` are ordered from the wire up to the application
+    pub fn new(codecs: Vec<Box<dyn Codec + Send>>) -> Self {
+        let layers = codecs
+            .into_iter()
+            .map(|codec| Layer {
+                codec,
+                pending: Vec::new(),
+            })
+            .collect();
+        CodecStack { layers }
+    }
+
+    /// Decode from the bytes already pending for layer `index`
+    fn decode_layer(&mut self, index: usize) -> Result<Option<Frame>> {
+        let Some(layer) = self.layers.get_mut(index) else {
+            return Ok(None);
+        };
+        let Some((consumed, frame)) = layer.codec.decode(&layer.pending)? else {
+            return Ok(None);
+        };
+        layer.pending.drain(..consumed);
+        self.lift(index, frame).map(Some)
+    }
+
+    /// Pass a frame produced by layer `index` to the layers around it
+    fn lift(&mut self, index: usize, frame: Frame) -> Result<Frame> {
+        match frame {
+            Frame::Message(data) if index + 1 < self.layers.len() => {
+                self.layers[index + 1].pending.extend_from_slice(&data);
+                Ok(self.decode_layer(index + 1)?.unwrap_or(Frame::Consumed))
+            }
+            Frame::Control(data) => Ok(Frame::Control(self.encode_below(index, data))),
+            other => Ok(other),
+        }
+    }
+
+    /// Encode `data` with every layer below `index`
+    fn encode_below(&mut self, index: usize, data: Vec<u8>) -> Vec<u8> {
+        self.layers[..index]
+            .iter_mut()
+            .rev()
+            .fold(data, |data, layer| layer.codec.encode(&data))
+    }
+}
+
+impl Codec for CodecStack {
+    fn decode(&mut self, buf: &[u8]) -> Result<Option<(usize, Frame)>> {
+        // Upper layers may still hold complete frames from earlier reads
+        for index in 1..self.layers.len() {
+            if let Some(frame) = self.decode_layer(index)? {
+                return Ok(Some((0, frame)));
+            }
+        }
+
+        let Some(layer) = self.layers.first_mut() else {
+            return Ok(None);
+        };
+        match layer.codec.decode(buf)? {
+            Some((consumed, frame)) => Ok(Some((consumed, self.lift(0, frame)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        let len = self.layers.len();
+        self.encode_below(len, data.to_vec())
+    }
+
+    fn transport(&self) -> Transport {
+        match self.layers.last() {
+            Some(layer) => layer.codec.transport(),
+            None => Transport::Raw,
+        }
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.layers.iter().any(|layer| layer.codec.is_handshaking())
+    }
+
+    fn peer_identity(&self) -> Option<PeerIdentity> {
+        self.layers
+            .iter()
+            .find_map(|layer| layer.codec.peer_identity())
+    }
+
+    fn wants_close(&self) -> bool {
+        self.layers.iter().any(|layer| layer.codec.wants_close())
+    }
+}
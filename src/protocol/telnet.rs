@@ -0,0 +1,171 @@
+use std::{
+    collections::HashSet,
+    io::Result,
+    sync::{Arc, Mutex},
+};
+
+use super::{Codec, Frame, Transport};
+
+/// Interpret As Command, starts every Telnet command
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+/// Subnegotiation begin and end
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+/// Options enabled on each side of a Telnet connection
+#[derive(Debug, Default)]
+struct OptionState {
+    /// Options the client agreed to perform (it sent `WILL`, we `DO`)
+    remote: HashSet<u8>,
+    /// Options we agreed to perform (it sent `DO`, we `WILL`)
+    local: HashSet<u8>,
+}
+
+/// Handle on the options a `TelnetCodec` negotiated
+///
+/// Clones share the state of the codec they came from, so a handler can
+/// keep one while the codec is owned by the client.
+#[derive(Debug, Clone, Default)]
+pub struct TelnetOptions {
+    state: Arc<Mutex<OptionState>>,
+}
+
+impl TelnetOptions {
+    /// Returns `true` if the client agreed to perform `option`
+    pub fn remote_enabled(&self, option: u8) -> bool {
+        self.with_state(|state| state.remote.contains(&option))
+    }
+
+    /// Returns `true` if we agreed to perform `option`
+    pub fn local_enabled(&self, option: u8) -> bool {
+        self.with_state(|state| state.local.contains(&option))
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut OptionState) -> T) -> T {
+        // The state stays consistent even if a holder panicked
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut state)
+    }
+}
+
+/// Strips Telnet commands from what clients send
+///
+/// `telnet` clients mix option negotiations (RFC 854, 855) into the data,
+/// which would otherwise reach the handler as control bytes. Commands are
+/// removed, negotiations are answered, refusing every option unless it was
+/// allowed with `TelnetCodec::accept`, and escaped `0xFF` bytes are
+/// unescaped. Outgoing `0xFF` bytes are escaped.
+///
+/// The codec passes the data on as it arrives, stack it under a framing
+/// codec to get messages:
+///
+/// ```
+/// use epoll_worker::{ServerConfig, protocol::{CodecStack, LineCodec, TelnetCodec}};
+///
+/// let config = ServerConfig::default().codec(|| {
+///     Box::new(CodecStack::new(vec![
+///         Box::new(TelnetCodec::new()),
+///         Box::new(LineCodec::new()),
+///     ]))
+/// });
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TelnetCodec {
+    accepted: HashSet<u8>,
+    options: TelnetOptions,
+}
+
+impl TelnetCodec {
+    /// Echo the client's input back (RFC 857)
+    pub const ECHO: u8 = 1;
+    /// No go ahead signals, full duplex (RFC 858)
+    pub const SUPPRESS_GO_AHEAD: u8 = 3;
+    /// Terminal type (RFC 1091)
+    pub const TERMINAL_TYPE: u8 = 24;
+    /// Window size (RFC 1073)
+    pub const NAWS: u8 = 31;
+
+    pub fn new() -> Self {
+        TelnetCodec::default()
+    }
+
+    /// Agree to enable `option` when either side asks for it
+    pub fn accept(mut self, option: u8) -> Self {
+        self.accepted.insert(option);
+        self
+    }
+
+    /// Handle on the options negotiated with the client
+    pub fn options(&self) -> TelnetOptions {
+        self.options.clone()
+    }
+
+    /// Answer a negotiation, returns `None` if it changes nothing
+    ///
+    /// Only changes of state are acknowledged, which is what keeps both
+    /// sides from answering each other forever.
+    fn negotiate(&mut self, command: u8, option: u8) -> Option<u8> {
+        let accepted = self.accepted.contains(&option);
+        self.options.with_state(|state| match command {
+            WILL if accepted => state.remote.insert(option).then_some(DO),
+            WILL => Some(DONT),
+            WONT => state.remote.remove(&option).then_some(DONT),
+            DO if accepted => state.local.insert(option).then_some(WILL),
+            DO => Some(WONT),
+            DONT => state.local.remove(&option).then_some(WONT),
+            _ => None,
+        })
+    }
+
+    /// Decode the command starting at `buf[0]`, an `IAC`
+    fn decode_command(&mut self, buf: &[u8]) -> Option<(usize, Frame)> {
+        match *buf.get(1)? {
+            IAC => Some((2, Frame::Message(vec![IAC]))),
+            command @ (WILL | WONT | DO | DONT) => {
+                let option = *buf.get(2)?;
+                let frame = match self.negotiate(command, option) {
+                    Some(reply) => Frame::Control(vec![IAC, reply, option]),
+                    None => Frame::Consumed,
+                };
+                Some((3, frame))
+            }
+            SB => {
+                // Skip to `IAC SE`, option data is not interpreted
+                let end = buf.windows(2).position(|pair| pair == [IAC, SE])?;
+                Some((end + 2, Frame::Consumed))
+            }
+            // NOP, go ahead, interrupt, are you there...
+            _ => Some((2, Frame::Consumed)),
+        }
+    }
+}
+
+impl Codec for TelnetCodec {
+    fn decode(&mut self, buf: &[u8]) -> Result<Option<(usize, Frame)>> {
+        match buf.iter().position(|&byte| byte == IAC) {
+            Some(0) => Ok(self.decode_command(buf)),
+            Some(command) => Ok(Some((command, Frame::Message(buf[..command].to_vec())))),
+            None if buf.is_empty() => Ok(None),
+            None => Ok(Some((buf.len(), Frame::Message(buf.to_vec())))),
+        }
+    }
+
+    fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(data.len());
+        for &byte in data {
+            if byte == IAC {
+                encoded.push(IAC);
+            }
+            encoded.push(byte);
+        }
+        encoded
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::Telnet
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Read, Result, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use rustls::{
+    RootCertStore, ServerConnection,
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+};
+
+use super::{Codec, Frame, PeerIdentity, Transport};
+
+/// Whether clients must authenticate with a certificate, see
+/// `TlsConfig::from_pem_files_with_client_auth`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAuth {
+    /// Handshakes without a valid client certificate fail
+    Required,
+    /// Clients may connect without a certificate, one they do send must
+    /// be valid
+    Optional,
+}
+
+/// Server side TLS settings shared by all connections
+#[derive(Clone)]
+pub struct TlsConfig {
+    inner: Arc<rustls::ServerConfig>,
+    /// Certificates added with `add_cert`, by lowercase host name
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+    /// Resolver the configuration was created with, for the other names
+    default_resolver: Arc<dyn ResolvesServerCert>,
+}
+
+impl TlsConfig {
+    /// Load a PEM encoded certificate chain and private key
+    pub fn from_pem_files(cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Result<Self> {
+        let certs = load_certs(cert_path)?;
+        let key = PrivateKeyDer::from_pem_file(key_path).map_err(invalid_input)?;
+
+        let config =
+            rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .map_err(invalid_input)?
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .map_err(invalid_input)?;
+
+        Ok(TlsConfig::from_rustls(Arc::new(config)))
+    }
+
+    /// Like `from_pem_files`, and verify the certificates of the clients
+    /// (mutual TLS) against the PEM encoded CAs in `client_ca_path`
+    ///
+    /// The verified chain is handed to `EventHandler::on_tls_handshake`,
+    /// which decides whether the client is authorized.
+    pub fn from_pem_files_with_client_auth(
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+        client_ca_path: impl AsRef<Path>,
+        auth: ClientAuth,
+    ) -> Result<Self> {
+        let certs = load_certs(cert_path)?;
+        let key = PrivateKeyDer::from_pem_file(key_path).map_err(invalid_input)?;
+        let mut roots = RootCertStore::empty();
+        for ca in load_certs(client_ca_path)? {
+            roots.add(ca).map_err(invalid_input)?;
+        }
+
+        let provider = Arc::new(ring::default_provider());
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+        let verifier = match auth {
+            ClientAuth::Required => verifier,
+            ClientAuth::Optional => verifier.allow_unauthenticated(),
+        }
+        .build()
+        .map_err(invalid_input)?;
+
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_input)?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)
+            .map_err(invalid_input)?;
+
+        Ok(TlsConfig::from_rustls(Arc::new(config)))
+    }
+
+    /// Use an existing rustls configuration
+    pub fn from_rustls(config: Arc<rustls::ServerConfig>) -> Self {
+        TlsConfig {
+            default_resolver: config.cert_resolver.clone(),
+            inner: config,
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Application protocols offered with ALPN, most preferred first,
+    /// e.g. `[b"h2", b"http/1.1"]`
+    ///
+    /// The protocol agreed on is reported in the `PeerIdentity` handed to
+    /// `EventHandler::on_tls_handshake`, see `AlpnDispatcher` to route
+    /// clients by it. Handshakes with clients offering only other
+    /// protocols fail, those offering none proceed without one.
+    pub fn alpn_protocols<P: Into<Vec<u8>>>(
+        mut self,
+        protocols: impl IntoIterator<Item = P>,
+    ) -> Self {
+        Arc::make_mut(&mut self.inner).alpn_protocols =
+            protocols.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Serve the PEM encoded certificate chain and private key to the
+    /// clients asking for `host` with SNI
+    ///
+    /// `host` is either a name or a `*.` wildcard covering one label,
+    /// e.g. `*.example.com` for `api.example.com`, an exact name winning
+    /// over a wildcard. Clients asking for no host or another one get the
+    /// certificate the configuration was created with. Connections already
+    /// handshaking keep the certificates they were offered.
+    pub fn add_cert(
+        mut self,
+        host: &str,
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let certs = load_certs(cert_path)?;
+        let key = PrivateKeyDer::from_pem_file(key_path).map_err(invalid_input)?;
+        let certified = CertifiedKey::from_der(certs, key, self.inner.crypto_provider())
+            .map_err(invalid_input)?;
+        self.hosts
+            .insert(host.to_ascii_lowercase(), Arc::new(certified));
+
+        let resolver = SniResolver {
+            hosts: self.hosts.clone(),
+            default: self.default_resolver.clone(),
+        };
+        Arc::make_mut(&mut self.inner).cert_resolver = Arc::new(resolver);
+        Ok(self)
+    }
+
+    /// Codec for one new TLS connection
+    pub fn codec(&self) -> Result<TlsCodec> {
+        let connection = ServerConnection::new(self.inner.clone()).map_err(invalid_input)?;
+        Ok(TlsCodec { connection })
+    }
+}
+
+/// TLS layer driven by the event loop
+///
+/// Decrypted data is delivered as messages as it arrives, without any
+/// framing. Stack it below a framing codec with `ServerContext::switch_stack`
+/// or `CodecStack` to get whole messages. The handshake is performed while
+/// decoding, its records are sent back as control frames.
+pub struct TlsCodec {
+    connection: ServerConnection,
+}
+
+impl TlsCodec {
+    /// TLS records waiting to be sent to the client
+    fn take_tls_output(&mut self) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        while self.connection.wants_write() {
+            self.connection.write_tls(&mut output)?;
+        }
+        Ok(output)
+    }
+}
+
+impl Codec for TlsCodec {
+    fn decode(&mut self, mut buf: &[u8]) -> Result<Option<(usize, Frame)>> {
+        if self.connection.wants_write() {
+            return Ok(Some((0, Frame::Control(self.take_tls_output()?))));
+        }
+
+        // Reading to the end only succeeds once the peer sent close_notify,
+        // otherwise it stops with `WouldBlock` when no plaintext is left
+        let mut plaintext = Vec::new();
+        let peer_closed = match self.connection.reader().read_to_end(&mut plaintext) {
+            Ok(_) => true,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+            Err(e) => return Err(e),
+        };
+        if !plaintext.is_empty() {
+            return Ok(Some((0, Frame::Message(plaintext))));
+        }
+        if peer_closed {
+            return Ok(Some((0, Frame::Close)));
+        }
+
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        let consumed = self.connection.read_tls(&mut buf)?;
+        self.connection
+            .process_new_packets()
+            .map_err(|e| crate::Error::Protocol(e.to_string()))?;
+        Ok(Some((consumed, Frame::Consumed)))
+    }
+
+    fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        // Before the handshake completes rustls buffers the plaintext,
+        // it is sent once `decode` finds the connection wanting to write
+        if let Err(e) = self.connection.writer().write_all(data) {
+            log::error!("Failed to encrypt {} bytes: {}", data.len(), e);
+            return Vec::new();
+        }
+        self.take_tls_output().unwrap_or_default()
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::Tls
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.connection.is_handshaking()
+    }
+
+    fn peer_identity(&self) -> Option<PeerIdentity> {
+        if self.connection.is_handshaking() {
+            return None;
+        }
+        let certificates = self
+            .connection
+            .peer_certificates()
+            .unwrap_or_default()
+            .iter()
+            .map(|certificate| certificate.to_vec())
+            .collect();
+        let server_name = self.connection.server_name().map(str::to_owned);
+        let alpn_protocol = self.connection.alpn_protocol().map(<[u8]>::to_vec);
+        Some(PeerIdentity::new(certificates, server_name).with_alpn_protocol(alpn_protocol))
+    }
+}
+
+/// Picks the certificate of the host a client asks for with SNI
+#[derive(Debug)]
+struct SniResolver {
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<dyn ResolvesServerCert>,
+}
+
+impl SniResolver {
+    fn lookup(&self, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        let name = server_name.to_ascii_lowercase();
+        if let Some(certified) = self.hosts.get(&name) {
+            return Some(certified.clone());
+        }
+        let (_, parent) = name.split_once('.')?;
+        self.hosts.get(&format!("*.{parent}")).cloned()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        match client_hello
+            .server_name()
+            .and_then(|name| self.lookup(name))
+        {
+            Some(certified) => Some(certified),
+            None => self.default.resolve(client_hello),
+        }
+    }
+}
+
+/// PEM encoded certificates of `path`
+fn load_certs(path: impl AsRef<Path>) -> Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(invalid_input)
+}
+
+fn invalid_input(error: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error::new(ErrorKind::InvalidInput, error)
+}
@@ -0,0 +1,284 @@
+use std::io::{Error, Result};
+
+use super::{Codec, Frame, Transport};
+
+/// Appended to the client key to compute `Sec-WebSocket-Accept`
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest handshake request we are willing to buffer
+const MAX_HANDSHAKE_LEN: usize = 8192;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// WebSocket server side codec (RFC 6455)
+///
+/// Answers the opening handshake and pings by itself, reassembles
+/// fragmented messages, and sends text frames for UTF-8 messages and
+/// binary frames otherwise.
+pub struct WebSocketCodec {
+    handshake_done: bool,
+    max_message_len: usize,
+    /// Payload of a fragmented message received so far
+    fragments: Vec<u8>,
+}
+
+impl WebSocketCodec {
+    pub fn new() -> Self {
+        WebSocketCodec::with_max_message_len(16 * 1024 * 1024)
+    }
+
+    /// Messages longer than `max_message_len` are rejected
+    pub fn with_max_message_len(max_message_len: usize) -> Self {
+        WebSocketCodec {
+            handshake_done: false,
+            max_message_len,
+            fragments: Vec::new(),
+        }
+    }
+
+    /// Codec for a connection that already completed the handshake,
+    /// e.g. after an HTTP handler answered the upgrade request itself
+    pub fn after_handshake() -> Self {
+        WebSocketCodec {
+            handshake_done: true,
+            ..WebSocketCodec::new()
+        }
+    }
+
+    fn decode_handshake(&mut self, buf: &[u8]) -> Result<Option<(usize, Frame)>> {
+        let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+            if buf.len() > MAX_HANDSHAKE_LEN {
+                return Err(invalid_data("websocket handshake too large"));
+            }
+            return Ok(None);
+        };
+
+        let request = String::from_utf8_lossy(&buf[..end]);
+        let key = request
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-key"))
+            .map(|(_, value)| value.trim())
+            .ok_or_else(|| invalid_data("missing Sec-WebSocket-Key header"))?;
+
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        );
+
+        self.handshake_done = true;
+        Ok(Some((end + 4, Frame::Control(response.into_bytes()))))
+    }
+
+    fn decode_frame(&mut self, buf: &[u8]) -> Result<Option<(usize, Frame)>> {
+        let Some(&[first, second]) = buf.first_chunk::<2>() else {
+            return Ok(None);
+        };
+
+        let fin = first & 0x80 != 0;
+        let opcode = first & 0x0F;
+        let masked = second & 0x80 != 0;
+        if !masked {
+            return Err(invalid_data("client frames must be masked"));
+        }
+
+        let (payload_len, mut offset) = match second & 0x7F {
+            126 => match buf.get(2..4) {
+                Some(len) => (u16::from_be_bytes([len[0], len[1]]) as usize, 4),
+                None => return Ok(None),
+            },
+            127 => match buf.get(2..10) {
+                Some(len) => {
+                    let mut bytes = [0u8; 8];
+                    bytes.copy_from_slice(len);
+                    (u64::from_be_bytes(bytes) as usize, 10)
+                }
+                None => return Ok(None),
+            },
+            len => (len as usize, 2),
+        };
+
+        if payload_len > self.max_message_len
+            || self.fragments.len() + payload_len > self.max_message_len
+        {
+            return Err(invalid_data("websocket message exceeds limit"));
+        }
+
+        let Some(mask) = buf.get(offset..offset + 4) else {
+            return Ok(None);
+        };
+        let mask = [mask[0], mask[1], mask[2], mask[3]];
+        offset += 4;
+
+        let frame_len = offset + payload_len;
+        let Some(payload) = buf.get(offset..frame_len) else {
+            return Ok(None);
+        };
+        let payload: Vec<u8> = payload
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+
+        let frame = match opcode {
+            OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                self.fragments.extend_from_slice(&payload);
+                if !fin {
+                    // Wait for the remaining fragments, nothing to deliver yet
+                    return Ok(Some((frame_len, Frame::Consumed)));
+                }
+                Frame::Message(std::mem::take(&mut self.fragments))
+            }
+            OPCODE_PING => Frame::Control(encode_frame(OPCODE_PONG, &payload)),
+            OPCODE_PONG => Frame::Consumed,
+            OPCODE_CLOSE => Frame::Close,
+            _ => return Err(invalid_data("unknown websocket opcode")),
+        };
+
+        Ok(Some((frame_len, frame)))
+    }
+}
+
+impl Default for WebSocketCodec {
+    fn default() -> Self {
+        WebSocketCodec::new()
+    }
+}
+
+impl Codec for WebSocketCodec {
+    fn decode(&mut self, buf: &[u8]) -> Result<Option<(usize, Frame)>> {
+        if self.handshake_done {
+            self.decode_frame(buf)
+        } else {
+            self.decode_handshake(buf)
+        }
+    }
+
+    fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        let opcode = if std::str::from_utf8(data).is_ok() {
+            OPCODE_TEXT
+        } else {
+            OPCODE_BINARY
+        };
+        encode_frame(opcode, data)
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::WebSocket
+    }
+}
+
+/// Build a final, unmasked frame as sent by servers
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn invalid_data(message: &str) -> Error {
+    crate::Error::Protocol(message.to_string()).into()
+}
+
+/// `Sec-WebSocket-Accept` value for the client's key
+fn accept_key(key: &str) -> String {
+    let mut input = key.as_bytes().to_vec();
+    input.extend_from_slice(HANDSHAKE_GUID.as_bytes());
+    base64_encode(&sha1(&input))
+}
+
+/// SHA-1 digest, only used for the handshake where it is mandated
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let triple = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (triple >> (18 - 6 * i)) & 0x3F;
+                encoded.push(ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+/// Message and byte rates allowed for a client or a source IP
+///
+/// Each rate is enforced with a token bucket holding one second worth of
+/// tokens, so short bursts up to the per second rate are let through.
+/// A message larger than the byte rate is admitted once the bucket is
+/// full, and the bytes it overdraws are paid back before the next one.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    messages_per_sec: Option<u32>,
+    bytes_per_sec: Option<u64>,
+}
+
+impl RateLimit {
+    /// Allow at most `rate` messages per second
+    pub fn messages_per_sec(mut self, rate: u32) -> Self {
+        self.messages_per_sec = Some(rate.max(1));
+        self
+    }
+
+    /// Allow at most `rate` bytes of messages per second
+    pub fn bytes_per_sec(mut self, rate: u64) -> Self {
+        self.bytes_per_sec = Some(rate.max(1));
+        self
+    }
+}
+
+/// What happens to a client that exceeds its rate limit
+///
+/// `EventHandler::on_rate_limited` is called in every case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitAction {
+    /// Drop the message and carry on
+    #[default]
+    Drop,
+    /// Hold the message back and stop reading from the client until its
+    /// limits admit it, then deliver it and carry on: nothing is lost, the
+    /// client is slowed down to its rate
+    PauseReads,
+    /// Drop the message and disconnect the client
+    Disconnect,
+}
+
+/// Token bucket refilled continuously at `rate` tokens per second
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate,
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        self.refilled_at = now;
+    }
+
+    /// Tokens needed before `amount` is admitted, capped to a full bucket
+    fn required(&self, amount: f64) -> f64 {
+        amount.min(self.rate)
+    }
+
+    fn time_until(&self, amount: f64) -> Duration {
+        // Tolerate rounding, a client resumed right on time must get through
+        let missing = self.required(amount) - self.tokens;
+        if missing <= 1e-9 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(missing / self.rate)
+    }
+}
+
+/// Buckets enforcing one `RateLimit`
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimiter {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        RateLimiter {
+            messages: limit
+                .messages_per_sec
+                .map(|rate| TokenBucket::new(rate as f64, now)),
+            bytes: limit
+                .bytes_per_sec
+                .map(|rate| TokenBucket::new(rate as f64, now)),
+        }
+    }
+
+    fn buckets(&mut self, len: usize) -> impl Iterator<Item = (&mut TokenBucket, f64)> {
+        let messages = self.messages.as_mut().map(|bucket| (bucket, 1.0));
+        let bytes = self.bytes.as_mut().map(|bucket| (bucket, len as f64));
+        messages.into_iter().chain(bytes)
+    }
+
+    /// How long until a message of `len` bytes is admitted, zero if it
+    /// can go through now
+    pub fn time_until(&mut self, len: usize, now: Instant) -> Duration {
+        self.buckets(len)
+            .map(|(bucket, amount)| {
+                bucket.refill(now);
+                bucket.time_until(amount)
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Take the tokens of an admitted message of `len` bytes
+    pub fn consume(&mut self, len: usize) {
+        for (bucket, amount) in self.buckets(len) {
+            bucket.tokens -= amount;
+        }
+    }
+
+    /// Returns `true` if the buckets are full, so forgetting them changes
+    /// nothing
+    pub fn is_idle(&mut self, now: Instant) -> bool {
+        self.buckets(0).all(|(bucket, _)| {
+            bucket.refill(now);
+            bucket.tokens >= bucket.rate
+        })
+    }
+}
@@ -0,0 +1,129 @@
+//! Low-level readiness API the event loop is built on
+//!
+//! The server waits on one [`PlatformReactor`], an epoll instance on
+//! Linux, a wepoll port on Windows with the `wepoll` feature. Other fds (pipes, timerfds, pidfds of child processes...) can be
+//! added to it with `EpollServer::register_fd`, their readiness is then
+//! reported to `EventHandler::on_custom_event` from the same loop as the
+//! clients, without another thread.
+//!
+//! A [`Reactor`] can also be created and driven directly, with
+//! [`Event`]s built from [`EventFlags`] and a [`PeerRole`] identifying
+//! the fd in the results of `wait`.
+
+use std::{
+    io::{Error, ErrorKind, Result},
+    time::Duration,
+};
+
+use crate::signal::SignalMask;
+
+#[cfg(target_os = "linux")]
+pub use crate::epoll::Epoll;
+pub use crate::epoll::{Event, EventFlags, MAX_CUSTOM_TOKEN, PeerRole};
+#[cfg(target_os = "linux")]
+pub use crate::mock_poller::MockPoller;
+#[cfg(all(windows, feature = "wepoll"))]
+pub use crate::wepoll::Wepoll;
+
+/// [`Reactor`] under the name of a poller, which is all it does
+pub use self::Reactor as Poller;
+
+/// OS handle of a registered socket or file
+#[cfg(unix)]
+pub type RawSource = std::os::fd::RawFd;
+
+/// OS handle of a registered socket
+#[cfg(windows)]
+pub type RawSource = std::os::windows::io::RawSocket;
+
+/// Kernel interface the event loop is driven through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// The platform's reactor, with one `epoll_ctl` per interest change
+    #[default]
+    Epoll,
+    /// epoll, with the `epoll_ctl` calls of a loop tick batched: the
+    /// clients' interest changes are queued as `IORING_OP_EPOLL_CTL`
+    /// entries of an io_uring and submitted in one `io_uring_enter`
+    /// (Linux 5.18 and later)
+    ///
+    /// Nothing else goes through the ring, reads and writes keep their
+    /// `recvmsg`, `sendmsg` and `sendfile` calls.
+    BatchedEpollCtl,
+}
+
+/// Readiness notification backend of the server
+///
+/// The event loop only talks to the OS through these four operations, so
+/// any API with epoll semantics can drive it: interests are registered
+/// per handle with an `Event` whose `data` comes back from `wait`
+/// unchanged. Implemented by `Epoll` on Linux, which can batch its
+/// modifications through an io_uring (`Backend::BatchedEpollCtl`), by
+/// `MockPoller` for tests, and by `Wepoll` on Windows behind the `wepoll`
+/// feature.
+pub trait Reactor: Sized {
+    /// Create a new, empty interest list
+    fn new() -> Result<Self>;
+
+    /// Create a new, empty interest list driven by `backend`
+    fn with_backend(backend: Backend) -> Result<Self> {
+        match backend {
+            Backend::Epoll => Self::new(),
+            _ => Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("{backend:?} backend is not available on this platform"),
+            )),
+        }
+    }
+
+    /// Add `source` to the interest list
+    fn add_interest(&self, source: RawSource, event: Event) -> Result<()>;
+
+    /// Replace the interests of a registered `source`
+    fn modify_interest(&self, source: RawSource, event: Event) -> Result<()>;
+
+    /// Replace the interests of a registered `source` like
+    /// `modify_interest`, possibly batched with other modifications until
+    /// `submit_modifications`, which then reports their failures
+    fn queue_modify_interest(&self, source: RawSource, event: Event) -> Result<()> {
+        self.modify_interest(source, event)
+    }
+
+    /// Apply the modifications of `queue_modify_interest`, failing with the
+    /// error of the first one that failed
+    fn submit_modifications(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Remove `source` from the interest list
+    ///
+    /// `source` is left open: whatever owns it, e.g. the client's stream,
+    /// closes it when dropped, so it is never closed twice nor a number
+    /// reused by another file in the meantime.
+    fn remove_interest(&self, source: RawSource) -> Result<()>;
+
+    /// Replace the interests of `source` by registering it again, for
+    /// registrations `modify_interest` is refused on
+    fn reregister(&self, source: RawSource, event: Event) -> Result<()> {
+        self.modify_interest(source, event)
+    }
+
+    /// Wait up to `timeout` (`None` blocks) for ready handles, filling
+    /// `events` up to its capacity
+    ///
+    /// `sigmask`, if given, is the thread's signal mask during the wait.
+    fn wait(
+        &self,
+        events: &mut Vec<Event>,
+        timeout: Option<Duration>,
+        sigmask: Option<&SignalMask>,
+    ) -> Result<()>;
+}
+
+/// Backend the server runs on for the target platform
+#[cfg(target_os = "linux")]
+pub type PlatformReactor = Epoll;
+
+/// Backend the server runs on for the target platform
+#[cfg(all(windows, feature = "wepoll"))]
+pub type PlatformReactor = Wepoll;
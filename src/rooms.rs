@@ -0,0 +1,70 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::client_id::ClientId;
+
+/// Named groups of clients, for broadcasting to a subset of them
+///
+/// Memberships are kept both ways so leaving every room on disconnect does
+/// not scan all rooms. Empty rooms are dropped.
+#[derive(Default)]
+pub(crate) struct Rooms {
+    members: HashMap<String, HashSet<ClientId>>,
+    joined: HashMap<ClientId, HashSet<String>>,
+}
+
+impl Rooms {
+    /// Add the client to `room`, returns `false` if it already was a member
+    pub fn join(&mut self, client_id: ClientId, room: String) -> bool {
+        let added = self
+            .members
+            .entry(room.clone())
+            .or_default()
+            .insert(client_id);
+        self.joined.entry(client_id).or_default().insert(room);
+        added
+    }
+
+    /// Remove the client from `room`, returns `false` if it was not a member
+    pub fn leave(&mut self, client_id: ClientId, room: &str) -> bool {
+        let Some(members) = self.members.get_mut(room) else {
+            return false;
+        };
+        let removed = members.remove(&client_id);
+        if members.is_empty() {
+            self.members.remove(room);
+        }
+        if let Some(rooms) = self.joined.get_mut(&client_id) {
+            rooms.remove(room);
+            if rooms.is_empty() {
+                self.joined.remove(&client_id);
+            }
+        }
+        removed
+    }
+
+    /// Remove the client from every room it joined
+    pub fn leave_all(&mut self, client_id: ClientId) {
+        for room in self.joined.remove(&client_id).unwrap_or_default() {
+            if let Some(members) = self.members.get_mut(&room) {
+                members.remove(&client_id);
+                if members.is_empty() {
+                    self.members.remove(&room);
+                }
+            }
+        }
+    }
+
+    pub fn members(&self, room: &str) -> Vec<ClientId> {
+        self.members
+            .get(room)
+            .map(|members| members.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn rooms_of(&self, client_id: ClientId) -> Vec<String> {
+        self.joined
+            .get(&client_id)
+            .map(|rooms| rooms.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
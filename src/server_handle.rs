@@ -0,0 +1,267 @@
+use std::{
+    any::Any,
+    fs::File,
+    io::{ErrorKind, Read, Result, Write},
+    net::TcpStream,
+    os::fd::{AsRawFd, FromRawFd, RawFd},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{Receiver, SendError, Sender, channel},
+    },
+    time::{Duration, Instant},
+};
+
+use log::error;
+
+use crate::{
+    bytes::Bytes,
+    client_id::ClientId,
+    ep_syscall,
+    error::Error,
+    ffi::{EFD_CLOEXEC, EFD_NONBLOCK},
+};
+
+/// Work sent to the event loop from other threads
+pub(crate) enum Command {
+    SendTo(ClientId, Bytes),
+    /// Data and the time the broadcast was requested
+    Broadcast(Bytes, Instant),
+    /// Connection accepted by an `Acceptor`, to be served by this loop
+    Adopt(TcpStream),
+    /// Handler to swap in, of the server's handler type
+    ReplaceHandler(Box<dyn Any + Send>),
+    /// Stop accepting and stop the loop once the clients are gone, or at
+    /// the deadline
+    Drain(Instant),
+}
+
+/// Client counts of a loop, shared with its handles
+#[derive(Debug, Default)]
+pub(crate) struct Load {
+    /// Clients connected at the end of the last tick
+    connected: AtomicUsize,
+    /// Connections handed over and not yet picked up by the loop
+    adopting: AtomicUsize,
+}
+
+/// eventfd registered in epoll, written to wake up `epoll_wait`
+pub(crate) struct Waker {
+    fd: File,
+}
+
+impl Waker {
+    pub fn new() -> Result<Self> {
+        let fd = ep_syscall!(eventfd(0, EFD_NONBLOCK | EFD_CLOEXEC))?;
+        Ok(Waker {
+            // SAFETY: the fd was just created and nothing else owns it
+            fd: unsafe { File::from_raw_fd(fd) },
+        })
+    }
+
+    pub fn wake(&self) -> Result<()> {
+        match (&self.fd).write(&1u64.to_ne_bytes()) {
+            Ok(_) => Ok(()),
+            // The counter is saturated, the loop is going to wake up anyway
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reset the counter so the next `wake` notifies epoll again
+    pub fn reset(&self) -> Result<()> {
+        let mut counter = [0u8; 8];
+        match (&self.fd).read(&mut counter) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Handle to a running server, usable from any thread
+///
+/// Obtained with `EpollServer::handle` and cheap to clone. Commands are
+/// queued and the event loop is woken up through an eventfd, so background
+/// work (database queries, blocking I/O) can hand its results back to the
+/// single threaded loop.
+#[derive(Clone)]
+pub struct ServerHandle {
+    commands: Sender<Command>,
+    waker: Arc<Waker>,
+    shutdown_signal: Arc<AtomicBool>,
+    load: Arc<Load>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(
+        commands: Sender<Command>,
+        waker: Arc<Waker>,
+        shutdown_signal: Arc<AtomicBool>,
+        load: Arc<Load>,
+    ) -> Self {
+        ServerHandle {
+            commands,
+            waker,
+            shutdown_signal,
+            load,
+        }
+    }
+
+    /// Queue data to be written to the client, see `ServerContext::send_to`
+    ///
+    /// Data for a client that is gone by the time the loop handles the
+    /// command is dropped. `Bytes` received from a client are forwarded
+    /// without a copy.
+    pub fn send_to(&self, client_id: ClientId, data: impl Into<Bytes>) -> crate::Result<()> {
+        self.send(Command::SendTo(client_id, data.into()))
+    }
+
+    /// Queue data to be written to every connected client
+    ///
+    /// Only clients connected when this is called receive the data, not
+    /// those accepted while the command waits for the loop. The clients
+    /// share one buffer, see `Broadcaster` for several loops.
+    pub fn broadcast(&self, data: impl Into<Bytes>) -> crate::Result<()> {
+        self.broadcast_emitted_at(data.into(), Instant::now())
+    }
+
+    pub(crate) fn broadcast_emitted_at(
+        &self,
+        data: Bytes,
+        emitted_at: Instant,
+    ) -> crate::Result<()> {
+        self.send(Command::Broadcast(data, emitted_at))
+    }
+
+    /// Swap the server's handler for `handler` between two iterations of
+    /// its loop, see `EpollServer::replace_handler`
+    ///
+    /// `handler` has to be of the server's handler type: any handler in a
+    /// `Box<dyn EventHandler + Send>` for a server created with one. A
+    /// handler of another type is dropped by the loop with an error logged.
+    pub fn replace_handler<T: Send + 'static>(&self, handler: T) -> crate::Result<()> {
+        self.send(Command::ReplaceHandler(Box::new(handler)))
+    }
+
+    /// Number of clients the server had at the end of its last tick
+    pub fn connected_clients(&self) -> usize {
+        self.load.connected.load(Ordering::Relaxed)
+    }
+
+    /// Serve `stream`, a connection accepted outside the server, e.g. one
+    /// received from an acceptor process with `fdpass::recv_fd`
+    ///
+    /// The stream is made non-blocking and served like a client of the
+    /// primary listener. Fails with `Error::ShutdownInProgress` once the
+    /// server is gone.
+    pub fn add_client(&self, stream: TcpStream) -> crate::Result<()> {
+        stream.set_nonblocking(true)?;
+        self.adopt(stream).map_err(|_| Error::ShutdownInProgress)
+    }
+
+    /// Hand an accepted connection over to the loop, which serves it like
+    /// one it accepted itself
+    ///
+    /// The stream is given back if the server is gone.
+    pub(crate) fn adopt(&self, stream: TcpStream) -> std::result::Result<(), TcpStream> {
+        self.load.adopting.fetch_add(1, Ordering::Relaxed);
+        if let Err(SendError(command)) = self.commands.send(Command::Adopt(stream)) {
+            self.load.adopting.fetch_sub(1, Ordering::Relaxed);
+            let Command::Adopt(stream) = command else {
+                unreachable!("the command sent is an adoption");
+            };
+            return Err(stream);
+        }
+        if let Err(e) = self.waker.wake() {
+            // The connection is picked up on the loop's next tick instead
+            error!("Failed to wake the loop up for a new connection: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Clients connected or on their way to the loop
+    pub(crate) fn load(&self) -> usize {
+        self.connected_clients() + self.load.adopting.load(Ordering::Relaxed)
+    }
+
+    /// Stop accepting new connections and let the existing clients finish
+    ///
+    /// `EpollServer::run` returns once every client disconnected, or when
+    /// `timeout` expires with some still connected (they are then closed
+    /// when the server is dropped). New connections wait in the listener's
+    /// backlog, for a process inheriting the listener (see
+    /// `EpollServer::from_listener`) to accept them: with both running side
+    /// by side, a restart drops no connection.
+    pub fn drain(&self, timeout: Duration) -> crate::Result<()> {
+        self.send(Command::Drain(Instant::now() + timeout))
+    }
+
+    /// Stop the event loop, `EpollServer::run` returns shortly after
+    pub fn shutdown(&self) -> crate::Result<()> {
+        self.shutdown_signal.store(true, Ordering::Relaxed);
+        Ok(self.waker.wake()?)
+    }
+
+    /// Fails with `Error::ShutdownInProgress` once the server is gone
+    fn send(&self, command: Command) -> crate::Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| Error::ShutdownInProgress)?;
+        Ok(self.waker.wake()?)
+    }
+}
+
+/// Receiving side of the handles, owned by the server
+pub(crate) struct CommandQueue {
+    commands: Receiver<Command>,
+    sender: Sender<Command>,
+    waker: Arc<Waker>,
+    load: Arc<Load>,
+}
+
+impl CommandQueue {
+    pub fn new() -> Result<Self> {
+        let (sender, commands) = channel();
+        Ok(CommandQueue {
+            commands,
+            sender,
+            waker: Arc::new(Waker::new()?),
+            load: Arc::default(),
+        })
+    }
+
+    pub fn handle(&self, shutdown_signal: Arc<AtomicBool>) -> ServerHandle {
+        ServerHandle::new(
+            self.sender.clone(),
+            self.waker.clone(),
+            shutdown_signal,
+            self.load.clone(),
+        )
+    }
+
+    /// Publish the number of connected clients to the handles
+    pub fn set_connected(&self, connected: usize) {
+        self.load.connected.store(connected, Ordering::Relaxed);
+    }
+
+    /// Account for an adopted connection, once `connected` includes it
+    pub fn finish_adoption(&self, connected: usize) {
+        self.set_connected(connected);
+        self.load.adopting.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn waker(&self) -> &Waker {
+        &self.waker
+    }
+
+    /// Next queued command, if any
+    pub fn try_next(&self) -> Option<Command> {
+        // The queue keeps a sender itself, so it never disconnects
+        self.commands.try_recv().ok()
+    }
+}
@@ -0,0 +1,81 @@
+use std::{io::Result, time::Duration};
+
+use crate::{
+    reactor::{Backend, Event, MockPoller, PlatformReactor, RawSource, Reactor},
+    signal::SignalMask,
+};
+
+/// Reactor of a server, the platform's or a [`MockPoller`] driven by tests
+// One per server, boxing the platform's would only add an indirection to
+// every wait
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum ServerReactor {
+    Platform(PlatformReactor),
+    Mock(MockPoller),
+}
+
+impl Reactor for ServerReactor {
+    fn new() -> Result<Self> {
+        Ok(ServerReactor::Platform(PlatformReactor::new()?))
+    }
+
+    fn with_backend(backend: Backend) -> Result<Self> {
+        Ok(ServerReactor::Platform(PlatformReactor::with_backend(
+            backend,
+        )?))
+    }
+
+    fn add_interest(&self, source: RawSource, event: Event) -> Result<()> {
+        match self {
+            ServerReactor::Platform(reactor) => reactor.add_interest(source, event),
+            ServerReactor::Mock(reactor) => reactor.add_interest(source, event),
+        }
+    }
+
+    fn modify_interest(&self, source: RawSource, event: Event) -> Result<()> {
+        match self {
+            ServerReactor::Platform(reactor) => reactor.modify_interest(source, event),
+            ServerReactor::Mock(reactor) => reactor.modify_interest(source, event),
+        }
+    }
+
+    fn queue_modify_interest(&self, source: RawSource, event: Event) -> Result<()> {
+        match self {
+            ServerReactor::Platform(reactor) => reactor.queue_modify_interest(source, event),
+            ServerReactor::Mock(reactor) => reactor.queue_modify_interest(source, event),
+        }
+    }
+
+    fn submit_modifications(&self) -> Result<()> {
+        match self {
+            ServerReactor::Platform(reactor) => reactor.submit_modifications(),
+            ServerReactor::Mock(reactor) => reactor.submit_modifications(),
+        }
+    }
+
+    fn remove_interest(&self, source: RawSource) -> Result<()> {
+        match self {
+            ServerReactor::Platform(reactor) => reactor.remove_interest(source),
+            ServerReactor::Mock(reactor) => reactor.remove_interest(source),
+        }
+    }
+
+    fn reregister(&self, source: RawSource, event: Event) -> Result<()> {
+        match self {
+            ServerReactor::Platform(reactor) => reactor.reregister(source, event),
+            ServerReactor::Mock(reactor) => reactor.reregister(source, event),
+        }
+    }
+
+    fn wait(
+        &self,
+        events: &mut Vec<Event>,
+        timeout: Option<Duration>,
+        sigmask: Option<&SignalMask>,
+    ) -> Result<()> {
+        match self {
+            ServerReactor::Platform(reactor) => reactor.wait(events, timeout, sigmask),
+            ServerReactor::Mock(reactor) => reactor.wait(events, timeout, sigmask),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{ErrorKind, Result},
+    path::Path,
+};
+
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+/// Client sessions carried across a restart, keyed by a resume token
+///
+/// On shutdown the handler saves the session of every client it wants to
+/// keep (usually from `EventHandler::on_shutdown`) under a token the
+/// client already knows, and writes the store to disk. After the restart
+/// the store is loaded again and each reconnecting client gets its session
+/// back by presenting its token:
+///
+/// ```no_run
+/// # use epoll_worker::SessionStore;
+/// let mut sessions = SessionStore::new();
+/// sessions.insert("resume-token-42", &("alice", 3u32))?;
+/// sessions.save("sessions.json")?;
+///
+/// // ...after the restart
+/// let mut sessions = SessionStore::load("sessions.json")?;
+/// let session: Option<(String, u32)> = sessions.take("resume-token-42")?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+///
+/// Sessions are stored as JSON, any type implementing serde's `Serialize`
+/// and `Deserialize` can be saved.
+#[derive(Debug, Default, Clone)]
+pub struct SessionStore {
+    sessions: HashMap<String, Value>,
+}
+
+impl SessionStore {
+    pub fn new() -> Self {
+        SessionStore::default()
+    }
+
+    /// Load the sessions saved at `path`, a missing file is an empty store
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(SessionStore::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(SessionStore {
+            sessions: serde_json::from_slice(&contents)?,
+        })
+    }
+
+    /// Write the sessions to `path`
+    ///
+    /// The file is replaced atomically, a crash while saving leaves the
+    /// previous sessions intact.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+
+        fs::write(&temp_path, serde_json::to_vec(&self.sessions)?)?;
+        fs::rename(&temp_path, path)
+    }
+
+    /// Save `session` under `token`, replacing any session it had
+    pub fn insert<T: Serialize>(&mut self, token: impl Into<String>, session: &T) -> Result<()> {
+        self.sessions
+            .insert(token.into(), serde_json::to_value(session)?);
+        Ok(())
+    }
+
+    /// Remove and return the session saved under `token`
+    ///
+    /// Each session can only be restored once, a replayed token finds
+    /// nothing.
+    pub fn take<T: DeserializeOwned>(&mut self, token: &str) -> Result<Option<T>> {
+        match self.sessions.remove(token) {
+            Some(session) => Ok(Some(serde_json::from_value(session)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Number of sessions waiting to be restored
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+}
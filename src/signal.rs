@@ -0,0 +1,49 @@
+/// Signals blocked while the event loop waits for events
+///
+/// The mask replaces the thread's signal mask for the duration of each
+/// wait, atomically (`epoll_pwait2`). Blocking a signal in the thread and
+/// leaving it out of the wait mask makes it arrive only while the loop is
+/// waiting, so a flag set by its handler is seen right after the wait
+/// instead of being lost between checking the flag and waiting.
+///
+/// ```
+/// use epoll_worker::{ServerConfig, SignalMask};
+///
+/// // Every signal may interrupt the wait
+/// let config = ServerConfig::default().wait_signal_mask(SignalMask::empty());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SignalMask {
+    /// Bit `n - 1` stands for signal `n`, like the kernel's `sigset_t`
+    bits: u64,
+}
+
+impl SignalMask {
+    /// Mask blocking no signal
+    pub fn empty() -> Self {
+        SignalMask::default()
+    }
+
+    /// Also block `signal`, signals outside `1..=64` are ignored
+    pub fn block(mut self, signal: i32) -> Self {
+        if let Some(bit) = Self::bit(signal) {
+            self.bits |= bit;
+        }
+        self
+    }
+
+    /// Returns `true` if `signal` is blocked by the mask
+    pub fn blocks(&self, signal: i32) -> bool {
+        Self::bit(signal).is_some_and(|bit| self.bits & bit != 0)
+    }
+
+    /// The mask as the kernel reads it
+    #[cfg(target_os = "linux")]
+    pub(crate) fn as_ptr(&self) -> *const u64 {
+        &self.bits
+    }
+
+    fn bit(signal: i32) -> Option<u64> {
+        (1..=64).contains(&signal).then(|| 1 << (signal - 1))
+    }
+}
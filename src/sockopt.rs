@@ -0,0 +1,300 @@
+use std::{
+    ffi::c_void,
+    io::{Error, ErrorKind, Result},
+    mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener, TcpStream, ToSocketAddrs},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    time::Duration,
+};
+
+use crate::{
+    ep_syscall, ep_syscall_retry,
+    ffi::{
+        AF_INET, AF_INET6, F_GETFD, F_GETFL, F_SETFD, F_SETFL, FD_CLOEXEC, IPPROTO_IPV6,
+        IPPROTO_TCP, IPV6_V6ONLY, O_NONBLOCK, SO_ACCEPTCONN, SO_DOMAIN, SO_KEEPALIVE, SO_REUSEADDR,
+        SO_TYPE, SOCK_CLOEXEC, SOCK_NONBLOCK, SOCK_STREAM, SOL_SOCKET, SockAddrIn, SockAddrIn6,
+        SockAddrStorage, TCP_CORK, TCP_KEEPCNT, TCP_KEEPIDLE, TCP_KEEPINTVL, TCP_NODELAY,
+    },
+};
+
+/// TCP keepalive probing, detects peers that vanished without closing
+///
+/// After `idle` without traffic the kernel sends a probe every `interval`,
+/// and drops the connection after `retries` unanswered probes. The client
+/// is then reported as disconnected like any other failed connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub retries: u32,
+}
+
+impl Default for TcpKeepalive {
+    fn default() -> Self {
+        TcpKeepalive {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+            retries: 5,
+        }
+    }
+}
+
+/// Enable or disable `TCP_NODELAY`, i.e. Nagle's algorithm
+pub(crate) fn set_nodelay(fd: RawFd, nodelay: bool) -> Result<()> {
+    set_option(fd, IPPROTO_TCP, TCP_NODELAY, nodelay as i32)
+}
+
+/// Enable or disable `TCP_CORK`
+///
+/// While corked only full segments are sent, clearing it sends whatever
+/// is left right away.
+pub(crate) fn set_cork(fd: RawFd, cork: bool) -> Result<()> {
+    set_option(fd, IPPROTO_TCP, TCP_CORK, cork as i32)
+}
+
+/// Enable keepalive probing with the given timing, or disable it
+pub(crate) fn set_keepalive(fd: RawFd, keepalive: Option<TcpKeepalive>) -> Result<()> {
+    let Some(keepalive) = keepalive else {
+        return set_option(fd, SOL_SOCKET, SO_KEEPALIVE, 0);
+    };
+
+    // The kernel counts in whole seconds, and rejects zero
+    let seconds = |duration: Duration| duration.as_secs().clamp(1, i32::MAX as u64) as i32;
+    set_option(fd, IPPROTO_TCP, TCP_KEEPIDLE, seconds(keepalive.idle))?;
+    set_option(fd, IPPROTO_TCP, TCP_KEEPINTVL, seconds(keepalive.interval))?;
+    set_option(
+        fd,
+        IPPROTO_TCP,
+        TCP_KEEPCNT,
+        keepalive.retries.clamp(1, i32::MAX as u32) as i32,
+    )?;
+    set_option(fd, SOL_SOCKET, SO_KEEPALIVE, 1)
+}
+
+/// How listening sockets are created, see `ServerConfig::listen_backlog`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ListenOptions {
+    pub backlog: u32,
+    pub reuse_addr: bool,
+    /// `None` keeps the system default (`net.ipv6.bindv6only`)
+    pub ipv6_only: Option<bool>,
+}
+
+impl Default for ListenOptions {
+    fn default() -> Self {
+        ListenOptions {
+            backlog: 1024,
+            reuse_addr: true,
+            ipv6_only: None,
+        }
+    }
+}
+
+/// Create a non-blocking listener on the first of `addrs` that binds,
+/// like `TcpListener::bind` but with the socket set up from `options`
+/// between `socket(2)` and `listen(2)`
+pub(crate) fn bind_listener<A: ToSocketAddrs>(
+    addrs: A,
+    options: ListenOptions,
+) -> Result<TcpListener> {
+    let mut last_error = None;
+    for addr in addrs.to_socket_addrs()? {
+        match bind_one(addr, options) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+fn bind_one(addr: SocketAddr, options: ListenOptions) -> Result<TcpListener> {
+    let domain = if addr.is_ipv4() { AF_INET } else { AF_INET6 };
+    let fd = ep_syscall!(socket(
+        domain,
+        SOCK_STREAM | SOCK_NONBLOCK | SOCK_CLOEXEC,
+        0
+    ))?;
+    // SAFETY: the fd was just created and nothing else owns it, it is
+    // closed if any of the steps below fails
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    if options.reuse_addr {
+        set_option(fd, SOL_SOCKET, SO_REUSEADDR, 1)?;
+    }
+    if addr.is_ipv6()
+        && let Some(ipv6_only) = options.ipv6_only
+    {
+        set_option(fd, IPPROTO_IPV6, IPV6_V6ONLY, ipv6_only as i32)?;
+    }
+    let (raw, len) = raw_addr(addr);
+    ep_syscall!(bind(fd, (&raw const raw).cast::<c_void>(), len))?;
+    let backlog = options.backlog.min(i32::MAX as u32) as i32;
+    ep_syscall!(listen(fd, backlog))?;
+    Ok(TcpListener::from(socket))
+}
+
+/// Start connecting a new non-blocking, close-on-exec socket to `addr`
+///
+/// Returns the socket and whether it is connected already; if not, it
+/// becomes writable once the connection is made or failed, and
+/// `TcpStream::take_error` tells which.
+#[cfg(feature = "async")]
+pub(crate) fn connect(addr: SocketAddr) -> Result<(TcpStream, bool)> {
+    let domain = if addr.is_ipv4() { AF_INET } else { AF_INET6 };
+    let fd = ep_syscall!(socket(
+        domain,
+        SOCK_STREAM | SOCK_NONBLOCK | SOCK_CLOEXEC,
+        0
+    ))?;
+    // SAFETY: the fd was just created and nothing else owns it
+    let stream = TcpStream::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    let (raw, len) = raw_addr(addr);
+    match ep_syscall!(connect(fd, (&raw const raw).cast::<c_void>(), len)) {
+        Ok(_) => Ok((stream, true)),
+        Err(e) if crate::error::os_error(&e) == Some(crate::ffi::EINPROGRESS) => {
+            Ok((stream, false))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// `addr` in the kernel's layout, with its length
+fn raw_addr(addr: SocketAddr) -> (SockAddrStorage, u32) {
+    let mut storage = SockAddrStorage::default();
+    let storage_ptr = &raw mut storage;
+    match addr {
+        SocketAddr::V4(addr) => {
+            let raw = SockAddrIn {
+                family: AF_INET as u16,
+                port: addr.port().to_be(),
+                addr: addr.ip().octets(),
+                zero: [0; 8],
+            };
+            // SAFETY: a `sockaddr_in` fits in and is less aligned than the
+            // storage
+            unsafe { storage_ptr.cast::<SockAddrIn>().write(raw) };
+            (storage, mem::size_of::<SockAddrIn>() as u32)
+        }
+        SocketAddr::V6(addr) => {
+            let raw = SockAddrIn6 {
+                family: AF_INET6 as u16,
+                port: addr.port().to_be(),
+                flowinfo: addr.flowinfo().to_be(),
+                addr: addr.ip().octets(),
+                scope_id: addr.scope_id(),
+            };
+            // SAFETY: as above, for a `sockaddr_in6`
+            unsafe { storage_ptr.cast::<SockAddrIn6>().write(raw) };
+            (storage, mem::size_of::<SockAddrIn6>() as u32)
+        }
+    }
+}
+
+/// Accept a connection with `accept4(2)`, its socket is created
+/// non-blocking and close-on-exec
+///
+/// Saves the `fcntl` of `set_nonblocking` on every connection, and the
+/// socket is never inherited by a process forked in between.
+pub(crate) fn accept(listener: &TcpListener) -> Result<(TcpStream, SocketAddr)> {
+    let mut storage = SockAddrStorage::default();
+    let mut len = mem::size_of::<SockAddrStorage>() as u32;
+    let fd = ep_syscall_retry!(accept4(
+        listener.as_raw_fd(),
+        (&raw mut storage).cast::<c_void>(),
+        &raw mut len,
+        SOCK_NONBLOCK | SOCK_CLOEXEC
+    ))?;
+    // SAFETY: the fd was just created and nothing else owns it
+    let stream = TcpStream::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    let addr = socket_addr(&storage)?;
+    Ok((stream, addr))
+}
+
+/// Address stored by the kernel in `storage`
+fn socket_addr(storage: &SockAddrStorage) -> Result<SocketAddr> {
+    match i32::from(storage.family) {
+        AF_INET => {
+            // SAFETY: the kernel wrote a `sockaddr_in`, which fits in and
+            // is less aligned than the storage
+            let raw = unsafe { &*(storage as *const SockAddrStorage).cast::<SockAddrIn>() };
+            Ok(SocketAddr::from((
+                Ipv4Addr::from(raw.addr),
+                u16::from_be(raw.port),
+            )))
+        }
+        AF_INET6 => {
+            // SAFETY: as above, for a `sockaddr_in6`
+            let raw = unsafe { &*(storage as *const SockAddrStorage).cast::<SockAddrIn6>() };
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(raw.addr),
+                u16::from_be(raw.port),
+                u32::from_be(raw.flowinfo),
+                raw.scope_id,
+            )))
+        }
+        family => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unexpected address family {family}"),
+        )),
+    }
+}
+
+/// Make `fd` close-on-exec, so processes spawned by handlers do not
+/// inherit it
+///
+/// For fds created elsewhere; those the server creates itself get the
+/// flag atomically (`SOCK_CLOEXEC`, `EPOLL_CLOEXEC`, ...).
+pub(crate) fn set_cloexec(fd: RawFd) -> Result<()> {
+    let flags = ep_syscall!(fcntl(fd, F_GETFD))?;
+    if flags & FD_CLOEXEC == 0 {
+        ep_syscall!(fcntl(fd, F_SETFD, flags | FD_CLOEXEC))?;
+    }
+    Ok(())
+}
+
+/// Make reads and writes on `fd` fail with `WouldBlock` instead of
+/// blocking the loop, for fds of any type (pipes, ttys, ...)
+pub(crate) fn set_nonblocking(fd: RawFd) -> Result<()> {
+    let flags = ep_syscall!(fcntl(fd, F_GETFL))?;
+    if flags & O_NONBLOCK == 0 {
+        ep_syscall!(fcntl(fd, F_SETFL, flags | O_NONBLOCK))?;
+    }
+    Ok(())
+}
+
+/// Whether `fd` is a listening TCP socket, IPv4 or IPv6
+pub(crate) fn is_tcp_listener(fd: RawFd) -> Result<bool> {
+    let domain = get_option(fd, SOL_SOCKET, SO_DOMAIN)?;
+    Ok((domain == AF_INET || domain == AF_INET6)
+        && get_option(fd, SOL_SOCKET, SO_TYPE)? == SOCK_STREAM
+        && get_option(fd, SOL_SOCKET, SO_ACCEPTCONN)? != 0)
+}
+
+fn get_option(fd: RawFd, level: i32, name: i32) -> Result<i32> {
+    let mut value = 0i32;
+    let mut len = mem::size_of::<i32>() as u32;
+    ep_syscall!(getsockopt(
+        fd,
+        level,
+        name,
+        (&raw mut value).cast::<c_void>(),
+        &mut len
+    ))?;
+    Ok(value)
+}
+
+fn set_option(fd: RawFd, level: i32, name: i32, value: i32) -> Result<()> {
+    ep_syscall!(setsockopt(
+        fd,
+        level,
+        name,
+        (&raw const value).cast::<c_void>(),
+        mem::size_of::<i32>() as u32
+    ))?;
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use std::{ffi::c_void, io::Result, mem, net::TcpListener, os::fd::AsRawFd};
+
+use crate::{
+    ep_syscall,
+    ffi::{IPPROTO_TCP, TCP_INFO, TcpInfo},
+};
+
+/// Accept path counters of the listening socket
+///
+/// A high `max_accepted_per_wakeup`, or a backlog that is often found
+/// full, means connections arrive faster than the event loop gets back to
+/// the listener: the accept path is the bottleneck, not the handler.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AcceptStats {
+    /// Number of times the listener was reported readable
+    pub wakeups: u64,
+    /// Number of connections accepted and handed to the handler
+    pub accepted: u64,
+    /// Number of connections turned away by the connection limit
+    pub rejected: u64,
+    /// Number of connections closed because the `AcceptFilter` or a
+    /// `Middleware` refused the peer
+    pub filtered: u64,
+    /// Number of connections closed because their IP exceeded
+    /// `ServerConfig::ip_accept_rate`
+    pub rate_limited: u64,
+    /// Most connections accepted in a single wakeup
+    pub max_accepted_per_wakeup: u64,
+    /// Longest accept queue seen at a wakeup
+    pub peak_backlog: u32,
+    /// Capacity of the accept queue, as granted by the kernel
+    pub backlog_limit: u32,
+    /// Wakeups that found the accept queue full, further connection
+    /// attempts were dropped by the kernel at that point
+    pub backlog_full_wakeups: u64,
+    /// Wakeups that stopped at `ServerConfig::max_accepts_per_wakeup`,
+    /// leaving connections for the next iteration
+    pub batch_limited_wakeups: u64,
+    /// Number of failed `accept` calls, see `EventHandler::on_accept_error`
+    pub errors: u64,
+    /// Number of accepted connections closed because setting them up
+    /// failed: socket options, registration or an unusable client id
+    pub setup_failures: u64,
+    /// Number of connections closed right away on the fd kept by
+    /// `ServerConfig::reserve_accept_fd`
+    pub shed: u64,
+}
+
+impl AcceptStats {
+    /// Mean number of connections accepted per wakeup
+    pub fn accepted_per_wakeup(&self) -> f64 {
+        if self.wakeups == 0 {
+            return 0.0;
+        }
+        self.accepted as f64 / self.wakeups as f64
+    }
+
+    /// Record one wakeup of the listener
+    ///
+    /// `backlog` is the accept queue length and limit at the time of the
+    /// wakeup, if the kernel reported them.
+    pub(crate) fn record_wakeup(&mut self, accepted: u64, backlog: Option<(u32, u32)>) {
+        self.wakeups += 1;
+        self.accepted += accepted;
+        self.max_accepted_per_wakeup = self.max_accepted_per_wakeup.max(accepted);
+
+        if let Some((queued, limit)) = backlog {
+            self.peak_backlog = self.peak_backlog.max(queued);
+            self.backlog_limit = limit;
+            if limit > 0 && queued >= limit {
+                self.backlog_full_wakeups += 1;
+            }
+        }
+    }
+
+    pub(crate) fn record_rejection(&mut self) {
+        self.rejected += 1;
+    }
+
+    pub(crate) fn record_filtered(&mut self) {
+        self.filtered += 1;
+    }
+
+    pub(crate) fn record_rate_limited(&mut self) {
+        self.rate_limited += 1;
+    }
+
+    pub(crate) fn record_batch_limited(&mut self) {
+        self.batch_limited_wakeups += 1;
+    }
+
+    pub(crate) fn record_error(&mut self) {
+        self.errors += 1;
+    }
+
+    pub(crate) fn record_setup_failure(&mut self) {
+        self.setup_failures += 1;
+    }
+
+    pub(crate) fn record_shed(&mut self, shed: u64) {
+        self.shed += shed;
+    }
+}
+
+/// Current length and capacity of the listener's accept queue
+///
+/// For listening sockets `TCP_INFO` reports the queue length in
+/// `tcpi_unacked` and its capacity in `tcpi_sacked`.
+pub(crate) fn listener_backlog(listener: &TcpListener) -> Result<(u32, u32)> {
+    let mut info = TcpInfo::default();
+    let mut len = mem::size_of::<TcpInfo>() as u32;
+    ep_syscall!(getsockopt(
+        listener.as_raw_fd(),
+        IPPROTO_TCP,
+        TCP_INFO,
+        (&raw mut info).cast::<c_void>(),
+        &raw mut len
+    ))?;
+    Ok((info.unacked, info.sacked))
+}
@@ -0,0 +1,92 @@
+use std::{
+    env,
+    io::{Error, ErrorKind, Result},
+    net::TcpListener,
+    os::{
+        fd::{FromRawFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::sockopt;
+
+/// Set once the passed sockets were adopted, they have a single owner
+static ADOPTED: AtomicBool = AtomicBool::new(false);
+
+/// First fd systemd passes, after stdin, stdout and stderr
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Listening sockets passed by systemd socket activation
+///
+/// systemd sets `LISTEN_PID` to the pid of the process the sockets are
+/// meant for, so a child that inherited the environment ignores them, and
+/// `LISTEN_FDS` to their number; they are the fds from 3 on. They are made
+/// non-blocking and close-on-exec. Fails with `NotFound` if the process
+/// was not socket activated, `InvalidInput` if one of the fds is not a
+/// listening TCP socket, and `AlreadyExists` if the sockets were already
+/// taken: they are handed out once per process, like `sd_listen_fds(1)`.
+pub(crate) fn listen_fds() -> Result<Vec<TcpListener>> {
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok());
+    if pid != Some(std::process::id()) {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            "no sockets passed by systemd to this process",
+        ));
+    }
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .filter(|&count| count > 0)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "LISTEN_FDS names no socket"))?;
+    let fds = LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count);
+
+    if ADOPTED.swap(true, Ordering::AcqRel) {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            "the sockets passed by systemd were already adopted",
+        ));
+    }
+    // Checked before any is owned, so a bad fd leaves them all untouched
+    for fd in fds.clone() {
+        if !sockopt::is_tcp_listener(fd).unwrap_or(false) {
+            ADOPTED.store(false, Ordering::Release);
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("fd {fd} passed by systemd is not a listening TCP socket"),
+            ));
+        }
+    }
+
+    fds.map(|fd| {
+        // SAFETY: systemd hands these fds over to this process, `ADOPTED`
+        // makes this the only place taking ownership of them
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+        sockopt::set_cloexec(fd)?;
+        Ok(listener)
+    })
+    .collect()
+}
+
+/// Send `state` (e.g. `READY=1`) to the service manager, like
+/// `sd_notify(3)`
+///
+/// Returns `false` without doing anything if `NOTIFY_SOCKET` is not set,
+/// i.e. the service is not of `Type=notify`.
+pub(crate) fn notify(state: &str) -> Result<bool> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    // A leading `@` names a socket in the abstract namespace
+    let addr = match path.as_encoded_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&path)?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(true)
+}
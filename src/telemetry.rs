@@ -0,0 +1,34 @@
+use std::time::{Duration, Instant};
+
+use crate::{client_id::ClientId, metrics::Metrics};
+
+/// Lifecycle of one sampled message, from its arrival to the flush of
+/// everything the handler queued in response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageTrace {
+    pub client_id: ClientId,
+    /// Start of the loop tick the message was read in
+    pub received_at: Instant,
+    /// Time spent in `EventHandler::on_message`
+    pub handler_duration: Duration,
+    /// Time the response spent in the client's write queue until its last
+    /// byte was handed to the kernel, `None` if nothing was queued
+    pub queue_time: Option<Duration>,
+    /// When the response was completely flushed, `None` if nothing was
+    /// queued
+    pub flushed_at: Option<Instant>,
+}
+
+/// Receives the measurements of the server
+///
+/// Installed with `EpollServer::set_telemetry`. Message traces are only
+/// recorded for the share of messages set by `ServerConfig::trace_sampling`.
+pub trait Telemetry {
+    /// Called once the response to a sampled message was flushed, or right
+    /// after the handler if it queued nothing
+    fn on_message_trace(&mut self, trace: &MessageTrace);
+
+    /// Called every `ServerConfig::metrics_interval` with a snapshot of
+    /// the server's counters
+    fn on_metrics(&mut self, _metrics: &Metrics) {}
+}
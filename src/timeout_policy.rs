@@ -0,0 +1,54 @@
+use std::time::{Duration, Instant};
+
+/// Earliest deadline the event loop has to wake up for
+///
+/// Every iteration the loop collects the deadlines it has pending (rate
+/// limited clients to resume, the next metrics report, a rebind retry,
+/// the next idle or message timeout) and waits until the earliest of them
+/// instead of for a fixed time, so none of them fires late and the loop
+/// does not wake up when nothing is due.
+#[derive(Debug, Default)]
+pub(crate) struct TimeoutPolicy {
+    earliest: Option<Instant>,
+}
+
+impl TimeoutPolicy {
+    /// Forget the deadlines of the previous iteration
+    pub fn clear(&mut self) {
+        self.earliest = None;
+    }
+
+    /// Wake up at `deadline` at the latest
+    pub fn track(&mut self, deadline: Instant) {
+        self.earliest = Some(
+            self.earliest
+                .map_or(deadline, |earliest| earliest.min(deadline)),
+        );
+    }
+
+    /// Wake up at each of `deadlines` at the latest
+    pub fn track_all(&mut self, deadlines: impl IntoIterator<Item = Instant>) {
+        for deadline in deadlines {
+            self.track(deadline);
+        }
+    }
+
+    /// Earliest deadline tracked
+    pub fn earliest(&self) -> Option<Instant> {
+        self.earliest
+    }
+
+    /// How long to wait from `now`, at most `max` (`None` waits forever)
+    ///
+    /// A deadline already passed gives a zero timeout, so the wait only
+    /// polls.
+    pub fn timeout(&self, now: Instant, max: Option<Duration>) -> Option<Duration> {
+        match self.earliest {
+            Some(deadline) => {
+                let until_deadline = deadline.saturating_duration_since(now);
+                Some(max.map_or(until_deadline, |max| max.min(until_deadline)))
+            }
+            None => max,
+        }
+    }
+}
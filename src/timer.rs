@@ -0,0 +1,72 @@
+use std::{
+    fs::File,
+    io::{ErrorKind, Read, Result},
+    os::fd::{AsRawFd, FromRawFd, RawFd},
+    time::Duration,
+};
+
+use crate::{
+    ep_syscall,
+    ffi::{CLOCK_MONOTONIC, ITimerSpec, TFD_CLOEXEC, TFD_NONBLOCK, TimeSpec},
+};
+
+/// Identifies a handler timer, chosen by the handler when setting it
+pub type TimerId = u64;
+
+/// Timer backed by a timerfd registered in the server's epoll instance
+pub(crate) struct Timer {
+    /// The timerfd, closing it also removes it from epoll
+    fd: File,
+    repeating: bool,
+}
+
+impl Timer {
+    pub fn new() -> Result<Self> {
+        let fd = ep_syscall!(timerfd_create(CLOCK_MONOTONIC, TFD_NONBLOCK | TFD_CLOEXEC))?;
+        Ok(Timer {
+            // SAFETY: the fd was just created and nothing else owns it
+            fd: unsafe { File::from_raw_fd(fd) },
+            repeating: false,
+        })
+    }
+
+    /// Arm the timer to fire after `delay`, and then every `interval`
+    ///
+    /// Re-arming replaces the previous setting.
+    pub fn arm(&mut self, delay: Duration, interval: Option<Duration>) -> Result<()> {
+        let spec = ITimerSpec {
+            interval: interval.map(TimeSpec::from).unwrap_or_default(),
+            // A zero value would disarm the timer, fire as soon as possible instead
+            value: TimeSpec::from(delay.max(Duration::from_nanos(1))),
+        };
+        ep_syscall!(timerfd_settime(
+            self.fd.as_raw_fd(),
+            0,
+            &raw const spec,
+            std::ptr::null_mut::<ITimerSpec>()
+        ))?;
+        self.repeating = interval.is_some();
+        Ok(())
+    }
+
+    /// Consume the pending expirations
+    ///
+    /// Returns `false` if the timer has not expired, e.g. for a stale event
+    /// of a timer that was re-armed in the meantime.
+    pub fn take_expirations(&mut self) -> Result<bool> {
+        let mut expirations = [0u8; 8];
+        match self.fd.read(&mut expirations) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn is_repeating(&self) -> bool {
+        self.repeating
+    }
+
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
@@ -0,0 +1,75 @@
+//! Spans and events for `tracing` subscribers, with the `tracing` feature
+//!
+//! Every client gets a `connection` span carrying its id and peer address,
+//! entered while its events are handled, and every iteration of the loop a
+//! `loop_iteration` span. Reads, writes and handler actions are recorded
+//! as `trace` level events inside them, so a subscriber can correlate all
+//! of a connection's activity. Without the feature the types here are
+//! empty and the functions do nothing, the loop calls them regardless.
+
+use std::net::SocketAddr;
+
+use crate::{client_id::ClientId, handler::HandlerAction};
+
+/// Span of one client, from its accept to its disconnect
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionSpan {
+    #[cfg(feature = "tracing")]
+    span: Option<tracing::Span>,
+}
+
+/// Keeps a span entered until dropped
+#[must_use]
+pub(crate) struct Entered {
+    #[cfg(feature = "tracing")]
+    _span: Option<tracing::span::EnteredSpan>,
+}
+
+impl ConnectionSpan {
+    #[allow(unused_variables)]
+    pub fn new(client_id: ClientId, peer_addr: SocketAddr) -> Self {
+        ConnectionSpan {
+            #[cfg(feature = "tracing")]
+            span: Some(tracing::info_span!(
+                "connection",
+                client_id = client_id.get(),
+                peer_addr = %peer_addr
+            )),
+        }
+    }
+
+    /// Enter the span, the client's state may be borrowed again meanwhile
+    pub fn enter(&self) -> Entered {
+        Entered {
+            #[cfg(feature = "tracing")]
+            _span: self.span.clone().map(tracing::Span::entered),
+        }
+    }
+}
+
+/// Enter the span of the `iteration`th iteration of the loop
+#[allow(unused_variables)]
+pub(crate) fn iteration(iteration: u64) -> Entered {
+    Entered {
+        #[cfg(feature = "tracing")]
+        _span: Some(tracing::trace_span!("loop_iteration", iteration).entered()),
+    }
+}
+
+#[allow(unused_variables)]
+pub(crate) fn read(bytes: u64) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(bytes, "read");
+}
+
+#[allow(unused_variables)]
+pub(crate) fn written(bytes: u64) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(bytes, "written");
+}
+
+#[allow(unused_variables)]
+pub(crate) fn action(action: &HandlerAction) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(action = action.name(), "action");
+}
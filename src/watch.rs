@@ -0,0 +1,175 @@
+//! Watching files from the event loop
+//!
+//! `ServerContext::watch_path` adds an inotify watch to the server, whose
+//! one inotify instance is created on the first watch and shares the
+//! server's epoll instance. Changes are reported to
+//! `EventHandler::on_file_event` from the same loop as the clients, e.g.
+//! to reload a configuration file or refresh cached static content.
+//!
+//! Watch the directory of a file rather than the file itself when it may
+//! be replaced by renaming another file over it, as editors and deployment
+//! tools do: the watch of a file follows its inode, not its name.
+
+use std::{
+    collections::HashSet,
+    ffi::{CString, OsString},
+    fs::File,
+    io::{Error, ErrorKind, Read, Result},
+    mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::ffi::{OsStrExt, OsStringExt},
+    },
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use log::debug;
+
+use crate::{
+    ep_syscall,
+    ffi::{
+        IN_CLOEXEC, IN_CLOSE_WRITE, IN_CREATE, IN_DELETE, IN_DELETE_SELF, IN_IGNORED, IN_MOVE_SELF,
+        IN_MOVED_FROM, IN_MOVED_TO, IN_NONBLOCK, IN_Q_OVERFLOW, InotifyEvent,
+    },
+};
+
+/// Identifies a watch, returned by `ServerContext::watch_path`
+///
+/// Watching the same file twice gives the same id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WatchId(i32);
+
+/// Change reported to `EventHandler::on_file_event`
+///
+/// Names are those of files in a watched directory, relative to it;
+/// `None` stands for the watched path itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileEvent {
+    /// A file opened for writing was closed, its new contents are complete
+    Modified(Option<PathBuf>),
+    /// A file was created in or moved into the watched directory
+    Created(PathBuf),
+    /// A file was deleted or moved away
+    ///
+    /// When it is the watched path itself, the watch is removed: it would
+    /// otherwise follow the file to its new name.
+    Removed(Option<PathBuf>),
+    /// The kernel's event queue overflowed and events were lost, reported
+    /// to every watch; rescan what was watched
+    Overflow,
+}
+
+/// Events every watch reports
+const WATCH_MASK: u32 = IN_CLOSE_WRITE
+    | IN_CREATE
+    | IN_MOVED_TO
+    | IN_DELETE
+    | IN_MOVED_FROM
+    | IN_DELETE_SELF
+    | IN_MOVE_SELF;
+
+/// Room for a few events with file names of up to `NAME_MAX` bytes
+const EVENT_BUFFER_SIZE: usize = 4096;
+
+/// The server's inotify instance
+pub(crate) struct Watcher {
+    /// The inotify fd, closing it also removes it from epoll
+    fd: File,
+    watches: HashSet<WatchId>,
+}
+
+impl Watcher {
+    pub fn new() -> Result<Self> {
+        let fd = ep_syscall!(inotify_init1(IN_NONBLOCK | IN_CLOEXEC))?;
+        Ok(Watcher {
+            // SAFETY: the fd was just created and nothing else owns it
+            fd: unsafe { File::from_raw_fd(fd) },
+            watches: HashSet::new(),
+        })
+    }
+
+    /// Watch the file or directory at `path`
+    pub fn add(&mut self, path: &Path) -> Result<WatchId> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "path contains a nul byte"))?;
+        let wd = ep_syscall!(inotify_add_watch(
+            self.fd.as_raw_fd(),
+            path.as_ptr(),
+            WATCH_MASK
+        ))?;
+        let id = WatchId(wd);
+        self.watches.insert(id);
+        Ok(id)
+    }
+
+    /// Stop watching, returns `false` if `id` was not watched
+    pub fn remove(&mut self, id: WatchId) -> Result<bool> {
+        if !self.watches.remove(&id) {
+            return Ok(false);
+        }
+        ep_syscall!(inotify_rm_watch(self.fd.as_raw_fd(), id.0))?;
+        Ok(true)
+    }
+
+    /// Read the pending events until none is left
+    pub fn read_events(&mut self) -> Result<Vec<(WatchId, FileEvent)>> {
+        let mut events = Vec::new();
+        let mut buffer = [0u8; EVENT_BUFFER_SIZE];
+        loop {
+            let read = match self.fd.read(&mut buffer) {
+                Ok(read) => read,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(events),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.parse_events(&buffer[..read], &mut events);
+        }
+    }
+
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+
+    /// Turn the `inotify_event`s in `buffer` into file events
+    fn parse_events(&mut self, mut buffer: &[u8], events: &mut Vec<(WatchId, FileEvent)>) {
+        const HEADER_LEN: usize = mem::size_of::<InotifyEvent>();
+        while buffer.len() >= HEADER_LEN {
+            // SAFETY: the kernel wrote a whole header, which may not be
+            // aligned within the buffer
+            let header = unsafe { ptr::read_unaligned(buffer.as_ptr().cast::<InotifyEvent>()) };
+            let end = (HEADER_LEN + header.len as usize).min(buffer.len());
+            let name = &buffer[HEADER_LEN..end];
+            buffer = &buffer[end..];
+
+            // The name is padded with nul bytes
+            let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            let name = (name_len > 0)
+                .then(|| PathBuf::from(OsString::from_vec(name[..name_len].to_vec())));
+            let id = WatchId(header.wd);
+
+            if header.mask & IN_Q_OVERFLOW != 0 {
+                events.extend(self.watches.iter().map(|&id| (id, FileEvent::Overflow)));
+            } else if !self.watches.contains(&id) {
+                // Queued before the watch was removed
+            } else if header.mask & IN_IGNORED != 0 {
+                // The watch is gone along with its file
+                self.watches.remove(&id);
+            } else if header.mask & (IN_CREATE | IN_MOVED_TO) != 0 {
+                if let Some(name) = name {
+                    events.push((id, FileEvent::Created(name)));
+                }
+            } else if header.mask & IN_CLOSE_WRITE != 0 {
+                events.push((id, FileEvent::Modified(name)));
+            } else if header.mask & (IN_DELETE | IN_MOVED_FROM | IN_DELETE_SELF) != 0 {
+                events.push((id, FileEvent::Removed(name)));
+            } else if header.mask & IN_MOVE_SELF != 0 {
+                // Stop following the file to its new name
+                if let Err(e) = self.remove(id) {
+                    debug!("Failed to remove the watch of a moved file: {}", e);
+                }
+                events.push((id, FileEvent::Removed(None)));
+            }
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    ffi::c_void,
+    io::{Error, Result},
+    time::Duration,
+};
+
+use log::{debug, error};
+
+use crate::{
+    Event, EventFlags, Operation, PeerRole,
+    reactor::{RawSource, Reactor},
+    signal::SignalMask,
+};
+
+/// Handle of a wepoll port, what `epoll_create1` returns
+type Handle = *mut c_void;
+
+/// wepoll's `epoll_event`, naturally aligned unlike the packed Linux one
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct WepollEvent {
+    events: u32,
+    data: u64,
+}
+
+impl From<&Event> for WepollEvent {
+    fn from(event: &Event) -> Self {
+        WepollEvent {
+            events: event.flags().bits(),
+            data: event.data(),
+        }
+    }
+}
+
+impl From<WepollEvent> for Event {
+    fn from(event: WepollEvent) -> Self {
+        Event::new(
+            EventFlags::from_bits(event.events),
+            PeerRole::from(event.data),
+        )
+    }
+}
+
+#[link(name = "wepoll")]
+unsafe extern "C" {
+    fn epoll_create1(flags: i32) -> Handle;
+    fn epoll_close(ephnd: Handle) -> i32;
+    fn epoll_ctl(ephnd: Handle, op: i32, sock: usize, event: *mut WepollEvent) -> i32;
+    fn epoll_wait(ephnd: Handle, events: *mut WepollEvent, maxevents: i32, timeout: i32) -> i32;
+}
+
+/// Windows backend built on [wepoll](https://github.com/piscisaureus/wepoll)
+///
+/// wepoll emulates epoll on top of `AFD_POLL`, with the same operations
+/// and flags, so it implements `Reactor` like `Epoll` does. Only sockets
+/// can be registered, and `EPOLLET` is not supported: interests have to
+/// be level-triggered or one-shot.
+///
+/// This is a skeleton: on Windows the crate is the `reactor` module only,
+/// the server itself (timers, the waker, `sendfile`...) is Linux only.
+/// The application links the wepoll library.
+pub struct Wepoll {
+    handle: Handle,
+}
+
+// SAFETY: a wepoll port is a kernel handle, usable from any thread
+unsafe impl Send for Wepoll {}
+
+impl Reactor for Wepoll {
+    fn new() -> Result<Self> {
+        // SAFETY: no pointer is passed, the result is checked below
+        let handle = unsafe { epoll_create1(0) };
+        if handle.is_null() {
+            return Err(Error::last_os_error());
+        }
+        Ok(Wepoll { handle })
+    }
+
+    fn add_interest(&self, source: RawSource, event: Event) -> Result<()> {
+        self.control_interest(Operation::Add, source, Some(WepollEvent::from(&event)))
+    }
+
+    fn modify_interest(&self, source: RawSource, event: Event) -> Result<()> {
+        self.control_interest(Operation::Mod, source, Some(WepollEvent::from(&event)))
+    }
+
+    fn remove_interest(&self, source: RawSource) -> Result<()> {
+        self.control_interest(Operation::Del, source, None)
+    }
+
+    /// Windows has no signals, `sigmask` is ignored. The timeout is
+    /// rounded up to the next millisecond.
+    fn wait(
+        &self,
+        events: &mut Vec<Event>,
+        timeout: Option<Duration>,
+        _sigmask: Option<&SignalMask>,
+    ) -> Result<()> {
+        let mut ready = vec![WepollEvent::default(); events.capacity() - events.len()];
+        let timeout = match timeout {
+            Some(timeout) => timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+
+        let max_events = ready.len().min(i32::MAX as usize) as i32;
+        // SAFETY: `ready` holds `max_events` entries for wepoll to fill
+        let res = unsafe { epoll_wait(self.handle, ready.as_mut_ptr(), max_events, timeout) };
+        if res < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        events.extend(ready.into_iter().take(res as usize).map(Event::from));
+        debug!("Received {} events from wepoll", res);
+        Ok(())
+    }
+}
+
+impl Wepoll {
+    fn control_interest(
+        &self,
+        op: Operation,
+        source: RawSource,
+        event: Option<WepollEvent>,
+    ) -> Result<()> {
+        let mut event = event.unwrap_or_default();
+        // SAFETY: `event` outlives the call, wepoll copies it
+        let res = unsafe { epoll_ctl(self.handle, op.into(), source as usize, &raw mut event) };
+        if res < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Wepoll {
+    fn drop(&mut self) {
+        // SAFETY: the handle came from `epoll_create1` and is closed once
+        if unsafe { epoll_close(self.handle) } < 0 {
+            error!("Failed to close wepoll port: {}", Error::last_os_error());
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::fd::AsRawFd,
+    sync::{atomic::Ordering, mpsc},
+    thread,
+    time::Duration,
+};
+
+use epoll_worker::{
+    Bytes, ClientId, EpollServer, HandlerAction, ServerContext,
+    async_handler::{AsyncHandler, AsyncIo, TaskFuture, async_handler},
+};
+
+/// Asks an upstream server for each reply, without blocking the loop
+struct UpstreamHandler {
+    upstream: std::net::SocketAddr,
+}
+
+impl AsyncHandler for UpstreamHandler {
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        io: &AsyncIo,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> TaskFuture {
+        let io = io.clone();
+        let upstream = self.upstream;
+        Box::pin(async move {
+            let mut stream = io.connect(upstream).await?;
+            stream.write_all(&data)?;
+            let mut reply = [0u8; 64];
+            loop {
+                match stream.read(&mut reply) {
+                    Ok(n) => return Ok(HandlerAction::Reply(reply[..n].to_vec().into())),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        io.readable(stream.as_raw_fd()).await?;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        })
+    }
+}
+
+#[test]
+fn async_handlers_await_outbound_connections() {
+    // Answers after a delay, so the task is pending while the loop goes on
+    let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in upstream.incoming().take(2) {
+            let mut stream = stream.unwrap();
+            let mut request = [0u8; 64];
+            let n = stream.read(&mut request).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            stream
+                .write_all(&request[..n].to_ascii_uppercase())
+                .unwrap();
+        }
+    });
+
+    // The adapter is not `Send`, the server is created on its thread
+    let (sender, receiver) = mpsc::channel();
+    let server_thread = thread::spawn(move || {
+        let handler = async_handler(UpstreamHandler {
+            upstream: upstream_addr,
+        })
+        .unwrap();
+        let mut server = EpollServer::new("127.0.0.1:0", handler).unwrap();
+        sender
+            .send((server.local_addr().unwrap(), server.shutdown_signal()))
+            .unwrap();
+        server.run(Some(10)).unwrap();
+    });
+    let (addr, shutdown) = receiver.recv().unwrap();
+
+    let mut first = TcpStream::connect(addr).unwrap();
+    let mut second = TcpStream::connect(addr).unwrap();
+    first.write_all(b"first").unwrap();
+    second.write_all(b"second").unwrap();
+    for (client, expected) in [(&mut first, &b"FIRST"[..]), (&mut second, b"SECOND")] {
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut reply = vec![0u8; expected.len()];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, expected);
+    }
+
+    shutdown.store(true, Ordering::Relaxed);
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,156 @@
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{
+        Arc, Mutex,
+        atomic::Ordering,
+        mpsc::{self, Receiver},
+    },
+    thread,
+    time::Duration,
+};
+
+use epoll_worker::{
+    AuditEvent, Bytes, ChaosConfig, ClientId, DisconnectReason, EpollServer, EventHandler,
+    HandlerAction, ListenerId, ServerConfig, ServerContext,
+};
+
+/// Echoes newline terminated lines, recording the messages it got
+#[derive(Default)]
+struct LineEcho {
+    messages: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl EventHandler for LineEcho {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        self.messages.lock().unwrap().push(data.to_vec());
+        Ok(HandlerAction::Reply(data))
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, data: &[u8]) -> bool {
+        data.ends_with(b"\n")
+    }
+}
+
+/// Run a server injecting `chaos` in its own thread, returning its
+/// address, its audit events and a function stopping it
+fn start_chaos_server(
+    handler: LineEcho,
+    chaos: ChaosConfig,
+) -> (SocketAddr, Receiver<AuditEvent>, impl FnOnce()) {
+    let config = ServerConfig::default().chaos(chaos);
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", handler, config).unwrap();
+    let (sender, events) = mpsc::channel();
+    server.set_audit_sink(sender);
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+    let stop = move || {
+        shutdown.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+    };
+    (addr, events, stop)
+}
+
+#[test]
+fn chaos_splits_and_stalls_io_without_losing_data() {
+    let handler = LineEcho::default();
+    let messages = handler.messages.clone();
+    let chaos = ChaosConfig::new()
+        .max_read(3)
+        .max_write(2)
+        .would_block_rate(0.5)
+        .seed(7);
+    let (addr, _, stop) = start_chaos_server(handler, chaos);
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut reader = BufReader::new(client.try_clone().unwrap());
+    for index in 0..20 {
+        let line = format!("line number {index}\n");
+        client.write_all(line.as_bytes()).unwrap();
+        let mut echoed = String::new();
+        reader.read_line(&mut echoed).unwrap();
+        assert_eq!(echoed, line);
+    }
+
+    // However the reads were cut, the handler saw whole lines
+    let messages = messages.lock().unwrap().clone();
+    assert_eq!(messages.len(), 20);
+    assert!(messages.iter().all(|message| message.ends_with(b"\n")));
+    stop();
+}
+
+#[test]
+fn chaos_resets_connections_through_the_error_path() {
+    let chaos = ChaosConfig::new().disconnect_rate(1.0);
+    let (addr, events, stop) = start_chaos_server(LineEcho::default(), chaos);
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client.write_all(b"hello\n").unwrap();
+    let mut buf = [0u8; 16];
+    match client.read(&mut buf) {
+        Ok(read) => assert_eq!(read, 0),
+        Err(e) => assert_eq!(e.kind(), ErrorKind::ConnectionReset),
+    }
+
+    let reason = events
+        .iter()
+        .find_map(|event| match event {
+            AuditEvent::Disconnected { reason, .. } => Some(reason),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(reason, DisconnectReason::Error);
+    stop();
+}
+
+#[test]
+fn chaos_drops_writes_the_peer_never_receives() {
+    let handler = LineEcho::default();
+    let messages = handler.messages.clone();
+    let chaos = ChaosConfig::new().write_drop_rate(1.0);
+    let (addr, _, stop) = start_chaos_server(handler, chaos);
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    client.write_all(b"lost\n").unwrap();
+    let mut buf = [0u8; 16];
+    let error = client.read(&mut buf).unwrap_err();
+    assert!(matches!(
+        error.kind(),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut
+    ));
+    assert_eq!(*messages.lock().unwrap(), [b"lost\n".to_vec()]);
+    stop();
+}
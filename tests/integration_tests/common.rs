@@ -5,7 +5,7 @@ use std::{
 
 use epoll_worker::{EpollServer, EventHandler};
 
-pub fn start_test_server<H: EventHandler>(
+pub fn start_test_server<H: EventHandler + 'static>(
     handler: H,
 ) -> (EpollServer<H>, SocketAddr, Arc<AtomicBool>) {
     let server = EpollServer::new("127.0.0.1:0", handler).unwrap();
@@ -0,0 +1,274 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use epoll_worker::{
+    AcceptError, Bytes, ClientId, EventHandler, HandlerAction, ListenerId, ServerContext,
+};
+
+use crate::common::{create_clients, start_test_server};
+
+/// Bytes queued by `EdgeHandler` for every message, far more than the
+/// socket buffers hold
+const FLOOD_LEN: usize = 8 * 1024 * 1024;
+
+/// What `EdgeHandler` does with each message
+#[derive(Default, Clone, Copy)]
+enum Reaction {
+    #[default]
+    Nothing,
+    /// Send the message to every client
+    Broadcast,
+    /// Queue `FLOOD_LEN` bytes for the sender
+    Flood,
+}
+
+/// Counts the callbacks, reacting to messages as told
+#[derive(Default)]
+struct EdgeHandler {
+    reaction: Reaction,
+    connections: Arc<AtomicUsize>,
+    messages: Arc<AtomicUsize>,
+    disconnects: Arc<AtomicUsize>,
+}
+
+impl EdgeHandler {
+    fn new(reaction: Reaction) -> Self {
+        EdgeHandler {
+            reaction,
+            ..EdgeHandler::default()
+        }
+    }
+}
+
+impl EventHandler for EdgeHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        self.messages.fetch_add(1, Ordering::SeqCst);
+        Ok(match self.reaction {
+            Reaction::Nothing => HandlerAction::None,
+            Reaction::Broadcast => HandlerAction::SendToAll(data),
+            Reaction::Flood => HandlerAction::Reply(vec![b'x'; FLOOD_LEN].into()),
+        })
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        self.disconnects.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+fn wait_for(timeout: Duration, condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    false
+}
+
+/// Soft limit on open files of the test process
+fn open_file_limit() -> usize {
+    let limits = std::fs::read_to_string("/proc/self/limits").unwrap_or_default();
+    limits
+        .lines()
+        .find(|line| line.starts_with("Max open files"))
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|soft| soft.parse().ok())
+        .unwrap_or(1024)
+}
+
+#[test]
+fn data_sent_right_before_closing_is_delivered() {
+    let handler = EdgeHandler::default();
+    let (messages, disconnects) = (handler.messages.clone(), handler.disconnects.clone());
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"last words").unwrap();
+    drop(client);
+
+    assert!(wait_for(Duration::from_secs(2), || {
+        disconnects.load(Ordering::SeqCst) == 1
+    }));
+    assert_eq!(messages.load(Ordering::SeqCst), 1);
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+#[test]
+fn silent_clients_stay_connected() {
+    let handler = EdgeHandler::default();
+    let (connections, messages, disconnects) = (
+        handler.connections.clone(),
+        handler.messages.clone(),
+        handler.disconnects.clone(),
+    );
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    let handle = thread::spawn(move || {
+        server.run(Some(10)).unwrap();
+        server
+    });
+
+    let _clients = create_clients(addr, 3);
+    assert!(wait_for(Duration::from_secs(2), || {
+        connections.load(Ordering::SeqCst) == 3
+    }));
+    thread::sleep(Duration::from_millis(200));
+
+    shutdown.store(true, Ordering::Relaxed);
+    let server = handle.join().unwrap();
+    assert_eq!(server.connected_clients().len(), 3);
+    assert_eq!(messages.load(Ordering::SeqCst), 0);
+    assert_eq!(disconnects.load(Ordering::SeqCst), 0);
+}
+
+/// Open `count` connections from several threads at once and check the
+/// server accepted every one
+fn accept_simultaneous_connects(count: usize) {
+    let threads = 8;
+    let per_thread = count.div_ceil(threads);
+
+    let handler = EdgeHandler::default();
+    let connections = handler.connections.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    let handle = thread::spawn(move || {
+        server.run(Some(10)).unwrap();
+        server
+    });
+
+    let connectors: Vec<_> = (0..threads)
+        .map(|_| thread::spawn(move || create_clients(addr, per_thread)))
+        .collect();
+    let clients: Vec<TcpStream> = connectors
+        .into_iter()
+        .flat_map(|connector| connector.join().unwrap())
+        .collect();
+    assert!(wait_for(Duration::from_secs(10), || {
+        connections.load(Ordering::SeqCst) == clients.len()
+    }));
+
+    shutdown.store(true, Ordering::Relaxed);
+    let server = handle.join().unwrap();
+    assert_eq!(server.metrics().accepted, clients.len() as u64);
+    assert_eq!(server.connected_clients().len(), clients.len());
+}
+
+#[test]
+fn hundreds_of_simultaneous_connects_are_accepted() {
+    accept_simultaneous_connects(512);
+}
+
+#[test]
+#[ignore = "slow, opens 10k connections"]
+fn ten_thousand_simultaneous_connects_are_accepted() {
+    // Both ends of every connection live in this process
+    let limit = open_file_limit();
+    assert!(
+        limit >= 22_000,
+        "needs `ulimit -n` of at least 22000, found {limit}"
+    );
+    accept_simultaneous_connects(10_000);
+}
+
+#[test]
+fn accept_errors_pause_accepting_unless_only_the_connection_failed() {
+    let classify = |errno| AcceptError::from(std::io::Error::from_raw_os_error(errno));
+
+    // EMFILE, ENFILE
+    assert!(matches!(classify(24), AcceptError::FdExhausted(_)));
+    assert!(matches!(classify(23), AcceptError::FdExhausted(_)));
+    // ENOBUFS
+    assert!(matches!(classify(105), AcceptError::OutOfMemory(_)));
+    // ECONNABORTED
+    let aborted = classify(103);
+    assert!(matches!(aborted, AcceptError::ConnectionAborted(_)));
+    assert!(!aborted.pauses_accepts());
+    assert!(classify(24).pauses_accepts());
+    assert_eq!(classify(24).io_error().raw_os_error(), Some(24));
+}
+
+#[test]
+fn slow_reader_does_not_stall_broadcasts() {
+    let handler = EdgeHandler::new(Reaction::Broadcast);
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    // Never reads, its queue grows while the others keep receiving
+    let _slow = TcpStream::connect(addr).unwrap();
+    let mut fast = TcpStream::connect(addr).unwrap();
+    fast.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+    let message = vec![b'b'; 64 * 1024];
+    let mut received = vec![0u8; message.len()];
+    for _ in 0..64 {
+        fast.write_all(&message).unwrap();
+        fast.read_exact(&mut received).unwrap();
+    }
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+#[test]
+fn shutdown_mid_flush_closes_clients() {
+    let handler = EdgeHandler::new(Reaction::Flood);
+    let messages = handler.messages.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    let handle = thread::spawn(move || {
+        server.run(Some(10)).unwrap();
+        server
+    });
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"go").unwrap();
+    assert!(wait_for(Duration::from_secs(2), || {
+        messages.load(Ordering::SeqCst) == 1
+    }));
+
+    // The flood cannot be written while the client does not read, the
+    // server goes away with most of it still queued
+    shutdown.store(true, Ordering::Relaxed);
+    drop(handle.join().unwrap());
+
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).unwrap();
+    assert!(!received.is_empty());
+    assert!(received.len() < FLOOD_LEN);
+}
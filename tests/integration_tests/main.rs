@@ -1,2 +1,11 @@
+#[cfg(feature = "async")]
+mod async_handler;
+#[cfg(feature = "chaos")]
+mod chaos;
 mod common;
+mod edge_cases;
+mod mock_poller;
+mod protocol;
 mod server;
+#[cfg(feature = "sessions")]
+mod session;
@@ -0,0 +1,203 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+};
+
+use epoll_worker::{
+    Bytes, ClientId, EpollServer, EventFlags, EventHandler, HandlerAction, ListenerId,
+    ServerConfig, ServerContext,
+    reactor::{MockPoller, PeerRole},
+};
+
+/// Replies to every message with `reply_len` copies of its first byte,
+/// recording who connected and left
+#[derive(Default)]
+struct ScriptedHandler {
+    reply_len: usize,
+    connected: Arc<Mutex<Vec<ClientId>>>,
+    disconnected: Arc<Mutex<Vec<ClientId>>>,
+}
+
+impl EventHandler for ScriptedHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        self.connected.lock().unwrap().push(client_id);
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::Reply(vec![data[0]; self.reply_len].into()))
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        client_id: ClientId,
+    ) -> std::io::Result<()> {
+        self.disconnected.lock().unwrap().push(client_id);
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+fn mock_server(
+    handler: ScriptedHandler,
+    config: ServerConfig,
+) -> (EpollServer<ScriptedHandler>, MockPoller, TcpListener) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let poller = MockPoller::new();
+    let server = EpollServer::with_mock_poller(
+        listener.try_clone().unwrap(),
+        handler,
+        config,
+        poller.clone(),
+    )
+    .unwrap();
+    (server, poller, listener)
+}
+
+/// Connect a client and let the server accept it, returning its id and
+/// the fd the server registered for it
+fn accept(
+    server: &mut EpollServer<ScriptedHandler>,
+    poller: &MockPoller,
+    listener: &TcpListener,
+    connected: &Mutex<Vec<ClientId>>,
+) -> (TcpStream, ClientId, i32) {
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let listener_fd = poller.source_of(PeerRole::Server).unwrap();
+    poller.inject(listener_fd, EventFlags::READ).unwrap();
+    server.run_once(Some(0)).unwrap();
+
+    let id = *connected.lock().unwrap().last().unwrap();
+    let fd = poller.source_of(PeerRole::Client(id)).unwrap();
+    (client, id, fd)
+}
+
+#[test]
+fn mock_poller_decides_when_clients_are_served() {
+    let handler = ScriptedHandler {
+        reply_len: 4,
+        ..Default::default()
+    };
+    let (connected, disconnected) = (handler.connected.clone(), handler.disconnected.clone());
+    let (mut server, poller, listener) = mock_server(handler, ServerConfig::default());
+
+    // Nothing is accepted until the listener is reported readable
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    server.run_once(Some(1000)).unwrap();
+    assert!(connected.lock().unwrap().is_empty());
+
+    let listener_fd = poller.source_of(PeerRole::Server).unwrap();
+    poller.inject(listener_fd, EventFlags::READ).unwrap();
+    server.run_once(Some(0)).unwrap();
+    let id = connected.lock().unwrap()[0];
+    let fd = poller.source_of(PeerRole::Client(id)).unwrap();
+
+    // Data sitting in the socket is only read once the client is reported
+    client.write_all(b"ping").unwrap();
+    server.run_once(Some(0)).unwrap();
+    client.set_nonblocking(true).unwrap();
+    let mut reply = [0u8; 4];
+    assert_eq!(
+        client.read(&mut reply).unwrap_err().kind(),
+        ErrorKind::WouldBlock
+    );
+
+    poller.inject(fd, EventFlags::READ).unwrap();
+    server.run_once(Some(0)).unwrap();
+    // The reply is queued, and written once the socket is reported writable
+    assert_eq!(
+        client.read(&mut reply).unwrap_err().kind(),
+        ErrorKind::WouldBlock
+    );
+    poller.inject(fd, EventFlags::WRITE).unwrap();
+    server.run_once(Some(0)).unwrap();
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"pppp");
+
+    drop(client);
+    poller.inject(fd, EventFlags::READ).unwrap();
+    server.run_once(Some(0)).unwrap();
+    assert_eq!(*disconnected.lock().unwrap(), [id]);
+    assert_eq!(poller.interests(fd), None);
+    assert_eq!(
+        poller.inject(fd, EventFlags::READ).unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn mock_poller_drains_a_backlogged_client_on_write_readiness() {
+    let reply_len = 8 << 20;
+    let handler = ScriptedHandler {
+        reply_len,
+        ..Default::default()
+    };
+    let connected = handler.connected.clone();
+    let (mut server, poller, listener) = mock_server(handler, ServerConfig::default());
+    let (mut client, _, fd) = accept(&mut server, &poller, &listener, &connected);
+
+    client.write_all(b"x").unwrap();
+    poller.inject(fd, EventFlags::READ).unwrap();
+    server.run_once(Some(0)).unwrap();
+    poller.inject(fd, EventFlags::WRITE).unwrap();
+    server.run_once(Some(0)).unwrap();
+    // The reply does not fit the socket buffers, the rest waits for the
+    // next write readiness
+    assert!(poller.interests(fd).unwrap().contains(EventFlags::WRITE));
+
+    client.set_nonblocking(true).unwrap();
+    let mut received = 0;
+    let mut buf = vec![0u8; 1 << 16];
+    for _ in 0..10_000 {
+        loop {
+            match client.read(&mut buf) {
+                Ok(read) => received += read,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => panic!("reading the reply failed: {e}"),
+            }
+        }
+        if !poller.interests(fd).unwrap().contains(EventFlags::WRITE) {
+            break;
+        }
+        poller.inject(fd, EventFlags::WRITE).unwrap();
+        server.run_once(Some(0)).unwrap();
+    }
+    client.set_nonblocking(false).unwrap();
+    let mut rest = vec![0u8; reply_len - received];
+    client.read_exact(&mut rest).unwrap();
+    assert!(!poller.interests(fd).unwrap().contains(EventFlags::WRITE));
+}
+
+#[test]
+fn mock_poller_reports_a_client_over_its_pending_limit() {
+    let handler = ScriptedHandler {
+        reply_len: 4 << 20,
+        ..Default::default()
+    };
+    let (connected, disconnected) = (handler.connected.clone(), handler.disconnected.clone());
+    let config = ServerConfig::default().max_pending_writes(1 << 20);
+    let (mut server, poller, listener) = mock_server(handler, config);
+    let (mut client, id, fd) = accept(&mut server, &poller, &listener, &connected);
+
+    client.write_all(b"x").unwrap();
+    poller.inject(fd, EventFlags::READ).unwrap();
+    server.run_once(Some(0)).unwrap();
+    assert_eq!(*disconnected.lock().unwrap(), [id]);
+    assert_eq!(poller.source_of(PeerRole::Client(id)), None);
+}
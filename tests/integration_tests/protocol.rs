@@ -0,0 +1,341 @@
+use epoll_worker::protocol::{
+    Codec, CodecStack, DualStackCodec, Frame, HttpCodec, HttpRequest, LengthPrefixedCodec,
+    LineCodec, ProxyHeader, ReadBuf, TelnetCodec, Transport, WebSocketCodec, decode_proxy_header,
+};
+
+const HANDSHAKE: &[u8] = b"GET /chat HTTP/1.1\r\n\
+Host: server.example.com\r\n\
+Upgrade: websocket\r\n\
+Connection: Upgrade\r\n\
+Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+Sec-WebSocket-Version: 13\r\n\r\n";
+
+/// Client frames are always masked
+fn masked_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = [0x37, 0xfa, 0x21, 0x3d];
+    let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+}
+
+#[test]
+fn length_prefixed_round_trip() {
+    let mut codec = LengthPrefixedCodec::default();
+    let mut encoded = codec.encode(b"hello");
+    encoded.extend_from_slice(&codec.encode(b"world")[..6]);
+
+    assert_eq!(
+        codec.decode(&encoded).unwrap(),
+        Some((9, Frame::Message(b"hello".to_vec())))
+    );
+    // Second frame is incomplete
+    assert_eq!(codec.decode(&encoded[9..]).unwrap(), None);
+}
+
+#[test]
+fn length_prefixed_rejects_oversized_frames() {
+    let mut codec = LengthPrefixedCodec::new(4);
+    assert!(codec.decode(&[0, 0, 0, 5, 1, 2, 3, 4, 5]).is_err());
+}
+
+#[test]
+fn websocket_handshake_and_messages() {
+    let mut codec = WebSocketCodec::new();
+    let (consumed, frame) = codec.decode(HANDSHAKE).unwrap().unwrap();
+    assert_eq!(consumed, HANDSHAKE.len());
+    let Frame::Control(response) = frame else {
+        panic!("expected handshake response");
+    };
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 101"));
+    assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+    let frame = masked_frame(0x1, b"Hello");
+    assert_eq!(
+        codec.decode(&frame).unwrap(),
+        Some((frame.len(), Frame::Message(b"Hello".to_vec())))
+    );
+    assert_eq!(codec.encode(b"Hello"), b"\x81\x05Hello");
+
+    let ping = masked_frame(0x9, b"hb");
+    assert_eq!(
+        codec.decode(&ping).unwrap(),
+        Some((ping.len(), Frame::Control(b"\x8a\x02hb".to_vec())))
+    );
+}
+
+#[test]
+fn dual_stack_detects_transport() {
+    let mut websocket = DualStackCodec::new();
+    assert_eq!(websocket.transport(), Transport::Unknown);
+    websocket.decode(HANDSHAKE).unwrap();
+    assert_eq!(websocket.transport(), Transport::WebSocket);
+
+    let mut raw = DualStackCodec::new();
+    let frame = LengthPrefixedCodec::default().encode(b"hi");
+    assert_eq!(
+        raw.decode(&frame).unwrap(),
+        Some((6, Frame::Message(b"hi".to_vec())))
+    );
+    assert_eq!(raw.transport(), Transport::LengthPrefixed);
+}
+
+#[test]
+fn codec_stack_layers_framing() {
+    let mut stack = CodecStack::new(vec![
+        Box::new(LengthPrefixedCodec::default()),
+        Box::new(WebSocketCodec::after_handshake()),
+    ]);
+    assert_eq!(stack.transport(), Transport::WebSocket);
+
+    let encoded = stack.encode(b"hi");
+    assert_eq!(encoded, b"\x00\x00\x00\x04\x81\x02hi");
+
+    let wire = LengthPrefixedCodec::default().encode(&masked_frame(0x1, b"hey"));
+    assert_eq!(
+        stack.decode(&wire).unwrap(),
+        Some((wire.len(), Frame::Message(b"hey".to_vec())))
+    );
+}
+
+#[test]
+fn line_codec_splits_on_newlines() {
+    let mut codec = LineCodec::new();
+    let buf = b"EHLO example.com\r\nQUIT\nNOOP";
+
+    assert_eq!(
+        codec.decode(buf).unwrap(),
+        Some((18, Frame::Message(b"EHLO example.com".to_vec())))
+    );
+    assert_eq!(
+        codec.decode(&buf[18..]).unwrap(),
+        Some((5, Frame::Message(b"QUIT".to_vec())))
+    );
+    // No terminator yet
+    assert_eq!(codec.decode(&buf[23..]).unwrap(), None);
+    assert_eq!(codec.encode(b"250 OK"), b"250 OK\r\n");
+}
+
+#[test]
+fn read_buf_decodes_frames_wrapping_around_its_end() {
+    let mut codec = LineCodec::new();
+    let mut buf = ReadBuf::with_capacity(16);
+    buf.extend_from_slice(b"first\nsec");
+    assert_eq!(
+        codec.decode_buf(&mut buf).unwrap(),
+        Some(Frame::Message(b"first".to_vec()))
+    );
+
+    // Lands in the free space at both ends without growing
+    buf.extend_from_slice(b"ond\nthird\n");
+    assert_eq!(buf.capacity(), 16);
+    assert_eq!(buf.as_slices(), (&b"second\nthi"[..], &b"rd\n"[..]));
+    for expected in [&b"second"[..], b"third"] {
+        assert_eq!(
+            codec.decode_buf(&mut buf).unwrap(),
+            Some(Frame::Message(expected.to_vec()))
+        );
+    }
+    assert!(buf.is_empty());
+    assert_eq!(codec.decode_buf(&mut buf).unwrap(), None);
+}
+
+#[test]
+fn proxy_header_v1_names_the_client() {
+    let header = b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\nhello";
+    // Incomplete until the `\r\n`
+    assert_eq!(decode_proxy_header(&header[..20]).unwrap(), None);
+    assert_eq!(
+        decode_proxy_header(header).unwrap(),
+        Some((
+            header.len() - 5,
+            Some(ProxyHeader {
+                source: "192.0.2.1:56324".parse().unwrap(),
+                destination: "198.51.100.7:443".parse().unwrap(),
+            })
+        ))
+    );
+
+    assert_eq!(
+        decode_proxy_header(b"PROXY UNKNOWN\r\n").unwrap(),
+        Some((15, None))
+    );
+    assert!(decode_proxy_header(b"PROXY TCP4 ::1 ::1 1 2\r\n").is_err());
+    assert!(decode_proxy_header(b"GET / HTTP/1.1\r\n").is_err());
+}
+
+#[test]
+fn proxy_header_v2_names_the_client() {
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+    // Version 2, PROXY command, TCP over IPv6, 36 bytes of addresses
+    header.extend_from_slice(&[0x21, 0x21, 0, 36]);
+    header.extend_from_slice(
+        &"2001:db8::1"
+            .parse::<std::net::Ipv6Addr>()
+            .unwrap()
+            .octets(),
+    );
+    header.extend_from_slice(
+        &"2001:db8::2"
+            .parse::<std::net::Ipv6Addr>()
+            .unwrap()
+            .octets(),
+    );
+    header.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+
+    assert_eq!(decode_proxy_header(&header[..30]).unwrap(), None);
+    assert_eq!(
+        decode_proxy_header(&header).unwrap(),
+        Some((
+            52,
+            Some(ProxyHeader {
+                source: "[2001:db8::1]:56324".parse().unwrap(),
+                destination: "[2001:db8::2]:443".parse().unwrap(),
+            })
+        ))
+    );
+
+    // LOCAL command, e.g. a health check from the proxy
+    header[12] = 0x20;
+    assert_eq!(decode_proxy_header(&header).unwrap(), Some((52, None)));
+}
+
+#[test]
+fn telnet_commands_are_stripped_and_answered() {
+    let mut codec = TelnetCodec::new().accept(TelnetCodec::SUPPRESS_GO_AHEAD);
+    let options = codec.options();
+
+    // IAC WILL SGA is accepted, IAC DO ECHO refused
+    assert_eq!(
+        codec.decode(&[255, 251, 3, b'h']).unwrap(),
+        Some((3, Frame::Control(vec![255, 253, 3])))
+    );
+    assert!(options.remote_enabled(TelnetCodec::SUPPRESS_GO_AHEAD));
+    assert_eq!(
+        codec.decode(&[255, 253, 1]).unwrap(),
+        Some((3, Frame::Control(vec![255, 252, 1])))
+    );
+    assert!(!options.local_enabled(TelnetCodec::ECHO));
+    // Repeating an accepted option needs no answer
+    assert_eq!(
+        codec.decode(&[255, 251, 3]).unwrap(),
+        Some((3, Frame::Consumed))
+    );
+
+    // Data up to the next command, then an escaped 0xFF
+    assert_eq!(
+        codec.decode(b"hi\xff\xff").unwrap(),
+        Some((2, Frame::Message(b"hi".to_vec())))
+    );
+    assert_eq!(
+        codec.decode(&[255, 255]).unwrap(),
+        Some((2, Frame::Message(vec![255])))
+    );
+    // Incomplete command
+    assert_eq!(codec.decode(&[255, 251]).unwrap(), None);
+    assert_eq!(codec.encode(&[1, 255]), vec![1, 255, 255]);
+}
+
+#[test]
+fn telnet_under_line_codec_yields_clean_lines() {
+    let mut stack = CodecStack::new(vec![
+        Box::new(TelnetCodec::new()),
+        Box::new(LineCodec::new()),
+    ]);
+    // Subnegotiation and a NOP in the middle of a line
+    let mut input = b"hel".to_vec();
+    input.extend_from_slice(&[255, 250, 24, 0, b'x', 255, 240, 255, 241]);
+    input.extend_from_slice(b"lo\r\n");
+
+    let mut buf = &input[..];
+    let mut messages = Vec::new();
+    while let Some((consumed, frame)) = stack.decode(buf).unwrap() {
+        buf = &buf[consumed..];
+        if let Frame::Message(data) = frame {
+            messages.push(data);
+        }
+    }
+    assert_eq!(messages, vec![b"hello".to_vec()]);
+    assert_eq!(stack.transport(), Transport::Line);
+}
+
+#[test]
+fn http_pipelined_requests_decode_one_by_one() {
+    let mut codec = HttpCodec::new();
+    let buf = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+GET /b HTTP/1.1\r\nHost: x\r\n\r\nGET /c HT";
+
+    let Some((used, Frame::Message(first))) = codec.decode(buf).unwrap() else {
+        panic!("expected the first request");
+    };
+    let request = HttpRequest::parse(&first).unwrap();
+    assert_eq!((request.method, request.target), ("POST", "/a"));
+    assert_eq!(request.body, b"hello");
+
+    let rest = &buf[used..];
+    let Some((used, Frame::Message(second))) = codec.decode(rest).unwrap() else {
+        panic!("expected the second request");
+    };
+    let request = HttpRequest::parse(&second).unwrap();
+    assert_eq!(request.target, "/b");
+    assert_eq!(request.header("HOST"), Some("x"));
+    assert!(request.keep_alive());
+
+    // The third request is not complete yet
+    assert_eq!(codec.decode(&rest[used..]).unwrap(), None);
+    assert!(!codec.wants_close());
+}
+
+#[test]
+fn http_connection_close_stops_decoding() {
+    let mut codec = HttpCodec::new();
+    let buf = b"GET / HTTP/1.1\r\nConnection: close\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+    let (used, _) = codec.decode(buf).unwrap().unwrap();
+    assert!(codec.wants_close());
+    assert_eq!(codec.decode(&buf[used..]).unwrap(), None);
+
+    // HTTP/1.0 closes unless asked not to
+    let mut codec = HttpCodec::new();
+    codec.decode(b"GET / HTTP/1.0\r\n\r\n").unwrap().unwrap();
+    assert!(codec.wants_close());
+    let mut codec = HttpCodec::new();
+    codec
+        .decode(b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n")
+        .unwrap()
+        .unwrap();
+    assert!(!codec.wants_close());
+}
+
+#[test]
+fn http_bad_requests_are_answered_by_the_codec() {
+    for (request, status) in [
+        (&b"GARBAGE\r\n\r\n"[..], "400"),
+        (b"GET / HTTP/2.0\r\n\r\n", "505"),
+        (
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "501",
+        ),
+        (
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\n",
+            "400",
+        ),
+        (b"POST / HTTP/1.1\r\nContent-Length: 99\r\n\r\n", "413"),
+    ] {
+        let mut codec = HttpCodec::with_limits(64, 16);
+        let Some((used, Frame::Control(response))) = codec.decode(request).unwrap() else {
+            panic!("expected a rejection");
+        };
+        assert_eq!(used, request.len());
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with(&format!("HTTP/1.1 {status} ")));
+        assert!(response.contains("Connection: close"));
+        assert!(codec.wants_close());
+    }
+
+    let mut codec = HttpCodec::with_limits(64, 16);
+    let Some((_, Frame::Control(response))) = codec.decode(&[b'a'; 65]).unwrap() else {
+        panic!("expected a rejection");
+    };
+    assert!(response.starts_with(b"HTTP/1.1 431 "));
+}
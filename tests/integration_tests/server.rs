@@ -1 +1,4063 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Write},
+    mem::ManuallyDrop,
+    net::{SocketAddr, TcpStream, UdpSocket},
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+    process::{Child, Command, Stdio},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
+use epoll_worker::{
+    Acceptor, AccessLog, AccessLogFormat, AlpnDispatcher, AuditEvent, Backend, Bytes, Cidr,
+    ClientId, ClientIdAllocator, ConsumeResult, DatagramHandler, DenyList, DisconnectReason,
+    Distribution, EpollServer, Error, ErrorDirective, ErrorPolicy, EventFlags, EventHandler,
+    HandlerAction, HandlerError, ListenerId, MessageTrace, Metrics, Middleware, OverflowAction,
+    PRIMARY_LISTENER, Priority, RateLimit, RateLimitAction, ServerConfig, ServerContext,
+    ServerError, SignalMask, TcpKeepalive, Telemetry, TimerId, TriggerMode,
+};
+
+use epoll_worker::{
+    fdpass,
+    protocol::{Codec, Frame, HttpCodec, HttpRequest, LineCodec, PeerIdentity, Transport},
+    reactor::MAX_CUSTOM_TOKEN,
+    watch::{FileEvent, WatchId},
+};
+
+use crate::common::{create_clients, start_test_server};
+
+/// Counts connections and never replies, so clients stay connected
+#[derive(Default)]
+struct CountingHandler {
+    connections: Arc<AtomicUsize>,
+    rejections: Arc<AtomicUsize>,
+    disconnects: Arc<AtomicUsize>,
+}
+
+impl EventHandler for CountingHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        self.disconnects.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+
+    fn on_connection_rejected(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _addr: SocketAddr,
+        _stream: &TcpStream,
+    ) {
+        self.rejections.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Peer address seen by the stream, address reported by the context,
+/// and whether the client was listed as connected
+type ProbedPeer = (SocketAddr, Option<SocketAddr>, bool);
+
+/// Records what the context reports about each new connection
+#[derive(Default)]
+struct ContextProbeHandler {
+    peers: Arc<Mutex<Vec<ProbedPeer>>>,
+    /// Id and socket fd of every client
+    fds: Arc<Mutex<Vec<(ClientId, RawFd)>>>,
+}
+
+impl EventHandler for ContextProbeHandler {
+    fn on_connection(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        _listener_id: ListenerId,
+        stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        let is_connected = ctx.connected_clients().contains(&client_id);
+        assert_eq!(ctx.client_fd(client_id), Some(stream.as_raw_fd()));
+        self.fds
+            .lock()
+            .unwrap()
+            .push((client_id, stream.as_raw_fd()));
+        self.peers.lock().unwrap().push((
+            stream.peer_addr()?,
+            ctx.client_addr(client_id),
+            is_connected,
+        ));
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+fn wait_for(condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    false
+}
+
+#[test]
+fn paused_accepts_are_served_after_resume() {
+    let handler = CountingHandler::default();
+    let connections = handler.connections.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+
+    server.pause_accepts().unwrap();
+    assert!(server.accepts_paused());
+
+    // The connections wait in the backlog until accepts are resumed
+    let _clients = create_clients(addr, 3);
+    server.resume_accepts().unwrap();
+    assert!(!server.accepts_paused());
+
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+    assert!(wait_for(|| connections.load(Ordering::SeqCst) == 3));
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+#[test]
+fn run_once_steps_the_loop_from_the_caller() {
+    let handler = CountingHandler::default();
+    let connections = handler.connections.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+
+    // Nothing happened yet, polling returns at once
+    assert_eq!(server.run_once(Some(0)).unwrap(), 0);
+
+    let _clients = create_clients(addr, 2);
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let mut handled = 0;
+    while connections.load(Ordering::SeqCst) < 2 && Instant::now() < deadline {
+        handled += server.run_once(Some(10)).unwrap();
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+    assert!(handled >= 1);
+
+    assert!(!server.is_shutting_down());
+    shutdown.store(true, Ordering::Relaxed);
+    assert!(server.is_shutting_down());
+    server.finish();
+}
+
+#[test]
+fn draining_servers_hand_new_connections_over() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let old = CountingHandler::default();
+    let old_connections = old.connections.clone();
+    let mut old_server = EpollServer::from_listener(listener.try_clone().unwrap(), old).unwrap();
+    let old_handle = old_server.handle();
+    let old_thread = thread::spawn(move || old_server.run(Some(10)).unwrap());
+
+    let _staying = TcpStream::connect(addr).unwrap();
+    assert!(wait_for(|| old_connections.load(Ordering::SeqCst) == 1));
+    let started = Instant::now();
+    old_handle.drain(Duration::from_millis(300)).unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    // The new process takes over the listener
+    let new = CountingHandler::default();
+    let new_connections = new.connections.clone();
+    let mut new_server = EpollServer::from_listener(listener, new).unwrap();
+    let new_handle = new_server.handle();
+    let new_thread = thread::spawn(move || new_server.run(Some(10)).unwrap());
+    let _arriving = TcpStream::connect(addr).unwrap();
+    assert!(wait_for(|| new_connections.load(Ordering::SeqCst) == 1));
+    assert_eq!(old_connections.load(Ordering::SeqCst), 1);
+
+    // The staying client keeps the old server up until the deadline
+    old_thread.join().unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(300));
+
+    new_handle.shutdown().unwrap();
+    new_thread.join().unwrap();
+}
+
+#[test]
+fn from_systemd_needs_socket_activation() {
+    // Not started by systemd, LISTEN_PID does not name this process
+    let server = EpollServer::from_systemd(CountingHandler::default(), ServerConfig::default());
+    assert_eq!(
+        server.err().map(|e| e.kind()),
+        Some(std::io::ErrorKind::NotFound)
+    );
+}
+
+unsafe extern "C" {
+    fn dup2(oldfd: RawFd, newfd: RawFd) -> RawFd;
+}
+
+/// Runs in a child process given a listener as fd 3, see
+/// `systemd_sockets_are_adopted_once`
+#[test]
+#[ignore = "run by systemd_sockets_are_adopted_once"]
+fn systemd_sockets_child() {
+    let expected: SocketAddr = std::env::var("EXPECTED_ADDR").unwrap().parse().unwrap();
+    let server =
+        EpollServer::from_systemd(CountingHandler::default(), ServerConfig::default()).unwrap();
+    assert_eq!(server.local_addr().unwrap(), expected);
+    // A second server must not own the same fds
+    let again = EpollServer::from_systemd(CountingHandler::default(), ServerConfig::default());
+    assert_eq!(
+        again.err().map(|e| e.kind()),
+        Some(std::io::ErrorKind::AlreadyExists)
+    );
+}
+
+#[test]
+fn systemd_sockets_are_adopted_once() {
+    use std::os::unix::process::CommandExt;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let listener_fd = listener.as_raw_fd();
+    let mut child = Command::new("sh");
+    child
+        .args(["-c", "LISTEN_PID=$$ LISTEN_FDS=1 exec \"$0\" \"$@\""])
+        .arg(std::env::current_exe().unwrap())
+        .args(["--exact", "server::systemd_sockets_child", "--ignored"])
+        .env("EXPECTED_ADDR", listener.local_addr().unwrap().to_string())
+        .stdout(Stdio::piped());
+    // SAFETY: dup2 is async-signal-safe. The copy at 3 is inherited as
+    // systemd would pass it, going through a spare fd in case the listener
+    // already is fd 3 and close-on-exec
+    unsafe {
+        child.pre_exec(move || {
+            if dup2(listener_fd, 100) < 0 || dup2(100, 3) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let output = child.output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 passed"));
+
+    // A socket that is not listening is refused
+    let output = Command::new("sh")
+        .args([
+            "-c",
+            "LISTEN_PID=$$ LISTEN_FDS=1 exec \"$0\" \"$@\" 3</dev/null",
+        ])
+        .arg(std::env::current_exe().unwrap())
+        .args(["--exact", "server::systemd_sockets_child", "--ignored"])
+        .env("EXPECTED_ADDR", "127.0.0.1:1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("not a listening TCP socket"));
+}
+
+#[test]
+fn draining_without_clients_stops_at_once() {
+    let (mut server, _addr, _shutdown) = start_test_server(CountingHandler::default());
+    let handle = server.handle();
+    let server_thread = thread::spawn(move || server.run(None).unwrap());
+
+    let started = Instant::now();
+    handle.drain(Duration::from_secs(10)).unwrap();
+    server_thread.join().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn context_knows_client_during_on_connection() {
+    let handler = ContextProbeHandler::default();
+    let peers = handler.peers.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+
+    let clients = create_clients(addr, 2);
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+    assert!(wait_for(|| peers.lock().unwrap().len() == 2));
+
+    let local_addrs: Vec<SocketAddr> = clients.iter().map(|c| c.local_addr().unwrap()).collect();
+    for (peer, ctx_addr, is_connected) in peers.lock().unwrap().iter() {
+        assert!(local_addrs.contains(peer));
+        assert_eq!(*ctx_addr, Some(*peer));
+        assert!(is_connected);
+    }
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+/// Replies to every datagram with its bytes reversed
+struct ReverseDatagramHandler;
+
+impl DatagramHandler for ReverseDatagramHandler {
+    fn on_datagram(
+        &mut self,
+        _ctx: &mut ServerContext,
+        addr: SocketAddr,
+        data: &[u8],
+    ) -> Option<(SocketAddr, Vec<u8>)> {
+        Some((addr, data.iter().rev().copied().collect()))
+    }
+}
+
+#[test]
+fn udp_socket_shares_the_event_loop() {
+    let (mut server, _addr, shutdown) = start_test_server(CountingHandler::default());
+    let udp_addr = server
+        .bind_udp("127.0.0.1:0", ReverseDatagramHandler)
+        .unwrap();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    socket.send_to(b"epoll", udp_addr).unwrap();
+
+    let mut buf = [0u8; 16];
+    let (len, from) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"llope");
+    assert_eq!(from, udp_addr);
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+#[test]
+fn dropping_server_says_goodbye_and_closes_clients() {
+    let handler = CountingHandler::default();
+    let connections = handler.connections.clone();
+    let disconnects = handler.disconnects.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    server.set_goodbye_message(Some(b"bye".to_vec()));
+
+    let mut clients = create_clients(addr, 2);
+    let handle = thread::spawn(move || {
+        server.run(Some(10)).unwrap();
+        server
+    });
+    assert!(wait_for(|| connections.load(Ordering::SeqCst) == 2));
+
+    shutdown.store(true, Ordering::Relaxed);
+    drop(handle.join().unwrap());
+    assert_eq!(disconnects.load(Ordering::SeqCst), 2);
+
+    for client in clients.iter_mut() {
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"bye");
+    }
+}
+
+#[test]
+fn connection_limit_stops_accepting() {
+    let handler = CountingHandler::default();
+    let connections = handler.connections.clone();
+    let rejections = handler.rejections.clone();
+    let config = ServerConfig::default().max_connections(2);
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", handler, config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+
+    let _clients = create_clients(addr, 4);
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    assert!(wait_for(|| rejections.load(Ordering::SeqCst) == 1));
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+    // The last client waits in the backlog instead of being rejected too
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(rejections.load(Ordering::SeqCst), 1);
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+#[test]
+fn accept_stats_count_connections_per_wakeup() {
+    let handler = CountingHandler::default();
+    let connections = handler.connections.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+
+    // The connections wait in the backlog, so one wakeup accepts them all
+    let _clients = create_clients(addr, 3);
+    thread::sleep(Duration::from_millis(20));
+    let handle = thread::spawn(move || {
+        server.run(Some(10)).unwrap();
+        server
+    });
+    assert!(wait_for(|| connections.load(Ordering::SeqCst) == 3));
+
+    shutdown.store(true, Ordering::Relaxed);
+    let server = handle.join().unwrap();
+    let stats = server.accept_stats();
+    assert_eq!(stats.accepted, 3);
+    assert_eq!(stats.rejected, 0);
+    assert!(stats.wakeups >= 1);
+    assert_eq!(stats.max_accepted_per_wakeup, 3);
+    assert_eq!(stats.peak_backlog, 3);
+    assert!(stats.backlog_limit > 0);
+}
+
+#[test]
+fn listeners_are_built_from_the_config() {
+    let handler = CountingHandler::default();
+    let connections = handler.connections.clone();
+    let config = ServerConfig::default()
+        .listen_backlog(7)
+        .ipv6_only(Some(false));
+    let mut server = EpollServer::new_with_config("[::]:0", handler, config).unwrap();
+    let port = server.local_addr().unwrap().port();
+    let shutdown = server.shutdown_signal();
+
+    // Dual-stack, an IPv4 client reaches the IPv6 listener
+    let _client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let handle = thread::spawn(move || {
+        server.run(Some(10)).unwrap();
+        server
+    });
+    assert!(wait_for(|| connections.load(Ordering::SeqCst) == 1));
+
+    shutdown.store(true, Ordering::Relaxed);
+    let server = handle.join().unwrap();
+    assert_eq!(server.accept_stats().backlog_limit, 7);
+
+    let config = ServerConfig::default().ipv6_only(Some(true));
+    let server =
+        EpollServer::new_with_config("[::]:0", CountingHandler::default(), config).unwrap();
+    let port = server.local_addr().unwrap().port();
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+}
+
+#[test]
+fn accept_batch_limit_spreads_the_backlog_over_wakeups() {
+    let handler = CountingHandler::default();
+    let connections = handler.connections.clone();
+    let config = ServerConfig::default().max_accepts_per_wakeup(2);
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", handler, config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+
+    let _clients = create_clients(addr, 7);
+    thread::sleep(Duration::from_millis(20));
+    let handle = thread::spawn(move || {
+        server.run(Some(1000)).unwrap();
+        server
+    });
+    // Requeued wakeups come without waiting for the timeout
+    assert!(wait_for(|| connections.load(Ordering::SeqCst) == 7));
+
+    shutdown.store(true, Ordering::Relaxed);
+    let server = handle.join().unwrap();
+    let stats = server.accept_stats();
+    assert_eq!(stats.accepted, 7);
+    assert_eq!(stats.max_accepted_per_wakeup, 2);
+    assert!(stats.wakeups >= 4);
+    assert!(stats.batch_limited_wakeups >= 3);
+}
+
+/// Start `count` `CountingHandler` workers behind an acceptor, returning
+/// its address, the connection count of each worker and a function
+/// stopping everything
+fn start_acceptor(
+    count: usize,
+    distribution: Distribution,
+) -> (SocketAddr, Vec<Arc<AtomicUsize>>, impl FnOnce()) {
+    let mut acceptor = Acceptor::bind("127.0.0.1:0").unwrap();
+    acceptor.set_distribution(distribution);
+    let mut counts = Vec::new();
+    let mut workers = Vec::new();
+    for _ in 0..count {
+        let handler = CountingHandler::default();
+        counts.push(handler.connections.clone());
+        let mut worker = EpollServer::new_worker(handler, ServerConfig::default()).unwrap();
+        assert!(!worker.is_listening());
+        let handle = worker.handle();
+        acceptor.add_worker(handle.clone());
+        workers.push((handle, thread::spawn(move || worker.run(Some(10)).unwrap())));
+    }
+
+    let addr = acceptor.local_addr().unwrap();
+    let shutdown = acceptor.shutdown_signal();
+    let acceptor_thread = thread::spawn(move || acceptor.run(Some(10)).unwrap());
+    let stop = move || {
+        shutdown.store(true, Ordering::Relaxed);
+        acceptor_thread.join().unwrap();
+        for (handle, thread) in workers {
+            handle.shutdown().unwrap();
+            thread.join().unwrap();
+        }
+    };
+    (addr, counts, stop)
+}
+
+#[test]
+fn acceptor_hands_connections_to_workers_in_turn() {
+    let (addr, counts, stop) = start_acceptor(3, Distribution::RoundRobin);
+
+    let _clients = create_clients(addr, 6);
+    let total = || {
+        counts
+            .iter()
+            .map(|count| count.load(Ordering::SeqCst))
+            .sum::<usize>()
+    };
+    assert!(wait_for(|| total() == 6));
+    for count in &counts {
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    stop();
+}
+
+#[test]
+fn broadcaster_reaches_the_clients_of_every_worker() {
+    let mut acceptor = Acceptor::bind("127.0.0.1:0").unwrap();
+    let mut workers = Vec::new();
+    for _ in 0..3 {
+        let mut worker =
+            EpollServer::new_worker(CountingHandler::default(), ServerConfig::default()).unwrap();
+        let handle = worker.handle();
+        acceptor.add_worker(handle.clone());
+        workers.push((handle, thread::spawn(move || worker.run(Some(10)).unwrap())));
+    }
+    let broadcaster = acceptor.broadcaster();
+    let addr = acceptor.local_addr().unwrap();
+    let shutdown = acceptor.shutdown_signal();
+    let acceptor_thread = thread::spawn(move || acceptor.run(Some(10)).unwrap());
+
+    let mut clients = create_clients(addr, 6);
+    assert!(wait_for(|| broadcaster.connected_clients() == 6));
+    broadcaster.broadcast(b"tick".to_vec()).unwrap();
+    for client in &mut clients {
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut received = [0u8; 4];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"tick");
+    }
+
+    // The other workers still get the data when one is gone
+    let (handle, thread) = workers.remove(0);
+    handle.shutdown().unwrap();
+    thread.join().unwrap();
+    assert!(matches!(
+        broadcaster.broadcast(b"tock".to_vec()),
+        Err(Error::ShutdownInProgress)
+    ));
+    let mut delivered = 0;
+    for client in &mut clients {
+        let mut received = [0u8; 4];
+        if client.read_exact(&mut received).is_ok() {
+            assert_eq!(&received, b"tock");
+            delivered += 1;
+        }
+    }
+    assert_eq!(delivered, 4);
+
+    shutdown.store(true, Ordering::Relaxed);
+    acceptor_thread.join().unwrap();
+    for (handle, thread) in workers {
+        handle.shutdown().unwrap();
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn passed_connections_are_served_by_workers() {
+    let mut worker =
+        EpollServer::new_worker(VersionHandler("v1"), ServerConfig::default()).unwrap();
+    let handle = worker.handle();
+    let thread = thread::spawn(move || worker.run(Some(10)).unwrap());
+
+    // Accept in "another process", and pass the connection over a Unix socket
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+    let (acceptor_end, worker_end) = UnixStream::pair().unwrap();
+    fdpass::send_fd(&acceptor_end, &accepted).unwrap();
+    drop(accepted);
+    let received = fdpass::recv_fd(&worker_end).unwrap();
+    handle.add_client(TcpStream::from(received)).unwrap();
+
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    client.write_all(b"version?").unwrap();
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"v1");
+
+    drop(acceptor_end);
+    assert_eq!(
+        fdpass::recv_fd(&worker_end).unwrap_err().kind(),
+        std::io::ErrorKind::UnexpectedEof
+    );
+
+    handle.shutdown().unwrap();
+    thread.join().unwrap();
+}
+
+#[test]
+fn acceptor_balances_bursts_over_the_least_loaded_workers() {
+    let (addr, counts, stop) = start_acceptor(2, Distribution::LeastLoaded);
+
+    // All in the backlog at once, queued connections count as load
+    let clients = create_clients(addr, 8);
+    let total = || {
+        counts
+            .iter()
+            .map(|count| count.load(Ordering::SeqCst))
+            .sum::<usize>()
+    };
+    assert!(wait_for(|| total() == 8));
+    for count in &counts {
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+    }
+
+    stop();
+    drop(clients);
+}
+
+/// Serve clones of `listener` on a new thread with a `CountingHandler`,
+/// allowing `max_connections` clients
+fn start_exclusive_loop(
+    listener: &std::net::TcpListener,
+    max_connections: usize,
+) -> (
+    CountingHandler,
+    impl FnOnce() -> EpollServer<CountingHandler>,
+) {
+    let handler = CountingHandler::default();
+    let counters = CountingHandler {
+        connections: handler.connections.clone(),
+        rejections: handler.rejections.clone(),
+        disconnects: handler.disconnects.clone(),
+    };
+    let config = ServerConfig::default()
+        .exclusive_accept(true)
+        .max_connections(max_connections);
+    let listener = listener.try_clone().unwrap();
+    let mut server = EpollServer::from_listener_with_config(listener, handler, config).unwrap();
+    let shutdown = server.shutdown_signal();
+    let thread = thread::spawn(move || {
+        server.run(Some(10)).unwrap();
+        server
+    });
+    let stop = move || {
+        shutdown.store(true, Ordering::Relaxed);
+        thread.join().unwrap()
+    };
+    (counters, stop)
+}
+
+#[test]
+fn exclusive_listeners_hand_over_when_a_loop_is_full() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // Turning the third one away takes the full loop off the listener,
+    // which an exclusive registration only allows by adding it again
+    let (first, stop_first) = start_exclusive_loop(&listener, 2);
+    let mut clients = create_clients(addr, 3);
+    assert!(wait_for(|| first.rejections.load(Ordering::SeqCst) == 1));
+    assert_eq!(first.connections.load(Ordering::SeqCst), 2);
+
+    let (second, stop_second) = start_exclusive_loop(&listener, 10);
+    clients.extend(create_clients(addr, 3));
+    assert!(wait_for(|| second.connections.load(Ordering::SeqCst) == 3));
+    assert_eq!(first.connections.load(Ordering::SeqCst), 2);
+
+    stop_first();
+    stop_second();
+    drop(clients);
+}
+
+const ONE_SHOT: TimerId = 1;
+const HEARTBEAT: TimerId = 2;
+
+/// Arms timers for the first client and records them firing
+#[derive(Default)]
+struct TimerHandler {
+    fired: Arc<Mutex<Vec<TimerId>>>,
+    one_shot_deadline: Option<Instant>,
+}
+
+impl EventHandler for TimerHandler {
+    fn on_connection(
+        &mut self,
+        ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        ctx.set_timer(Duration::from_millis(10), ONE_SHOT)?;
+        self.one_shot_deadline = Some(ctx.deadline_in(Duration::from_millis(10)));
+        Ok(ctx.set_interval(Duration::from_millis(5), HEARTBEAT)?)
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+
+    fn on_timer(&mut self, ctx: &mut ServerContext, timer_id: TimerId) {
+        if timer_id == ONE_SHOT {
+            let deadline = self.one_shot_deadline.unwrap();
+            assert!(ctx.is_expired(deadline));
+            assert_eq!(ctx.time_until(deadline), Duration::ZERO);
+        }
+        let mut fired = self.fired.lock().unwrap();
+        fired.push(timer_id);
+        if fired.iter().filter(|&&id| id == HEARTBEAT).count() == 3 {
+            assert!(ctx.cancel_timer(HEARTBEAT));
+        }
+    }
+}
+
+#[test]
+fn timers_fire_on_the_event_loop() {
+    let handler = TimerHandler::default();
+    let fired = handler.fired.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+
+    let _client = TcpStream::connect(addr).unwrap();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+    assert!(wait_for(|| fired.lock().unwrap().len() == 4));
+
+    // Neither the one-shot nor the cancelled interval fire again
+    thread::sleep(Duration::from_millis(50));
+    let fired = fired.lock().unwrap().clone();
+    assert_eq!(fired.iter().filter(|&&id| id == ONE_SHOT).count(), 1);
+    assert_eq!(fired.iter().filter(|&&id| id == HEARTBEAT).count(), 3);
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+/// Counts its ticks
+#[derive(Default)]
+struct TickHandler {
+    ticks: Arc<AtomicUsize>,
+}
+
+impl EventHandler for TickHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+
+    fn on_tick(&mut self, _ctx: &mut ServerContext) {
+        self.ticks.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn ticks_wake_up_a_loop_waiting_forever() {
+    let handler = TickHandler::default();
+    let ticks = handler.ticks.clone();
+    let config = ServerConfig::default()
+        .wait_timeout(None)
+        .tick_interval(Some(Duration::from_millis(20)));
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", handler, config).unwrap();
+    let stop = server.handle();
+
+    let started = Instant::now();
+    let handle = thread::spawn(move || server.run(None).unwrap());
+    assert!(wait_for(|| ticks.load(Ordering::SeqCst) >= 3));
+    assert!(started.elapsed() >= Duration::from_millis(60));
+
+    stop.shutdown().unwrap();
+    handle.join().unwrap();
+}
+
+/// Answers every message with its version
+struct VersionHandler(&'static str);
+
+impl EventHandler for VersionHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::Reply(self.0.into()))
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+#[test]
+fn replaced_handlers_serve_existing_connections() {
+    let handler: Box<dyn EventHandler + Send> = Box::new(VersionHandler("v1"));
+    let (mut server, addr, _shutdown) = start_test_server(handler);
+    let handle = server.handle();
+    let server_thread = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut reply = [0u8; 2];
+    client.write_all(b"?").unwrap();
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"v1");
+
+    let replacement: Box<dyn EventHandler + Send> = Box::new(VersionHandler("v2"));
+    handle.replace_handler(replacement).unwrap();
+    // Not the server's handler type, dropped
+    handle.replace_handler(VersionHandler("v3")).unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    client.write_all(b"?").unwrap();
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"v2");
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+/// Records the listener each client connected through
+#[derive(Default)]
+struct ListenerProbeHandler {
+    listeners: Arc<Mutex<Vec<ListenerId>>>,
+}
+
+impl EventHandler for ListenerProbeHandler {
+    fn on_connection(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        assert_eq!(ctx.listener_id(client_id), Some(listener_id));
+        self.listeners.lock().unwrap().push(listener_id);
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+#[test]
+fn added_listeners_tell_their_clients_apart() {
+    const ADMIN: ListenerId = 9;
+
+    let handler = ListenerProbeHandler::default();
+    let listeners = handler.listeners.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    let admin_addr = server.add_listener("127.0.0.1:0", ADMIN).unwrap();
+    assert!(matches!(
+        server.add_listener("127.0.0.1:0", ADMIN),
+        Err(Error::InvalidInput(_))
+    ));
+    assert!(matches!(
+        server.add_listener("127.0.0.1:0", PRIMARY_LISTENER),
+        Err(Error::InvalidInput(_))
+    ));
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let _public = TcpStream::connect(addr).unwrap();
+    assert!(wait_for(|| listeners.lock().unwrap().len() == 1));
+    let _admin = TcpStream::connect(admin_addr).unwrap();
+    assert!(wait_for(|| listeners.lock().unwrap().len() == 2));
+    assert_eq!(*listeners.lock().unwrap(), [PRIMARY_LISTENER, ADMIN]);
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+/// Records the fds of its clients
+#[derive(Default)]
+struct FdProbeHandler {
+    fds: Arc<Mutex<Vec<RawFd>>>,
+}
+
+impl EventHandler for FdProbeHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        self.fds.lock().unwrap().push(stream.as_raw_fd());
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+/// Whether `fd` of this process is closed on exec, from its `O_CLOEXEC`
+/// open flag in procfs
+fn is_cloexec(fd: RawFd) -> bool {
+    const O_CLOEXEC: u32 = 0o2000000;
+    let info = std::fs::read_to_string(format!("/proc/self/fdinfo/{fd}")).unwrap();
+    let flags = info
+        .lines()
+        .find_map(|line| line.strip_prefix("flags:"))
+        .unwrap();
+    u32::from_str_radix(flags.trim(), 8).unwrap() & O_CLOEXEC != 0
+}
+
+#[test]
+fn accepted_sockets_are_not_inherited_by_children() {
+    let handler = FdProbeHandler::default();
+    let fds = handler.fds.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let _client = TcpStream::connect(addr).unwrap();
+    assert!(wait_for(|| fds.lock().unwrap().len() == 1));
+    assert!(is_cloexec(fds.lock().unwrap()[0]));
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+/// Records the custom fd events it is called with
+#[derive(Default)]
+struct CustomFdHandler {
+    events: Arc<Mutex<Vec<(u64, EventFlags)>>>,
+}
+
+impl EventHandler for CustomFdHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+
+    fn on_custom_event(&mut self, _ctx: &mut ServerContext, token: u64, flags: EventFlags) {
+        self.events.lock().unwrap().push((token, flags));
+    }
+}
+
+#[test]
+fn registered_fds_are_reported_with_their_token() {
+    let handler = CustomFdHandler::default();
+    let events = handler.events.clone();
+    let (mut server, _addr, shutdown) = start_test_server(handler);
+
+    let (reader, mut writer) = std::io::pipe().unwrap();
+    let interest = EventFlags::READ | EventFlags::EDGE;
+    server.register_fd(reader.as_raw_fd(), interest, 7).unwrap();
+    let too_large = server.register_fd(reader.as_raw_fd(), interest, MAX_CUSTOM_TOKEN + 1);
+    assert!(matches!(too_large, Err(Error::InvalidInput(_))));
+    let closed = server.register_fd(-1, interest, 8);
+    assert!(matches!(
+        closed,
+        Err(Error::Syscall {
+            op: "epoll_ctl",
+            errno: 9
+        })
+    ));
+
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+    writer.write_all(b"ready").unwrap();
+    assert!(wait_for(|| !events.lock().unwrap().is_empty()));
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].0, 7);
+    assert!(events[0].1.contains(EventFlags::READ));
+}
+
+#[test]
+fn one_shot_fds_are_reported_once_until_rearmed() {
+    let handler = CustomFdHandler::default();
+    let events = handler.events.clone();
+    let (mut server, _addr, _shutdown) = start_test_server(handler);
+
+    let (reader, mut writer) = std::io::pipe().unwrap();
+    let fd = reader.as_raw_fd();
+    let interest = EventFlags::READ | EventFlags::ONESHOT;
+    server.register_fd(fd, interest, 3).unwrap();
+    let again = server.register_fd(fd, interest, 4);
+    assert!(matches!(again, Err(Error::AlreadyRegistered(again)) if again == fd));
+
+    // Left unread, a level-triggered registration would report it every time
+    writer.write_all(b"ready").unwrap();
+    for _ in 0..3 {
+        server.run_once(Some(10)).unwrap();
+    }
+    assert_eq!(events.lock().unwrap().len(), 1);
+
+    server.rearm_fd(fd).unwrap();
+    server.run_once(Some(10)).unwrap();
+    assert_eq!(*events.lock().unwrap(), [(3, EventFlags::READ); 2]);
+
+    server.deregister_fd(fd).unwrap();
+    assert!(matches!(server.rearm_fd(fd), Err(Error::NotRegistered(_))));
+    assert!(matches!(
+        server.deregister_fd(fd),
+        Err(Error::NotRegistered(_))
+    ));
+}
+
+/// Runs `echo` with each message and streams its output back
+#[derive(Default)]
+struct EchoProcessHandler {
+    children: HashMap<u64, (ClientId, Child)>,
+    next_token: u64,
+}
+
+impl EventHandler for EchoProcessHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        let mut child = Command::new("echo")
+            .arg(String::from_utf8_lossy(&data).trim())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        self.next_token += 1;
+        ctx.register_pipe(child.stdout.take().unwrap(), self.next_token)?;
+        self.children.insert(self.next_token, (client_id, child));
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+
+    fn on_fd_readable(&mut self, ctx: &mut ServerContext, token: u64, fd: RawFd) {
+        // The server owns the pipe, only borrow it
+        let mut pipe = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        let mut buffer = [0u8; 256];
+        loop {
+            match pipe.read(&mut buffer) {
+                Ok(0) => {
+                    if let Some((client_id, mut child)) = self.children.remove(&token) {
+                        child.wait().unwrap();
+                        ctx.send_to(client_id, "[done]").unwrap();
+                    }
+                    return;
+                }
+                Ok(n) => {
+                    let client_id = self.children[&token].0;
+                    ctx.send_to(client_id, buffer[..n].to_vec()).unwrap();
+                }
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+#[test]
+fn child_process_output_is_streamed_from_its_pipe() {
+    let (mut server, addr, shutdown) = start_test_server(EchoProcessHandler::default());
+    let (reader, _writer) = std::io::pipe().unwrap();
+    server.register_pipe(reader, MAX_CUSTOM_TOKEN).unwrap();
+    let (reader, _writer) = std::io::pipe().unwrap();
+    assert!(matches!(
+        server.register_pipe(reader, MAX_CUSTOM_TOKEN),
+        Err(Error::InvalidInput(_))
+    ));
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    client.write_all(b"hello").unwrap();
+    let mut reply = Vec::new();
+    let mut buffer = [0u8; 64];
+    while !reply.ends_with(b"[done]") {
+        let n = client.read(&mut buffer).unwrap();
+        assert!(n > 0);
+        reply.extend_from_slice(&buffer[..n]);
+    }
+    assert_eq!(reply, b"hello\n[done]");
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+#[derive(Default)]
+struct FileWatchHandler {
+    events: Arc<Mutex<Vec<(WatchId, FileEvent)>>>,
+}
+
+impl EventHandler for FileWatchHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+
+    fn on_file_event(&mut self, _ctx: &mut ServerContext, watch_id: WatchId, event: FileEvent) {
+        self.events.lock().unwrap().push((watch_id, event));
+    }
+}
+
+#[test]
+fn watched_directories_report_file_changes() {
+    let dir = std::env::temp_dir().join(format!("epoll-worker-watch-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let handler = FileWatchHandler::default();
+    let events = handler.events.clone();
+    let (mut server, _addr, shutdown) = start_test_server(handler);
+    let watch_id = server.watch_path(&dir).unwrap();
+    assert_eq!(server.watch_path(&dir).unwrap(), watch_id);
+    assert!(server.watch_path(dir.join("missing")).is_err());
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let name = std::path::PathBuf::from("config.toml");
+    std::fs::write(dir.join(&name), "reloaded = true").unwrap();
+    std::fs::remove_file(dir.join(&name)).unwrap();
+    assert!(wait_for(|| events.lock().unwrap().len() == 3));
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+    std::fs::remove_dir(&dir).unwrap();
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            (watch_id, FileEvent::Created(name.clone())),
+            (watch_id, FileEvent::Modified(Some(name.clone()))),
+            (watch_id, FileEvent::Removed(Some(name))),
+        ]
+    );
+}
+
+/// Replies at once and twice more later on, the later reply deferred
+/// first, then disconnects the client
+struct DeferringHandler;
+
+impl EventHandler for DeferringHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        let later = HandlerAction::Reply("second|".into());
+        ctx.defer(client_id, Duration::from_millis(120), later)?;
+        let sooner = HandlerAction::SendToAll("first|".into());
+        ctx.defer(client_id, Duration::from_millis(60), sooner)?;
+        ctx.defer(
+            client_id,
+            Duration::from_millis(180),
+            HandlerAction::Disconnect,
+        )?;
+        Ok(HandlerAction::Reply("now|".into()))
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+#[test]
+fn deferred_actions_are_handled_when_due() {
+    let (mut server, addr, _shutdown) = start_test_server(DeferringHandler);
+    let server_handle = server.handle();
+    // Waits forever, so only the timer can make the deferred actions run
+    let handle = thread::spawn(move || server.run(None).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let start = Instant::now();
+    client.write_all(b"go").unwrap();
+    let mut replies = Vec::new();
+    let mut buffer = [0u8; 64];
+    while replies.iter().filter(|&&b| b == b'|').count() < 3 {
+        let n = client.read(&mut buffer).unwrap();
+        assert!(n > 0);
+        replies.extend_from_slice(&buffer[..n]);
+    }
+    assert_eq!(replies, b"now|first|second|");
+    assert!(start.elapsed() >= Duration::from_millis(120));
+    assert_eq!(client.read(&mut buffer).unwrap(), 0);
+    assert!(start.elapsed() >= Duration::from_millis(180));
+
+    server_handle.shutdown().unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn server_handle_sends_to_a_connected_client() {
+    let handler = CountingHandler::default();
+    let connections = handler.connections.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    let handle = server.handle();
+    let server_thread = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    assert!(wait_for(|| connections.load(Ordering::SeqCst) == 1));
+    // Same storage slot, another generation: not this client
+    handle
+        .send_to(ClientId::new((1 << 32) | 1), b"stale".to_vec())
+        .unwrap();
+    // Sending twice checks the connection outlives a drained queue, `Bytes`
+    // are sent like vectors
+    for message in [&b"first"[..], b"second"] {
+        handle
+            .send_to(ClientId::new(1), Bytes::from(message))
+            .unwrap();
+        let mut received = vec![0u8; message.len()];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, message);
+    }
+
+    shutdown.store(true, Ordering::Relaxed);
+    server_thread.join().unwrap();
+}
+
+#[test]
+fn server_handle_wakes_up_a_blocked_loop() {
+    // Without a timeout only the handle can wake the loop up
+    let config = ServerConfig::default().wait_timeout(None);
+    let mut server =
+        EpollServer::new_with_config("127.0.0.1:0", CountingHandler::default(), config).unwrap();
+    let handle = server.handle();
+    let server_thread = thread::spawn(move || server.run(None).unwrap());
+    thread::sleep(Duration::from_millis(20));
+    handle
+        .send_to(ClientId::new(42), b"nobody".to_vec())
+        .unwrap();
+    handle.clone().shutdown().unwrap();
+
+    let deadline = Instant::now() + Duration::from_millis(500);
+    while !server_thread.is_finished() {
+        assert!(Instant::now() < deadline, "run() did not return");
+        thread::sleep(Duration::from_millis(5));
+    }
+    server_thread.join().unwrap();
+
+    // The server is gone, commands can no longer be delivered
+    assert!(matches!(
+        handle.broadcast(b"late".to_vec()),
+        Err(Error::ShutdownInProgress)
+    ));
+}
+
+/// Collects the traces reported by the server
+struct CollectingTelemetry(Arc<Mutex<Vec<MessageTrace>>>);
+
+impl Telemetry for CollectingTelemetry {
+    fn on_message_trace(&mut self, trace: &MessageTrace) {
+        self.0.lock().unwrap().push(trace.clone());
+    }
+}
+
+#[test]
+fn sampled_messages_are_traced() {
+    let traces = Arc::new(Mutex::new(Vec::new()));
+    let config = ServerConfig::default().trace_sampling(2);
+    let mut server =
+        EpollServer::new_with_config("127.0.0.1:0", CountingHandler::default(), config).unwrap();
+    server.set_telemetry(CollectingTelemetry(traces.clone()));
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+    for message in [b"one", b"two", b"six", b"ten"] {
+        client.write_all(message).unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(wait_for(|| traces.lock().unwrap().len() == 2));
+
+    // The handler never replies, so there is nothing to flush
+    let client_id = traces.lock().unwrap()[0].client_id;
+    for trace in traces.lock().unwrap().iter() {
+        assert_eq!(trace.client_id, client_id);
+        assert_eq!(trace.queue_time, None);
+        assert_eq!(trace.flushed_at, None);
+    }
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+/// Records every message and fails on `bad` ones
+#[derive(Default)]
+struct FailingHandler {
+    messages: Arc<Mutex<Vec<Vec<u8>>>>,
+    disconnects: Arc<AtomicUsize>,
+}
+
+impl EventHandler for FailingHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        self.messages.lock().unwrap().push(data.to_vec());
+        match &data[..] {
+            b"bad" => Err(HandlerError::new(ErrorPolicy::Ignore, "transient").into()),
+            b"worse" => Err(std::io::Error::other("plain error")),
+            _ => Ok(HandlerAction::None),
+        }
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        self.disconnects.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+#[test]
+fn handler_errors_follow_the_error_policy() {
+    let handler = FailingHandler::default();
+    let messages = handler.messages.clone();
+    let disconnects = handler.disconnects.clone();
+    // The policy covers plain errors, `bad` brings its own
+    let config = ServerConfig::default().handler_error_policy(ErrorPolicy::Ignore);
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", handler, config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+    for message in [&b"bad"[..], b"worse", b"good"] {
+        client.write_all(message).unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    // The client survived both errors
+    assert!(wait_for(|| messages.lock().unwrap().len() == 3));
+    assert_eq!(disconnects.load(Ordering::SeqCst), 0);
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+/// Fails every connection, stopping the server once its error budget of
+/// one is spent
+#[derive(Default)]
+struct ErrorBudgetHandler {
+    errors: Arc<Mutex<Vec<String>>>,
+}
+
+impl EventHandler for ErrorBudgetHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Err(std::io::Error::other("no thanks"))
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+
+    fn on_error(
+        &mut self,
+        _ctx: &mut ServerContext,
+        error: &ServerError,
+        client_id: Option<ClientId>,
+    ) -> ErrorDirective {
+        assert_eq!(error.default_directive(), ErrorDirective::Ignore);
+        assert!(client_id.is_some());
+        let mut errors = self.errors.lock().unwrap();
+        errors.push(error.to_string());
+        match errors.len() {
+            1 => ErrorDirective::Ignore,
+            _ => ErrorDirective::Stop,
+        }
+    }
+}
+
+#[test]
+fn on_error_can_stop_the_server() {
+    let handler = ErrorBudgetHandler::default();
+    let errors = handler.errors.clone();
+    let (mut server, addr, _shutdown) = start_test_server(handler);
+    let handle = thread::spawn(move || {
+        server.run(Some(10)).unwrap();
+        server
+    });
+
+    let _first = TcpStream::connect(addr).unwrap();
+    assert!(wait_for(|| errors.lock().unwrap().len() == 1));
+    assert!(!handle.is_finished());
+
+    let _second = TcpStream::connect(addr).unwrap();
+    let server = handle.join().unwrap();
+    // Both clients were kept, as on_connection errors are by default
+    assert_eq!(server.connected_clients().len(), 2);
+    assert_eq!(
+        errors.lock().unwrap()[0],
+        "`on_connection` failed: no thanks"
+    );
+}
+
+/// Echoes, and fails every disconnect
+#[derive(Default)]
+struct FailingDisconnectHandler {
+    errors: Arc<Mutex<Vec<String>>>,
+}
+
+impl EventHandler for FailingDisconnectHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::Reply(data))
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Err(std::io::Error::other("cleanup failed"))
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+
+    fn on_error(
+        &mut self,
+        _ctx: &mut ServerContext,
+        error: &ServerError,
+        client_id: Option<ClientId>,
+    ) -> ErrorDirective {
+        assert_eq!(error.default_directive(), ErrorDirective::Ignore);
+        assert!(client_id.is_some());
+        self.errors.lock().unwrap().push(error.to_string());
+        ErrorDirective::Ignore
+    }
+}
+
+#[test]
+fn failed_disconnects_go_to_on_error_and_the_server_keeps_serving() {
+    let handler = FailingDisconnectHandler::default();
+    let errors = handler.errors.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    let handle = thread::spawn(move || server.run(Some(10)));
+
+    let first = TcpStream::connect(addr).unwrap();
+    let mut second = TcpStream::connect(addr).unwrap();
+    second
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    second.write_all(b"ping").unwrap();
+    let mut reply = [0u8; 4];
+    second.read_exact(&mut reply).unwrap();
+    drop(first);
+    assert!(wait_for(|| errors.lock().unwrap().len() == 1));
+
+    second.write_all(b"pong").unwrap();
+    second.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"pong");
+    assert!(!handle.is_finished());
+    assert_eq!(
+        errors.lock().unwrap()[0],
+        "`on_disconnect` failed: cleanup failed"
+    );
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn every_trigger_mode_keeps_delivering_events() {
+    for mode in [
+        TriggerMode::EdgeTriggered,
+        TriggerMode::LevelTriggered,
+        TriggerMode::OneShot,
+    ] {
+        let handler = FailingHandler::default();
+        let messages = handler.messages.clone();
+        let config = ServerConfig::default().trigger_mode(mode);
+        let mut server = EpollServer::new_with_config("127.0.0.1:0", handler, config).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_signal();
+        let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+        // Later connections and messages need the fds to stay armed
+        let mut clients = create_clients(addr, 2);
+        thread::sleep(Duration::from_millis(20));
+        clients.extend(create_clients(addr, 1));
+        for _ in 0..2 {
+            for client in clients.iter_mut() {
+                client.write_all(b"good").unwrap();
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(
+            wait_for(|| messages.lock().unwrap().len() == 6),
+            "{mode:?} lost events"
+        );
+
+        shutdown.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn batched_epoll_ctl_backend_rearms_one_shot_clients() {
+    let handler = FailingHandler::default();
+    let messages = handler.messages.clone();
+    // One-shot clients are re-armed with a modification after every event
+    let config = ServerConfig::default()
+        .backend(Backend::BatchedEpollCtl)
+        .trigger_mode(TriggerMode::OneShot);
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", handler, config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut clients = create_clients(addr, 3);
+    for _ in 0..5 {
+        for client in clients.iter_mut() {
+            client.write_all(b"good").unwrap();
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(wait_for(|| messages.lock().unwrap().len() == 15));
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+#[test]
+fn batched_epoll_ctl_backend_reports_failed_rearms() {
+    let config = ServerConfig::default().backend(Backend::BatchedEpollCtl);
+    let mut server =
+        EpollServer::new_with_config("127.0.0.1:0", CustomFdHandler::default(), config).unwrap();
+    let (reader, _writer) = std::io::pipe().unwrap();
+    let fd = reader.as_raw_fd();
+    server
+        .register_fd(fd, EventFlags::READ | EventFlags::ONESHOT, 3)
+        .unwrap();
+
+    // Another pipe takes over the fd number, the registration went away
+    // with the pipe it was made for
+    let (other, _other_writer) = std::io::pipe().unwrap();
+    // SAFETY: `fd` stays owned by `reader`, now as a copy of `other`
+    assert_eq!(unsafe { dup2(other.as_raw_fd(), fd) }, fd);
+    assert!(matches!(server.rearm_fd(fd), Err(Error::NotRegistered(failed)) if failed == fd));
+}
+
+#[test]
+fn sub_millisecond_wait_timeouts_are_honoured() {
+    let config = ServerConfig::default()
+        .wait_timeout(Some(Duration::from_micros(100)))
+        .wait_signal_mask(SignalMask::empty());
+    let mut server =
+        EpollServer::new_with_config("127.0.0.1:0", CountingHandler::default(), config).unwrap();
+    let shutdown = server.shutdown_signal();
+    let handle = thread::spawn(move || {
+        server.run(None).unwrap();
+        server
+    });
+
+    thread::sleep(Duration::from_millis(250));
+    shutdown.store(true, Ordering::Relaxed);
+    let server = handle.join().unwrap();
+    // Timeouts rounded up to milliseconds would allow about 250 waits
+    let waits = server.metrics().waits;
+    assert!(waits > 400, "only {waits} waits");
+}
+
+/// Bytes of every chunk sent to the sink client
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// Forwards numbered chunks to the first client whenever another client
+/// asks, so writes pile up while the first client is still being flushed
+#[derive(Default)]
+struct BurstHandler {
+    sink: Option<ClientId>,
+    connections: Arc<AtomicUsize>,
+    next_chunk: u32,
+}
+
+impl EventHandler for BurstHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        self.sink.get_or_insert(client_id);
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        let sink = self.sink.unwrap();
+        for _ in 0..64 {
+            let mut chunk = vec![0u8; CHUNK_LEN];
+            chunk[..4].copy_from_slice(&self.next_chunk.to_be_bytes());
+            self.next_chunk += 1;
+            ctx.send_to(sink, chunk)?;
+        }
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+#[test]
+fn writes_queued_while_flushing_keep_flowing() {
+    let handler = BurstHandler::default();
+    let connections = handler.connections.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut sink = TcpStream::connect(addr).unwrap();
+    assert!(wait_for(|| connections.load(Ordering::SeqCst) == 1));
+    let mut producer = TcpStream::connect(addr).unwrap();
+
+    // Every burst lands while the sink is writable and partly flushed,
+    // far more is queued than the socket buffers hold
+    for _ in 0..8 {
+        producer.write_all(b"burst").unwrap();
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    sink.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let mut chunk = vec![0u8; CHUNK_LEN];
+    for expected in 0..128u32 {
+        sink.read_exact(&mut chunk).unwrap();
+        assert_eq!(chunk[..4], expected.to_be_bytes());
+    }
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+/// Queues a large payload on `bulk`, and answers `ping` with a high
+/// priority `PONG`
+struct PriorityHandler;
+
+impl EventHandler for PriorityHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        if &data[..] == b"ping" {
+            return Ok(HandlerAction::ReplyWithPriority(
+                "PONG".into(),
+                Priority::High,
+            ));
+        }
+        for _ in 0..512 {
+            ctx.send_to(client_id, vec![b'.'; CHUNK_LEN])?;
+        }
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+#[test]
+fn high_priority_replies_overtake_queued_payloads() {
+    let (mut server, addr, shutdown) = start_test_server(PriorityHandler);
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"bulk").unwrap();
+    // Far more is queued than the socket buffers hold
+    thread::sleep(Duration::from_millis(100));
+    client.write_all(b"ping").unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let total = 512 * CHUNK_LEN + 4;
+    let mut received = vec![0u8; total];
+    client.read_exact(&mut received).unwrap();
+    let pong = received
+        .windows(4)
+        .position(|window| window == b"PONG")
+        .unwrap();
+    // Behind what was already in the socket buffers and the chunk being
+    // written, ahead of the rest
+    assert!(pong % CHUNK_LEN == 0, "PONG split a chunk at {pong}");
+    assert!(pong < total / 2, "PONG arrived at {pong} of {total}");
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+#[test]
+fn broadcasts_skip_clients_accepted_after_emission() {
+    let handler = CountingHandler::default();
+    let connections = handler.connections.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    let handle = server.handle();
+
+    // The client is accepted in the same tick that handles the broadcast,
+    // but only after the broadcast was requested
+    let mut client = TcpStream::connect(addr).unwrap();
+    handle.broadcast(b"before you".to_vec()).unwrap();
+    let server_thread = thread::spawn(move || server.run(Some(10)).unwrap());
+    assert!(wait_for(|| connections.load(Ordering::SeqCst) == 1));
+
+    client
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let mut buf = [0u8; 16];
+    let err = client.read(&mut buf).unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    ));
+
+    shutdown.store(true, Ordering::Relaxed);
+    server_thread.join().unwrap();
+}
+
+/// Records `TCP_NODELAY` as configured, and after turning it off per client
+#[derive(Default)]
+struct NodelayProbeHandler {
+    observed: Arc<Mutex<Vec<(bool, bool)>>>,
+}
+
+impl EventHandler for NodelayProbeHandler {
+    fn on_connection(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        _listener_id: ListenerId,
+        stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        let configured = stream.nodelay()?;
+        assert!(ctx.set_nodelay(client_id, false)?);
+        assert!(ctx.set_keepalive(client_id, None)?);
+        self.observed
+            .lock()
+            .unwrap()
+            .push((configured, stream.nodelay()?));
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+#[test]
+fn socket_options_apply_on_accept_and_per_client() {
+    let handler = NodelayProbeHandler::default();
+    let observed = handler.observed.clone();
+    let config = ServerConfig::default()
+        .nodelay(true)
+        .keepalive(Some(TcpKeepalive::default()));
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", handler, config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+
+    let _client = TcpStream::connect(addr).unwrap();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+    assert!(wait_for(|| !observed.lock().unwrap().is_empty()));
+    assert_eq!(observed.lock().unwrap()[0], (true, false));
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+/// Per-client state kept in the context
+struct Session {
+    name: String,
+    messages: usize,
+}
+
+/// Names clients on connect and counts their messages in their session
+#[derive(Default)]
+struct SessionHandler {
+    seen: Arc<Mutex<Vec<(String, usize)>>>,
+}
+
+impl EventHandler for SessionHandler {
+    fn on_connection(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        let session = Session {
+            name: format!("guest-{client_id}"),
+            messages: 0,
+        };
+        assert!(ctx.set_client_data(client_id, session));
+        assert!(ctx.set_client_data(client_id, 7u32));
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        let session = ctx.get_client_data_mut::<Session>(client_id).unwrap();
+        session.messages += 1;
+        let seen = (session.name.clone(), session.messages);
+        self.seen.lock().unwrap().push(seen);
+
+        // Values of other types are kept apart
+        assert_eq!(ctx.get_client_data::<u32>(client_id), Some(&7));
+        assert_eq!(ctx.get_client_data::<String>(client_id), None);
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+#[test]
+fn client_data_follows_the_client() {
+    let handler = SessionHandler::default();
+    let seen = handler.seen.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+    for _ in 0..2 {
+        client.write_all(b"hello").unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(wait_for(|| seen.lock().unwrap().len() == 2));
+
+    let seen = seen.lock().unwrap();
+    assert!(seen[0].0.starts_with("guest-"));
+    assert_eq!(seen[1], (seen[0].0.clone(), 2));
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+#[test]
+fn client_ids_count_up_from_one() {
+    let handler = ContextProbeHandler::default();
+    let fds = handler.fds.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+
+    let _clients = create_clients(addr, 3);
+    let handle = thread::spawn(move || {
+        server.run(Some(10)).unwrap();
+        server
+    });
+    assert!(wait_for(|| fds.lock().unwrap().len() == 3));
+    shutdown.store(true, Ordering::Relaxed);
+    let server = handle.join().unwrap();
+
+    let fds = fds.lock().unwrap();
+    let ids: Vec<u64> = fds.iter().map(|&(id, _)| id.get()).collect();
+    assert_eq!(ids, [1, 2, 3]);
+    for &(id, fd) in fds.iter() {
+        assert_eq!(server.client_id_by_fd(fd), Some(id));
+    }
+}
+
+/// Joins and leaves the lobby on request, relays everything else to it
+struct RoomHandler;
+
+impl EventHandler for RoomHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        match &data[..] {
+            b"join" => assert!(ctx.join(client_id, "lobby")),
+            b"leave" => assert!(ctx.leave(client_id, "lobby")),
+            _ => {
+                return Ok(HandlerAction::BroadcastRoom {
+                    room: "lobby".to_string(),
+                    data,
+                });
+            }
+        }
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+/// Whether `client` receives something within 100ms
+fn receives_anything(client: &mut TcpStream) -> bool {
+    client
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let mut buf = [0u8; 64];
+    client.read(&mut buf).is_ok()
+}
+
+#[test]
+fn room_broadcasts_reach_the_other_members_only() {
+    let (mut server, addr, shutdown) = start_test_server(RoomHandler);
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut clients = create_clients(addr, 3);
+    for client in &mut clients[..2] {
+        client.write_all(b"join").unwrap();
+    }
+    thread::sleep(Duration::from_millis(50));
+
+    clients[0].write_all(b"hello").unwrap();
+    let mut received = [0u8; 5];
+    clients[1]
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    clients[1].read_exact(&mut received).unwrap();
+    assert_eq!(&received, b"hello");
+    assert!(!receives_anything(&mut clients[0]));
+    assert!(!receives_anything(&mut clients[2]));
+
+    // Once the only other member left, nobody hears the sender
+    clients[1].write_all(b"leave").unwrap();
+    thread::sleep(Duration::from_millis(50));
+    clients[0].write_all(b"anyone?").unwrap();
+    assert!(!receives_anything(&mut clients[1]));
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+/// Hands out ids from an application level range
+#[derive(Default)]
+struct SequentialIds {
+    next: u64,
+}
+
+impl ClientIdAllocator for SequentialIds {
+    fn allocate(&mut self, _peer_addr: SocketAddr) -> ClientId {
+        self.next += 1;
+        ClientId::from(1000 + self.next)
+    }
+}
+
+#[test]
+fn id_allocator_picks_client_ids() {
+    let handler = ContextProbeHandler::default();
+    let peers = handler.peers.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    server.set_id_allocator(SequentialIds::default());
+
+    let _clients = create_clients(addr, 2);
+    let handle = thread::spawn(move || {
+        server.run(Some(10)).unwrap();
+        server
+    });
+    assert!(wait_for(|| peers.lock().unwrap().len() == 2));
+    shutdown.store(true, Ordering::Relaxed);
+    let server = handle.join().unwrap();
+
+    let ids = server.connected_clients();
+    assert_eq!(ids.len(), 2);
+    assert!(ids.contains(&ClientId::new(1001)) && ids.contains(&ClientId::new(1002)));
+}
+
+/// Hands out the same id to everyone, so only the first client fits
+struct RepeatingIds;
+
+impl ClientIdAllocator for RepeatingIds {
+    fn allocate(&mut self, _peer_addr: SocketAddr) -> ClientId {
+        ClientId::new(7)
+    }
+}
+
+#[test]
+fn connections_failing_setup_are_closed_without_pausing_accepts() {
+    let handler = ContextProbeHandler::default();
+    let peers = handler.peers.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    server.set_id_allocator(RepeatingIds);
+
+    let mut clients = create_clients(addr, 3);
+    let handle = thread::spawn(move || {
+        server.run(Some(10)).unwrap();
+        server
+    });
+    assert!(wait_for(|| peers.lock().unwrap().len() == 1));
+    // The duplicate ids only cost their own connections
+    for client in &mut clients[1..] {
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        assert_eq!(client.read(&mut [0u8; 8]).unwrap(), 0);
+    }
+    shutdown.store(true, Ordering::Relaxed);
+    let server = handle.join().unwrap();
+
+    let stats = server.accept_stats();
+    assert_eq!(stats.setup_failures, 2);
+    assert_eq!(stats.errors, 0);
+    assert_eq!(server.connected_clients(), [ClientId::new(7)]);
+}
+
+/// Keeps every metrics report
+struct MetricsTelemetry(Arc<Mutex<Vec<Metrics>>>);
+
+impl Telemetry for MetricsTelemetry {
+    fn on_message_trace(&mut self, _trace: &MessageTrace) {}
+
+    fn on_metrics(&mut self, metrics: &Metrics) {
+        self.0.lock().unwrap().push(*metrics);
+    }
+}
+
+#[test]
+fn metrics_are_reported_periodically() {
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let config = ServerConfig::default()
+        .wait_timeout(None)
+        .metrics_interval(Some(Duration::from_millis(20)));
+    let mut server =
+        EpollServer::new_with_config("127.0.0.1:0", CountingHandler::default(), config).unwrap();
+    server.set_telemetry(MetricsTelemetry(reports.clone()));
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+
+    let mut clients = create_clients(addr, 2);
+    clients[0].write_all(b"hello").unwrap();
+    let handle = thread::spawn(move || {
+        server.run(None).unwrap();
+        server
+    });
+
+    // Reports keep coming even though the loop otherwise blocks
+    assert!(wait_for(|| {
+        let reports = reports.lock().unwrap();
+        reports.len() >= 2 && reports.last().unwrap().bytes_read == 5
+    }));
+    shutdown.store(true, Ordering::Relaxed);
+    let server = handle.join().unwrap();
+
+    let metrics = server.metrics();
+    assert_eq!(metrics.accepted, 2);
+    assert_eq!(metrics.active, 2);
+    assert_eq!(metrics.disconnected, 0);
+    assert_eq!(metrics.bytes_read, 5);
+    assert!(metrics.waits >= 2 && metrics.events >= 2);
+    assert!(metrics.events_per_wait() > 0.0);
+}
+
+/// Send a raw HTTP request and read the whole response
+fn http_get(addr: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn metrics_endpoint_serves_prometheus_text() {
+    let config = ServerConfig::default().metrics_addr("127.0.0.1:0".parse().unwrap());
+    let handler = CountingHandler::default();
+    let connections = handler.connections.clone();
+    let server = EpollServer::new_with_config("127.0.0.1:0", handler, config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let metrics_addr = server.metrics_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+
+    let _client = TcpStream::connect(addr).unwrap();
+    let handle = thread::spawn(move || {
+        let mut server = server;
+        server.run(Some(10)).unwrap();
+    });
+    assert!(wait_for(|| connections.load(Ordering::SeqCst) == 1));
+
+    let response = http_get(metrics_addr, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("# TYPE epoll_worker_connections_active gauge\n"));
+    assert!(response.contains("\nepoll_worker_connections_active 1\n"));
+    assert!(response.contains("\nepoll_worker_connections_accepted_total 1\n"));
+
+    let response = http_get(metrics_addr, "/other");
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+#[test]
+fn metrics_display_as_one_stats_line() {
+    let metrics = Metrics {
+        accepted: 3,
+        active: 2,
+        disconnected: 1,
+        waits: 4,
+        events: 6,
+        ..Metrics::default()
+    };
+    let line = metrics.to_string();
+    assert!(!line.contains('\n'));
+    for pair in [
+        "active=2",
+        "accepted=3",
+        "disconnected=1",
+        "events_per_wait=1.50",
+    ] {
+        assert!(line.contains(pair), "{pair} missing from {line}");
+    }
+}
+
+/// Records the clients still connected when the server shuts down
+#[derive(Default)]
+struct ShutdownHandler {
+    connections: Arc<AtomicUsize>,
+    at_shutdown: Arc<Mutex<Option<Vec<ClientId>>>>,
+}
+
+impl EventHandler for ShutdownHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+
+    fn on_shutdown(&mut self, ctx: &mut ServerContext) {
+        *self.at_shutdown.lock().unwrap() = Some(ctx.connected_clients());
+    }
+}
+
+#[test]
+fn shutdown_hook_sees_connected_clients() {
+    let handler = ShutdownHandler::default();
+    let connections = handler.connections.clone();
+    let at_shutdown = handler.at_shutdown.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+
+    let _clients = create_clients(addr, 2);
+    let handle = thread::spawn(move || {
+        server.run(Some(10)).unwrap();
+        server
+    });
+    assert!(wait_for(|| connections.load(Ordering::SeqCst) == 2));
+    assert!(at_shutdown.lock().unwrap().is_none());
+
+    shutdown.store(true, Ordering::Relaxed);
+    let server = handle.join().unwrap();
+
+    let mut ids = at_shutdown.lock().unwrap().take().unwrap();
+    let mut connected = server.connected_clients();
+    ids.sort_unstable();
+    connected.sort_unstable();
+    assert_eq!(ids.len(), 2);
+    assert_eq!(ids, connected);
+}
+
+/// Counts delivered and rate limited messages, keeping the delivered ones
+#[derive(Default)]
+struct RateLimitedHandler {
+    messages: Arc<AtomicUsize>,
+    limited: Arc<AtomicUsize>,
+    received: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl EventHandler for RateLimitedHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        self.messages.fetch_add(1, Ordering::SeqCst);
+        self.received.lock().unwrap().push(data.to_vec());
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+
+    fn on_rate_limited(&mut self, _ctx: &mut ServerContext, _client_id: ClientId) {
+        self.limited.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Start a server limited by `config`, returning its counters
+fn start_rate_limited_server(
+    config: ServerConfig,
+) -> (
+    SocketAddr,
+    Arc<AtomicUsize>,
+    Arc<AtomicUsize>,
+    impl FnOnce(),
+) {
+    let handler = RateLimitedHandler::default();
+    let (messages, limited) = (handler.messages.clone(), handler.limited.clone());
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", handler, config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+    let stop = move || {
+        shutdown.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+    };
+    (addr, messages, limited, stop)
+}
+
+/// Send each message on its own, so they are not read together
+fn send_spaced(client: &mut TcpStream, count: usize) {
+    for _ in 0..count {
+        client.write_all(b"ping").unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn messages_over_the_client_rate_are_dropped() {
+    let limit = RateLimit::default().messages_per_sec(2);
+    let config = ServerConfig::default().client_rate_limit(limit);
+    let (addr, messages, limited, stop) = start_rate_limited_server(config);
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    send_spaced(&mut client, 5);
+    assert!(wait_for(|| limited.load(Ordering::SeqCst) == 3));
+    assert_eq!(messages.load(Ordering::SeqCst), 2);
+    stop();
+}
+
+#[test]
+fn clients_of_one_ip_share_its_rate() {
+    let limit = RateLimit::default().bytes_per_sec(8);
+    let config = ServerConfig::default().ip_rate_limit(limit);
+    let (addr, messages, limited, stop) = start_rate_limited_server(config);
+
+    let mut clients = create_clients(addr, 2);
+    send_spaced(&mut clients[0], 2);
+    send_spaced(&mut clients[1], 1);
+    assert!(wait_for(|| limited.load(Ordering::SeqCst) == 1));
+    assert_eq!(messages.load(Ordering::SeqCst), 2);
+    stop();
+}
+
+#[test]
+fn rate_limited_client_is_paused_until_it_may_send() {
+    let limit = RateLimit::default().messages_per_sec(10);
+    let config = ServerConfig::default()
+        .codec(|| Box::new(LineCodec::new()))
+        .client_rate_limit(limit)
+        .rate_limit_action(RateLimitAction::PauseReads);
+    let handler = RateLimitedHandler::default();
+    let (limited, received) = (handler.limited.clone(), handler.received.clone());
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", handler, config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    // A burst of 10 goes through, the 5 lines over it wait for the rate
+    // instead of being lost
+    let mut client = TcpStream::connect(addr).unwrap();
+    let lines: Vec<String> = (0..15).map(|index| format!("line {index}")).collect();
+    client
+        .write_all(format!("{}\n", lines.join("\n")).as_bytes())
+        .unwrap();
+    assert!(wait_for(|| received.lock().unwrap().len() == 15));
+    let received: Vec<String> = received
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|line| String::from_utf8_lossy(line).trim_end().to_string())
+        .collect();
+    assert_eq!(received, lines);
+    assert_eq!(limited.load(Ordering::SeqCst), 5);
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+#[test]
+fn cidr_blocks_match_addresses() {
+    let block: Cidr = "10.1.2.3/16".parse().unwrap();
+    assert_eq!(block.to_string(), "10.1.0.0/16");
+    assert!(block.contains("10.1.255.1".parse().unwrap()));
+    assert!(block.contains("::ffff:10.1.0.9".parse().unwrap()));
+    assert!(!block.contains("10.2.0.1".parse().unwrap()));
+
+    let host: Cidr = "fd00::1".parse().unwrap();
+    assert!(host.contains("fd00::1".parse().unwrap()));
+    assert!(!host.contains("fd00::2".parse().unwrap()));
+
+    assert!(
+        "0.0.0.0/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains("8.8.8.8".parse().unwrap())
+    );
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("localhost/8".parse::<Cidr>().is_err());
+}
+
+#[test]
+fn accept_filter_closes_refused_peers() {
+    let handler = CountingHandler::default();
+    let connections = handler.connections.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    server.set_accept_filter(DenyList::new(["127.0.0.0/8".parse().unwrap()]));
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let handle = thread::spawn(move || {
+        server.run(Some(10)).unwrap();
+        server
+    });
+
+    // Closed without the handler ever seeing it
+    assert_eq!(client.read(&mut [0u8; 16]).unwrap(), 0);
+    shutdown.store(true, Ordering::Relaxed);
+    let server = handle.join().unwrap();
+    assert_eq!(connections.load(Ordering::SeqCst), 0);
+    assert_eq!(server.accept_stats().filtered, 1);
+    assert_eq!(server.accept_stats().accepted, 0);
+}
+
+/// Counts newline terminated messages, discarding the data of clients
+/// over their read buffer limit
+#[derive(Default)]
+struct LineHandler {
+    messages: Arc<AtomicUsize>,
+    overflows: Arc<AtomicUsize>,
+}
+
+impl EventHandler for LineHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        self.messages.fetch_add(1, Ordering::SeqCst);
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, data: &[u8]) -> bool {
+        data.ends_with(b"\n")
+    }
+
+    fn on_buffer_overflow(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> OverflowAction {
+        self.overflows.fetch_add(1, Ordering::SeqCst);
+        OverflowAction::Discard
+    }
+}
+
+/// Run a `LineHandler` server with `config`, returning its message count
+/// and a function stopping it and handing the server back
+fn start_line_server(
+    config: ServerConfig,
+) -> (
+    SocketAddr,
+    Arc<AtomicUsize>,
+    impl FnOnce() -> EpollServer<LineHandler>,
+) {
+    let handler = LineHandler::default();
+    let messages = handler.messages.clone();
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", handler, config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+    let handle = thread::spawn(move || {
+        server.run(Some(10)).unwrap();
+        server
+    });
+    let stop = move || {
+        shutdown.store(true, Ordering::Relaxed);
+        handle.join().unwrap()
+    };
+    (addr, messages, stop)
+}
+
+#[test]
+fn oversized_messages_disconnect_the_client() {
+    let config = ServerConfig::hardened().max_message_size(16);
+    let (addr, messages, stop) = start_line_server(config);
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    client.write_all(b"short\n").unwrap();
+    assert!(wait_for(|| messages.load(Ordering::SeqCst) == 1));
+
+    client.write_all(&[b'x'; 32]).unwrap();
+    assert_eq!(client.read(&mut [0u8; 16]).unwrap(), 0);
+    assert_eq!(messages.load(Ordering::SeqCst), 1);
+    stop();
+}
+
+#[test]
+fn unfinished_messages_time_out() {
+    let config = ServerConfig::default().message_timeout(Some(Duration::from_millis(100)));
+    let (addr, messages, stop) = start_line_server(config);
+
+    let mut slow = TcpStream::connect(addr).unwrap();
+    let mut done = TcpStream::connect(addr).unwrap();
+    for client in [&slow, &done] {
+        client
+            .set_read_timeout(Some(Duration::from_secs(3)))
+            .unwrap();
+    }
+    let started = Instant::now();
+    slow.write_all(b"partial").unwrap();
+    done.write_all(b"complete\n").unwrap();
+
+    // Enforced when due, not at the next once a second check
+    assert_eq!(slow.read(&mut [0u8; 16]).unwrap(), 0);
+    assert!(started.elapsed() < Duration::from_millis(600));
+    assert_eq!(messages.load(Ordering::SeqCst), 1);
+
+    done.set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let err = done.read(&mut [0u8; 16]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+    stop();
+}
+
+#[test]
+fn read_buffer_overflows_go_to_the_handler() {
+    let handler = LineHandler::default();
+    let (messages, overflows) = (handler.messages.clone(), handler.overflows.clone());
+    let config = ServerConfig::default().max_read_buffer(1024);
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", handler, config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    // Never terminated, discarded a kilobyte at a time
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[b'x'; 256 * 1024]).unwrap();
+    assert!(wait_for(|| overflows.load(Ordering::SeqCst) > 0));
+
+    client.write_all(b"\nhello\n").unwrap();
+    assert!(wait_for(|| messages.load(Ordering::SeqCst) >= 1));
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+    // Each overflow throws away at most the limit plus one 4 KiB read
+    assert!(overflows.load(Ordering::SeqCst) >= 256 * 1024 / (1024 + 4096));
+}
+
+#[test]
+fn connections_over_the_ip_accept_rate_are_closed() {
+    let config = ServerConfig::default().ip_accept_rate(2);
+    let (addr, _, stop) = start_line_server(config);
+
+    let mut clients = create_clients(addr, 4);
+    let mut closed = 0;
+    for client in &mut clients {
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        if matches!(client.read(&mut [0u8; 16]), Ok(0)) {
+            closed += 1;
+        }
+    }
+
+    let server = stop();
+    assert_eq!(closed, 2);
+    assert_eq!(server.accept_stats().rate_limited, 2);
+    assert_eq!(server.accept_stats().accepted, 2);
+}
+
+/// Client address reported by the context, and the message it sent
+type AddressedMessage = (Option<SocketAddr>, Vec<u8>);
+
+/// Records the client address the context reports for each message
+#[derive(Default)]
+struct AddrHandler {
+    addrs: Arc<Mutex<Vec<AddressedMessage>>>,
+}
+
+impl EventHandler for AddrHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        self.addrs
+            .lock()
+            .unwrap()
+            .push((ctx.client_addr(client_id), data.to_vec()));
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, data: &[u8]) -> bool {
+        data.ends_with(b"\n")
+    }
+}
+
+#[test]
+fn proxy_protocol_reports_the_client_behind_the_proxy() {
+    let handler = AddrHandler::default();
+    let addrs = handler.addrs.clone();
+    let config = ServerConfig::default().proxy_protocol(true);
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", handler, config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    // The header may arrive on its own, before any data
+    client
+        .write_all(b"PROXY TCP4 203.0.113.9 127.0.0.1 40000 80\r\n")
+        .unwrap();
+    thread::sleep(Duration::from_millis(50));
+    client.write_all(b"hello\n").unwrap();
+    assert!(wait_for(|| !addrs.lock().unwrap().is_empty()));
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+    assert_eq!(
+        *addrs.lock().unwrap(),
+        [(
+            Some("203.0.113.9:40000".parse().unwrap()),
+            b"hello\n".to_vec()
+        )]
+    );
+}
+
+/// Keeps every message it receives, without its trailing newline
+#[derive(Default)]
+struct KeepingHandler {
+    kept: Arc<Mutex<Vec<Bytes>>>,
+}
+
+impl EventHandler for KeepingHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        let line = data.slice(..data.len() - 1);
+        self.kept.lock().unwrap().push(line);
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, data: &[u8]) -> bool {
+        data.ends_with(b"\n")
+    }
+}
+
+#[test]
+fn kept_messages_are_not_overwritten_by_later_reads() {
+    let handler = KeepingHandler::default();
+    let kept = handler.kept.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"first\n").unwrap();
+    assert!(wait_for(|| kept.lock().unwrap().len() == 1));
+    client.write_all(b"second\n").unwrap();
+    assert!(wait_for(|| kept.lock().unwrap().len() == 2));
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+    assert_eq!(*kept.lock().unwrap(), [&b"first"[..], &b"second"[..]]);
+}
+
+/// Answers in two parts, with `ReplyParts` or by corking around `send_to`
+struct MultipartHandler;
+
+impl EventHandler for MultipartHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        let parts = vec![Bytes::from(b"head:"), data.clone()];
+        if data == b"corked" {
+            assert!(ctx.cork(client_id)?);
+            assert_eq!(ctx.is_corked(client_id), Some(true));
+            for part in parts {
+                ctx.send_to(client_id, part)?;
+            }
+            assert!(ctx.uncork(client_id)?);
+            assert_eq!(ctx.is_corked(client_id), Some(false));
+            return Ok(HandlerAction::None);
+        }
+        Ok(HandlerAction::ReplyParts(parts))
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+#[test]
+fn multipart_responses_arrive_whole() {
+    let (mut server, addr, shutdown) = start_test_server(MultipartHandler);
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    for message in [&b"parts"[..], b"corked"] {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        client.write_all(message).unwrap();
+
+        let expected = [&b"head:"[..], message].concat();
+        let mut response = vec![0u8; expected.len()];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(response, expected);
+    }
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+/// Chunks `ChunkHandler` sends in answer to a message
+const STREAMED_CHUNKS: usize = 4;
+const STREAMED_CHUNK_LEN: usize = 256 * 1024;
+
+/// Streams `STREAMED_CHUNKS` chunks, queueing each once the previous one is out
+#[derive(Default)]
+struct ChunkHandler {
+    sent: usize,
+    flushed: Arc<Mutex<Vec<u64>>>,
+}
+
+impl EventHandler for ChunkHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        self.sent = 1;
+        Ok(HandlerAction::Reply(vec![b'c'; STREAMED_CHUNK_LEN].into()))
+    }
+
+    fn on_write_complete(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        bytes_flushed: u64,
+    ) {
+        self.flushed.lock().unwrap().push(bytes_flushed);
+        if self.sent < STREAMED_CHUNKS {
+            self.sent += 1;
+            ctx.send_to(client_id, vec![b'c'; STREAMED_CHUNK_LEN])
+                .unwrap();
+        }
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+#[test]
+fn drained_queues_ask_for_the_next_chunk() {
+    let handler = ChunkHandler::default();
+    let flushed = handler.flushed.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    client.write_all(b"go").unwrap();
+    let mut received = vec![0u8; STREAMED_CHUNKS * STREAMED_CHUNK_LEN];
+    client.read_exact(&mut received).unwrap();
+    assert!(wait_for(|| flushed.lock().unwrap().len() == STREAMED_CHUNKS));
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+    assert_eq!(
+        *flushed.lock().unwrap(),
+        [STREAMED_CHUNK_LEN as u64; STREAMED_CHUNKS]
+    );
+}
+
+/// Bytes of the last reply `FileHandler` sends before closing
+const LAST_REPLY_LEN: usize = 4 * 1024 * 1024;
+
+/// Answers `file` with the file at `path` and anything else with a last
+/// reply of `LAST_REPLY_LEN` bytes
+struct FileHandler {
+    path: std::path::PathBuf,
+}
+
+impl EventHandler for FileHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        if data != b"file" {
+            let reply = vec![b'z'; LAST_REPLY_LEN];
+            return Ok(HandlerAction::ReplyAndClose(reply.into()));
+        }
+        let file = std::fs::File::open(&self.path)?;
+        let len = file.metadata()?.len() as usize;
+        Ok(HandlerAction::SendFile {
+            file,
+            offset: 0,
+            len,
+        })
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+#[test]
+fn files_are_sent_whole_and_the_connection_kept() {
+    let path = std::env::temp_dir().join(format!("epoll-worker-sendfile-{}", std::process::id()));
+    let contents: Vec<u8> = (0..512 * 1024).map(|index| (index % 253) as u8).collect();
+    std::fs::write(&path, &contents).unwrap();
+    let (mut server, addr, shutdown) = start_test_server(FileHandler { path: path.clone() });
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    for _ in 0..2 {
+        client.write_all(b"file").unwrap();
+        let mut received = vec![0u8; contents.len()];
+        client.read_exact(&mut received).unwrap();
+        assert!(received == contents);
+    }
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn last_replies_are_written_before_closing() {
+    let path = std::env::temp_dir();
+    let (mut server, addr, shutdown) = start_test_server(FileHandler { path });
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    client.write_all(b"bye").unwrap();
+    // Ends only once the server closed the connection
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).unwrap();
+    assert_eq!(received.len(), LAST_REPLY_LEN);
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+/// Answers `done` with a last reply and half-closes, keeping every message
+#[derive(Default)]
+struct HalfCloseHandler {
+    messages: Arc<Mutex<Vec<Bytes>>>,
+}
+
+impl EventHandler for HalfCloseHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        let done = data == b"done";
+        self.messages.lock().unwrap().push(data);
+        if !done {
+            return Ok(HandlerAction::None);
+        }
+        ctx.send_to(client_id, b"bye")?;
+        Ok(HandlerAction::FinishWrite)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+#[test]
+fn half_closed_clients_are_still_read() {
+    let handler = HalfCloseHandler::default();
+    let messages = handler.messages.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    client.write_all(b"done").unwrap();
+    // Ends at the FIN sent after the reply
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).unwrap();
+    assert_eq!(received, b"bye");
+
+    client.write_all(b"more").unwrap();
+    assert!(wait_for(|| messages.lock().unwrap().len() == 2));
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+    assert_eq!(*messages.lock().unwrap(), [b"done", b"more"]);
+}
+
+/// Bytes `StreamHandler` streams in answer to a message
+const STREAMED_LEN: usize = 1024 * 1024 + 17;
+
+/// Streams `STREAMED_LEN` bytes counting up from 0, wrapping at 251
+struct StreamHandler;
+
+impl EventHandler for StreamHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        let mut produced = 0;
+        let source = move |buf: &mut [u8]| {
+            let len = buf.len().min(STREAMED_LEN - produced);
+            for byte in &mut buf[..len] {
+                *byte = (produced % 251) as u8;
+                produced += 1;
+            }
+            Ok(len)
+        };
+        Ok(HandlerAction::ReplyStream(Box::new(source)))
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+#[test]
+fn streamed_responses_are_pulled_until_the_source_ends() {
+    let (mut server, addr, shutdown) = start_test_server(StreamHandler);
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    client.write_all(b"go").unwrap();
+    let mut received = vec![0u8; STREAMED_LEN];
+    client.read_exact(&mut received).unwrap();
+    assert!(
+        received
+            .iter()
+            .enumerate()
+            .all(|(index, &byte)| byte == (index % 251) as u8)
+    );
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+/// Refuses every second connection, uppercases messages and drops the
+/// ones starting with `#`
+#[derive(Default)]
+struct ShoutingMiddleware {
+    accepted: usize,
+}
+
+impl Middleware for ShoutingMiddleware {
+    fn on_accept(&mut self, _client_id: ClientId, _peer_addr: SocketAddr) -> bool {
+        self.accepted += 1;
+        self.accepted % 2 == 1
+    }
+
+    fn on_inbound_data(
+        &mut self,
+        _client_id: ClientId,
+        data: Vec<u8>,
+    ) -> std::io::Result<Option<Vec<u8>>> {
+        if data.starts_with(b"#") {
+            return Ok(None);
+        }
+        Ok(Some(data.to_ascii_uppercase()))
+    }
+}
+
+#[test]
+fn middlewares_run_before_the_handler() {
+    let handler = AddrHandler::default();
+    let addrs = handler.addrs.clone();
+    let (mut server, addr, shutdown) = start_test_server(handler);
+    server.add_middleware(ShoutingMiddleware::default());
+    let handle = thread::spawn(move || {
+        server.run(Some(10)).unwrap();
+        server
+    });
+
+    let mut kept = TcpStream::connect(addr).unwrap();
+    let mut refused = TcpStream::connect(addr).unwrap();
+    refused
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    assert_eq!(refused.read(&mut [0u8; 16]).unwrap(), 0);
+
+    kept.write_all(b"#comment\n").unwrap();
+    thread::sleep(Duration::from_millis(50));
+    kept.write_all(b"hello\n").unwrap();
+    assert!(wait_for(|| !addrs.lock().unwrap().is_empty()));
+
+    shutdown.store(true, Ordering::Relaxed);
+    let server = handle.join().unwrap();
+    let messages: Vec<Vec<u8>> = addrs
+        .lock()
+        .unwrap()
+        .drain(..)
+        .map(|(_, data)| data)
+        .collect();
+    assert_eq!(messages, [b"HELLO\n".to_vec()]);
+    assert_eq!(server.accept_stats().filtered, 1);
+}
+
+/// Access log destination the test can read back
+#[derive(Clone, Default)]
+struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedLog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn access_log_records_each_message() {
+    let log = SharedLog::default();
+    let (mut server, addr, shutdown) = start_test_server(VersionHandler("v1"));
+    server.add_middleware(AccessLog::new(log.clone(), AccessLogFormat::Json));
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut reply = [0u8; 2];
+    for request in [&b"?"[..], b"???"] {
+        client.write_all(request).unwrap();
+        client.read_exact(&mut reply).unwrap();
+    }
+    assert!(wait_for(|| log
+        .0
+        .lock()
+        .unwrap()
+        .split(|&b| b == b'\n')
+        .count()
+        > 2));
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+    let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 2, "{log}");
+    let peer = client.local_addr().unwrap();
+    for (line, bytes_in) in lines.iter().zip([1, 3]) {
+        assert!(line.starts_with("{\"time\":\""), "{line}");
+        assert!(
+            line.contains(&format!("\"peer_addr\":\"{peer}\"")),
+            "{line}"
+        );
+        assert!(
+            line.contains(&format!("\"bytes_in\":{bytes_in},\"bytes_out\":2,")),
+            "{line}"
+        );
+        assert!(line.ends_with("\"action\":\"reply\"}"), "{line}");
+    }
+}
+
+#[test]
+fn audit_events_follow_each_connection() {
+    let (sender, events) = std::sync::mpsc::channel();
+    let (mut server, addr, shutdown) = start_test_server(CountingHandler::default());
+    server.set_audit_sink(sender);
+    let handle = thread::spawn(move || {
+        server.run(Some(10)).unwrap();
+        server
+    });
+
+    let leaving = TcpStream::connect(addr).unwrap();
+    let staying = TcpStream::connect(addr).unwrap();
+    let (leaving_addr, staying_addr) =
+        (leaving.local_addr().unwrap(), staying.local_addr().unwrap());
+    let accepted: Vec<(ClientId, SocketAddr)> = (0..2)
+        .map(
+            |_| match events.recv_timeout(Duration::from_secs(2)).unwrap() {
+                AuditEvent::Accepted {
+                    client_id,
+                    peer_addr,
+                    listener_id,
+                } => {
+                    assert_eq!(listener_id, PRIMARY_LISTENER);
+                    (client_id, peer_addr)
+                }
+                other => panic!("unexpected event {other:?}"),
+            },
+        )
+        .collect();
+    let id_of = |addr| accepted.iter().find(|(_, peer)| *peer == addr).unwrap().0;
+
+    drop(leaving);
+    assert_eq!(
+        events.recv_timeout(Duration::from_secs(2)).unwrap(),
+        AuditEvent::Disconnected {
+            client_id: id_of(leaving_addr),
+            peer_addr: leaving_addr,
+            reason: DisconnectReason::PeerClosed,
+        }
+    );
+
+    shutdown.store(true, Ordering::Relaxed);
+    drop(handle.join().unwrap());
+    assert_eq!(
+        events.recv_timeout(Duration::from_secs(2)).unwrap(),
+        AuditEvent::Disconnected {
+            client_id: id_of(staying_addr),
+            peer_addr: staying_addr,
+            reason: DisconnectReason::ServerShutdown,
+        }
+    );
+}
+
+/// Processes uploads in fixed size records, as soon as they arrive
+#[derive(Default)]
+struct RecordHandler {
+    consumed: Arc<AtomicUsize>,
+    largest_chunk: Arc<AtomicUsize>,
+}
+
+/// Size of the records `RecordHandler` consumes
+const RECORD_LEN: usize = 1000;
+
+impl EventHandler for RecordHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        unreachable!("streamed clients get chunks");
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        unreachable!("streamed clients get chunks");
+    }
+
+    fn on_data_chunk(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: &[u8],
+    ) -> std::io::Result<ConsumeResult> {
+        self.largest_chunk.fetch_max(data.len(), Ordering::SeqCst);
+        // One record at a time, the rest is passed again
+        if data.len() < RECORD_LEN {
+            return Ok(ConsumeResult::Consumed(0));
+        }
+        self.consumed.fetch_add(RECORD_LEN, Ordering::SeqCst);
+        Ok(ConsumeResult::Consumed(RECORD_LEN))
+    }
+}
+
+#[test]
+fn streaming_reads_keep_memory_bounded() {
+    let handler = RecordHandler::default();
+    let (consumed, largest_chunk) = (handler.consumed.clone(), handler.largest_chunk.clone());
+    let config = ServerConfig::default().streaming_reads(true);
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", handler, config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let upload_len = 4 * 1024 * RECORD_LEN;
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&vec![7u8; upload_len]).unwrap();
+    assert!(wait_for(|| consumed.load(Ordering::SeqCst) == upload_len));
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+    // Never more than one read on top of an unfinished record
+    assert!(largest_chunk.load(Ordering::SeqCst) < RECORD_LEN + 4096);
+}
+
+struct ChatterHandler;
+
+impl EventHandler for ChatterHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut ServerContext,
+        client_id: ClientId,
+        _data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        for i in 0..2000 {
+            ctx.send_to(client_id, format!("{i:04}\n"))?;
+            if i % 500 == 0 {
+                // Too large to coalesce, keeps its place among the others
+                ctx.send_to(client_id, vec![b'#'; 2048])?;
+            }
+        }
+        Ok(HandlerAction::None)
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+#[test]
+fn coalesced_messages_arrive_in_order() {
+    let config = ServerConfig::default().coalesce_writes(1024);
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", ChatterHandler, config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client.write_all(b"go").unwrap();
+
+    let mut expected = Vec::new();
+    for i in 0..2000 {
+        expected.extend_from_slice(format!("{i:04}\n").as_bytes());
+        if i % 500 == 0 {
+            expected.extend_from_slice(&[b'#'; 2048]);
+        }
+    }
+    let mut received = vec![0u8; expected.len()];
+    client.read_exact(&mut received).unwrap();
+    assert!(received == expected, "messages arrived out of order");
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+/// Handshakes with a `knock <name> [<protocol>]` line, then frames lines
+/// like `LineCodec`, standing in for a TLS codec
+#[derive(Default)]
+struct KnockCodec {
+    name: Option<String>,
+    protocol: Option<Vec<u8>>,
+}
+
+impl Codec for KnockCodec {
+    fn decode(&mut self, buf: &[u8]) -> std::io::Result<Option<(usize, Frame)>> {
+        let Some(end) = buf.iter().position(|&byte| byte == b'\n') else {
+            return Ok(None);
+        };
+        let line = String::from_utf8_lossy(&buf[..end]).into_owned();
+        match &self.name {
+            None => {
+                let mut words = line.trim_start_matches("knock ").split(' ');
+                self.name = words.next().map(str::to_owned);
+                self.protocol = words.next().map(|protocol| protocol.as_bytes().to_vec());
+                Ok(Some((end + 1, Frame::Consumed)))
+            }
+            Some(_) => Ok(Some((end + 1, Frame::Message(line.into_bytes())))),
+        }
+    }
+
+    fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        [data, b"\n"].concat()
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::Line
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.name.is_none()
+    }
+
+    fn peer_identity(&self) -> Option<PeerIdentity> {
+        let identity = PeerIdentity::new(Vec::new(), self.name.clone());
+        Some(identity.with_alpn_protocol(self.protocol.clone()))
+    }
+}
+
+/// Echoes the messages of the clients that knocked as `friend`
+#[derive(Default)]
+struct GatekeeperHandler {
+    messages: Arc<Mutex<Vec<Bytes>>>,
+}
+
+impl EventHandler for GatekeeperHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        self.messages.lock().unwrap().push(data.clone());
+        Ok(HandlerAction::Reply(data))
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+
+    fn on_tls_handshake(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        peer_identity: &PeerIdentity,
+    ) -> std::io::Result<()> {
+        match peer_identity.server_name.as_deref() {
+            Some("friend") => Ok(()),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "unknown peer",
+            )),
+        }
+    }
+}
+
+#[test]
+fn handshake_hook_authorizes_clients_before_their_data() {
+    let handler = GatekeeperHandler::default();
+    let messages = handler.messages.clone();
+    let config = ServerConfig::default().codec(|| Box::new(KnockCodec::default()));
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", handler, config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut friend = TcpStream::connect(addr).unwrap();
+    friend
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    friend.write_all(b"knock friend\nhello\n").unwrap();
+    let mut reply = [0u8; 6];
+    friend.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"hello\n");
+
+    // The stranger's message arrives along with its handshake, and is
+    // dropped with the connection
+    let mut stranger = TcpStream::connect(addr).unwrap();
+    stranger
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    stranger.write_all(b"knock stranger\nsecret\n").unwrap();
+    let mut rest = Vec::new();
+    stranger.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(*messages.lock().unwrap(), [Bytes::from(b"hello".to_vec())]);
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+/// Replies to every message with its tag, recording who it served
+struct TaggedHandler {
+    tag: &'static str,
+    served: Arc<Mutex<Vec<(&'static str, ClientId)>>>,
+}
+
+impl EventHandler for TaggedHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        self.served.lock().unwrap().push((self.tag, client_id));
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        let reply = [self.tag.as_bytes(), b":", &data].concat();
+        Ok(HandlerAction::Reply(reply.into()))
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+#[test]
+fn alpn_dispatcher_routes_clients_by_protocol() {
+    let served = Arc::new(Mutex::new(Vec::new()));
+    let tagged = |tag| TaggedHandler {
+        tag,
+        served: served.clone(),
+    };
+    let dispatcher = AlpnDispatcher::new(tagged("raw"))
+        .route("h2", tagged("h2"))
+        .route("http/1.1", tagged("http"));
+    assert_eq!(
+        dispatcher.protocols(),
+        [b"h2".to_vec(), b"http/1.1".to_vec()]
+    );
+    let config = ServerConfig::default().codec(|| Box::new(KnockCodec::default()));
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", dispatcher, config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    for (knock, expected) in [
+        ("knock a http/1.1\nping\n", "http:ping\n"),
+        ("knock b h2\nping\n", "h2:ping\n"),
+        ("knock c spdy/3\nping\n", "raw:ping\n"),
+        ("knock d\nping\n", "raw:ping\n"),
+    ] {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        client.write_all(knock.as_bytes()).unwrap();
+        let mut reply = vec![0u8; expected.len()];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, expected.as_bytes());
+    }
+    let tags: Vec<_> = served.lock().unwrap().iter().map(|(tag, _)| *tag).collect();
+    assert_eq!(tags, ["http", "h2", "raw", "raw"]);
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+/// Answers every request with its target
+struct TargetHandler;
+
+impl EventHandler for TargetHandler {
+    fn on_connection(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        _listener_id: ListenerId,
+        _stream: &TcpStream,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+        data: Bytes,
+    ) -> std::io::Result<HandlerAction> {
+        let request = HttpRequest::parse(&data)?;
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            request.target.len(),
+            request.target
+        );
+        Ok(HandlerAction::Reply(response.into()))
+    }
+
+    fn on_disconnect(
+        &mut self,
+        _ctx: &mut ServerContext,
+        _client_id: ClientId,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn is_data_complete(&mut self, _data: &[u8]) -> bool {
+        true
+    }
+}
+
+#[test]
+fn http_codec_keeps_alive_and_answers_pipelined_requests_in_order() {
+    let config = ServerConfig::default().codec(|| Box::new(HttpCodec::new()));
+    let mut server = EpollServer::new_with_config("127.0.0.1:0", TargetHandler, config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_signal();
+    let handle = thread::spawn(move || server.run(Some(10)).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let ok = |target: &str| format!("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{target}");
+
+    // The connection outlives a response
+    client.write_all(b"GET /a HTTP/1.1\r\n\r\n").unwrap();
+    let mut reply = vec![0u8; ok("/a").len()];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, ok("/a").as_bytes());
+
+    // Pipelined requests are answered in order, the last one closes
+    client
+        .write_all(
+            b"GET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\nConnection: close\r\n\r\n\
+GET /d HTTP/1.1\r\n\r\n",
+        )
+        .unwrap();
+    let mut replies = String::new();
+    client.read_to_string(&mut replies).unwrap();
+    assert_eq!(replies, ok("/b") + &ok("/c"));
+
+    // A bad request is answered and closes the connection
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    client.write_all(b"nonsense\r\n\r\n").unwrap();
+    let mut reply = String::new();
+    client.read_to_string(&mut reply).unwrap();
+    assert!(reply.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
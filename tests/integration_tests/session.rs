@@ -0,0 +1,41 @@
+use std::{env, fs, process};
+
+use epoll_worker::SessionStore;
+
+#[test]
+fn sessions_survive_a_restart() {
+    let path = env::temp_dir().join(format!("epoll-worker-sessions-{}.json", process::id()));
+
+    let mut sessions = SessionStore::new();
+    sessions
+        .insert("token-a", &("alice".to_string(), 3u32))
+        .unwrap();
+    sessions.insert("token-b", &vec![1u8, 2, 3]).unwrap();
+    sessions.save(&path).unwrap();
+
+    let mut restored = SessionStore::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(restored.len(), 2);
+    assert_eq!(
+        restored.take::<(String, u32)>("token-a").unwrap(),
+        Some(("alice".to_string(), 3))
+    );
+    assert_eq!(
+        restored.take::<Vec<u8>>("token-b").unwrap(),
+        Some(vec![1, 2, 3])
+    );
+
+    // Tokens are single use and unknown ones find nothing
+    assert_eq!(restored.take::<Vec<u8>>("token-b").unwrap(), None);
+    assert!(restored.is_empty());
+
+    // A store that was never saved starts empty
+    assert!(SessionStore::load(&path).unwrap().is_empty());
+}
+
+#[test]
+fn mismatched_session_type_is_an_error() {
+    let mut sessions = SessionStore::new();
+    sessions.insert("token", &"not a number").unwrap();
+    assert!(sessions.take::<u64>("token").is_err());
+}